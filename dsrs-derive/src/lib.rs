@@ -0,0 +1,299 @@
+//! `#[derive(Signature)]`, re-exported as `dsrs::Signature`.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{Attribute, Data, DeriveInput, Fields, Ident, LitStr, Type, parse_macro_input};
+
+/// Implements `DSPySignature` for a struct whose fields are marked
+/// `#[input(desc = "...")]` or `#[output(desc = "...")]`, generating
+/// `{Name}Input` and `{Name}Output` structs from them. The instruction comes
+/// from `#[signature(instruction = "...")]`, or else the doc comment. See
+/// `dsrs::signature!` for the prompt and parsing format, which is the same.
+#[proc_macro_derive(Signature, attributes(signature, input, output))]
+pub fn derive_signature(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Which side of the signature a field is on.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Input,
+    Output,
+}
+
+struct Field<'a> {
+    name: &'a Ident,
+    ty: &'a Type,
+    desc: String,
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &input.generics,
+            "Signature can't be derived for generic structs",
+        ));
+    }
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    name,
+                    "Signature needs named fields",
+                ));
+            }
+        },
+        _ => return Err(syn::Error::new_spanned(name, "Signature needs a struct")),
+    };
+
+    let mut inputs = Vec::new();
+    let mut outputs = Vec::new();
+    for field in fields {
+        let Some(ident) = &field.ident else {
+            continue;
+        };
+        let (side, desc) = field_attr(ident, &field.attrs)?;
+        let field = Field {
+            name: ident,
+            ty: &field.ty,
+            desc,
+        };
+        match side {
+            Side::Input => inputs.push(field),
+            Side::Output => outputs.push(field),
+        }
+    }
+    if inputs.is_empty() || outputs.is_empty() {
+        return Err(syn::Error::new_spanned(
+            name,
+            "Signature needs at least one #[input] and one #[output] field",
+        ));
+    }
+    let instruction = instruction(name, &input.attrs)?;
+
+    let vis = &input.vis;
+    let input_name = format_ident!("{name}Input");
+    let output_name = format_ident!("{name}Output");
+    let input_doc = format!("Input of [`{name}`].");
+    let output_doc = format!("Output of [`{name}`].");
+    let struct_fields = |fields: &[Field]| {
+        fields
+            .iter()
+            .map(|Field { name, ty, desc }| quote! { #[doc = #desc] pub #name: #ty, })
+            .collect::<TokenStream2>()
+    };
+    let input_fields = struct_fields(&inputs);
+    let output_fields = struct_fields(&outputs);
+    let in_names: Vec<&Ident> = inputs.iter().map(|field| field.name).collect();
+    let out_names: Vec<&Ident> = outputs.iter().map(|field| field.name).collect();
+    let field_metas = |fields: &[Field]| {
+        fields
+            .iter()
+            .map(|Field { name, desc, .. }| {
+                let name = name.to_string();
+                quote! {
+                    (#name, ::dsrs::signatures::FieldMeta { desc: Some(#desc), constraints: None })
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+    let (input_metas, output_metas) = (field_metas(&inputs), field_metas(&outputs));
+    let field_specs = |fields: &[Field]| {
+        fields
+            .iter()
+            .map(|Field { name, ty, desc }| {
+                let name = name.to_string();
+                quote! {
+                    private::FieldSpec {
+                        name: #name,
+                        desc: #desc,
+                        optional: private::is_optional::<#ty>(),
+                    }
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+    let (input_specs, output_specs) = (field_specs(&inputs), field_specs(&outputs));
+    let in_strs: Vec<String> = in_names.iter().map(ToString::to_string).collect();
+    let out_strs: Vec<String> = out_names.iter().map(ToString::to_string).collect();
+
+    Ok(quote! {
+        #[doc = #input_doc]
+        #[derive(Clone, Debug, PartialEq, ::serde::Serialize, ::serde::Deserialize)]
+        #vis struct #input_name { #input_fields }
+
+        #[doc = #output_doc]
+        #[derive(Clone, Debug, PartialEq, ::serde::Serialize, ::serde::Deserialize)]
+        #vis struct #output_name { #output_fields }
+
+        impl #name {
+            /// The task instruction at the top of the prompt.
+            pub const INSTRUCTION: &'static str = #instruction;
+
+            /// The input fields by name, in prompt order.
+            pub fn input_fields() -> ::std::vec::Vec<(&'static str, ::dsrs::signatures::FieldMeta)> {
+                ::std::vec![#(#input_metas),*]
+            }
+
+            /// The output fields by name, in prompt order.
+            pub fn output_fields() -> ::std::vec::Vec<(&'static str, ::dsrs::signatures::FieldMeta)> {
+                ::std::vec![#(#output_metas),*]
+            }
+        }
+
+        impl ::std::convert::From<#name> for ::dsrs::Example<#name> {
+            fn from(value: #name) -> Self {
+                ::dsrs::Example::new(
+                    #input_name { #(#in_names: value.#in_names),* },
+                    #output_name { #(#out_names: value.#out_names),* },
+                )
+            }
+        }
+
+        impl ::dsrs::signatures::DSPySignature for #name {
+            type Input = #input_name;
+            type Output = #output_name;
+
+            fn generate_prompt(&self, input: &Self::Input) -> ::std::string::String {
+                self.generate_prompt_with_demos(input, &[])
+            }
+
+            fn format_demo(&self, input: &Self::Input, output: &Self::Output) -> ::std::string::String {
+                use ::dsrs::signatures::__private as private;
+                private::format_demo(
+                    &[#(#in_strs),*],
+                    &private::to_value(input),
+                    &[#(#out_strs),*],
+                    &private::to_value(output),
+                )
+            }
+
+            fn generate_prompt_with_demos(
+                &self,
+                input: &Self::Input,
+                demos: &[::std::string::String],
+            ) -> ::std::string::String {
+                use ::dsrs::signatures::__private as private;
+                private::render_prompt(
+                    #instruction,
+                    &[#(#input_specs),*],
+                    &[#(#output_specs),*],
+                    demos,
+                    &private::to_value(input),
+                )
+            }
+
+            fn parse_output(
+                &self,
+                response: &str,
+            ) -> ::std::result::Result<Self::Output, ::std::boxed::Box<dyn ::std::error::Error>> {
+                use ::dsrs::signatures::__private as private;
+                let fields = private::split_fields(response, &[#(#out_strs),*]);
+                Ok(#output_name {
+                    #(#out_names: private::field(&fields, #out_strs)?,)*
+                })
+            }
+        }
+    })
+}
+
+/// A field's side and description.
+type FieldAttr = (Side, String);
+
+/// The side and description of a field, from its `#[input]` or `#[output]`
+/// attribute. Without a `desc`, the field's doc comment is used.
+fn field_attr(name: &Ident, attrs: &[Attribute]) -> syn::Result<FieldAttr> {
+    let mut found = None;
+    for attr in attrs {
+        let side = if attr.path().is_ident("input") {
+            Side::Input
+        } else if attr.path().is_ident("output") {
+            Side::Output
+        } else {
+            continue;
+        };
+        if found.is_some() {
+            return Err(syn::Error::new_spanned(
+                attr,
+                "a field can only have one #[input] or #[output] attribute",
+            ));
+        }
+        let mut desc = None;
+        if !matches!(attr.meta, syn::Meta::Path(_)) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("desc") {
+                    desc = Some(meta.value()?.parse::<LitStr>()?.value());
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `desc = \"...\"`"))
+                }
+            })?;
+        }
+        found = Some((side, desc));
+    }
+    let Some((side, desc)) = found else {
+        return Err(syn::Error::new_spanned(
+            name,
+            "every field needs #[input(desc = \"...\")] or #[output(desc = \"...\")]",
+        ));
+    };
+    match desc.or_else(|| doc_comment(attrs)) {
+        Some(desc) => Ok((side, desc)),
+        None => Err(syn::Error::new_spanned(
+            name,
+            "missing description; add `desc = \"...\"` or a doc comment",
+        )),
+    }
+}
+
+/// The instruction from `#[signature(instruction = "...")]` or the doc
+/// comment.
+fn instruction(name: &Ident, attrs: &[Attribute]) -> syn::Result<String> {
+    let mut instruction = None;
+    for attr in attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("signature"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("instruction") {
+                instruction = Some(meta.value()?.parse::<LitStr>()?.value());
+                Ok(())
+            } else {
+                Err(meta.error("expected `instruction = \"...\"`"))
+            }
+        })?;
+    }
+    instruction.or_else(|| doc_comment(attrs)).ok_or_else(|| {
+        syn::Error::new_spanned(
+            name,
+            "missing instruction; add #[signature(instruction = \"...\")] or a doc comment",
+        )
+    })
+}
+
+/// The lines of the doc comment, trimmed and joined with spaces.
+fn doc_comment(attrs: &[Attribute]) -> Option<String> {
+    let lines: Vec<String> = attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("doc"))
+        .filter_map(|attr| match &attr.meta {
+            syn::Meta::NameValue(syn::MetaNameValue {
+                value:
+                    syn::Expr::Lit(syn::ExprLit {
+                        lit: syn::Lit::Str(text),
+                        ..
+                    }),
+                ..
+            }) => Some(text.value().trim().to_string()),
+            _ => None,
+        })
+        .filter(|line| !line.is_empty())
+        .collect();
+    (!lines.is_empty()).then(|| lines.join(" "))
+}
@@ -0,0 +1,168 @@
+//! Batch scheduling for [`LLMClient::complete_batch`](crate::LLMClient::complete_batch).
+//!
+//! Items are dispatched from a priority queue by a fixed pool of workers, so
+//! at most `concurrency` requests are in flight and higher-priority items are
+//! always started before lower-priority ones. Equal priorities run in input
+//! order. Because a batch is finite and every queued item is eventually
+//! popped, low-priority items are delayed but never starved. Priorities only
+//! order items *within* one batch; concurrent batches sharing a client compete
+//! for its `max_concurrent_requests` slots first-come, first-served.
+
+use futures_util::future::join_all;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::future::Future;
+use std::sync::Mutex;
+
+/// A batch prompt with a scheduling priority.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BatchItem {
+    pub prompt: String,
+    /// Higher values are dispatched first. Defaults to 0.
+    pub priority: i32,
+}
+
+impl BatchItem {
+    /// Creates an item with the default priority.
+    pub fn new(prompt: impl Into<String>) -> Self {
+        Self {
+            prompt: prompt.into(),
+            priority: 0,
+        }
+    }
+
+    /// Sets the scheduling priority.
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+/// Anything that can be submitted to a batch: plain prompts or [`BatchItem`]s.
+pub trait BatchPrompt {
+    fn prompt(&self) -> &str;
+
+    fn priority(&self) -> i32 {
+        0
+    }
+}
+
+impl BatchPrompt for BatchItem {
+    fn prompt(&self) -> &str {
+        &self.prompt
+    }
+
+    fn priority(&self) -> i32 {
+        self.priority
+    }
+}
+
+impl BatchPrompt for String {
+    fn prompt(&self) -> &str {
+        self
+    }
+}
+
+impl BatchPrompt for &str {
+    fn prompt(&self) -> &str {
+        self
+    }
+}
+
+/// A queued item: highest priority first, then lowest index first.
+#[derive(PartialEq, Eq)]
+struct Queued {
+    priority: i32,
+    index: usize,
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.index.cmp(&self.index))
+    }
+}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Runs `task(index)` for every priority in `priorities` with at most
+/// `concurrency` tasks at once, returning results in input order.
+pub(crate) async fn run_prioritized<T, F, Fut>(
+    priorities: &[i32],
+    concurrency: usize,
+    task: F,
+) -> Vec<T>
+where
+    F: Fn(usize) -> Fut,
+    Fut: Future<Output = T>,
+{
+    let queue: BinaryHeap<Queued> = priorities
+        .iter()
+        .enumerate()
+        .map(|(index, &priority)| Queued { priority, index })
+        .collect();
+    let queue = Mutex::new(queue);
+    let next = || {
+        queue
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .pop()
+    };
+
+    let workers = (0..concurrency.clamp(1, priorities.len().max(1))).map(|_| async {
+        let mut done = Vec::new();
+        while let Some(Queued { index, .. }) = next() {
+            done.push((index, task(index).await));
+        }
+        done
+    });
+
+    let mut results: Vec<_> = join_all(workers).await.into_iter().flatten().collect();
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_higher_priority_dispatches_first() {
+        let order = Mutex::new(Vec::new());
+        let results = run_prioritized(&[0, 5, 0, 9, 5], 1, |index| {
+            order.lock().unwrap().push(index);
+            async move { index * 10 }
+        })
+        .await;
+        assert_eq!(*order.lock().unwrap(), [3, 1, 4, 0, 2]);
+        assert_eq!(results, [0, 10, 20, 30, 40]);
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_is_bounded() {
+        let in_flight = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        run_prioritized(&[0; 12], 3, |_| async {
+            let now = in_flight.fetch_add(1, AtomicOrdering::SeqCst) + 1;
+            peak.fetch_max(now, AtomicOrdering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            in_flight.fetch_sub(1, AtomicOrdering::SeqCst);
+        })
+        .await;
+        assert_eq!(peak.load(AtomicOrdering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_empty_batch() {
+        let results: Vec<()> = run_prioritized(&[], 4, |_| async {}).await;
+        assert!(results.is_empty());
+    }
+}
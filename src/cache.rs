@@ -0,0 +1,269 @@
+//! Response caching for [`LLMClient`](crate::LLMClient).
+//!
+//! A client built with [`LLMClientBuilder::cache`](crate::client::LLMClientBuilder::cache)
+//! looks every request up by its [`ChatRequest::stable_hash_hex`](crate::client::ChatRequest::stable_hash_hex)
+//! (model, messages, generation settings and output options) before
+//! sending it, and stores successful completions under that key. Hits never
+//! touch the network. Streams, audio and tool requests are not cached.
+//!
+//! A cached response is replayed as is, which only matches what the API
+//! would do at temperature 0: at higher temperatures every call would get a
+//! fresh sample, but a cached client keeps returning the first one.
+//!
+//! The key doesn't include the endpoint or provider, so use separate caches
+//! (or directories) for endpoints that serve different models under the
+//! same name.
+
+use crate::client::Completion;
+use crate::errors::{DSRSError, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+
+/// Stores completions by request key.
+pub trait Cache: Send + Sync {
+    /// The completion stored under `key`, if any.
+    fn get(&self, key: &str) -> Option<Completion>;
+
+    /// Stores `completion` under `key`. Failures aren't reported: a cache
+    /// that can't be written behaves like an empty one.
+    fn put(&self, key: &str, completion: &Completion);
+
+    /// Removes every entry.
+    fn clear(&self) -> Result<()>;
+}
+
+impl fmt::Debug for dyn Cache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Cache")
+    }
+}
+
+/// A cache that lives as long as the process, optionally bounded to a
+/// number of entries.
+#[derive(Debug, Default)]
+pub struct MemoryCache {
+    entries: Mutex<Entries>,
+    capacity: Option<usize>,
+}
+
+/// A completion and the tick of its last use.
+type Entry = (Completion, u64);
+
+/// Entries with the tick of their last use, and the keys by that tick, so
+/// the least recently used one is the first.
+#[derive(Debug, Default)]
+struct Entries {
+    completions: HashMap<String, Entry>,
+    by_use: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl Entries {
+    /// Marks `key` as just used, having last been used at `last_used`.
+    fn touch(&mut self, key: &str, last_used: Option<u64>) -> u64 {
+        if let Some(tick) = last_used {
+            self.by_use.remove(&tick);
+        }
+        self.tick += 1;
+        self.by_use.insert(self.tick, key.to_string());
+        self.tick
+    }
+}
+
+impl MemoryCache {
+    /// An unbounded cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A cache of at most `capacity` entries, evicting the least recently
+    /// used (read or written) when full.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            capacity: Some(capacity),
+            ..Self::default()
+        }
+    }
+
+    fn entries(&self) -> std::sync::MutexGuard<'_, Entries> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Cache for MemoryCache {
+    fn get(&self, key: &str) -> Option<Completion> {
+        let mut entries = self.entries();
+        let (completion, last_used) = entries.completions.get(key)?.clone();
+        let tick = entries.touch(key, Some(last_used));
+        entries
+            .completions
+            .insert(key.to_string(), (completion.clone(), tick));
+        Some(completion)
+    }
+
+    fn put(&self, key: &str, completion: &Completion) {
+        if self.capacity == Some(0) {
+            return;
+        }
+        let mut entries = self.entries();
+        let last_used = entries.completions.get(key).map(|(_, tick)| *tick);
+        let tick = entries.touch(key, last_used);
+        entries
+            .completions
+            .insert(key.to_string(), (completion.clone(), tick));
+        if let Some(capacity) = self.capacity
+            && entries.completions.len() > capacity
+            && let Some((_, oldest)) = entries.by_use.pop_first()
+        {
+            entries.completions.remove(&oldest);
+        }
+    }
+
+    fn clear(&self) -> Result<()> {
+        *self.entries() = Entries::default();
+        Ok(())
+    }
+}
+
+/// A cache of one JSON file per entry in a directory, shared between runs.
+#[derive(Clone, Debug)]
+pub struct DiskCache {
+    dir: PathBuf,
+}
+
+impl DiskCache {
+    /// A cache in `dir`, which is created on the first write.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// The default cache directory: `$DSRS_CACHE_DIR` if set, else
+    /// `dsrs` under `$XDG_CACHE_HOME` or `~/.cache`. `None` if neither a
+    /// cache nor a home directory is configured.
+    pub fn default_dir() -> Option<PathBuf> {
+        let var = |name| std::env::var_os(name).filter(|value| !value.is_empty());
+        if let Some(dir) = var("DSRS_CACHE_DIR") {
+            return Some(PathBuf::from(dir));
+        }
+        var("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| var("HOME").map(|home| Path::new(&home).join(".cache")))
+            .map(|cache| cache.join("dsrs"))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.json"))
+    }
+}
+
+impl Cache for DiskCache {
+    fn get(&self, key: &str) -> Option<Completion> {
+        let text = std::fs::read_to_string(self.path(key)).ok()?;
+        serde_json::from_str(&text).ok()
+    }
+
+    fn put(&self, key: &str, completion: &Completion) {
+        let written = serde_json::to_string(completion)
+            .map_err(|err| err.to_string())
+            .and_then(|json| {
+                std::fs::create_dir_all(&self.dir)
+                    .and_then(|()| std::fs::write(self.path(key), json))
+                    .map_err(|err| err.to_string())
+            });
+        if let Err(err) = written {
+            tracing::warn!(dir = %self.dir.display(), "failed to write cache entry: {err}");
+        }
+    }
+
+    fn clear(&self) -> Result<()> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(clear_error(&self.dir, err)),
+        };
+        for entry in entries {
+            let path = entry.map_err(|err| clear_error(&self.dir, err))?.path();
+            // Only our own entries, in case the directory is shared
+            if path.extension().is_some_and(|ext| ext == "json") {
+                std::fs::remove_file(&path).map_err(|err| clear_error(&path, err))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn clear_error(path: &Path, err: std::io::Error) -> DSRSError {
+    DSRSError::ConfigError(format!("Failed to clear cache {}: {err}", path.display()))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn completion(text: &str) -> Completion {
+        Completion {
+            text: text.to_string(),
+            model: Some("gpt-4o".to_string()),
+            usage: None,
+            finish_reason: Some("stop".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_memory_cache_round_trip() {
+        let cache = MemoryCache::new();
+        assert_eq!(cache.get("key"), None);
+        cache.put("key", &completion("Paris"));
+        assert_eq!(cache.get("key"), Some(completion("Paris")));
+        cache.clear().unwrap();
+        assert_eq!(cache.get("key"), None);
+    }
+
+    #[test]
+    fn test_memory_cache_evicts_least_recently_used() {
+        let cache = MemoryCache::with_capacity(2);
+        cache.put("a", &completion("A"));
+        cache.put("b", &completion("B"));
+        // Reading "a" makes "b" the least recently used
+        assert_eq!(cache.get("a"), Some(completion("A")));
+        cache.put("c", &completion("C"));
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("a"), Some(completion("A")));
+        assert_eq!(cache.get("c"), Some(completion("C")));
+
+        // Overwriting an entry doesn't evict another
+        cache.put("c", &completion("C2"));
+        assert_eq!(cache.get("a"), Some(completion("A")));
+        assert_eq!(cache.get("c"), Some(completion("C2")));
+
+        let none = MemoryCache::with_capacity(0);
+        none.put("a", &completion("A"));
+        assert_eq!(none.get("a"), None);
+    }
+
+    #[test]
+    fn test_disk_cache_round_trip() {
+        let dir = std::env::temp_dir().join(format!("dsrs-cache-{}", std::process::id()));
+        let cache = DiskCache::new(&dir);
+        // Clearing a directory that doesn't exist yet is fine
+        cache.clear().unwrap();
+        assert_eq!(cache.get("key"), None);
+
+        cache.put("key", &completion("Paris"));
+        std::fs::write(dir.join("notes.txt"), "kept").unwrap();
+        // A second handle on the same directory sees the entry
+        assert_eq!(DiskCache::new(&dir).get("key"), Some(completion("Paris")));
+
+        cache.clear().unwrap();
+        assert_eq!(cache.get("key"), None);
+        assert!(dir.join("notes.txt").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
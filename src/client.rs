@@ -1,18 +1,46 @@
-use crate::errors::DSRSError;
+use crate::batch::{BatchPrompt, run_prioritized};
+use crate::cache::{Cache, MemoryCache};
+use crate::config::Config;
+use crate::cost::{CostTracker, Pricing};
+use crate::embeddings::{self, EmbeddingRequest, EmbeddingResponse};
+use crate::errors::{self, DSRSError, Result};
+use crate::provider::Provider;
+use crate::retry::RetryPolicy;
+#[cfg(feature = "stream")]
+use crate::stream::CompletionStream;
+use crate::tokens::{self, ByPrefix, TokenCounter};
+use crate::tools::{Tool, ToolCall, ToolCallResult, ToolChoice};
+use crate::trace::{TraceRecord, TraceWriter};
+use crate::transport::{Header, HttpResponse, HttpTransport, ReqwestTransport};
+use base64::Engine;
 use dotenvy::dotenv;
-use reqwest::{Client, ClientBuilder};
+use flate2::read::GzDecoder;
+#[cfg(feature = "stream")]
+use reqwest::RequestBuilder;
+use reqwest::header::{CONTENT_ENCODING, HeaderName, HeaderValue, RETRY_AFTER};
+use reqwest::{Client, ClientBuilder, NoProxy, Proxy, StatusCode};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use std::fmt;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+pub use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 
-const DEFAULT_LLM_ENDPOINT: &str = "https://api.openai.com/v1/chat/completions";
-#[allow(dead_code)]
 const DEFAULT_MODEL: &str = "gpt-3.5-turbo";
 #[allow(dead_code)]
 const DEFAULT_MAX_TOKENS: u32 = 1000;
 #[allow(dead_code)]
 const DEFAULT_TEMPERATURE: f32 = 0.7;
-const MAX_PROMPT_LENGTH: usize = 32000; // ~8k tokens ≈ 32k chars
 const REQUEST_TIMEOUT_SECS: u64 = 30;
+const JSON_INSTRUCTION: &str = "Respond with a JSON object.";
+/// Upper bound on a decompressed error body, so a hostile one can't exhaust
+/// memory.
+const MAX_ERROR_BODY: u64 = 1 << 20;
 
 /// Request payload for the LLM API.
 #[derive(Serialize)]
@@ -23,47 +51,818 @@ pub struct ChatRequest {
     pub max_tokens: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub temperature: Option<f32>, // New: Optional temperature for creativity
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    /// Output modalities, e.g. `["text", "audio"]`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modalities: Option<Vec<String>>,
+    /// Voice and format for audio output; required when requesting audio.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio: Option<AudioConfig>,
+    /// Constrains the output format, e.g. to JSON matching a schema.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+    /// How many choices to generate; the provider default is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<u32>,
+    /// Tools the model may call instead of answering directly.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<Tool>>,
+    /// Whether and which of the `tools` the model must call.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
+    /// Nucleus sampling: only tokens within this probability mass are
+    /// considered. Between 0 and 1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    /// Sequences that end the completion where they would appear.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+}
+
+/// Generation settings for [`LLMClient::complete_with_params`] and
+/// [`LLMClientBuilder::default_params`]. Unset fields are left to the
+/// provider, or to the client's defaults for a single call.
+///
+/// ```
+/// use dsrs::client::CompletionParams;
+///
+/// let params = CompletionParams::new().max_tokens(200).top_p(0.9).stop(["\n\n"]);
+/// assert_eq!(params.stop.as_deref(), Some(&["\n\n".to_string()][..]));
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompletionParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    /// How many choices to generate; see
+    /// [`LLMClient::complete_n_with_params`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<u32>,
+    /// The most time a call may take, retries included, after which it
+    /// fails with [`DSRSError::Timeout`]. Not recorded in traces.
+    #[serde(skip)]
+    pub timeout: Option<Duration>,
+}
+
+impl CompletionParams {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn max_tokens(mut self, max_tokens: u32) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    /// Sets nucleus sampling; must be between 0 and 1.
+    pub fn top_p(mut self, top_p: f32) -> Self {
+        self.top_p = Some(top_p);
+        self
+    }
+
+    pub fn frequency_penalty(mut self, penalty: f32) -> Self {
+        self.frequency_penalty = Some(penalty);
+        self
+    }
+
+    pub fn presence_penalty(mut self, penalty: f32) -> Self {
+        self.presence_penalty = Some(penalty);
+        self
+    }
+
+    /// Ends completions at any of `sequences`.
+    pub fn stop<S: Into<String>>(mut self, sequences: impl IntoIterator<Item = S>) -> Self {
+        self.stop = Some(sequences.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Asks for `n` choices in one request; must be at least 1.
+    pub fn n(mut self, n: u32) -> Self {
+        self.n = Some(n);
+        self
+    }
+
+    /// Sets a deadline for the whole call, across every retry; unlike
+    /// [`LLMClientBuilder::timeout`], which limits each attempt.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Fails with [`DSRSError::ConfigError`] if `top_p` is outside
+    /// `0.0..=1.0` or `n` is 0.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(top_p) = self.top_p
+            && !(0.0..=1.0).contains(&top_p)
+        {
+            return Err(DSRSError::ConfigError(format!(
+                "top_p must be between 0 and 1, got {top_p}"
+            )));
+        }
+        if self.n == Some(0) {
+            return Err(DSRSError::ConfigError("n must be at least 1".to_string()));
+        }
+        Ok(())
+    }
+
+    /// These params, with unset fields taken from `defaults`.
+    fn or(&self, defaults: &CompletionParams) -> CompletionParams {
+        CompletionParams {
+            max_tokens: self.max_tokens.or(defaults.max_tokens),
+            temperature: self.temperature.or(defaults.temperature),
+            top_p: self.top_p.or(defaults.top_p),
+            frequency_penalty: self.frequency_penalty.or(defaults.frequency_penalty),
+            presence_penalty: self.presence_penalty.or(defaults.presence_penalty),
+            stop: self.stop.clone().or_else(|| defaults.stop.clone()),
+            n: self.n.or(defaults.n),
+            timeout: self.timeout.or(defaults.timeout),
+        }
+    }
+}
+
+/// The `response_format` request field for structured outputs.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    /// Plain text (the provider default).
+    Text,
+    /// Any syntactically valid JSON object.
+    JsonObject,
+    /// JSON conforming to the given schema.
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+impl ResponseFormat {
+    /// Strict structured output matching `schema`.
+    pub fn json_schema(name: impl Into<String>, schema: serde_json::Value) -> Self {
+        ResponseFormat::JsonSchema {
+            json_schema: JsonSchemaFormat {
+                name: name.into(),
+                schema,
+                strict: Some(true),
+            },
+        }
+    }
+}
+
+/// A named JSON schema for [`ResponseFormat::JsonSchema`].
+#[derive(Clone, Debug, Serialize)]
+pub struct JsonSchemaFormat {
+    /// Schema name; letters, digits, underscores and dashes only.
+    pub name: String,
+    pub schema: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
+}
+
+/// Audio output settings for models that support the `audio` modality.
+#[derive(Clone, Debug, Serialize)]
+pub struct AudioConfig {
+    /// Voice to speak with, e.g. `"alloy"`.
+    pub voice: String,
+    /// Encoding of the returned audio, e.g. `"wav"` or `"mp3"`.
+    pub format: String,
+}
+
+impl AudioConfig {
+    /// Creates an audio config with the given voice and format.
+    pub fn new(voice: impl Into<String>, format: impl Into<String>) -> Self {
+        Self {
+            voice: voice.into(),
+            format: format.into(),
+        }
+    }
+}
+
+impl ChatRequest {
+    /// Returns a deterministic 64-bit hash of the request.
+    ///
+    /// The hash covers, in this fixed order: `model`, every message's `role`
+    /// and `content` (message order is significant), `max_tokens`,
+    /// `temperature`, `modalities`, the `audio` voice and format,
+    /// `response_format` (as canonical JSON, with sorted keys), then `n`,
+    /// `tools`, `tool_choice`, `top_p`, the penalties, `stop` and the tool
+    /// fields of messages, each only when set and after a label naming it.
+    /// `stream` is excluded since it only changes how the response is
+    /// delivered, not its content. Fields are length-prefixed and hashed with FNV-1a, so the
+    /// value is stable across runs, platforms and crate versions and does not
+    /// depend on struct field order or JSON key ordering. Use it to key caches
+    /// consistently with the crate's own.
+    pub fn stable_hash(&self) -> u64 {
+        let mut hasher = StableHasher::new();
+        hasher.write_str(&self.model);
+        hasher.write_u64(self.messages.len() as u64);
+        for message in &self.messages {
+            hasher.write_str(&message.role);
+            hasher.write_str(&message.content);
+            if let Some(calls) = &message.tool_calls {
+                hasher.write_json("tool_calls", calls);
+            }
+            if let Some(id) = &message.tool_call_id {
+                hasher.write_str("tool_call_id");
+                hasher.write_str(id);
+            }
+        }
+        hasher.write_opt(self.max_tokens.map(u64::from));
+        hasher.write_opt(self.temperature.map(canonical_f32_bits));
+        hasher.write_present(self.modalities.is_some());
+        if let Some(modalities) = &self.modalities {
+            hasher.write_u64(modalities.len() as u64);
+            for modality in modalities {
+                hasher.write_str(modality);
+            }
+        }
+        hasher.write_present(self.audio.is_some());
+        if let Some(audio) = &self.audio {
+            hasher.write_str(&audio.voice);
+            hasher.write_str(&audio.format);
+        }
+        hasher.write_present(self.response_format.is_some());
+        if let Some(format) = &self.response_format {
+            hasher.write_str(&canonical_json(format));
+        }
+        if let Some(n) = self.n {
+            hasher.write_str("n");
+            hasher.write_u64(u64::from(n));
+        }
+        if let Some(tools) = &self.tools {
+            hasher.write_json("tools", tools);
+        }
+        if let Some(choice) = &self.tool_choice {
+            hasher.write_json("tool_choice", choice);
+        }
+        let sampling = [
+            ("top_p", self.top_p),
+            ("frequency_penalty", self.frequency_penalty),
+            ("presence_penalty", self.presence_penalty),
+        ];
+        for (label, value) in sampling {
+            if let Some(value) = value {
+                hasher.write_str(label);
+                hasher.write_u64(canonical_f32_bits(value));
+            }
+        }
+        if let Some(stop) = &self.stop {
+            hasher.write_json("stop", stop);
+        }
+        hasher.finish()
+    }
+
+    /// Returns [`ChatRequest::stable_hash`] as a 16-character hex string.
+    pub fn stable_hash_hex(&self) -> String {
+        format!("{:016x}", self.stable_hash())
+    }
+}
+
+/// Maps `-0.0` to `0.0` so equal temperatures always hash the same.
+fn canonical_f32_bits(value: f32) -> u64 {
+    let value = if value == 0.0 { 0.0 } else { value };
+    u64::from(value.to_bits())
+}
+
+/// `value` as JSON with every object's keys sorted, whatever order its
+/// fields were declared or its map entries inserted in.
+fn canonical_json(value: &impl Serialize) -> String {
+    fn sorted(value: serde_json::Value) -> serde_json::Value {
+        match value {
+            serde_json::Value::Object(map) => {
+                let mut entries: Vec<_> = map.into_iter().collect();
+                entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+                serde_json::Value::Object(
+                    entries
+                        .into_iter()
+                        .map(|(key, value)| (key, sorted(value)))
+                        .collect(),
+                )
+            }
+            serde_json::Value::Array(values) => {
+                serde_json::Value::Array(values.into_iter().map(sorted).collect())
+            }
+            other => other,
+        }
+    }
+    serde_json::to_value(value)
+        .map(|value| sorted(value).to_string())
+        .unwrap_or_default()
+}
+
+/// 64-bit FNV-1a hasher. `std`'s `DefaultHasher` is not guaranteed to be
+/// stable between Rust releases, which rules it out for persisted keys.
+struct StableHasher(u64);
+
+impl StableHasher {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    fn new() -> Self {
+        Self(Self::OFFSET_BASIS)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(Self::PRIME);
+        }
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.write(&value.to_le_bytes());
+    }
+
+    fn write_str(&mut self, value: &str) {
+        self.write_u64(value.len() as u64);
+        self.write(value.as_bytes());
+    }
+
+    fn write_present(&mut self, present: bool) {
+        self.write(&[u8::from(present)]);
+    }
+
+    /// Writes `value` as canonical JSON after a `label` that keeps it apart
+    /// from the fields around it.
+    fn write_json(&mut self, label: &str, value: &impl Serialize) {
+        self.write_str(label);
+        self.write_str(&canonical_json(value));
+    }
+
+    fn write_opt(&mut self, value: Option<u64>) {
+        self.write_present(value.is_some());
+        if let Some(value) = value {
+            self.write_u64(value);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
 }
 
 /// A single message in a chat conversation.
-#[derive(Serialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Message {
     pub role: String,
     pub content: String,
+    /// The calls an assistant message asked for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// The call a `tool` message answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl Message {
+    /// A message with an arbitrary role.
+    pub fn new(role: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            role: role.into(),
+            content: content.into(),
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    /// Instructions that steer the whole conversation.
+    pub fn system(content: impl Into<String>) -> Self {
+        Self::new("system", content)
+    }
+
+    /// A turn from the user.
+    pub fn user(content: impl Into<String>) -> Self {
+        Self::new("user", content)
+    }
+
+    /// A previous reply from the model.
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self::new("assistant", content)
+    }
+
+    /// A previous reply in which the model asked for `calls`, to send back
+    /// ahead of their results.
+    pub fn assistant_tool_calls(calls: Vec<ToolCall>) -> Self {
+        Self {
+            tool_calls: Some(calls),
+            ..Self::assistant("")
+        }
+    }
+
+    /// The result of running the tool call with id `call_id`.
+    pub fn tool(call_id: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            tool_call_id: Some(call_id.into()),
+            ..Self::new("tool", content)
+        }
+    }
 }
 
 /// Response from LLM Chat Completions API.
 #[derive(Deserialize)]
 pub struct ChatResponse {
+    /// Missing when the body only carries an `error`.
+    #[serde(default)]
     pub choices: Vec<Choice>,
     #[serde(default)] // Handle cases where error might be present
     pub error: Option<ApiError>, // New: Parse error field if present
+    /// The model that actually served the request, as echoed by the provider.
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub usage: Option<Usage>,
 }
 
 /// A single choice/completion from the API response.
 #[derive(Deserialize)]
 pub struct Choice {
+    /// Position among the choices of a request with [`ChatRequest::n`] set.
+    #[serde(default)]
+    pub index: u32,
     pub message: MessageResponse,
+    /// Why generation stopped: `"stop"`, `"length"` (hit `max_tokens`), ...
+    #[serde(default)]
+    pub finish_reason: Option<String>,
+}
+
+/// Token counts the provider billed for a request.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    #[serde(default)]
+    pub prompt_tokens: u32,
+    #[serde(default)]
+    pub completion_tokens: u32,
+    #[serde(default)]
+    pub total_tokens: u32,
 }
 
 /// The message content within a choice.
 #[derive(Deserialize)]
 pub struct MessageResponse {
-    pub content: String,
+    /// Text content; may be `null` when the model only returned audio.
+    #[serde(default)]
+    pub content: Option<MessageContent>,
+    #[serde(default)]
+    pub audio: Option<AudioResponse>,
+    /// Tools the model wants called; usually there is no text alongside.
+    #[serde(default)]
+    pub tool_calls: Option<Vec<ToolCall>>,
+}
+
+impl MessageResponse {
+    /// The message text, with array content flattened into one string.
+    pub fn text(&self) -> Option<String> {
+        self.content.as_ref().map(MessageContent::text)
+    }
+
+    /// The requested tool calls if there are any, else the text.
+    pub fn into_tool_result(self) -> ToolCallResult {
+        match self.tool_calls {
+            Some(calls) if !calls.is_empty() => ToolCallResult::ToolCalls(calls),
+            _ => ToolCallResult::Text(self.text().unwrap_or_default()),
+        }
+    }
+}
+
+/// Message content, which providers send either as a plain string or as an
+/// array of typed parts (e.g. for vision or multimodal responses).
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+impl MessageContent {
+    /// The text of the content; for parts, every part's `text` concatenated.
+    pub fn text(&self) -> String {
+        match self {
+            MessageContent::Text(text) => text.clone(),
+            MessageContent::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| part.text.as_deref())
+                .collect(),
+        }
+    }
+}
+
+/// One typed part of array-form [`MessageContent`].
+#[derive(Clone, Debug, Deserialize)]
+pub struct ContentPart {
+    /// Part type, e.g. `"text"` or `"image_url"`.
+    #[serde(rename = "type")]
+    pub part_type: String,
+    #[serde(default)]
+    pub text: Option<String>,
+    /// Any other fields of the part, kept for advanced access.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Audio returned for requests using the `audio` modality.
+#[derive(Deserialize)]
+pub struct AudioResponse {
+    pub id: String,
+    /// Base64-encoded audio in the requested format.
+    pub data: String,
+    #[serde(default)]
+    pub transcript: Option<String>,
+    #[serde(default)]
+    pub expires_at: Option<u64>,
 }
 
+/// A completion that may include spoken audio alongside its text.
+#[derive(Debug)]
+pub struct AudioCompletion {
+    /// Text content, falling back to the audio transcript.
+    pub text: String,
+    /// Decoded audio bytes, in the format from [`AudioConfig::format`].
+    pub audio: Option<Vec<u8>>,
+    /// Provider id of the audio, for referencing it in later turns.
+    pub audio_id: Option<String>,
+}
+
+/// A completed response, as returned by [`LLMClient::complete_detailed`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Completion {
+    /// The generated text.
+    pub text: String,
+    /// The model that served the request, if the provider reported it.
+    pub model: Option<String>,
+    /// Billed token counts, if the provider reported them.
+    pub usage: Option<Usage>,
+    /// Why generation stopped, e.g. `"stop"` or `"length"`.
+    pub finish_reason: Option<String>,
+}
+
+impl Completion {
+    /// Whether generation was cut off by `max_tokens` (`finish_reason ==
+    /// "length"`), meaning the text is likely incomplete.
+    pub fn is_truncated(&self) -> bool {
+        self.finish_reason.as_deref() == Some("length")
+    }
+
+    /// Parses the text as JSON into `T`, ignoring a surrounding Markdown code
+    /// fence (```` ```json ... ``` ````) if the model added one.
+    ///
+    /// Fails with [`DSRSError::ParseError`] holding the full text.
+    pub fn into_result<T: DeserializeOwned>(self) -> Result<T> {
+        serde_json::from_str(strip_code_fences(&self.text)).map_err(|err| DSRSError::ParseError {
+            message: err.to_string(),
+            raw: self.text,
+        })
+    }
+}
+
+/// Returns the contents of the first Markdown code fence in `text`, or all
+/// of `text` (trimmed) if it has none.
+pub(crate) fn strip_code_fences(text: &str) -> &str {
+    let Some(start) = text.find("```") else {
+        return text.trim();
+    };
+    // Skip the info string, e.g. `json`
+    let body = &text[start + 3..];
+    let body = body.find('\n').map_or("", |newline| &body[newline + 1..]);
+    let end = body.find("```").unwrap_or(body.len());
+    body[..end].trim()
+}
+
+/// The body of an error response: `{"error": {...}}`.
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: ApiError,
+}
+
+/// An error the provider described in a response body.
 #[derive(Deserialize)]
 pub struct ApiError {
     pub message: String,
-    #[serde(rename = "type")]
+    /// Empty when the provider didn't give one.
+    #[serde(default, rename = "type")]
     pub error_type: String,
     pub code: Option<String>,
 }
 
+/// The message, followed by the type and code when there are any.
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.error_type.is_empty() && self.code.is_none() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(
+                f,
+                "{} (type: {}, code: {:?})",
+                self.message, self.error_type, self.code
+            )
+        }
+    }
+}
+
+/// What to do when a prompt doesn't fit the model's context window along
+/// with the requested `max_tokens`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TooLongPolicy {
+    /// Reject the request with [`DSRSError::PromptTooLong`].
+    #[default]
+    Error,
+    /// Drop characters from the start of the prompt, keeping the end.
+    TruncateStart,
+    /// Drop characters from the end of the prompt, keeping the start.
+    TruncateEnd,
+}
+
+impl TooLongPolicy {
+    /// Applies the policy to `prompt`, keeping as many whole chars as fit in
+    /// `budget` tokens as measured by `count`.
+    fn apply(self, prompt: &str, budget: usize, count: impl Fn(&str) -> usize) -> Result<&str> {
+        let tokens = count(prompt);
+        if tokens <= budget {
+            return Ok(prompt);
+        }
+        if self == TooLongPolicy::Error {
+            return Err(DSRSError::PromptTooLong(tokens, budget));
+        }
+        let boundaries: Vec<usize> = prompt
+            .char_indices()
+            .map(|(index, _)| index)
+            .chain([prompt.len()])
+            .collect();
+        let chars = boundaries.len() - 1;
+        let keep = |kept_chars: usize| match self {
+            TooLongPolicy::TruncateStart => &prompt[boundaries[chars - kept_chars]..],
+            _ => &prompt[..boundaries[kept_chars]],
+        };
+        // Binary search for the longest slice that fits: `low` always fits
+        // and `high` never does
+        let (mut low, mut high) = (0, chars);
+        while high - low > 1 {
+            let mid = low + (high - low) / 2;
+            if count(keep(mid)) <= budget {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        let kept = keep(low);
+        tracing::warn!(
+            policy = ?self,
+            tokens,
+            budget,
+            dropped_chars = chars - low,
+            "prompt too long, truncating"
+        );
+        Ok(kept)
+    }
+}
+
+/// The pluggable parts a client shares with its clones.
+type SharedCounter = Arc<dyn TokenCounter>;
+type SharedCache = Arc<dyn Cache>;
+type SharedTransport = Arc<dyn HttpTransport>;
+
+/// A request ready to send, with the call's deadline.
+type Deadlined = (ChatRequest, Option<Duration>);
+
+/// A header's name and value, borrowed for logging.
+type HeaderRef<'a> = (&'a str, &'a str);
+
 /// HTTP client for interacting with LLM providers via OpenAI-compatible API.
-#[derive(Debug)]
+///
+/// Clones share the connection pool, concurrency limit, cache and
+/// [`CostTracker`].
+#[derive(Clone, Debug)]
 pub struct LLMClient {
+    /// Used for streams; other requests go through `transport`.
+    #[cfg(feature = "stream")]
     client: Client,
+    transport: Arc<dyn HttpTransport>,
+    too_long_policy: TooLongPolicy,
+    concurrency: Option<Arc<Semaphore>>,
+    retry_policy: RetryPolicy,
+    token_counter: Option<SharedCounter>,
+    context_windows: ByPrefix<usize>,
+    cache: Option<SharedCache>,
+    #[cfg(feature = "stream")]
+    max_stream_duration: Option<Duration>,
+    system_prompt: Option<String>,
+    json_mode: bool,
+    /// `None` for servers that need no key, or for [`LLMClient::new`]
+    /// without credentials; requests then fail with
+    /// [`DSRSError::ConfigError`] unless `auth_optional`.
+    api_key: Option<String>,
+    auth_optional: bool,
+    /// Sends the API key as is in this header instead of the provider's
+    /// authentication header.
+    auth_header: Option<String>,
+    /// Sent with every request, replacing provider headers of the same name.
+    extra_headers: Vec<Header>,
+    endpoint: String,
+    /// Routes by model-name prefix, overriding `endpoint`, `provider` and
+    /// the credentials.
+    routes: ByPrefix<Route>,
+    embeddings_endpoint: String,
+    default_model: String,
+    provider: Provider,
+    hooks: Hooks,
+    default_params: CompletionParams,
+    trace: Option<Arc<TraceWriter>>,
+    cancel: Option<CancellationToken>,
+    costs: Arc<CostTracker>,
+}
+
+/// Where requests for models with a given name prefix go; see
+/// [`LLMClientBuilder::route`]. A URL converts into a route speaking the
+/// OpenAI format without credentials.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Route {
+    endpoint: String,
+    provider: Provider,
+    api_key: Option<String>,
+}
+
+impl Route {
+    /// Sends requests to `endpoint` in the OpenAI format, without
+    /// credentials.
+    pub fn new(endpoint: impl Into<String>) -> Self {
+        Self {
+            endpoint: endpoint.into(),
+            provider: Provider::OpenAI,
+            api_key: None,
+        }
+    }
+
+    /// Speaks `provider`'s API format and sends the key, if any, in its
+    /// authentication header.
+    pub fn provider(mut self, provider: Provider) -> Self {
+        self.provider = provider;
+        self
+    }
+
+    /// Authenticates requests with `key`.
+    pub fn api_key(mut self, key: impl Into<String>) -> Self {
+        self.api_key = Some(key.into()).filter(|key| !key.is_empty());
+        self
+    }
+}
+
+impl From<&str> for Route {
+    fn from(endpoint: &str) -> Self {
+        Self::new(endpoint)
+    }
+}
+
+impl From<String> for Route {
+    fn from(endpoint: String) -> Self {
+        Self::new(endpoint)
+    }
+}
+
+/// Where and how requests for a model are sent.
+struct Target<'a> {
+    endpoint: &'a str,
+    provider: Provider,
+    api_key: Option<&'a str>,
+    /// Sent per a route rather than the client's own settings.
+    routed: bool,
+}
+
+/// Called with every chat request before it's sent; see
+/// [`LLMClientBuilder::on_request`].
+pub type RequestHook = Arc<dyn Fn(&ChatRequest) + Send + Sync>;
+
+/// Called with every chat request and the response it got; see
+/// [`LLMClientBuilder::on_response`].
+pub type ResponseHook = Arc<dyn Fn(&ChatRequest, &HttpResponse) + Send + Sync>;
+
+#[derive(Clone, Default)]
+struct Hooks {
+    on_request: Option<RequestHook>,
+    on_response: Option<ResponseHook>,
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("on_request", &self.on_request.is_some())
+            .field("on_response", &self.on_response.is_some())
+            .finish()
+    }
 }
 
 impl Default for LLMClient {
@@ -72,77 +871,3805 @@ impl Default for LLMClient {
     }
 }
 
-impl LLMClient {
-    /// Creates a new LLM client with default HTTP settings.
-    pub fn new() -> Self {
-        let client = ClientBuilder::new()
-            .timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS))
-            .build()
-            .unwrap_or_else(|_| Client::new());
-        Self { client }
-    }
+/// Builder for [`LLMClient`], created with [`LLMClient::builder`].
+#[derive(Debug, Default)]
+pub struct LLMClientBuilder {
+    too_long_policy: TooLongPolicy,
+    max_concurrent_requests: Option<usize>,
+    retry_policy: RetryPolicy,
+    token_counter: Option<SharedCounter>,
+    context_windows: ByPrefix<usize>,
+    cache: Option<SharedCache>,
+    transport: Option<SharedTransport>,
+    #[cfg(feature = "stream")]
+    max_stream_duration: Option<Duration>,
+    system_prompt: Option<String>,
+    json_mode: bool,
+    api_key: Option<String>,
+    no_auth: bool,
+    auth_header: Option<String>,
+    extra_headers: Vec<Header>,
+    endpoint: Option<String>,
+    routes: ByPrefix<Route>,
+    embeddings_endpoint: Option<String>,
+    timeout: Option<Duration>,
+    default_model: Option<String>,
+    provider: Provider,
+    // An unparseable `LLM_PROVIDER`, reported by `build`
+    provider_error: Option<String>,
+    hooks: Hooks,
+    default_params: CompletionParams,
+    trace_file: Option<PathBuf>,
+    pricing: Pricing,
+    budget_usd: Option<f64>,
+    proxies: Vec<Proxy>,
+    // `None` until set, which leaves reqwest's default of reading it
+    proxy_from_env: Option<bool>,
+}
 
-    /// Sends a prompt to the LLM provider and returns the completion.
-    pub async fn complete(
-        &self,
-        prompt: &str,
-        model: &str,
-        max_tokens: Option<u32>,
-        temperature: Option<f32>, // New param: Defaults to 0.7 if None
-    ) -> Result<String, DSRSError> {
+impl LLMClientBuilder {
+    /// A builder preset from the environment: `LLM_API_KEY`, `LLM_ENDPOINT`,
+    /// `LLM_PROVIDER` and `DSRS_TRACE` (a [`trace_file`](Self::trace_file)),
+    /// loading a `.env` file first if there is one. Methods called
+    /// afterwards override those values.
+    pub fn from_env() -> Self {
         dotenv().ok();
-        let api_key = std::env::var("LLM_API_KEY")
-            .map_err(|err| DSRSError::ConfigError(format!("LLM_API_KEY not set: {err}")))?;
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
 
-        if prompt.len() > MAX_PROMPT_LENGTH {
-            return Err(DSRSError::PromptTooLong(prompt.len(), MAX_PROMPT_LENGTH));
-        }
-        let request = ChatRequest {
-            model: model.to_string(),
-            messages: vec![Message {
-                role: "user".to_string(),
-                content: prompt.to_string(),
-            }],
-            max_tokens,
-            temperature,
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let mut builder = Self {
+            api_key: var("LLM_API_KEY"),
+            endpoint: var("LLM_ENDPOINT"),
+            trace_file: var("DSRS_TRACE")
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from),
+            ..Self::default()
         };
+        if let Some(name) = var("LLM_PROVIDER") {
+            match name.parse() {
+                Ok(provider) => builder.provider = provider,
+                Err(err) => builder.provider_error = Some(format!("LLM_PROVIDER: {err}")),
+            }
+        }
+        builder
+    }
+
+    /// Sets how over-long prompts are handled. Defaults to [`TooLongPolicy::Error`].
+    pub fn on_too_long(mut self, policy: TooLongPolicy) -> Self {
+        self.too_long_policy = policy;
+        self
+    }
 
-        let endpoint =
-            std::env::var("LLM_ENDPOINT").unwrap_or_else(|_| DEFAULT_LLM_ENDPOINT.to_string());
+    /// Caps how many requests this client has in flight at once, across all
+    /// tasks sharing it. Streams hold their slot until they end. Unbounded by
+    /// default.
+    pub fn max_concurrent_requests(mut self, limit: usize) -> Self {
+        self.max_concurrent_requests = Some(limit);
+        self
+    }
 
-        let response = self
-            .client
-            .post(&endpoint)
-            .header("Authorization", format!("Bearer {api_key}"))
-            .header("Content-Type", "application/json")
-            .json(&request)
-            .send()
-            .await
-            .map_err(|err| DSRSError::NetworkError(format!("Request failed: {err}")))?;
+    /// Sets how transient failures are retried. Defaults to
+    /// [`RetryPolicy::default`]: up to 3 retries with jittered exponential
+    /// backoff.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
 
-        if !response.status().is_success() {
-            return Err(DSRSError::ApiError(format!("HTTP {}", response.status())));
-        }
+    /// Sets how many times transient failures are retried, keeping the rest
+    /// of the retry policy. `0` disables retries.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.retry_policy = self.retry_policy.with_max_retries(max_retries);
+        self
+    }
 
-        let chat_response: ChatResponse = response
-            .json()
-            .await
-            .map_err(|err| DSRSError::ApiError(format!("Failed to parse response: {err}")))?;
+    /// Uses `counter` for every token count this client makes, e.g. a real
+    /// tokenizer for the configured provider. Defaults to the per-model
+    /// counters in [`crate::tokens`].
+    pub fn token_counter(mut self, counter: Arc<dyn TokenCounter>) -> Self {
+        self.token_counter = Some(counter);
+        self
+    }
 
-        // Check for embedded error in JSON
-        if let Some(err) = chat_response.error {
-            return Err(DSRSError::ApiError(format!(
-                "{} (type: {}, code: {:?})",
-                err.message, err.error_type, err.code
-            )));
-        }
+    /// Serves repeated requests from `cache` instead of the API; see
+    /// [`crate::cache`]. Since hits need no credentials, a client with a
+    /// cache may be built without an API key, and then only misses fail.
+    /// Only use a cache at temperature 0, unless replaying one sample for
+    /// every identical request is what you want.
+    pub fn cache(mut self, cache: Arc<dyn Cache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
 
-        if chat_response.choices.is_empty() {
-            return Err(DSRSError::ApiError(
-                "No response choices returned".to_string(),
-            ));
-        }
+    /// Caches up to `capacity` responses in memory, evicting the least
+    /// recently used; shorthand for [`cache`](LLMClientBuilder::cache)
+    /// with a [`MemoryCache::with_capacity`](crate::cache::MemoryCache::with_capacity).
+    pub fn memory_cache(self, capacity: usize) -> Self {
+        self.cache(Arc::new(MemoryCache::with_capacity(capacity)))
+    }
+
+    /// Sends completion and embedding requests through `transport` instead
+    /// of `reqwest`, e.g. a fake returning canned responses in tests; see
+    /// [`crate::transport`]. The [`timeout`](LLMClientBuilder::timeout) is
+    /// then up to the transport.
+    pub fn transport(mut self, transport: Arc<dyn HttpTransport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Calls `hook` with every chat request just before it's sent, retries
+    /// and streams included, e.g. for audit logging. Cache hits aren't sent
+    /// and don't call it. Replaces any previous hook.
+    pub fn on_request(mut self, hook: impl Fn(&ChatRequest) + Send + Sync + 'static) -> Self {
+        self.hooks.on_request = Some(Arc::new(hook));
+        self
+    }
+
+    /// Calls `hook` with every chat request and the raw response it got,
+    /// error statuses included. Streams aren't read into a response and
+    /// don't call it. Replaces any previous hook.
+    pub fn on_response(
+        mut self,
+        hook: impl Fn(&ChatRequest, &HttpResponse) + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.on_response = Some(Arc::new(hook));
+        self
+    }
+
+    /// Adds a header to every request, including streams and embeddings,
+    /// e.g. `OpenAI-Project`. A header of the same name (compared
+    /// case-insensitively) set by the provider or an earlier call is
+    /// replaced, so this can also set `Authorization` outright. Credential
+    /// headers aren't sent to [`route`](Self::route)s. Values are
+    /// logged at debug level, except those of credential headers: the
+    /// [`auth_header`](Self::auth_header), `Authorization`, `x-api-key` and
+    /// `api-key`.
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        let name = name.into();
+        self.extra_headers
+            .retain(|(existing, _)| !existing.eq_ignore_ascii_case(&name));
+        self.extra_headers.push((name, value.into()));
+        self
+    }
+
+    /// Sets the `OpenAI-Organization` header, for keys that belong to
+    /// several organizations.
+    pub fn organization(self, id: impl Into<String>) -> Self {
+        self.header("OpenAI-Organization", id)
+    }
+
+    /// Sends the API key as the whole value of header `name` instead of the
+    /// provider's usual authentication header, e.g. `api-key` for Azure
+    /// OpenAI, which takes no `Bearer` prefix.
+    pub fn auth_header(mut self, name: impl Into<String>) -> Self {
+        self.auth_header = Some(name.into());
+        self
+    }
+
+    /// Sends requests for models whose name starts with `prefix` per
+    /// `route` instead of the client's endpoint, e.g. `llama` to a local
+    /// Ollama server while `gpt-` models go to OpenAI. Among routes the
+    /// longest matching prefix wins. A routed request speaks the route's
+    /// [`Provider`] format and carries only the route's own key: the
+    /// client's API key, its [`auth_header`](Self::auth_header) and
+    /// credential [`header`](Self::header)s never go to a route's host.
+    ///
+    /// ```
+    /// use dsrs::client::{LLMClient, Route};
+    /// use dsrs::provider::Provider;
+    ///
+    /// let client = LLMClient::builder()
+    ///     .api_key("sk-openai")
+    ///     .route("llama", "http://localhost:11434/v1/chat/completions")
+    ///     .route(
+    ///         "claude-",
+    ///         Route::new("https://gateway.test/v1/messages")
+    ///             .provider(Provider::Anthropic)
+    ///             .api_key("gateway-key"),
+    ///     )
+    ///     .build()
+    ///     .unwrap();
+    /// assert_eq!(client.endpoint("llama3"), "http://localhost:11434/v1/chat/completions");
+    /// ```
+    pub fn route(mut self, prefix: impl Into<String>, route: impl Into<Route>) -> Self {
+        self.routes.push((prefix.into(), route.into()));
+        self
+    }
+
+    /// Sets the context window, in tokens, of models whose name starts with
+    /// `model`, for custom endpoints or models missing from
+    /// [`tokens::context_window`]. Takes precedence over the built-in table;
+    /// among overrides the longest matching prefix wins.
+    pub fn context_window(mut self, model: impl Into<String>, tokens: usize) -> Self {
+        self.context_windows.push((model.into(), tokens));
+        self
+    }
+
+    /// Caps how long a single stream may run, from sending the request to
+    /// the last token. A stream that runs over ends with
+    /// [`DSRSError::Timeout`]; the content received until then stays
+    /// available from [`CompletionStream::text`]. Unbounded by default.
+    #[cfg(feature = "stream")]
+    pub fn max_stream_duration(mut self, limit: Duration) -> Self {
+        self.max_stream_duration = Some(limit);
+        self
+    }
+
+    /// Sends `prompt` as a system message ahead of every single-prompt
+    /// request ([`LLMClient::complete`] and friends). It counts toward the
+    /// context window. [`LLMClient::complete_chat`] sends its messages
+    /// as given and doesn't add it.
+    pub fn system_prompt(mut self, prompt: impl Into<String>) -> Self {
+        self.system_prompt = Some(prompt.into());
+        self
+    }
+
+    /// Requests JSON mode (`response_format: json_object`) for every
+    /// completion, as [`LLMClient::complete_json`] does for one. Single-prompt
+    /// requests get the same JSON instruction when they don't mention JSON;
+    /// [`LLMClient::complete_chat`] messages must ask for JSON themselves.
+    /// Off by default.
+    pub fn json_mode(mut self, enabled: bool) -> Self {
+        self.json_mode = enabled;
+        self
+    }
+
+    /// Sets the API key sent as the bearer token. Required unless the
+    /// endpoint is on this machine or [`no_auth`](LLMClientBuilder::no_auth)
+    /// is set.
+    pub fn api_key(mut self, key: impl Into<String>) -> Self {
+        self.no_auth = false;
+        self.api_key = Some(key.into());
+        self
+    }
+
+    /// Sends requests without an API key, for self-hosted servers that take
+    /// none. Replaces a key set earlier, e.g. from `LLM_API_KEY`. Endpoints
+    /// on `localhost` or a loopback address don't need this: without a key
+    /// they are sent requests unauthenticated anyway.
+    pub fn no_auth(mut self) -> Self {
+        self.no_auth = true;
+        self.api_key = None;
+        self
+    }
+
+    /// Sends requests to `url`, an OpenAI-compatible chat completions
+    /// endpoint. Defaults to OpenAI's.
+    pub fn endpoint(mut self, url: impl Into<String>) -> Self {
+        self.endpoint = Some(url.into());
+        self
+    }
+
+    /// Sends embedding requests to `url`. Defaults to `embeddings` next to
+    /// the chat endpoint, e.g. `.../v1/embeddings` for
+    /// `.../v1/chat/completions`.
+    pub fn embeddings_endpoint(mut self, url: impl Into<String>) -> Self {
+        self.embeddings_endpoint = Some(url.into());
+        self
+    }
+
+    /// Sets the total time a request may take, and the connect and idle-read
+    /// timeouts. Defaults to 30 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sends requests through `proxy`, e.g.
+    /// `reqwest::Proxy::all("http://proxy.corp:3128")?`; call it again to
+    /// add one per scheme. Once a proxy is set the proxy variables from the
+    /// environment are ignored. SOCKS proxies (`socks5://`) need `reqwest`'s
+    /// `socks` feature, which an application turns on by depending on
+    /// `reqwest` with it. Requests through a custom
+    /// [`transport`](LLMClientBuilder::transport) don't use it.
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxies.push(proxy);
+        self
+    }
+
+    /// Whether to send requests through the proxy in `HTTPS_PROXY`,
+    /// `HTTP_PROXY` or `ALL_PROXY` (lowercase too), except to hosts in
+    /// `NO_PROXY`, when no [`proxy`](LLMClientBuilder::proxy) is set. On by
+    /// default, as with `reqwest`; [`build`](LLMClientBuilder::build) fails
+    /// on a malformed URL in them.
+    pub fn proxy_from_env(mut self, enabled: bool) -> Self {
+        self.proxy_from_env = Some(enabled);
+        self
+    }
+
+    /// Sets the API format to speak, which also picks the default endpoint
+    /// and how the key is sent. Defaults to [`Provider::OpenAI`].
+    pub fn provider(mut self, provider: Provider) -> Self {
+        self.provider = provider;
+        self.provider_error = None;
+        self
+    }
+
+    /// Sets the model used when the caller doesn't pick one, e.g. by
+    /// [`Predict`](crate::modules::Predict). Defaults to `gpt-3.5-turbo`.
+    pub fn default_model(mut self, model: impl Into<String>) -> Self {
+        self.default_model = Some(model.into());
+        self
+    }
+
+    /// Sets generation params for every chat request, e.g. a `top_p`,
+    /// `stop` sequences or a deadline, which bounds every chat call including
+    /// [`LLMClient::complete_n`] and tool calls. Arguments of a call, like its
+    /// `max_tokens`, and the params of [`LLMClient::complete_with_params`]
+    /// take precedence.
+    pub fn default_params(mut self, params: CompletionParams) -> Self {
+        self.default_params = params;
+        self
+    }
+
+    /// Appends a [`TraceRecord`] for every chat request to the JSONL file at
+    /// `path`: the messages, params, responses, usage, latency and any
+    /// error. Cache hits and streams aren't recorded. Writes are buffered
+    /// until the client is dropped, and a failed write is logged as a
+    /// warning rather than failing the request. Read the file back with
+    /// [`read_trace`](crate::trace::read_trace).
+    pub fn trace_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.trace_file = Some(path.into());
+        self
+    }
+
+    /// Prices responses with `pricing` instead of the built-in
+    /// [`Pricing::new`], e.g. to add a custom model or change the default
+    /// price of unknown ones; see [`crate::cost`].
+    pub fn pricing(mut self, pricing: Pricing) -> Self {
+        self.pricing = pricing;
+        self
+    }
+
+    /// Caps the client's estimated spend at `dollars` US dollars. A chat
+    /// request whose worst case, its counted prompt tokens plus all of
+    /// `max_tokens` (or the rest of the context window without one), could
+    /// take the spend over it fails with [`DSRSError::BudgetExceeded`]
+    /// without being sent. Unlimited by default.
+    pub fn budget_usd(mut self, dollars: f64) -> Self {
+        self.budget_usd = Some(dollars);
+        self
+    }
+
+    /// Applies the settings `config` has: its model becomes the
+    /// [`default_model`](Self::default_model), its `max_tokens` and
+    /// `temperature` go into the [`default_params`](Self::default_params),
+    /// and its endpoint and timeout replace the builder's. Call it after
+    /// `default_params`, which would otherwise overwrite them.
+    pub fn config(mut self, config: &Config) -> Self {
+        if let Some(model) = &config.model {
+            self.default_model = Some(model.clone());
+        }
+        if let Some(endpoint) = &config.endpoint {
+            self.endpoint = Some(endpoint.clone());
+        }
+        if let Some(timeout) = config.timeout {
+            self.timeout = Some(timeout);
+        }
+        if let Some(max_tokens) = config.max_tokens {
+            self.default_params.max_tokens = Some(max_tokens);
+        }
+        if let Some(temperature) = config.temperature {
+            self.default_params.temperature = Some(temperature);
+        }
+        self
+    }
+
+    /// Builds the client. Fails with [`DSRSError::ConfigError`] if a setting
+    /// or a proxy variable is invalid, if the
+    /// [`trace_file`](LLMClientBuilder::trace_file) can't be opened, or if
+    /// no API key was set for a remote endpoint (without
+    /// [`no_auth`](LLMClientBuilder::no_auth) or a
+    /// [`cache`](LLMClientBuilder::cache)).
+    pub fn build(self) -> Result<LLMClient> {
+        self.build_with_env(|name| std::env::var(name).ok())
+    }
+
+    /// Like [`build`](Self::build), reading the proxy variables through
+    /// `var`.
+    fn build_with_env(mut self, var: impl Fn(&str) -> Option<String>) -> Result<LLMClient> {
+        if let Some(err) = self.provider_error {
+            return Err(DSRSError::ConfigError(err));
+        }
+        if self.api_key.as_deref() == Some("") {
+            self.api_key = None;
+        }
+        if self.api_key.is_none() && self.cache.is_none() && !self.auth_optional() {
+            return Err(DSRSError::ConfigError(
+                "No API key configured; call api_key() or no_auth(), or set LLM_API_KEY"
+                    .to_string(),
+            ));
+        }
+        self.default_params.validate()?;
+        for (name, value) in &self.extra_headers {
+            if HeaderName::from_bytes(name.as_bytes()).is_err()
+                || HeaderValue::from_str(value).is_err()
+            {
+                return Err(DSRSError::ConfigError(format!(
+                    "Invalid header {name}: {value:?}"
+                )));
+            }
+        }
+        if let Some(name) = &self.auth_header
+            && HeaderName::from_bytes(name.as_bytes()).is_err()
+        {
+            return Err(DSRSError::ConfigError(format!(
+                "Invalid auth header name {name:?}"
+            )));
+        }
+        if let Some(budget) = self.budget_usd
+            && !(budget.is_finite() && budget >= 0.0)
+        {
+            return Err(DSRSError::ConfigError(format!(
+                "The budget must be a non-negative number of dollars, got {budget}"
+            )));
+        }
+        if self.max_concurrent_requests == Some(0) {
+            return Err(DSRSError::ConfigError(
+                "max_concurrent_requests must be at least 1".to_string(),
+            ));
+        }
+        let client = self.http_client_with(var)?;
+        let trace = self.open_trace()?;
+        Ok(LLMClient {
+            trace: trace.map(Arc::new),
+            ..self.into_client(client)
+        })
+    }
+
+    fn open_trace(&self) -> Result<Option<TraceWriter>> {
+        self.trace_file.clone().map(TraceWriter::open).transpose()
+    }
+
+    /// Whether requests may go without an API key: with
+    /// [`no_auth`](LLMClientBuilder::no_auth), to a local endpoint, or when
+    /// a [`header`](LLMClientBuilder::header) carries the credentials.
+    fn auth_optional(&self) -> bool {
+        let auth_header = self
+            .auth_header
+            .as_deref()
+            .unwrap_or(self.provider.auth_header());
+        self.no_auth
+            || self.endpoint.as_deref().is_some_and(is_local_endpoint)
+            || self
+                .extra_headers
+                .iter()
+                .any(|(name, _)| name.eq_ignore_ascii_case(auth_header))
+    }
+
+    fn request_timeout(&self) -> Duration {
+        self.timeout
+            .unwrap_or(Duration::from_secs(REQUEST_TIMEOUT_SECS))
+    }
+
+    fn http_client(&self) -> Result<Client> {
+        self.http_client_with(|name| std::env::var(name).ok())
+    }
+
+    /// Like [`http_client`](Self::http_client), reading the proxy variables
+    /// through `var`.
+    fn http_client_with(&self, var: impl Fn(&str) -> Option<String>) -> Result<Client> {
+        let timeout = self.request_timeout();
+        // The total timeout is applied per request so streams aren't cut off;
+        // connect and idle-read timeouts still guard every request. Proxies
+        // from the environment are read here rather than by `reqwest`.
+        let mut builder = ClientBuilder::new()
+            .connect_timeout(timeout)
+            .read_timeout(timeout)
+            .no_proxy();
+        for proxy in &self.proxies {
+            builder = builder.proxy(proxy.clone());
+        }
+        if self.proxies.is_empty() && self.proxy_from_env != Some(false) {
+            builder = env_proxies(builder, var)?;
+        }
+        builder
+            .build()
+            .map_err(|err| DSRSError::ConfigError(format!("Failed to build HTTP client: {err}")))
+    }
+
+    fn into_client(mut self, client: Client) -> LLMClient {
+        let auth_optional = self.auth_optional();
+        let endpoint = self
+            .endpoint
+            .take()
+            .unwrap_or_else(|| self.provider.default_endpoint().to_string());
+        LLMClient {
+            transport: self.transport.take().unwrap_or_else(|| {
+                Arc::new(ReqwestTransport::new(
+                    client.clone(),
+                    self.request_timeout(),
+                ))
+            }),
+            #[cfg(feature = "stream")]
+            client,
+            too_long_policy: self.too_long_policy,
+            concurrency: self
+                .max_concurrent_requests
+                .map(|limit| Arc::new(Semaphore::new(limit))),
+            retry_policy: self.retry_policy,
+            token_counter: self.token_counter,
+            context_windows: self.context_windows,
+            cache: self.cache,
+            #[cfg(feature = "stream")]
+            max_stream_duration: self.max_stream_duration,
+            system_prompt: self.system_prompt,
+            json_mode: self.json_mode,
+            api_key: self.api_key,
+            auth_optional,
+            auth_header: self.auth_header,
+            extra_headers: self.extra_headers,
+            embeddings_endpoint: self
+                .embeddings_endpoint
+                .unwrap_or_else(|| embeddings::embeddings_url(&endpoint)),
+            endpoint,
+            routes: self.routes,
+            default_model: self
+                .default_model
+                .unwrap_or_else(|| DEFAULT_MODEL.to_string()),
+            provider: self.provider,
+            hooks: self.hooks,
+            default_params: self.default_params,
+            trace: None,
+            cancel: None,
+            costs: Arc::new(CostTracker::new(self.pricing, self.budget_usd)),
+        }
+    }
+}
+
+impl LLMClient {
+    /// Creates a client configured from the environment, like
+    /// [`LLMClient::from_env`].
+    ///
+    /// This can't fail: if `LLM_API_KEY` isn't set, the client is still
+    /// created and every request fails with [`DSRSError::ConfigError`]
+    /// instead. Prefer [`LLMClient::from_env`] to catch that up front.
+    pub fn new() -> Self {
+        let builder = LLMClientBuilder::from_env();
+        if let Some(err) = &builder.provider_error {
+            tracing::warn!("{err}; using the default provider");
+        }
+        let client = builder.http_client().unwrap_or_else(|_| Client::new());
+        let trace = builder.open_trace().unwrap_or_else(|err| {
+            tracing::warn!("{err}; not tracing requests");
+            None
+        });
+        LLMClient {
+            trace: trace.map(Arc::new),
+            ..builder.into_client(client)
+        }
+    }
+
+    /// Creates a client from `LLM_API_KEY` and `LLM_ENDPOINT`, reading a
+    /// `.env` file first if there is one. The environment is read once, here;
+    /// later changes to it don't affect the client.
+    pub fn from_env() -> Result<Self> {
+        LLMClientBuilder::from_env().build()
+    }
+
+    /// Returns a builder for configuring a client explicitly. Nothing is
+    /// read from the environment; use [`LLMClientBuilder::from_env`] to start
+    /// from it instead.
+    pub fn builder() -> LLMClientBuilder {
+        LLMClientBuilder::default()
+    }
+
+    /// A copy of this client that neither reads nor writes its cache, for
+    /// calls that need a fresh response.
+    pub fn without_cache(&self) -> Self {
+        Self {
+            cache: None,
+            ..self.clone()
+        }
+    }
+
+    /// A clone of this client whose chat requests stop when `token` is
+    /// cancelled, e.g. from a UI's stop button. A request in flight is
+    /// dropped, retries included, and fails with [`DSRSError::Cancelled`],
+    /// as do later ones. Streams aren't affected; drop them to stop.
+    pub fn with_cancellation(&self, token: CancellationToken) -> Self {
+        Self {
+            cancel: Some(token),
+            ..self.clone()
+        }
+    }
+
+    /// The estimated cost in US dollars of every chat response this client
+    /// and its clones have received; see [`crate::cost`].
+    pub fn total_cost(&self) -> f64 {
+        self.costs.total_cost()
+    }
+
+    /// The prices responses are costed at.
+    pub fn pricing(&self) -> &Pricing {
+        self.costs.pricing()
+    }
+
+    /// The model used when the caller doesn't pick one.
+    pub fn default_model(&self) -> &str {
+        &self.default_model
+    }
+
+    /// The context window of `model` in tokens that prompts are checked
+    /// against: a [`context_window`](LLMClientBuilder::context_window)
+    /// override, else the built-in table, else
+    /// [`tokens::DEFAULT_CONTEXT_WINDOW`].
+    pub fn context_window(&self, model: &str) -> usize {
+        let overrides = self
+            .context_windows
+            .iter()
+            .map(|(prefix, window)| (prefix.as_str(), *window));
+        tokens::lookup_prefix(overrides, model)
+            .or_else(|| tokens::context_window(model))
+            .unwrap_or(tokens::DEFAULT_CONTEXT_WINDOW)
+    }
+
+    /// The chat completions URL requests for `model` go to: the endpoint of
+    /// the longest matching [`route`](LLMClientBuilder::route), else the
+    /// default endpoint.
+    pub fn endpoint(&self, model: &str) -> &str {
+        self.target(model).endpoint
+    }
+
+    /// The longest matching route for `model`, else the client's own
+    /// endpoint, provider and key.
+    fn target(&self, model: &str) -> Target<'_> {
+        self.routes
+            .iter()
+            .filter(|(prefix, _)| model.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or_else(
+                || self.own_target(&self.endpoint),
+                |(_, route)| Target {
+                    endpoint: &route.endpoint,
+                    provider: route.provider,
+                    api_key: route.api_key.as_deref(),
+                    routed: true,
+                },
+            )
+    }
+
+    /// `endpoint` with the client's provider and credentials.
+    fn own_target<'a>(&'a self, endpoint: &'a str) -> Target<'a> {
+        Target {
+            endpoint,
+            provider: self.provider,
+            api_key: self.api_key.as_deref(),
+            routed: false,
+        }
+    }
+
+    /// Counts the tokens in `text` for `model`, using the configured
+    /// [`TokenCounter`] if there is one and the model's counter otherwise.
+    pub fn count_tokens(&self, text: &str, model: &str) -> usize {
+        match &self.token_counter {
+            Some(counter) => counter.count_tokens(text),
+            None => tokens::count_tokens(text, model),
+        }
+    }
+
+    /// Sends a prompt to the LLM provider and returns the completion.
+    ///
+    /// Fails with [`DSRSError::PromptTooLong`] if the prompt and `max_tokens`
+    /// together don't fit the model's context window (see
+    /// [`tokens::context_window`]) and the [`TooLongPolicy`] doesn't truncate.
+    pub async fn complete(
+        &self,
+        prompt: &str,
+        model: &str,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>, // New param: Defaults to 0.7 if None
+    ) -> Result<String> {
+        self.complete_detailed(prompt, model, max_tokens, temperature)
+            .await
+            .map(|completion| completion.text)
+    }
+
+    /// Like [`LLMClient::complete_detailed`], with generation settings from
+    /// `params` in place of positional arguments. Settings it leaves unset
+    /// fall back to the client's
+    /// [`default_params`](LLMClientBuilder::default_params). Fails with
+    /// [`DSRSError::ConfigError`] if `top_p` is outside `0.0..=1.0`, and with
+    /// [`DSRSError::Timeout`] if the params'
+    /// [`timeout`](CompletionParams::timeout) runs out first.
+    pub async fn complete_with_params(
+        &self,
+        prompt: &str,
+        model: &str,
+        params: &CompletionParams,
+    ) -> Result<Completion> {
+        self.complete_chat_with_params(&self.single_turn(prompt), model, params)
+            .await
+    }
+
+    /// Like [`LLMClient::complete_with_params`] for a whole conversation,
+    /// sent as-is like with [`LLMClient::complete_chat`]. With
+    /// [`n`](CompletionParams::n) set, returns the first choice; see
+    /// [`LLMClient::complete_n_with_params`] for all of them.
+    pub async fn complete_chat_with_params(
+        &self,
+        messages: &[Message],
+        model: &str,
+        params: &CompletionParams,
+    ) -> Result<Completion> {
+        let (request, deadline) = self.params_request(messages, model, params)?;
+        let reply = self.send(&request, deadline).await?;
+        Ok(reply.into_completion())
+    }
+
+    /// Asks for `n` completions of `prompt` in one request and returns their
+    /// texts in the provider's order, e.g. for self-consistency sampling.
+    /// Use a nonzero temperature, or the candidates will likely be the same.
+    /// These requests bypass the cache. Fails with [`DSRSError::ConfigError`]
+    /// if `n` is 0, and with [`DSRSError::ApiError`] if no choices come back.
+    pub async fn complete_n(
+        &self,
+        prompt: &str,
+        model: &str,
+        n: u32,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+    ) -> Result<Vec<String>> {
+        let params = CompletionParams {
+            max_tokens,
+            temperature,
+            n: Some(n),
+            ..CompletionParams::default()
+        };
+        self.complete_n_with_params(prompt, model, &params).await
+    }
+
+    /// Like [`LLMClient::complete_n`], with the number of choices and other
+    /// generation settings from `params`, applied as in
+    /// [`LLMClient::complete_with_params`]. Asks for one choice if `params`
+    /// and the client's defaults leave [`n`](CompletionParams::n) unset.
+    pub async fn complete_n_with_params(
+        &self,
+        prompt: &str,
+        model: &str,
+        params: &CompletionParams,
+    ) -> Result<Vec<String>> {
+        let (request, deadline) = self.params_request(&self.single_turn(prompt), model, params)?;
+        let replies = self.send_all(&request, deadline).await?;
+        Ok(replies
+            .into_iter()
+            .map(|reply| reply.message.text().unwrap_or_default())
+            .collect())
+    }
+
+    /// Builds the request for `messages` with `params` over the client's
+    /// defaults, and returns it with the call's deadline.
+    fn params_request(
+        &self,
+        messages: &[Message],
+        model: &str,
+        params: &CompletionParams,
+    ) -> Result<Deadlined> {
+        params.validate()?;
+        let params = params.or(&self.default_params);
+        let mut request =
+            self.build_chat_request(messages, model, params.max_tokens, params.temperature)?;
+        request.top_p = params.top_p;
+        request.frequency_penalty = params.frequency_penalty;
+        request.presence_penalty = params.presence_penalty;
+        request.stop = params.stop;
+        request.n = params.n;
+        Ok((request, params.timeout))
+    }
+
+    /// Sends a whole conversation as-is and returns the assistant's reply.
+    ///
+    /// The context window check covers the combined content of all
+    /// messages. Truncating [`TooLongPolicy`]s only trim the last message, so
+    /// earlier turns are never cut mid-conversation.
+    pub async fn complete_chat(
+        &self,
+        messages: &[Message],
+        model: &str,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+    ) -> Result<String> {
+        let params = CompletionParams {
+            max_tokens,
+            temperature,
+            ..CompletionParams::default()
+        };
+        self.complete_chat_with_params(messages, model, &params)
+            .await
+            .map(|completion| completion.text)
+    }
+
+    /// Sends a conversation along with `tools` the model may call, and
+    /// returns either its text answer or the calls it wants made. To run an
+    /// agent loop, append [`Message::assistant_tool_calls`] and one
+    /// [`Message::tool`] result per call to the conversation and send it
+    /// again. Tool requests bypass the cache. The anthropic provider doesn't
+    /// support tools.
+    pub async fn complete_with_tools(
+        &self,
+        messages: &[Message],
+        tools: &[Tool],
+        model: &str,
+    ) -> Result<ToolCallResult> {
+        self.send_with_tools(messages, tools, None, model).await
+    }
+
+    /// Like [`LLMClient::complete_with_tools`], but with a [`ToolChoice`],
+    /// e.g. to force a particular call.
+    pub async fn complete_with_tool_choice(
+        &self,
+        messages: &[Message],
+        tools: &[Tool],
+        tool_choice: ToolChoice,
+        model: &str,
+    ) -> Result<ToolCallResult> {
+        self.send_with_tools(messages, tools, Some(tool_choice), model)
+            .await
+    }
+
+    async fn send_with_tools(
+        &self,
+        messages: &[Message],
+        tools: &[Tool],
+        tool_choice: Option<ToolChoice>,
+        model: &str,
+    ) -> Result<ToolCallResult> {
+        let mut request = self.build_chat_request(messages, model, None, None)?;
+        // An empty list is rejected by the API, so leave it out
+        request.tools = (!tools.is_empty()).then(|| tools.to_vec());
+        request.tool_choice = tool_choice;
+        let reply = self.send(&request, self.default_params.timeout).await?;
+        Ok(reply.message.into_tool_result())
+    }
+
+    /// Like [`LLMClient::complete`], but returns a [`Completion`] with the
+    /// token usage, serving model and finish reason, which can also be parsed
+    /// further with [`Completion::into_result`].
+    pub async fn complete_detailed(
+        &self,
+        prompt: &str,
+        model: &str,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+    ) -> Result<Completion> {
+        let params = CompletionParams {
+            max_tokens,
+            temperature,
+            ..CompletionParams::default()
+        };
+        self.complete_with_params(prompt, model, &params).await
+    }
+
+    /// Completes many prompts with at most `concurrency` requests in flight.
+    ///
+    /// Accepts plain prompts (`String`/`&str`) or [`BatchItem`](crate::batch::BatchItem)s
+    /// carrying a priority; higher-priority items are dispatched first (see
+    /// [`crate::batch`] for the scheduling rules). Results are returned in
+    /// input order and one failure doesn't affect the others.
+    pub async fn complete_batch<P: BatchPrompt>(
+        &self,
+        prompts: &[P],
+        model: &str,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+        concurrency: usize,
+    ) -> Vec<Result<String>> {
+        self.complete_batch_with_progress(
+            prompts,
+            model,
+            max_tokens,
+            temperature,
+            concurrency,
+            |_, _| {},
+        )
+        .await
+    }
+
+    /// Like [`LLMClient::complete_batch`], calling `progress` with the number
+    /// of finished prompts (succeeded or failed) and the batch size each time
+    /// one finishes.
+    pub async fn complete_batch_with_progress<P: BatchPrompt>(
+        &self,
+        prompts: &[P],
+        model: &str,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+        concurrency: usize,
+        progress: impl Fn(usize, usize) + Sync,
+    ) -> Vec<Result<String>> {
+        let priorities: Vec<i32> = prompts.iter().map(BatchPrompt::priority).collect();
+        let finished = AtomicUsize::new(0);
+        let (finished, progress) = (&finished, &progress);
+        run_prioritized(&priorities, concurrency, |index| async move {
+            let result = self
+                .complete(prompts[index].prompt(), model, max_tokens, temperature)
+                .await;
+            progress(finished.fetch_add(1, Ordering::SeqCst) + 1, prompts.len());
+            result
+        })
+        .await
+    }
+
+    /// Sends a prompt requesting both text and audio output.
+    ///
+    /// Sets `modalities: ["text", "audio"]` with the given voice and format,
+    /// and decodes the returned base64 audio.
+    pub async fn complete_with_audio(
+        &self,
+        prompt: &str,
+        model: &str,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+        audio: AudioConfig,
+    ) -> Result<AudioCompletion> {
+        let mut request = self.build_request(prompt, model, max_tokens, temperature)?;
+        request.modalities = Some(vec!["text".to_string(), "audio".to_string()]);
+        request.audio = Some(audio);
+
+        let message = self
+            .send(&request, self.default_params.timeout)
+            .await?
+            .message;
+        let text = message.text();
+        let (audio, audio_id, transcript) = match message.audio {
+            Some(audio) => {
+                let bytes = base64::engine::general_purpose::STANDARD
+                    .decode(&audio.data)
+                    .map_err(|err| DSRSError::ApiError(format!("Invalid audio data: {err}")))?;
+                (Some(bytes), Some(audio.id), audio.transcript)
+            }
+            None => (None, None, None),
+        };
+        Ok(AudioCompletion {
+            text: text.or(transcript).unwrap_or_default(),
+            audio,
+            audio_id,
+        })
+    }
+
+    /// Sends a prompt with a `response_format` constraint and returns the raw
+    /// completion text, e.g. JSON for [`ResponseFormat::JsonSchema`].
+    pub async fn complete_with_format(
+        &self,
+        prompt: &str,
+        model: &str,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+        response_format: ResponseFormat,
+    ) -> Result<String> {
+        let mut request = self.build_request(prompt, model, max_tokens, temperature)?;
+        request.response_format = Some(response_format);
+        let reply = self.send(&request, self.default_params.timeout).await?;
+        Ok(reply.message.text().unwrap_or_default())
+    }
+
+    /// Sends a prompt in JSON mode (`response_format: json_object`) and
+    /// deserializes the reply into `T`, as [`Completion::into_result`] does.
+    ///
+    /// The API rejects JSON mode unless the word "JSON" appears in the
+    /// messages, so a short instruction is appended to the prompt when
+    /// neither it nor the system prompt mentions it. To constrain the output
+    /// to a schema, use [`LLMClient::complete_with_format`] with
+    /// [`ResponseFormat::json_schema`] instead.
+    pub async fn complete_json<T: DeserializeOwned>(
+        &self,
+        prompt: &str,
+        model: &str,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+    ) -> Result<T> {
+        let mut messages = self.single_turn(prompt);
+        ask_for_json(&mut messages);
+        let mut request = self.build_chat_request(&messages, model, max_tokens, temperature)?;
+        request.response_format = Some(ResponseFormat::JsonObject);
+        self.send(&request, self.default_params.timeout)
+            .await?
+            .into_completion()
+            .into_result()
+    }
+
+    /// Embeds `input` with an embedding model such as
+    /// `text-embedding-3-small`.
+    pub async fn embed(&self, input: &str, model: &str) -> Result<Vec<f32>> {
+        let mut vectors = self.embed_many(&[input.to_string()], model).await?;
+        vectors
+            .pop()
+            .ok_or_else(|| DSRSError::ApiError("No embedding returned".to_string()))
+    }
+
+    /// Embeds every input in one request, returning the vectors in input
+    /// order. Only OpenAI-compatible providers offer embeddings.
+    pub async fn embed_many(&self, inputs: &[String], model: &str) -> Result<Vec<Vec<f32>>> {
+        if inputs.is_empty() {
+            return Ok(Vec::new());
+        }
+        if self.provider != Provider::OpenAI {
+            return Err(DSRSError::ConfigError(format!(
+                "The {} provider doesn't offer embeddings",
+                self.provider
+            )));
+        }
+        let request = EmbeddingRequest {
+            model: model.to_string(),
+            input: inputs.to_vec(),
+        };
+        let response = self
+            .retry_policy
+            .run(|| self.send_embeddings(&request))
+            .await?;
+        response.into_vectors(inputs.len())
+    }
+
+    /// Sends a prompt and streams the completion back token by token.
+    ///
+    /// The request is sent when the returned stream is first polled. Unlike
+    /// [`LLMClient::complete`] the total request timeout does not apply, since
+    /// long generations are expected to stay open; connect and idle-read
+    /// timeouts still do, as does
+    /// [`max_stream_duration`](LLMClientBuilder::max_stream_duration) if set.
+    ///
+    /// The stream counts against the [budget](LLMClientBuilder::budget_usd)
+    /// like any request: its worst case is reserved before it's sent, and a
+    /// request that could go over yields [`DSRSError::BudgetExceeded`] as
+    /// its only item. When it ends it's priced from the usage the provider
+    /// reported, which OpenAI-compatible servers are asked to include, or
+    /// at the worst case without it; see [`CompletionStream::usage`].
+    #[cfg(feature = "stream")]
+    pub fn complete_stream(
+        &self,
+        prompt: &str,
+        model: &str,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+    ) -> CompletionStream {
+        let request = self
+            .build_request(prompt, model, max_tokens, temperature)
+            .and_then(|mut request| {
+                request.stream = Some(true);
+                let reservation = self
+                    .costs
+                    .reserve(self.worst_case_cost(&request))
+                    .inspect_err(|err| self.log_failure(&request.model, err))?;
+                let price = self.costs.pricing().price_of(&request.model);
+                Ok((self.post(&request)?, reservation, price))
+            });
+        let (request, reservation, price) = match request {
+            Ok(request) => request,
+            Err(err) => return CompletionStream::failed(err),
+        };
+
+        let concurrency = self.concurrency.clone();
+        CompletionStream::new(Box::pin(async move {
+            let permit = acquire(concurrency).await?;
+            let response = request.header("Accept", "text/event-stream").send().await?;
+            if !response.status().is_success() {
+                return Err(error_from_response(HttpResponse::read(response).await?));
+            }
+            Ok((response, permit))
+        }))
+        .max_duration(self.max_stream_duration)
+        .cost(reservation, price)
+    }
+
+    /// Sends `request`, retrying per the client's [`RetryPolicy`], and
+    /// returns the first choice.
+    async fn send(&self, request: &ChatRequest, deadline: Option<Duration>) -> Result<Reply> {
+        // Cached entries only hold text, so audio and tool replies bypass
+        // the cache
+        let cached = self
+            .cache
+            .as_deref()
+            .filter(|_| request.audio.is_none() && request.tools.is_none())
+            .map(|cache| (cache, request.stable_hash_hex()));
+        if let Some((cache, key)) = &cached
+            && let Some(completion) = cache.get(key)
+        {
+            tracing::debug!(key, "cache hit");
+            return Ok(Reply::from(completion));
+        }
+
+        let reply = self
+            .send_all(request, deadline)
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| DSRSError::ApiError("No response choices returned".to_string()))?;
+        if let Some((cache, key)) = &cached {
+            cache.put(key, &reply.completion());
+        }
+        Ok(reply)
+    }
+
+    /// Sends `request`, retrying per the client's [`RetryPolicy`], and
+    /// returns every choice. Fails with [`DSRSError::Timeout`] if `deadline`
+    /// runs out across all attempts, and with [`DSRSError::Cancelled`] if
+    /// the client's cancellation token fires first. Runs in a `chat` span recording the model,
+    /// prompt length, elapsed time, outcome, billed tokens and estimated
+    /// cost, which an info event repeats when the request is done.
+    async fn send_all(
+        &self,
+        request: &ChatRequest,
+        deadline: Option<Duration>,
+    ) -> Result<Vec<Reply>> {
+        let reservation = self
+            .costs
+            .reserve(self.worst_case_cost(request))
+            .inspect_err(|err| self.log_failure(&request.model, err))?;
+        let prompt_chars: usize = request
+            .messages
+            .iter()
+            .map(|message| message.content.chars().count())
+            .sum();
+        let span = tracing::info_span!(
+            "chat",
+            model = request.model,
+            prompt_chars,
+            elapsed_ms = tracing::field::Empty,
+            status = tracing::field::Empty,
+            prompt_tokens = tracing::field::Empty,
+            completion_tokens = tracing::field::Empty,
+            cost_usd = tracing::field::Empty,
+        );
+        let started = Instant::now();
+        let attempts = self
+            .retry_policy
+            .run(|| self.send_once_all(request))
+            .instrument(span.clone());
+        let attempts = async {
+            match deadline {
+                Some(limit) => tokio::time::timeout(limit, attempts)
+                    .await
+                    .unwrap_or(Err(DSRSError::Timeout(Some(limit)))),
+                None => attempts.await,
+            }
+        };
+        let result = match &self.cancel {
+            Some(token) => tokio::select! {
+                biased;
+                () = token.cancelled() => Err(DSRSError::Cancelled),
+                result = attempts => result,
+            },
+            None => attempts.await,
+        };
+        let elapsed_ms = u64::try_from(started.elapsed().as_millis()).unwrap_or(u64::MAX);
+        let status = if result.is_ok() { "ok" } else { "error" };
+        span.record("elapsed_ms", elapsed_ms);
+        span.record("status", status);
+        match &result {
+            Ok(replies) => {
+                let usage = replies.first().and_then(|reply| reply.usage);
+                let prompt_tokens = usage.map(|usage| usage.prompt_tokens);
+                let completion_tokens = usage.map(|usage| usage.completion_tokens);
+                let cost_usd = reservation
+                    .settle(usage.map(|usage| self.costs.pricing().cost(&request.model, &usage)));
+                if let Some(usage) = usage {
+                    span.record("prompt_tokens", usage.prompt_tokens);
+                    span.record("completion_tokens", usage.completion_tokens);
+                }
+                span.record("cost_usd", cost_usd);
+                tracing::info!(
+                    parent: &span,
+                    model = request.model,
+                    prompt_chars,
+                    elapsed_ms,
+                    status,
+                    prompt_tokens,
+                    completion_tokens,
+                    cost_usd,
+                    "chat request finished"
+                )
+            }
+            Err(err) => tracing::info!(
+                parent: &span,
+                model = request.model,
+                prompt_chars,
+                elapsed_ms,
+                status,
+                error = %self.redact(&err.to_string()),
+                "chat request finished"
+            ),
+        }
+        if let Some(trace) = &self.trace {
+            trace.write(&TraceRecord::new(request, &result, elapsed_ms, |text| {
+                self.redact(text)
+            }));
+        }
+        result
+    }
+
+    /// The most `request` could cost: its counted prompt tokens, plus
+    /// `max_tokens` for each choice, or the rest of the context window
+    /// without one.
+    fn worst_case_cost(&self, request: &ChatRequest) -> f64 {
+        let model = &request.model;
+        let prompt_tokens: usize = request
+            .messages
+            .iter()
+            .map(|message| self.count_tokens(&message.content, model))
+            .sum();
+        let per_choice = match request.max_tokens {
+            Some(tokens) => tokens as usize,
+            None => self.context_window(model).saturating_sub(prompt_tokens),
+        };
+        let completion_tokens = per_choice * request.n.unwrap_or(1) as usize;
+        self.costs
+            .pricing()
+            .price_of(model)
+            .cost(prompt_tokens as u64, completion_tokens as u64)
+    }
+
+    /// Makes a single attempt at sending `request`, returning every choice.
+    async fn send_once_all(&self, request: &ChatRequest) -> Result<Vec<Reply>> {
+        let target = self.target(&request.model);
+        let body = target.provider.request_body(request)?;
+        let endpoint = target.endpoint;
+        let headers = self.headers(&target)?;
+        let _permit = acquire(self.concurrency.clone()).await?;
+        self.log_request(request, endpoint, &headers, &body);
+        let response = self
+            .transport
+            .post_json(endpoint, &headers, &body)
+            .await
+            .inspect_err(|err| self.log_failure(&request.model, err))?;
+        tracing::debug!(
+            status = response.status,
+            body_bytes = response.body.len(),
+            "received chat response"
+        );
+        tracing::trace!(
+            body = %self.redact(&String::from_utf8_lossy(&response.body)),
+            "chat response body"
+        );
+        if let Some(hook) = &self.hooks.on_response {
+            hook(request, &response);
+        }
+
+        if !response.is_success() {
+            let err = error_from_response(response);
+            self.log_failure(&request.model, &err);
+            return Err(err);
+        }
+
+        let replies = target.provider.read_replies(&response.body)?;
+        let truncated = replies
+            .iter()
+            .filter(|reply| reply.finish_reason.as_deref() == Some("length"))
+            .count();
+        if truncated > 0 {
+            tracing::warn!(
+                model = request.model,
+                max_tokens = request.max_tokens,
+                truncated,
+                "completion hit the token limit and is likely cut off"
+            );
+        }
+        Ok(replies)
+    }
+
+    /// Makes a single attempt at an embeddings request.
+    async fn send_embeddings(&self, request: &EmbeddingRequest) -> Result<EmbeddingResponse> {
+        let body = serde_json::to_value(request)?;
+        let headers = self.headers(&self.own_target(&self.embeddings_endpoint))?;
+        let _permit = acquire(self.concurrency.clone()).await?;
+        let response = self
+            .transport
+            .post_json(&self.embeddings_endpoint, &headers, &body)
+            .await?;
+
+        if !response.is_success() {
+            return Err(error_from_response(response));
+        }
+
+        serde_json::from_slice(&response.body)
+            .map_err(|err| DSRSError::undecodable(err, &response.body))
+    }
+
+    /// Validates the prompt and assembles a single-turn request.
+    fn build_request(
+        &self,
+        prompt: &str,
+        model: &str,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+    ) -> Result<ChatRequest> {
+        self.build_chat_request(&self.single_turn(prompt), model, max_tokens, temperature)
+    }
+
+    /// The messages for a single prompt: the system prompt, if any, then
+    /// `prompt` as the user turn, asking for JSON in JSON mode.
+    pub(crate) fn single_turn(&self, prompt: &str) -> Vec<Message> {
+        self.conversation(&[Message::user(prompt)])
+    }
+
+    /// `messages` after the system prompt, unless there is none or they
+    /// start with their own, asking for JSON in JSON mode.
+    pub(crate) fn conversation(&self, messages: &[Message]) -> Vec<Message> {
+        let own_system = messages
+            .first()
+            .is_some_and(|message| message.role == "system");
+        let mut messages: Vec<Message> = self
+            .system_prompt
+            .iter()
+            .filter(|_| !own_system)
+            .map(Message::system)
+            .chain(messages.iter().cloned())
+            .collect();
+        if self.json_mode {
+            ask_for_json(&mut messages);
+        }
+        messages
+    }
+
+    /// Checks that the messages and `max_tokens` fit the model's context
+    /// window and assembles a request.
+    fn build_chat_request(
+        &self,
+        messages: &[Message],
+        model: &str,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+    ) -> Result<ChatRequest> {
+        let Some(last) = messages.last() else {
+            return Err(DSRSError::ConfigError("No messages to send".to_string()));
+        };
+        let defaults = &self.default_params;
+        let max_tokens = max_tokens.or(defaults.max_tokens);
+        let count = |text: &str| self.count_tokens(text, model);
+        let window = self.context_window(model);
+        let completion_tokens = max_tokens.map_or(0, |tokens| tokens as usize);
+        let prompt_tokens: usize = messages.iter().map(|message| count(&message.content)).sum();
+        let earlier_tokens = prompt_tokens - count(&last.content);
+        let mut messages = messages.to_vec();
+        if prompt_tokens + completion_tokens > window {
+            // Only the last message is trimmed, with whatever room the
+            // earlier turns and the completion leave
+            let available = match self.too_long_policy {
+                TooLongPolicy::Error => None,
+                _ => window.checked_sub(earlier_tokens + completion_tokens),
+            };
+            let Some(available) = available else {
+                return Err(DSRSError::PromptTooLong(
+                    prompt_tokens + completion_tokens,
+                    window,
+                ));
+            };
+            let kept = self
+                .too_long_policy
+                .apply(&last.content, available, count)?;
+            if let Some(message) = messages.last_mut() {
+                message.content = kept.to_string();
+            }
+        }
+
+        Ok(ChatRequest {
+            model: model.to_string(),
+            messages,
+            max_tokens,
+            temperature: temperature.or(defaults.temperature),
+            stream: None,
+            modalities: None,
+            audio: None,
+            response_format: self.json_mode.then_some(ResponseFormat::JsonObject),
+            n: None,
+            tools: None,
+            tool_choice: None,
+            top_p: defaults.top_p,
+            frequency_penalty: defaults.frequency_penalty,
+            presence_penalty: defaults.presence_penalty,
+            stop: defaults.stop.clone(),
+        })
+    }
+
+    /// Prepares an authenticated POST of `request` to the configured endpoint.
+    #[cfg(feature = "stream")]
+    fn post(&self, request: &ChatRequest) -> Result<RequestBuilder> {
+        let target = self.target(&request.model);
+        let mut body = target.provider.request_body(request)?;
+        if request.stream == Some(true) && target.provider == Provider::OpenAI {
+            // Otherwise OpenAI streams report no usage to price them by
+            body["stream_options"] = serde_json::json!({"include_usage": true});
+        }
+        let endpoint = target.endpoint;
+        let headers = self.headers(&target)?;
+        self.log_request(request, endpoint, &headers, &body);
+        let mut post = self.client.post(endpoint).json(&body);
+        for (name, value) in headers {
+            post = post.header(name, value);
+        }
+        Ok(post)
+    }
+
+    /// Runs the request hook and logs the request at debug level, with the
+    /// API key and the values of credential headers redacted. The body,
+    /// which holds the prompt, is only logged at trace level.
+    fn log_request(
+        &self,
+        request: &ChatRequest,
+        url: &str,
+        headers: &[Header],
+        body: &serde_json::Value,
+    ) {
+        if let Some(hook) = &self.hooks.on_request {
+            hook(request);
+        }
+        tracing::debug!(
+            url,
+            model = request.model,
+            messages = request.messages.len(),
+            headers = %self.redact(&format!("{:?}", self.loggable_headers(headers))),
+            "sending chat request"
+        );
+        tracing::trace!(body = %self.redact(&body.to_string()), "chat request body");
+    }
+
+    /// Warns about a failed attempt, such as a connection error or a
+    /// non-success HTTP status, singling out rate limits.
+    fn log_failure(&self, model: &str, err: &DSRSError) {
+        match err {
+            DSRSError::RateLimited { retry_after, .. } => tracing::warn!(
+                model,
+                retry_after_ms = retry_after.map(|delay| delay.as_millis()),
+                error = %self.redact(&err.to_string()),
+                "rate limited"
+            ),
+            _ => tracing::warn!(
+                model,
+                error = %self.redact(&err.to_string()),
+                "chat request failed"
+            ),
+        }
+    }
+
+    /// `headers` with the values of those that may carry credentials, such
+    /// as `Authorization` or Azure's `api-key`, replaced by `[REDACTED]`.
+    fn loggable_headers<'a>(&self, headers: &'a [Header]) -> Vec<HeaderRef<'a>> {
+        headers
+            .iter()
+            .map(|(name, value)| {
+                let value = if self.is_credential_header(name) {
+                    "[REDACTED]"
+                } else {
+                    value.as_str()
+                };
+                (name.as_str(), value)
+            })
+            .collect()
+    }
+
+    /// `text` with every occurrence of the API key, or of a route's,
+    /// replaced by `[REDACTED]`, for logging headers and bodies.
+    fn redact(&self, text: &str) -> String {
+        self.api_key
+            .iter()
+            .chain(
+                self.routes
+                    .iter()
+                    .filter_map(|(_, route)| route.api_key.as_ref()),
+            )
+            .filter(|key| !key.is_empty())
+            .fold(text.to_string(), |text, key| {
+                text.replace(key.as_str(), "[REDACTED]")
+            })
+    }
+
+    /// The headers for a request to `target`: its provider's, with its API
+    /// key if there is one, then the [extra ones](LLMClientBuilder::header),
+    /// less those carrying credentials for a routed request.
+    fn headers(&self, target: &Target) -> Result<Vec<Header>> {
+        if !target.routed
+            && self.api_key.is_none()
+            && !self.auth_optional
+            && !is_local_endpoint(target.endpoint)
+        {
+            return Err(DSRSError::ConfigError("LLM_API_KEY not set".to_string()));
+        }
+        let mut headers = match (&self.auth_header, target.api_key) {
+            (Some(name), Some(key)) if !target.routed => {
+                let mut headers = target.provider.headers(None);
+                headers.push((name.clone(), key.to_string()));
+                headers
+            }
+            (_, key) => target.provider.headers(key),
+        };
+        for (name, value) in &self.extra_headers {
+            if target.routed && self.is_credential_header(name) {
+                continue;
+            }
+            headers.retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+            headers.push((name.clone(), value.clone()));
+        }
+        Ok(headers)
+    }
+
+    /// Whether header `name` may carry credentials: the
+    /// [`auth_header`](LLMClientBuilder::auth_header), a provider's
+    /// authentication header or Azure's `api-key`.
+    fn is_credential_header(&self, name: &str) -> bool {
+        self.auth_header
+            .iter()
+            .map(String::as_str)
+            .chain([
+                self.provider.auth_header(),
+                Provider::OpenAI.auth_header(),
+                Provider::Anthropic.auth_header(),
+                "api-key",
+            ])
+            .any(|credential| credential.eq_ignore_ascii_case(name))
+    }
+}
+
+/// The first choice of a response, with the response-level metadata.
+pub(crate) struct Reply {
+    pub(crate) message: MessageResponse,
+    pub(crate) finish_reason: Option<String>,
+    pub(crate) model: Option<String>,
+    pub(crate) usage: Option<Usage>,
+}
+
+impl Reply {
+    fn into_completion(self) -> Completion {
+        Completion {
+            text: self.message.text().unwrap_or_default(),
+            model: self.model,
+            usage: self.usage,
+            finish_reason: self.finish_reason,
+        }
+    }
+
+    fn completion(&self) -> Completion {
+        Completion {
+            text: self.message.text().unwrap_or_default(),
+            model: self.model.clone(),
+            usage: self.usage,
+            finish_reason: self.finish_reason.clone(),
+        }
+    }
+}
+
+impl From<Completion> for Reply {
+    fn from(completion: Completion) -> Self {
+        Self {
+            message: MessageResponse {
+                content: Some(MessageContent::Text(completion.text)),
+                audio: None,
+                tool_calls: None,
+            },
+            finish_reason: completion.finish_reason,
+            model: completion.model,
+            usage: completion.usage,
+        }
+    }
+}
+
+/// Appends [`JSON_INSTRUCTION`] to the last message unless some message
+/// already mentions JSON, which the API requires in JSON mode.
+fn ask_for_json(messages: &mut [Message]) {
+    let mentions_json = messages
+        .iter()
+        .any(|message| message.content.to_ascii_lowercase().contains("json"));
+    if !mentions_json && let Some(message) = messages.last_mut() {
+        message.content = format!("{}\n\n{JSON_INSTRUCTION}", message.content);
+    }
+}
+
+/// Decompresses a gzipped body, or `None` if it's corrupt or inflates past
+/// [`MAX_ERROR_BODY`].
+fn gunzip(body: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    GzDecoder::new(body)
+        .take(MAX_ERROR_BODY + 1)
+        .read_to_end(&mut out)
+        .ok()?;
+    (out.len() as u64 <= MAX_ERROR_BODY).then_some(out)
+}
+
+/// Describes a non-success response, including the provider's error message
+/// when the body is an OpenAI-style error object (Anthropic's errors have
+/// the same `error` object). Bodies are gunzipped first
+/// if `Content-Encoding` says so, since some gateways compress errors unasked.
+fn error_from_response(response: HttpResponse) -> DSRSError {
+    let status = StatusCode::from_u16(response.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    // Only the delay-seconds form; HTTP-date values are rare from APIs
+    let retry_after = response
+        .header(RETRY_AFTER.as_str())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs);
+    let gzipped = response
+        .header(CONTENT_ENCODING.as_str())
+        .is_some_and(|value| {
+            let value = value.trim();
+            value.eq_ignore_ascii_case("gzip") || value.eq_ignore_ascii_case("x-gzip")
+        });
+    let body = if gzipped {
+        gunzip(&response.body)
+    } else {
+        Some(response.body)
+    };
+
+    let message = match body.as_deref().and_then(error_detail) {
+        Some(detail) => format!("HTTP {status}: {detail}"),
+        None => format!("HTTP {status}"),
+    };
+    match status {
+        StatusCode::TOO_MANY_REQUESTS => DSRSError::RateLimited {
+            message,
+            retry_after,
+        },
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => DSRSError::AuthError(message),
+        _ => DSRSError::HttpError {
+            status: status.as_u16(),
+            message,
+            retry_after,
+        },
+    }
+}
+
+/// What an error response's body says went wrong: its structured `error`,
+/// which may also be a bare string or a top-level `message`, else a snippet
+/// of the raw body, such as a gateway's HTML error page. `None` for an
+/// empty body.
+fn error_detail(body: &[u8]) -> Option<String> {
+    if let Ok(ErrorBody { error }) = serde_json::from_slice(body) {
+        return Some(error.to_string());
+    }
+    if let Ok(value) = serde_json::from_slice::<serde_json::Value>(body) {
+        let error = value.get("error").unwrap_or(&value);
+        if let Some(message) = error
+            .get("message")
+            .and_then(serde_json::Value::as_str)
+            .or(error.as_str())
+        {
+            return Some(message.to_string());
+        }
+    }
+    Some(errors::body_snippet(body)).filter(|snippet| !snippet.is_empty())
+}
+
+/// Adds the proxies named by the environment as `var` reads it to
+/// `builder`: `HTTPS_PROXY` for HTTPS requests, `HTTP_PROXY` for plain HTTP
+/// and `ALL_PROXY` for both, each in either case, skipping the hosts in
+/// `NO_PROXY`. Fails with [`DSRSError::ConfigError`] on a URL that isn't
+/// valid.
+fn env_proxies(
+    mut builder: ClientBuilder,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<ClientBuilder> {
+    let lookup = |name: &str| {
+        var(name)
+            .or_else(|| var(&name.to_ascii_lowercase()))
+            .filter(|value| !value.trim().is_empty())
+    };
+    let no_proxy = lookup("NO_PROXY");
+    for (name, proxy) in [
+        ("HTTPS_PROXY", Proxy::https::<String> as fn(String) -> _),
+        ("HTTP_PROXY", Proxy::http),
+        ("ALL_PROXY", Proxy::all),
+    ] {
+        let Some(url) = lookup(name) else { continue };
+        let proxy = proxy(url.trim().to_string())
+            .map_err(|err| DSRSError::ConfigError(format!("Invalid {name} {url:?}: {err}")))?;
+        builder = builder.proxy(proxy.no_proxy(no_proxy.as_deref().and_then(NoProxy::from_string)));
+    }
+    Ok(builder)
+}
+
+/// Whether `endpoint` is served from this machine (`localhost` or a loopback
+/// address), like a local Ollama or vLLM server.
+fn is_local_endpoint(endpoint: &str) -> bool {
+    let Ok(url) = reqwest::Url::parse(endpoint) else {
+        return false;
+    };
+    match url.host_str() {
+        Some("localhost") => true,
+        Some(host) => host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback()),
+        None => false,
+    }
+}
+
+/// Waits for a slot under the client's concurrency limit, if it has one.
+async fn acquire(concurrency: Option<Arc<Semaphore>>) -> Result<Option<OwnedSemaphorePermit>> {
+    match concurrency {
+        Some(semaphore) => semaphore
+            .acquire_owned()
+            .await
+            .map(Some)
+            .map_err(|err| DSRSError::ConfigError(format!("Concurrency limiter closed: {err}"))),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::cost::Price;
+    use crate::logging::tests::capture;
+    use crate::test_util::{FakeTransport, MockResponse, MockServer};
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use serde_json::json;
+    use std::io::Write;
+
+    const ERROR_JSON: &str = "{\"error\":{\"message\":\"Rate limit reached for gpt-4o\",\"type\":\"requests\",\"code\":\"rate_limit_exceeded\"}}";
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// A builder with credentials, so `build` succeeds.
+    fn test_builder() -> LLMClientBuilder {
+        LLMClient::builder().api_key("test-key")
+    }
+
+    fn request(content: &str, temperature: Option<f32>) -> ChatRequest {
+        ChatRequest {
+            model: "gpt-3.5-turbo".to_string(),
+            messages: vec![Message::user(content)],
+            max_tokens: Some(100),
+            temperature,
+            stream: None,
+            modalities: None,
+            audio: None,
+            response_format: None,
+            n: None,
+            tools: None,
+            tool_choice: None,
+            top_p: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            stop: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrent_requests_limits_permits() {
+        let client = test_builder().max_concurrent_requests(1).build().unwrap();
+        let held = acquire(client.concurrency.clone()).await.unwrap();
+        assert!(held.is_some());
+
+        let waiting = acquire(client.concurrency.clone());
+        let blocked = tokio::time::timeout(Duration::from_millis(20), waiting).await;
+        assert!(blocked.is_err(), "second request should wait for a slot");
+
+        drop(held);
+        assert!(acquire(client.concurrency.clone()).await.unwrap().is_some());
+        assert!(
+            acquire(LLMClient::new().concurrency)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_max_concurrent_requests_rejects_zero() {
+        let result = test_builder().max_concurrent_requests(0).build();
+        assert!(matches!(result, Err(DSRSError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_from_env_fills_unset_fields() {
+        let vars = |name: &str| match name {
+            "LLM_API_KEY" => Some("env-key".to_string()),
+            "LLM_ENDPOINT" => Some("http://env.invalid/v1/chat/completions".to_string()),
+            "LLM_PROVIDER" => Some("anthropic".to_string()),
+            _ => None,
+        };
+        let client = LLMClientBuilder::from_vars(vars).build().unwrap();
+        assert_eq!(client.api_key.as_deref(), Some("env-key"));
+        assert_eq!(client.endpoint, "http://env.invalid/v1/chat/completions");
+        assert_eq!(client.provider, Provider::Anthropic);
+        assert!(
+            LLMClientBuilder::from_vars(|name| (name == "LLM_PROVIDER").then(|| "bard".to_string()))
+                .api_key("key")
+                .build()
+                .is_err()
+        );
+
+        // Explicit settings win over the environment
+        let client = LLMClientBuilder::from_vars(vars)
+            .endpoint("http://explicit.invalid/v1/chat/completions")
+            .build()
+            .unwrap();
+        assert_eq!(client.api_key.as_deref(), Some("env-key"));
+        assert_eq!(
+            client.endpoint,
+            "http://explicit.invalid/v1/chat/completions"
+        );
+    }
+
+    #[test]
+    fn test_build_requires_api_key() {
+        for builder in [LLMClient::builder(), LLMClient::builder().api_key("")] {
+            assert!(matches!(builder.build(), Err(DSRSError::ConfigError(_))));
+        }
+        let client = test_builder().default_model("gpt-4o").build().unwrap();
+        assert_eq!(client.default_model(), "gpt-4o");
+        assert_eq!(client.endpoint, Provider::OpenAI.default_endpoint());
+        assert_eq!(
+            test_builder().build().unwrap().default_model(),
+            DEFAULT_MODEL
+        );
+    }
+
+    #[test]
+    fn test_local_endpoints_need_no_key() {
+        for endpoint in [
+            "http://localhost:11434/v1/chat/completions",
+            "http://127.0.0.1:8000/v1/chat/completions",
+            "http://[::1]:8000/v1/chat/completions",
+        ] {
+            assert!(is_local_endpoint(endpoint), "{endpoint}");
+            assert!(LLMClient::builder().endpoint(endpoint).build().is_ok());
+        }
+        for endpoint in ["https://api.openai.com/v1/chat/completions", "not a url"] {
+            assert!(!is_local_endpoint(endpoint), "{endpoint}");
+            assert!(LLMClient::builder().endpoint(endpoint).build().is_err());
+        }
+
+        // no_auth opts in for any endpoint, dropping a key set before it
+        let client = test_builder()
+            .no_auth()
+            .endpoint("https://llm.internal/v1/chat/completions")
+            .build()
+            .unwrap();
+        assert_eq!(client.api_key, None);
+        assert!(client.headers(&client.target("gpt-4o")).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unauthenticated_local_server() {
+        // Ollama-style replies: extra fields, no usage, and an error object
+        // next to empty choices
+        let reply = serde_json::json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1_700_000_000,
+            "model": "llama3",
+            "system_fingerprint": "fp_ollama",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": "Paris", "images": null},
+                "finish_reason": "stop",
+                "logprobs": null
+            }]
+        });
+        let failure = serde_json::json!({
+            "choices": [],
+            "error": {"message": "model 'llama9' not found", "type": "api_error", "code": null}
+        });
+        let server = MockServer::start(vec![
+            MockResponse::new(200, reply.to_string()),
+            MockResponse::new(200, failure.to_string()),
+        ])
+        .await;
+        let client = LLMClient::builder().endpoint(server.url()).build().unwrap();
+
+        let completion = client
+            .complete_detailed("Capital of France?", "llama3", None, None)
+            .await
+            .unwrap();
+        assert_eq!(completion.text, "Paris");
+        assert_eq!(completion.model.as_deref(), Some("llama3"));
+        assert_eq!(completion.usage, None);
+        assert_eq!(server.requests()[0].header("Authorization"), None);
+
+        let err = client
+            .complete("Capital of France?", "llama9", None, None)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "API error: model 'llama9' not found (type: api_error, code: None)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cache_serves_repeated_requests() {
+        let server = MockServer::start(vec![
+            MockResponse::completion("Paris"),
+            MockResponse::completion("Paris, again"),
+        ])
+        .await;
+        let cache = Arc::new(MemoryCache::new());
+        let client = test_builder()
+            .endpoint(server.url())
+            .cache(cache.clone())
+            .build()
+            .unwrap();
+
+        for _ in 0..2 {
+            let reply = client.complete("Capital?", "gpt-4o", Some(10), None).await;
+            assert_eq!(reply.unwrap(), "Paris");
+        }
+        assert_eq!(server.requests().len(), 1);
+
+        // Different parameters are a different entry
+        client
+            .complete("Capital?", "gpt-4o", Some(10), Some(0.0))
+            .await
+            .unwrap();
+        assert_eq!(server.requests().len(), 2);
+
+        // Bypassing the cache always sends the request
+        let fresh = client.without_cache();
+        fresh
+            .complete("Capital?", "gpt-4o", Some(10), None)
+            .await
+            .unwrap();
+        assert_eq!(server.requests().len(), 3);
+
+        // Hits need no API key; misses to a remote endpoint still fail for
+        // the lack of one
+        let offline = LLMClient::builder().cache(cache).build().unwrap();
+        let reply = offline.complete("Capital?", "gpt-4o", Some(10), None).await;
+        assert_eq!(reply.unwrap(), "Paris");
+        assert!(matches!(
+            offline
+                .complete("Capital of Peru?", "gpt-4o", None, None)
+                .await,
+            Err(DSRSError::ConfigError(_))
+        ));
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_clients_keep_their_own_configuration() {
+        let first = MockServer::start(vec![MockResponse::completion("from first")]).await;
+        let second = MockServer::start(vec![MockResponse::completion("from second")]).await;
+        let client_for = |server: &MockServer, key: &str| {
+            LLMClient::builder()
+                .api_key(key)
+                .endpoint(server.url())
+                .build()
+                .unwrap()
+        };
+        let (a, b) = (client_for(&first, "key-a"), client_for(&second, "key-b"));
+
+        let (reply_a, reply_b) = tokio::join!(
+            a.complete("hi", "gpt-4o", None, None),
+            b.complete("hi", "gpt-4o", None, None)
+        );
+        assert_eq!(reply_a.unwrap(), "from first");
+        assert_eq!(reply_b.unwrap(), "from second");
+        assert_eq!(
+            first.requests()[0].header("Authorization"),
+            Some("Bearer key-a")
+        );
+        assert_eq!(
+            second.requests()[0].header("Authorization"),
+            Some("Bearer key-b")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_requests_go_through_the_proxy() {
+        let proxy = MockServer::start(vec![MockResponse::completion("via proxy")]).await;
+        let proxy_url = proxy.url().replace("/v1/chat/completions", "");
+        // `.invalid` names never resolve, so only the proxy can answer
+        let client = test_builder()
+            .endpoint("http://llm.invalid/v1/chat/completions")
+            .proxy(Proxy::http(&proxy_url).unwrap())
+            .build()
+            .unwrap();
+        let reply = client.complete("hi", "gpt-4o", None, None).await.unwrap();
+        assert_eq!(reply, "via proxy");
+        assert_eq!(proxy.requests()[0].header("Host"), Some("llm.invalid"));
+
+        // Nothing listens on port 1
+        let unreachable = test_builder()
+            .endpoint("http://llm.invalid/v1/chat/completions")
+            .proxy(Proxy::all("http://127.0.0.1:1").unwrap())
+            .proxy_from_env(false)
+            .max_retries(0)
+            .build()
+            .unwrap();
+        let err = unreachable
+            .complete("hi", "gpt-4o", None, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DSRSError::NetworkError(_)), "{err:?}");
+
+        // Malformed URLs are refused up front rather than at request time
+        assert!(Proxy::all("http://[::1").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_from_env_reads_https_proxy() {
+        let proxy = MockServer::start(vec![MockResponse::completion("tunneled")]).await;
+        let proxy_url = proxy.url().replace("/v1/chat/completions", "");
+        let env = |name: &str| (name == "https_proxy").then(|| proxy_url.clone());
+        let builder = || {
+            test_builder()
+                .endpoint("https://llm.invalid/v1/chat/completions")
+                .max_retries(0)
+        };
+
+        // HTTPS requests tunnel through the proxy, here one that can't
+        // complete the TLS handshake
+        let client = builder().proxy_from_env(true).build_with_env(env).unwrap();
+        client
+            .complete("hi", "gpt-4o", None, None)
+            .await
+            .unwrap_err();
+        assert_eq!(proxy.requests().len(), 1);
+        assert_eq!(proxy.requests()[0].header("Host"), Some("llm.invalid:443"));
+
+        // Turned off, the variable is ignored and the name doesn't resolve
+        let client = builder().proxy_from_env(false).build_with_env(env).unwrap();
+        let err = client
+            .complete("hi", "gpt-4o", None, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DSRSError::NetworkError(_)), "{err:?}");
+        assert_eq!(proxy.requests().len(), 1);
+
+        let err = builder()
+            .build_with_env(|name| (name == "HTTPS_PROXY").then(|| "http://[::1".to_string()))
+            .unwrap_err();
+        assert!(
+            matches!(&err, DSRSError::ConfigError(message) if message.starts_with("Invalid HTTPS_PROXY \"http://[::1\"")),
+            "{err:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_timeout_is_configurable() {
+        let server = MockServer::start(vec![
+            MockResponse::completion("late").delay(Duration::from_millis(500)),
+        ])
+        .await;
+        let client = test_builder()
+            .endpoint(server.url())
+            .timeout(Duration::from_millis(50))
+            .max_retries(0)
+            .build()
+            .unwrap();
+        let result = client.complete("hi", "gpt-4o", None, None).await;
+        assert!(
+            matches!(result, Err(DSRSError::Timeout(Some(limit))) if limit == Duration::from_millis(50)),
+            "{result:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deadline_bounds_every_attempt_together() {
+        // Each attempt fails fast, but the backoff would outlast the deadline
+        let server = MockServer::start(vec![MockResponse::new(503, "busy")]).await;
+        let client = test_builder()
+            .endpoint(server.url())
+            .retry_policy(
+                RetryPolicy::new(5)
+                    .base_delay(Duration::from_millis(250))
+                    .jitter(false),
+            )
+            .build()
+            .unwrap();
+        let limit = Duration::from_millis(400);
+        // Loads the tokenizer, so only the request is timed
+        tokens::count_tokens("hi", "gpt-4o");
+        let started = Instant::now();
+        let result = client
+            .complete_with_params("hi", "gpt-4o", &CompletionParams::new().timeout(limit))
+            .await;
+        assert!(
+            matches!(result, Err(DSRSError::Timeout(Some(found))) if found == limit),
+            "{result:?}"
+        );
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(server.requests().len(), 2);
+
+        // A default deadline applies to positional calls too
+        let slow = MockServer::start(vec![
+            MockResponse::completion("late").delay(Duration::from_secs(5)),
+        ])
+        .await;
+        let client = test_builder()
+            .endpoint(slow.url())
+            .default_params(CompletionParams::new().timeout(Duration::from_millis(50)))
+            .build()
+            .unwrap();
+        let result = client.complete("hi", "gpt-4o", None, None).await;
+        assert!(
+            matches!(result, Err(DSRSError::Timeout(Some(_)))),
+            "{result:?}"
+        );
+        // And to the other chat paths, which share the same send
+        let result = client.complete_n("hi", "gpt-4o", 2, None, Some(0.9)).await;
+        assert!(
+            matches!(result, Err(DSRSError::Timeout(Some(_)))),
+            "{result:?}"
+        );
+        let result = client
+            .complete_with_tools(&[Message::user("hi")], &[], "gpt-4o")
+            .await;
+        assert!(
+            matches!(result, Err(DSRSError::Timeout(Some(_)))),
+            "{result:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cancellation_stops_requests() {
+        let server = MockServer::start(vec![
+            MockResponse::completion("late").delay(Duration::from_secs(5)),
+        ])
+        .await;
+        let token = CancellationToken::new();
+        let client = test_builder()
+            .endpoint(server.url())
+            .build()
+            .unwrap()
+            .with_cancellation(token.clone());
+        let canceller = tokio::spawn({
+            let token = token.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                token.cancel();
+            }
+        });
+        tokens::count_tokens("hi", "gpt-4o");
+        let started = Instant::now();
+        let result = client.complete("hi", "gpt-4o", None, None).await;
+        assert!(matches!(result, Err(DSRSError::Cancelled)), "{result:?}");
+        assert!(started.elapsed() < Duration::from_secs(1));
+        canceller.await.unwrap();
+
+        // Once cancelled, nothing more is sent
+        let result = client.complete("again", "gpt-4o", None, None).await;
+        assert!(matches!(result, Err(DSRSError::Cancelled)), "{result:?}");
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_missing_credentials_fail_requests_without_panicking() {
+        let client = LLMClient::builder().into_client(Client::new());
+        let result = client.complete("hi", "gpt-4o", None, None).await;
+        assert!(matches!(result, Err(DSRSError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_count_tokens_prefers_configured_counter() {
+        struct Words;
+        impl TokenCounter for Words {
+            fn count_tokens(&self, text: &str) -> usize {
+                text.split_whitespace().count()
+            }
+        }
+
+        let text = "one two three";
+        let default = LLMClient::new();
+        assert_eq!(
+            default.count_tokens(text, "gpt-4o"),
+            tokens::count_tokens(text, "gpt-4o")
+        );
+        let custom = test_builder()
+            .token_counter(Arc::new(Words))
+            .build()
+            .unwrap();
+        assert_eq!(custom.count_tokens(text, "gpt-4o"), 3);
+    }
+
+    #[test]
+    fn test_stable_hash_is_deterministic() {
+        let a = request("hello", Some(0.0));
+        let b = request("hello", Some(-0.0));
+        assert_eq!(a.stable_hash(), b.stable_hash());
+        assert_eq!(a.stable_hash_hex().len(), 16);
+    }
+
+    #[test]
+    fn test_too_long_policy() {
+        let prompt = "abcdefgh";
+        assert!(matches!(
+            TooLongPolicy::Error.apply(prompt, 4, str::len),
+            Err(DSRSError::PromptTooLong(8, 4))
+        ));
+        assert_eq!(
+            TooLongPolicy::TruncateStart
+                .apply(prompt, 4, str::len)
+                .unwrap(),
+            "efgh"
+        );
+        assert_eq!(
+            TooLongPolicy::TruncateEnd
+                .apply(prompt, 4, str::len)
+                .unwrap(),
+            "abcd"
+        );
+        assert_eq!(
+            TooLongPolicy::Error.apply(prompt, 8, str::len).unwrap(),
+            prompt
+        );
+    }
+
+    fn error_for(response: HttpResponse) -> String {
+        error_from_response(response).to_string()
+    }
+
+    #[test]
+    fn test_error_body_is_reported() {
+        assert_eq!(
+            error_for(HttpResponse::new(429, ERROR_JSON)),
+            "Rate limited: HTTP 429 Too Many Requests: Rate limit reached for gpt-4o \
+             (type: requests, code: Some(\"rate_limit_exceeded\"))"
+        );
+        // Gateways' own error pages and ad hoc error shapes
+        assert_eq!(
+            error_for(HttpResponse::new(
+                502,
+                "<html>\n  <h1>Bad Gateway</h1>\n</html>"
+            )),
+            "API error: HTTP 502 Bad Gateway: <html> <h1>Bad Gateway</h1> </html>"
+        );
+        assert_eq!(
+            error_for(HttpResponse::new(400, r#"{"error": "model not found"}"#)),
+            "API error: HTTP 400 Bad Request: model not found"
+        );
+        assert_eq!(
+            error_for(HttpResponse::new(404, r#"{"message": "no such route"}"#)),
+            "API error: HTTP 404 Not Found: no such route"
+        );
+        assert_eq!(
+            error_for(HttpResponse::new(500, "")),
+            "API error: HTTP 500 Internal Server Error"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_statuses_map_to_error_variants() {
+        let server = MockServer::respond_with(|index, _| {
+            let status = [401, 403, 429, 404, 500][index];
+            MockResponse::new(status, ERROR_JSON)
+        })
+        .await;
+        let client = test_builder()
+            .endpoint(server.url())
+            .max_retries(0)
+            .build()
+            .unwrap();
+        let mut errors = Vec::new();
+        for _ in 0..5 {
+            errors.push(
+                client
+                    .complete("hi", "gpt-4o", None, None)
+                    .await
+                    .unwrap_err(),
+            );
+        }
+
+        assert!(
+            matches!(&errors[0], DSRSError::AuthError(message) if message.starts_with("HTTP 401 Unauthorized: Rate limit reached")),
+            "{:?}",
+            errors[0]
+        );
+        assert!(
+            matches!(&errors[1], DSRSError::AuthError(message) if message.starts_with("HTTP 403"))
+        );
+        assert!(matches!(errors[2], DSRSError::RateLimited { .. }));
+        assert!(matches!(
+            errors[3],
+            DSRSError::HttpError { status: 404, .. }
+        ));
+        assert!(matches!(
+            errors[4],
+            DSRSError::HttpError { status: 500, .. }
+        ));
+        let retryable: Vec<bool> = errors.iter().map(DSRSError::is_retryable).collect();
+        assert_eq!(retryable, [false, false, true, false, true]);
+        assert!(
+            errors[0]
+                .to_string()
+                .starts_with("Authentication failed: HTTP 401")
+        );
+    }
+
+    #[test]
+    fn test_retry_after_is_captured() {
+        let err =
+            error_from_response(HttpResponse::new(429, ERROR_JSON).with_header("Retry-After", "7"));
+        assert!(matches!(
+            err,
+            DSRSError::RateLimited { retry_after: Some(delay), .. }
+                if delay == Duration::from_secs(7)
+        ));
+        assert!(err.to_string().ends_with("; retry after 7s"));
+    }
+
+    fn retrying_client(server: &MockServer, policy: RetryPolicy) -> LLMClient {
+        test_builder()
+            .api_key("test-key")
+            .endpoint(server.url())
+            .retry_policy(policy)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_retries_rate_limits_until_success() {
+        let server = MockServer::start(vec![
+            MockResponse::new(429, ERROR_JSON),
+            MockResponse::new(429, ERROR_JSON).header("Retry-After", "5"),
+            MockResponse::completion("Paris"),
+        ])
+        .await;
+        let delays = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorder = Arc::clone(&delays);
+        let policy = RetryPolicy::new(3)
+            .base_delay(Duration::from_millis(10))
+            .max_delay(Duration::from_millis(40))
+            .jitter(false)
+            .on_retry(move |attempt, _, delay| recorder.lock().unwrap().push((attempt, delay)));
+
+        let client = retrying_client(&server, policy);
+        let response = client.complete("Capital?", "gpt-4o", None, None).await;
+        assert_eq!(response.unwrap(), "Paris");
+        assert_eq!(server.requests().len(), 3);
+        // Backoff first, then the server's Retry-After capped at max_delay
+        assert_eq!(
+            *delays.lock().unwrap(),
+            [
+                (1, Duration::from_millis(10)),
+                (2, Duration::from_millis(40))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_errors_are_not_retried() {
+        let server = MockServer::start(vec![MockResponse::new(401, ERROR_JSON)]).await;
+        let client = retrying_client(&server, RetryPolicy::new(3));
+        let result = client.complete("Hi", "gpt-4o", None, None).await;
+        assert!(matches!(result, Err(DSRSError::AuthError(_))));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retries_can_be_disabled() {
+        let server = MockServer::start(vec![MockResponse::new(503, "")]).await;
+        let client = test_builder()
+            .endpoint(server.url())
+            .max_retries(0)
+            .build()
+            .unwrap();
+        let result = client.complete("Hi", "gpt-4o", None, None).await;
+        assert!(matches!(
+            result,
+            Err(DSRSError::HttpError { status: 503, .. })
+        ));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_retries_report_attempts() {
+        let server = MockServer::start(vec![MockResponse::new(503, "")]).await;
+        let policy = RetryPolicy::new(2).base_delay(Duration::from_millis(1));
+        let client = retrying_client(&server, policy);
+        let err = client
+            .complete("Hi", "gpt-4o", None, None)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Failed after 3 attempts: API error: HTTP 503 Service Unavailable"
+        );
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn test_gzipped_error_body_is_decompressed() {
+        let gzipped = HttpResponse::new(429, gzip(ERROR_JSON.as_bytes()))
+            .with_header("Content-Encoding", "gzip");
+        assert_eq!(
+            error_for(gzipped),
+            error_for(HttpResponse::new(429, ERROR_JSON))
+        );
+
+        // A body that claims gzip but isn't still yields the status
+        let bogus = HttpResponse::new(500, ERROR_JSON).with_header("Content-Encoding", "gzip");
+        assert_eq!(
+            error_for(bogus),
+            "API error: HTTP 500 Internal Server Error"
+        );
+        let mut truncated = gzip(ERROR_JSON.as_bytes());
+        truncated.truncate(40);
+        assert_eq!(gunzip(&truncated), None);
+    }
+
+    #[test]
+    fn test_gzip_bombs_are_not_inflated() {
+        let body = vec![b' '; MAX_ERROR_BODY as usize];
+        assert_eq!(gunzip(&gzip(&body)).map(|out| out.len()), Some(body.len()));
+        // A megabyte of spaces compresses to about a kilobyte
+        let bomb = gzip(&vec![b' '; MAX_ERROR_BODY as usize + 1]);
+        assert!(bomb.len() < 2048);
+        assert_eq!(gunzip(&bomb), None);
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Capital {
+        city: String,
+        population: u64,
+    }
+
+    fn completion(text: &str) -> Completion {
+        Completion {
+            text: text.to_string(),
+            model: None,
+            usage: None,
+            finish_reason: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_complete_detailed_reports_usage_and_finish_reason() {
+        let body = serde_json::json!({
+            "model": "gpt-4o-2024-08-06",
+            "choices": [{
+                "message": {"role": "assistant", "content": "The French capital is"},
+                "finish_reason": "length"
+            }],
+            "usage": {"prompt_tokens": 12, "completion_tokens": 5, "total_tokens": 17}
+        });
+        let server = MockServer::start(vec![MockResponse::new(200, body.to_string())]).await;
+        let client = test_builder().endpoint(server.url()).build().unwrap();
+
+        let completion = client
+            .complete_detailed("Capital of France?", "gpt-4o", Some(5), None)
+            .await
+            .unwrap();
+        assert_eq!(completion.text, "The French capital is");
+        assert_eq!(completion.model.as_deref(), Some("gpt-4o-2024-08-06"));
+        assert_eq!(
+            completion.usage,
+            Some(Usage {
+                prompt_tokens: 12,
+                completion_tokens: 5,
+                total_tokens: 17
+            })
+        );
+        assert!(completion.is_truncated());
+    }
+
+    #[tokio::test]
+    async fn test_anthropic_provider_end_to_end() {
+        let body = serde_json::json!({
+            "type": "message",
+            "content": [{"type": "text", "text": "Paris"}],
+            "stop_reason": "end_turn",
+            "usage": {"input_tokens": 9, "output_tokens": 1}
+        });
+        let server = MockServer::start(vec![MockResponse::new(200, body.to_string())]).await;
+        let client = test_builder()
+            .provider(Provider::Anthropic)
+            .endpoint(server.url())
+            .system_prompt("Be brief.")
+            .build()
+            .unwrap();
+
+        let completion = client
+            .complete_detailed("Capital of France?", "claude-sonnet-4-20250514", None, None)
+            .await
+            .unwrap();
+        assert_eq!(completion.text, "Paris");
+        assert_eq!(completion.finish_reason.as_deref(), Some("stop"));
+
+        let request = &server.requests()[0];
+        assert_eq!(request.header("x-api-key"), Some("test-key"));
+        assert_eq!(request.header("anthropic-version"), Some("2023-06-01"));
+        assert_eq!(request.header("Authorization"), None);
+        let body = request.json();
+        assert_eq!(body["system"], "Be brief.");
+        assert_eq!(body["max_tokens"], 1024);
+        assert_eq!(body["messages"][0]["content"], "Capital of France?");
+    }
+
+    fn fake_client(transport: &Arc<FakeTransport>) -> LLMClient {
+        test_builder()
+            .endpoint("https://llm.test/v1/chat/completions")
+            .transport(Arc::clone(transport) as Arc<dyn HttpTransport>)
+            .retry_policy(RetryPolicy::none())
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_fake_transport_serves_canned_responses() {
+        let transport = FakeTransport::new(vec![HttpResponse::json(
+            200,
+            &json!({
+                "model": "gpt-4o-2024-08-06",
+                "choices": [{
+                    "message": {"role": "assistant", "content": "Paris"},
+                    "finish_reason": "length"
+                }],
+                "usage": {"prompt_tokens": 9, "completion_tokens": 1, "total_tokens": 10}
+            }),
+        )]);
+        let completion = fake_client(&transport)
+            .complete_detailed("Capital of France?", "gpt-4o", Some(5), None)
+            .await
+            .unwrap();
+        assert_eq!(completion.text, "Paris");
+        assert!(completion.is_truncated());
+        assert_eq!(completion.model.as_deref(), Some("gpt-4o-2024-08-06"));
+        assert_eq!(completion.usage.map(|usage| usage.total_tokens), Some(10));
+
+        let sent = transport.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].url, "https://llm.test/v1/chat/completions");
+        assert_eq!(
+            sent[0].headers,
+            [("Authorization".to_string(), "Bearer test-key".to_string())]
+        );
+        assert_eq!(sent[0].body["max_tokens"], 5);
+        assert_eq!(sent[0].body["messages"][0]["content"], "Capital of France?");
+    }
+
+    #[tokio::test]
+    async fn test_spend_accumulates_until_the_budget_refuses_a_request() {
+        let reply = json!({
+            "choices": [{"message": {"role": "assistant", "content": "Paris"}}],
+            "usage": {"prompt_tokens": 1000, "completion_tokens": 500, "total_tokens": 1500}
+        });
+        let transport = FakeTransport::new(vec![
+            HttpResponse::json(200, &reply),
+            HttpResponse::json(200, &reply),
+            HttpResponse::json(200, &reply),
+        ]);
+        // gpt-4o: $2.50 in and $10 out per million, so $0.0075 a response
+        // and up to $0.01 for 1000 completion tokens
+        let client = test_builder()
+            .transport(Arc::clone(&transport) as Arc<dyn HttpTransport>)
+            .retry_policy(RetryPolicy::none())
+            .budget_usd(0.02)
+            .build()
+            .unwrap();
+        for _ in 0..2 {
+            client
+                .complete("Hi", "gpt-4o", Some(1000), None)
+                .await
+                .unwrap();
+        }
+        assert!((client.total_cost() - 0.015).abs() < 1e-9);
+        assert!((client.without_cache().total_cost() - 0.015).abs() < 1e-9);
+
+        let err = client
+            .complete("Hi", "gpt-4o", Some(1000), None)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            DSRSError::BudgetExceeded { spent, budget } if (spent - 0.015).abs() < 1e-9 && budget == 0.02
+        ));
+        assert_eq!(transport.sent().len(), 2);
+        // A smaller worst case still fits
+        client
+            .complete("Hi", "gpt-4o", Some(100), None)
+            .await
+            .unwrap();
+        assert!((client.total_cost() - 0.0225).abs() < 1e-9);
+    }
+
+    #[cfg(feature = "stream")]
+    #[tokio::test]
+    async fn test_streams_count_against_the_budget() {
+        use futures_util::StreamExt;
+
+        let sse = |body: &str| {
+            MockResponse::new(200, format!("{body}data: [DONE]\n\n"))
+                .header("Content-Type", "text/event-stream")
+        };
+        let server = MockServer::start(vec![
+            sse("data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}]}\n\n\
+                 data: {\"choices\":[],\"usage\":{\"prompt_tokens\":1000,\"completion_tokens\":500,\"total_tokens\":1500}}\n\n"),
+            MockResponse::new(500, ERROR_JSON),
+            sse("data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}]}\n\n"),
+        ])
+        .await;
+        let client = test_builder()
+            .endpoint(server.url())
+            .budget_usd(0.02)
+            .build()
+            .unwrap();
+
+        // The first stream's worst case, about $0.01, is held while it's open
+        let first = client.complete_stream("Hi", "gpt-4o", Some(1000), None);
+        let refused: Vec<_> = client
+            .complete_stream("Hi", "gpt-4o", Some(1000), None)
+            .collect()
+            .await;
+        assert!(
+            matches!(refused.as_slice(), [Err(DSRSError::BudgetExceeded { .. })]),
+            "{refused:?}"
+        );
+        let tokens: Vec<_> = first.collect().await;
+        assert_eq!(tokens.len(), 1);
+        // Priced from the reported usage: 1000 in and 500 out
+        assert!((client.total_cost() - 0.0075).abs() < 1e-9);
+        assert_eq!(
+            server.requests()[0].json()["stream_options"],
+            json!({"include_usage": true})
+        );
+
+        // An error response costs nothing; a stream without usage costs its
+        // worst case
+        let failed: Vec<_> = client
+            .complete_stream("Hi", "gpt-4o", Some(1000), None)
+            .collect()
+            .await;
+        assert!(failed[0].is_err());
+        assert!((client.total_cost() - 0.0075).abs() < 1e-9);
+        let tokens: Vec<_> = client
+            .complete_stream("Hi", "gpt-4o", Some(1000), None)
+            .collect()
+            .await;
+        assert_eq!(tokens.len(), 1);
+        assert!((client.total_cost() - 0.0175).abs() < 1e-5);
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_responses_are_priced_with_the_configured_pricing() {
+        let reply = json!({
+            "choices": [{"message": {"role": "assistant", "content": "Paris"}}],
+            "usage": {"prompt_tokens": 1000, "completion_tokens": 1000, "total_tokens": 2000}
+        });
+        let transport = FakeTransport::new(vec![
+            HttpResponse::json(200, &reply),
+            HttpResponse::json(200, &reply),
+        ]);
+        let client = test_builder()
+            .transport(Arc::clone(&transport) as Arc<dyn HttpTransport>)
+            .pricing(
+                Pricing::new()
+                    .price("llama", Price::FREE)
+                    .default_price(Price::new(1.0, 1.0)),
+            )
+            .build()
+            .unwrap();
+        client.complete("Hi", "llama3", None, None).await.unwrap();
+        assert_eq!(client.total_cost(), 0.0);
+        client.complete("Hi", "my-model", None, None).await.unwrap();
+        assert!((client.total_cost() - 0.002).abs() < 1e-9);
+
+        assert!(matches!(
+            test_builder().budget_usd(-1.0).build(),
+            Err(DSRSError::ConfigError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_fake_transport_error_responses() {
+        // An error embedded in a 200 body
+        let embedded = HttpResponse::json(
+            200,
+            &json!({
+                "choices": [],
+                "error": {"message": "model overloaded", "type": "server_error", "code": null}
+            }),
+        );
+        let empty = HttpResponse::json(200, &json!({"choices": []}));
+        let garbled = HttpResponse::new(200, "<html>");
+        let transport = FakeTransport::new(vec![
+            embedded,
+            empty,
+            garbled,
+            HttpResponse::new(401, ERROR_JSON),
+        ]);
+        let client = fake_client(&transport);
+        let mut errors = Vec::new();
+        for _ in 0..4 {
+            errors.push(
+                client
+                    .complete("hi", "gpt-4o", None, None)
+                    .await
+                    .unwrap_err(),
+            );
+        }
+
+        assert_eq!(
+            errors[0].to_string(),
+            "API error: model overloaded (type: server_error, code: None)"
+        );
+        assert_eq!(
+            errors[1].to_string(),
+            "API error: No response choices returned"
+        );
+        let garbled = errors[2].to_string();
+        assert!(
+            garbled.starts_with("API error: Failed to parse response")
+                && garbled.ends_with("; body: <html>"),
+            "{garbled}"
+        );
+        assert!(matches!(errors[3], DSRSError::AuthError(_)));
+    }
+
+    #[tokio::test]
+    async fn test_complete_n_returns_every_choice() {
+        let choice = |index: u32, content: &str| json!({"index": index, "message": {"role": "assistant", "content": content}});
+        let transport = FakeTransport::new(vec![
+            HttpResponse::json(
+                200,
+                &json!({"choices": [choice(0, "4"), choice(1, "four"), choice(2, "4.0")]}),
+            ),
+            HttpResponse::json(200, &json!({"choices": []})),
+            HttpResponse::json(200, &json!({"choices": [choice(0, "4"), choice(1, "4")]})),
+        ]);
+        let client = fake_client(&transport);
+
+        let texts = client
+            .complete_n("What is 2 + 2?", "gpt-4o", 3, None, Some(1.0))
+            .await
+            .unwrap();
+        assert_eq!(texts, ["4", "four", "4.0"]);
+        assert_eq!(transport.sent()[0].body["n"], 3);
+
+        let err = client
+            .complete_n("What is 2 + 2?", "gpt-4o", 3, None, Some(1.0))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "API error: No response choices returned");
+
+        // n = 0 fails before anything is sent
+        let err = client
+            .complete_n("What is 2 + 2?", "gpt-4o", 0, None, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DSRSError::ConfigError(_)));
+        assert_eq!(transport.sent().len(), 2);
+
+        // The params version sends the other settings along
+        let params = CompletionParams::new().n(2).top_p(0.5).stop(["\n"]);
+        let texts = client
+            .complete_n_with_params("What is 2 + 2?", "gpt-4o", &params)
+            .await
+            .unwrap();
+        assert_eq!(texts, ["4", "4"]);
+        let body = &transport.sent()[2].body;
+        assert_eq!(body["n"], 2);
+        assert_eq!(body["top_p"], 0.5);
+        assert_eq!(body["stop"], json!(["\n"]));
+        assert!(matches!(
+            client
+                .complete_n_with_params("hi", "gpt-4o", &CompletionParams::new().n(0))
+                .await,
+            Err(DSRSError::ConfigError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_completion_params() {
+        let transport = FakeTransport::new(vec![HttpResponse::json(
+            200,
+            &json!({"choices": [{"message": {"role": "assistant", "content": "1, 2, 3"}}]}),
+        )]);
+        let client = test_builder()
+            .endpoint("https://llm.test/v1/chat/completions")
+            .transport(Arc::clone(&transport) as Arc<dyn HttpTransport>)
+            .default_params(CompletionParams::new().top_p(0.5).stop(["4"]))
+            .build()
+            .unwrap();
+
+        // Defaults apply to every request
+        client
+            .complete("Count", "gpt-4o", Some(10), None)
+            .await
+            .unwrap();
+        let body = &transport.sent()[0].body;
+        assert_eq!(body["top_p"], 0.5);
+        assert_eq!(body["stop"], json!(["4"]));
+        assert!(body.get("presence_penalty").is_none());
+
+        // and fill in what the call's params leave unset
+        let params = CompletionParams::new()
+            .max_tokens(20)
+            .temperature(0.0)
+            .top_p(1.0)
+            .frequency_penalty(0.25)
+            .presence_penalty(-0.5);
+        let completion = client
+            .complete_with_params("Count", "gpt-4o", &params)
+            .await
+            .unwrap();
+        assert_eq!(completion.text, "1, 2, 3");
+        let body = &transport.sent()[1].body;
+        assert_eq!(body["max_tokens"], 20);
+        assert_eq!(body["temperature"], 0.0);
+        assert_eq!(body["top_p"], 1.0);
+        assert_eq!(body["frequency_penalty"], 0.25);
+        assert_eq!(body["presence_penalty"], -0.5);
+        assert_eq!(body["stop"], json!(["4"]));
+
+        for top_p in [-0.1, 1.5, f32::NAN] {
+            let err = client
+                .complete_with_params("Count", "gpt-4o", &CompletionParams::new().top_p(top_p))
+                .await
+                .unwrap_err();
+            assert!(matches!(err, DSRSError::ConfigError(_)), "{top_p}");
+        }
+        let err = test_builder()
+            .default_params(CompletionParams::new().top_p(2.0))
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Configuration error: top_p must be between 0 and 1, got 2"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_routes_pick_the_endpoint_by_model() {
+        let transport = FakeTransport::new(vec![HttpResponse::json(
+            200,
+            &json!({"choices": [{"message": {"role": "assistant", "content": "Hi"}}]}),
+        )]);
+        let client = test_builder()
+            .endpoint("https://api.openai.test/v1/chat/completions")
+            .route("llama", "http://localhost:11434/v1/chat/completions")
+            .route("llama3-70b", "https://gpu-box.test/v1/chat/completions")
+            .transport(Arc::clone(&transport) as Arc<dyn HttpTransport>)
+            .build()
+            .unwrap();
+        for model in ["gpt-4o", "llama3", "llama3-70b-instruct"] {
+            client.complete("Hello", model, None, None).await.unwrap();
+        }
+        let urls: Vec<String> = transport.sent().into_iter().map(|sent| sent.url).collect();
+        assert_eq!(
+            urls,
+            [
+                "https://api.openai.test/v1/chat/completions",
+                "http://localhost:11434/v1/chat/completions",
+                "https://gpu-box.test/v1/chat/completions",
+            ]
+        );
+        assert_eq!(
+            client.endpoint("llama2"),
+            "http://localhost:11434/v1/chat/completions"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_routes_carry_their_own_provider_and_credentials() {
+        let openai = json!({"choices": [{"message": {"role": "assistant", "content": "Hi"}}]});
+        let anthropic = json!({"content": [{"type": "text", "text": "Hi"}]});
+        let transport = FakeTransport::new(vec![
+            HttpResponse::json(200, &openai),
+            HttpResponse::json(200, &openai),
+            HttpResponse::json(200, &anthropic),
+        ]);
+        let client = test_builder()
+            .endpoint("https://api.openai.test/v1/chat/completions")
+            .header("api-key", "azure-secret")
+            .header("OpenAI-Project", "proj_1")
+            .route("llama", "https://gateway.test/v1/chat/completions")
+            .route(
+                "claude-",
+                Route::new("https://anthropic.test/v1/messages")
+                    .provider(Provider::Anthropic)
+                    .api_key("gateway-key"),
+            )
+            .transport(Arc::clone(&transport) as Arc<dyn HttpTransport>)
+            .build()
+            .unwrap();
+        for model in ["gpt-4o", "llama3", "claude-sonnet-4"] {
+            client
+                .complete("Hello", model, Some(10), None)
+                .await
+                .unwrap();
+        }
+
+        let sent = transport.sent();
+        let header = |index: usize, name: &str| {
+            sent[index]
+                .headers
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.clone())
+        };
+        assert_eq!(
+            header(0, "Authorization").as_deref(),
+            Some("Bearer test-key")
+        );
+        assert_eq!(header(0, "api-key").as_deref(), Some("azure-secret"));
+        // No credentials go to a route without a key
+        assert_eq!(header(1, "Authorization"), None);
+        assert_eq!(header(1, "api-key"), None);
+        assert_eq!(header(1, "OpenAI-Project").as_deref(), Some("proj_1"));
+        assert_eq!(header(2, "x-api-key").as_deref(), Some("gateway-key"));
+        assert_eq!(header(2, "Authorization"), None);
+        assert!(header(2, "anthropic-version").is_some());
+        assert_eq!(sent[2].body["max_tokens"], 10);
+        assert_eq!(sent[2].body["messages"][0]["content"], "Hello");
+    }
+
+    #[tokio::test]
+    async fn test_config_sets_builder_defaults() {
+        let transport = FakeTransport::new(vec![HttpResponse::json(
+            200,
+            &json!({"choices": [{"message": {"role": "assistant", "content": "Hi"}}]}),
+        )]);
+        let config = Config {
+            model: Some("llama3".to_string()),
+            endpoint: Some("https://llm.test/v1/chat/completions".to_string()),
+            max_tokens: Some(2000),
+            temperature: Some(0.2),
+            timeout: Some(Duration::from_secs(5)),
+        };
+        let client = test_builder()
+            .transport(Arc::clone(&transport) as Arc<dyn HttpTransport>)
+            .default_params(CompletionParams::new().top_p(0.5))
+            .config(&config)
+            .build()
+            .unwrap();
+        assert_eq!(client.default_model(), "llama3");
+
+        // Arguments of the call still win over the config
+        client
+            .complete("Hello", "llama3", None, None)
+            .await
+            .unwrap();
+        client
+            .complete("Hello", "llama3", Some(10), Some(1.0))
+            .await
+            .unwrap();
+        let sent = transport.sent();
+        assert_eq!(sent[0].url, "https://llm.test/v1/chat/completions");
+        assert_eq!(sent[0].body["max_tokens"], 2000);
+        assert_eq!(sent[0].body["top_p"], 0.5);
+        assert!((sent[0].body["temperature"].as_f64().unwrap() - 0.2).abs() < 1e-6);
+        assert_eq!(sent[1].body["max_tokens"], 10);
+        assert_eq!(sent[1].body["temperature"], 1.0);
+        assert_eq!(transport.sent().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_logs_redact_credential_headers() {
+        // As with `-vv`
+        let (subscriber, buffer) = capture(tracing::Level::DEBUG);
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let transport = FakeTransport::new(vec![HttpResponse::json(
+            200,
+            &json!({"choices": [{"message": {"role": "assistant", "content": "Hi"}}]}),
+        )]);
+        let client = LLMClient::builder()
+            .no_auth()
+            .endpoint("https://llm.test/v1/chat/completions")
+            .header("api-key", "secret")
+            .header("X-Request-Source", "tests")
+            .transport(Arc::clone(&transport) as Arc<dyn HttpTransport>)
+            .build()
+            .unwrap();
+        client.complete("Hi", "gpt-4o", None, None).await.unwrap();
+
+        let logs = buffer.contents();
+        assert!(logs.contains(r#"("api-key", "[REDACTED]")"#), "{logs}");
+        assert!(logs.contains(r#"("X-Request-Source", "tests")"#), "{logs}");
+        assert!(!logs.contains("secret"), "{logs}");
+        assert_eq!(transport.sent()[0].headers[0].1, "secret");
+    }
+
+    #[tokio::test]
+    async fn test_logs_redact_the_api_key() {
+        let (subscriber, buffer) = capture(tracing::Level::TRACE);
+        let _guard = tracing::subscriber::set_default(subscriber);
+        // A gateway echoing the key back must not leak it either
+        let transport = FakeTransport::new(vec![HttpResponse::json(
+            401,
+            &json!({"error": {"message": "Incorrect API key provided: test-key", "type": "invalid_request_error"}}),
+        )]);
+        let client = fake_client(&transport);
+        client
+            .complete("Hi", "gpt-4o", None, None)
+            .await
+            .unwrap_err();
+
+        let logs = buffer.contents();
+        assert!(logs.contains("sending chat request"), "{logs}");
+        assert!(logs.contains("chat request body"), "{logs}");
+        assert!(
+            logs.contains(r#"[("Authorization", "[REDACTED]")]"#),
+            "{logs}"
+        );
+        assert!(logs.contains("received chat response status=401"), "{logs}");
+        assert!(
+            logs.contains("Incorrect API key provided: [REDACTED]"),
+            "{logs}"
+        );
+        assert!(
+            logs.contains(r#"chat request finished model="gpt-4o" prompt_chars=2"#),
+            "{logs}"
+        );
+        assert!(!logs.contains("test-key"), "{logs}");
+        // The transport still got the real key
+        assert_eq!(
+            transport.sent()[0]
+                .headers
+                .iter()
+                .find(|(name, _)| name == "Authorization")
+                .map(|(_, value)| value.as_str()),
+            Some("Bearer test-key")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_debug_logs_retries_and_tokens_but_not_prompts() {
+        let (subscriber, buffer) = capture(tracing::Level::DEBUG);
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let transport = FakeTransport::new(vec![
+            HttpResponse::new(429, "slow down"),
+            HttpResponse::json(
+                200,
+                &json!({
+                    "choices": [{"message": {"role": "assistant", "content": "Paris"}}],
+                    "usage": {"prompt_tokens": 9, "completion_tokens": 1, "total_tokens": 10}
+                }),
+            ),
+        ]);
+        let client = test_builder()
+            .endpoint("https://llm.test/v1/chat/completions")
+            .transport(Arc::clone(&transport) as Arc<dyn HttpTransport>)
+            .retry_policy(RetryPolicy::new(1).base_delay(Duration::ZERO).jitter(false))
+            .build()
+            .unwrap();
+        client
+            .complete("Secret question?", "gpt-4o", None, None)
+            .await
+            .unwrap();
+
+        let logs = buffer.contents();
+        assert!(
+            logs.contains(
+                r#" WARN chat{model="gpt-4o" prompt_chars=16}: dsrs::client: rate limited"#
+            ),
+            "{logs}"
+        );
+        assert!(
+            logs.contains("retrying after a transient failure attempt=1 delay_ms=0"),
+            "{logs}"
+        );
+        assert!(
+            logs.contains(r#"status="ok" prompt_tokens=9 completion_tokens=1"#),
+            "{logs}"
+        );
+        assert!(!logs.contains("Secret question"), "{logs}");
+        assert!(!logs.contains("Paris"), "{logs}");
+    }
+
+    #[tokio::test]
+    async fn test_request_and_response_hooks() {
+        let transport = FakeTransport::new(vec![
+            HttpResponse::new(503, "busy"),
+            HttpResponse::json(
+                200,
+                &json!({"choices": [{"message": {"role": "assistant", "content": "Paris"}}]}),
+            ),
+        ]);
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (requests, responses) = (Arc::clone(&seen), Arc::clone(&seen));
+        let client = test_builder()
+            .endpoint("https://llm.test/v1/chat/completions")
+            .transport(Arc::clone(&transport) as Arc<dyn HttpTransport>)
+            .retry_policy(RetryPolicy::new(1).base_delay(Duration::ZERO))
+            .on_request(move |request| {
+                requests
+                    .lock()
+                    .unwrap()
+                    .push(format!("request {}", request.model));
+            })
+            .on_response(move |request, response| {
+                let line = format!("response {} {}", request.model, response.status);
+                responses.lock().unwrap().push(line);
+            })
+            .build()
+            .unwrap();
+
+        let text = client.complete("Hi", "gpt-4o", None, None).await.unwrap();
+        assert_eq!(text, "Paris");
+        // Each attempt is reported, the failed one included
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                "request gpt-4o",
+                "response gpt-4o 503",
+                "request gpt-4o",
+                "response gpt-4o 200"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_complete_with_tools_round_trip() {
+        let call = json!({
+            "id": "call_1",
+            "type": "function",
+            "function": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"}
+        });
+        let transport = FakeTransport::new(vec![
+            HttpResponse::json(
+                200,
+                &json!({"choices": [{
+                    "message": {"role": "assistant", "content": null, "tool_calls": [call]},
+                    "finish_reason": "tool_calls"
+                }]}),
+            ),
+            HttpResponse::json(
+                200,
+                &json!({"choices": [{"message": {"role": "assistant", "content": "Sunny, 21°C."}}]}),
+            ),
+        ]);
+        let client = test_builder()
+            .endpoint("https://llm.test/v1/chat/completions")
+            .transport(Arc::clone(&transport) as Arc<dyn HttpTransport>)
+            .cache(Arc::new(MemoryCache::new()))
+            .build()
+            .unwrap();
+        let tools = [Tool::function(
+            "get_weather",
+            "Current weather for a city",
+            json!({"type": "object", "properties": {"city": {"type": "string"}}}),
+        )];
+        let mut messages = vec![Message::user("Weather in Paris?")];
+
+        let result = client
+            .complete_with_tools(&messages, &tools, "gpt-4o")
+            .await
+            .unwrap();
+        let ToolCallResult::ToolCalls(calls) = result else {
+            panic!("expected tool calls, got {result:?}");
+        };
+        assert_eq!(calls[0].function.name, "get_weather");
+        let sent = &transport.sent()[0].body;
+        assert_eq!(sent["tools"][0]["function"]["name"], "get_weather");
+        assert!(sent.get("tool_choice").is_none());
+
+        messages.push(Message::assistant_tool_calls(calls.clone()));
+        messages.push(Message::tool(
+            &calls[0].id,
+            "{\"forecast\":\"sunny\",\"celsius\":21}",
+        ));
+        let result = client
+            .complete_with_tool_choice(&messages, &tools, ToolChoice::None, "gpt-4o")
+            .await
+            .unwrap();
+        assert_eq!(result, ToolCallResult::Text("Sunny, 21°C.".to_string()));
+        let sent = &transport.sent()[1].body;
+        assert_eq!(sent["tool_choice"], "none");
+        assert_eq!(sent["messages"][1]["tool_calls"][0], call);
+        assert_eq!(
+            sent["messages"][2],
+            json!({"role": "tool", "content": "{\"forecast\":\"sunny\",\"celsius\":21}", "tool_call_id": "call_1"})
+        );
+
+        // Tool requests aren't served from the cache
+        client
+            .complete_with_tools(&messages, &tools, "gpt-4o")
+            .await
+            .unwrap();
+        assert_eq!(transport.sent().len(), 3);
+    }
+
+    #[test]
+    fn test_provider_sets_default_endpoint() {
+        let client = test_builder()
+            .provider(Provider::Anthropic)
+            .build()
+            .unwrap();
+        assert_eq!(client.endpoint, "https://api.anthropic.com/v1/messages");
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Sum {
+        total: u32,
+    }
+
+    #[tokio::test]
+    async fn test_complete_json_requests_json_mode() {
+        let server = MockServer::start(vec![
+            MockResponse::completion("```json\n{\"total\": 4}\n```"),
+            MockResponse::completion("{\"total\": 5}"),
+            MockResponse::completion("The total is 4."),
+        ])
+        .await;
+        let client = test_builder().endpoint(server.url()).build().unwrap();
+
+        let sum: Sum = client
+            .complete_json("Add 2 and 2.", "gpt-4o", None, None)
+            .await
+            .unwrap();
+        assert_eq!(sum, Sum { total: 4 });
+        let body = server.requests()[0].json();
+        assert_eq!(body["response_format"]["type"], "json_object");
+        assert_eq!(
+            body["messages"][0]["content"],
+            format!("Add 2 and 2.\n\n{JSON_INSTRUCTION}")
+        );
+
+        // Prompts that already ask for JSON are sent unchanged
+        let prompt = "Add 2 and 3; reply as json with a `total` field.";
+        let sum: Sum = client
+            .complete_json(prompt, "gpt-4o", None, None)
+            .await
+            .unwrap();
+        assert_eq!(sum, Sum { total: 5 });
+        assert_eq!(
+            server.requests()[1].json()["messages"][0]["content"],
+            prompt
+        );
+
+        let result = client
+            .complete_json::<Sum>("Add 2 and 2.", "gpt-4o", None, None)
+            .await;
+        assert!(matches!(
+            result,
+            Err(DSRSError::ParseError { raw, .. }) if raw == "The total is 4."
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_batch_bounds_in_flight_requests_and_keeps_order() {
+        // Echoes each prompt back after a delay; "bad" prompts are rejected
+        let server = MockServer::respond_with(|_, request| {
+            let prompt = request.json()["messages"][0]["content"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string();
+            let response = match prompt.as_str() {
+                "bad" => MockResponse::new(400, ERROR_JSON),
+                _ => MockResponse::completion(&prompt.to_uppercase()),
+            };
+            response.delay(Duration::from_millis(30))
+        })
+        .await;
+        let client = test_builder()
+            .endpoint(server.url())
+            .max_retries(0)
+            .build()
+            .unwrap();
+
+        let prompts: Vec<String> = ["a", "b", "bad", "c", "d", "e", "f", "g"]
+            .map(String::from)
+            .to_vec();
+        let seen = std::sync::Mutex::new(Vec::new());
+        let results = client
+            .complete_batch_with_progress(&prompts, "gpt-4o", None, None, 3, |done, total| {
+                seen.lock().unwrap().push((done, total));
+            })
+            .await;
+
+        assert!(
+            (2..=3).contains(&server.max_in_flight()),
+            "{}",
+            server.max_in_flight()
+        );
+        assert_eq!(server.requests().len(), prompts.len());
+        let texts: Vec<_> = results
+            .iter()
+            .map(|result| result.as_deref().ok())
+            .collect();
+        assert_eq!(
+            texts,
+            [
+                Some("A"),
+                Some("B"),
+                None,
+                Some("C"),
+                Some("D"),
+                Some("E"),
+                Some("F"),
+                Some("G")
+            ]
+        );
+        assert!(matches!(
+            results[2],
+            Err(DSRSError::HttpError { status: 400, .. })
+        ));
+        let seen = seen.into_inner().unwrap();
+        assert_eq!(seen, (1..=8).map(|done| (done, 8)).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_extra_headers_go_with_every_request() {
+        let embeddings = json!({"data": [{"index": 0, "embedding": [0.1]}]});
+        // The stream is only requested with the `stream` feature
+        let server = MockServer::start(vec![
+            MockResponse::completion("Hi"),
+            MockResponse::new(200, embeddings.to_string())
+                .header("Content-Type", "application/json"),
+            MockResponse::new(
+                200,
+                "data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}]}\n\ndata: [DONE]\n\n",
+            )
+            .header("Content-Type", "text/event-stream"),
+        ])
+        .await;
+        let client = LLMClient::builder()
+            .api_key("azure-key")
+            .auth_header("api-key")
+            .organization("org-123")
+            .header("OpenAI-Project", "proj-old")
+            .header("openai-project", "proj-456")
+            .endpoint(server.url())
+            .build()
+            .unwrap();
+        client
+            .complete("Hello", "gpt-4o", None, None)
+            .await
+            .unwrap();
+        client
+            .embed("Hello", "text-embedding-3-small")
+            .await
+            .unwrap();
+        #[cfg(feature = "stream")]
+        {
+            use futures_util::StreamExt;
+            let tokens: Vec<_> = client
+                .complete_stream("Hello", "gpt-4o", None, None)
+                .collect()
+                .await;
+            assert_eq!(tokens.len(), 1);
+        }
+        let requests = server.requests();
+        assert_eq!(requests.len(), if cfg!(feature = "stream") { 3 } else { 2 });
+        for request in requests {
+            assert_eq!(request.header("api-key"), Some("azure-key"));
+            assert_eq!(request.header("Authorization"), None);
+            assert_eq!(request.header("OpenAI-Organization"), Some("org-123"));
+            assert_eq!(request.header("OpenAI-Project"), Some("proj-456"));
+        }
+
+        // A header carrying the credentials stands in for the API key
+        let client = LLMClient::builder()
+            .endpoint("https://gateway.test/v1/chat/completions")
+            .header("Authorization", "Token abc")
+            .build()
+            .unwrap();
+        assert_eq!(
+            client.headers(&client.target("gpt-4o")).unwrap(),
+            [("Authorization".to_string(), "Token abc".to_string())]
+        );
+        let err = test_builder()
+            .header("Bad Header", "x")
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, DSRSError::ConfigError(_)), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_embed_many_posts_all_inputs_at_once() {
+        let body = serde_json::json!({
+            "data": [
+                {"index": 0, "embedding": [0.1, 0.2]},
+                {"index": 1, "embedding": [0.3, 0.4]}
+            ]
+        });
+        let server = MockServer::start(vec![
+            MockResponse::new(200, body.to_string()).header("Content-Type", "application/json"),
+        ])
+        .await;
+        let chat_url = server.url();
+        let client = test_builder().endpoint(&chat_url).build().unwrap();
+        assert_eq!(
+            client.embeddings_endpoint,
+            chat_url.replace("/chat/completions", "/embeddings")
+        );
+
+        let inputs = ["first".to_string(), "second".to_string()];
+        let vectors = client
+            .embed_many(&inputs, "text-embedding-3-small")
+            .await
+            .unwrap();
+        assert_eq!(vectors, [vec![0.1, 0.2], vec![0.3, 0.4]]);
+        let request = &server.requests()[0];
+        assert_eq!(request.header("Authorization"), Some("Bearer test-key"));
+        assert_eq!(
+            request.json(),
+            serde_json::json!({"model": "text-embedding-3-small", "input": ["first", "second"]})
+        );
+
+        // A single input expects a single vector back
+        assert!(matches!(
+            client.embed("first", "text-embedding-3-small").await,
+            Err(DSRSError::ApiError(_))
+        ));
+        let anthropic = test_builder()
+            .provider(Provider::Anthropic)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            anthropic.embed("first", "voyage-3").await,
+            Err(DSRSError::ConfigError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_json_mode_applies_to_every_request() {
+        let server = MockServer::start(vec![MockResponse::completion("{\"total\": 4}")]).await;
+        let plain = test_builder().endpoint(server.url()).build().unwrap();
+        plain
+            .complete("Add 2 and 2.", "gpt-4o", None, None)
+            .await
+            .unwrap();
+        let body = server.requests()[0].json();
+        assert!(body.get("response_format").is_none());
+        assert_eq!(body["messages"][0]["content"], "Add 2 and 2.");
+
+        let json = test_builder()
+            .endpoint(server.url())
+            .json_mode(true)
+            .build()
+            .unwrap();
+        json.complete("Add 2 and 2.", "gpt-4o", None, None)
+            .await
+            .unwrap();
+        let body = server.requests()[1].json();
+        assert_eq!(body["response_format"]["type"], "json_object");
+        assert_eq!(
+            body["messages"][0]["content"],
+            format!("Add 2 and 2.\n\n{JSON_INSTRUCTION}")
+        );
+
+        // The instruction isn't added twice
+        let sum: Sum = json
+            .complete_json("Add 2 and 2.", "gpt-4o", None, None)
+            .await
+            .unwrap();
+        assert_eq!(sum, Sum { total: 4 });
+        assert_eq!(server.requests()[2].json()["messages"], body["messages"]);
+    }
+
+    #[test]
+    fn test_usage_and_finish_reason_are_optional() {
+        let response: ChatResponse =
+            serde_json::from_str(r#"{"choices": [{"message": {"content": "Hi"}}]}"#).unwrap();
+        assert!(response.usage.is_none() && response.model.is_none());
+        assert!(response.choices[0].finish_reason.is_none());
+        assert!(!completion("Hi").is_truncated());
+    }
+
+    #[test]
+    fn test_into_result_parses_unfenced_json() {
+        let capital: Capital = completion(" {\"city\": \"Paris\", \"population\": 2100000}\n")
+            .into_result()
+            .unwrap();
+        assert_eq!(
+            capital,
+            Capital {
+                city: "Paris".to_string(),
+                population: 2_100_000
+            }
+        );
+    }
+
+    #[test]
+    fn test_into_result_strips_code_fences() {
+        for text in [
+            "```json\n{\"city\": \"Paris\", \"population\": 1}\n```",
+            "```\n{\"city\": \"Paris\", \"population\": 1}\n```\n",
+            "Here you go:\n```json\n{\"city\": \"Paris\", \"population\": 1}\n```\nAnything else?",
+        ] {
+            let capital: Capital = completion(text).into_result().unwrap();
+            assert_eq!(capital.city, "Paris", "{text}");
+        }
+    }
+
+    #[test]
+    fn test_into_result_keeps_raw_text_on_failure() {
+        let text = "```json\n{\"city\": \"Paris\"}\n```";
+        match completion(text).into_result::<Capital>() {
+            Err(DSRSError::ParseError { message, raw }) => {
+                assert!(message.contains("population"), "{message}");
+                assert_eq!(raw, text);
+            }
+            other => panic!("expected a parse error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_message_constructors() {
+        assert_eq!(Message::system("Be brief.").role, "system");
+        assert_eq!(Message::user("Hi").role, "user");
+        assert_eq!(Message::assistant("Hello!").role, "assistant");
+        assert_eq!(Message::new("tool", "42").content, "42");
+    }
+
+    #[test]
+    fn test_chat_request_keeps_history() {
+        let client = LLMClient::new();
+        let history = [
+            Message::system("Be brief."),
+            Message::user("Capital of France?"),
+            Message::assistant("Paris."),
+            Message::user("And Germany?"),
+        ];
+        let request = client
+            .build_chat_request(&history, "gpt-4o", None, None)
+            .unwrap();
+        assert_eq!(request.messages, history);
+
+        assert!(matches!(
+            client.build_chat_request(&[], "gpt-4o", None, None),
+            Err(DSRSError::ConfigError(_))
+        ));
+    }
+
+    #[test]
+    fn test_system_prompt_is_prepended() {
+        let request = LLMClient::new()
+            .build_request("Hi", "gpt-4o", None, None)
+            .unwrap();
+        assert_eq!(request.messages, [Message::user("Hi")]);
+
+        let client = test_builder().system_prompt("Be brief.").build().unwrap();
+        let request = client.build_request("Hi", "gpt-4o", None, None).unwrap();
+        assert_eq!(
+            request.messages,
+            [Message::system("Be brief."), Message::user("Hi")]
+        );
+
+        // The limit covers system and user content together; each "a " is
+        // one token, plus one for the trailing space
+        let client = test_builder()
+            .system_prompt("a ".repeat(8192))
+            .build()
+            .unwrap();
+        assert!(matches!(
+            client.build_request("Hi", "gpt-4", None, None),
+            Err(DSRSError::PromptTooLong(8194, 8192))
+        ));
+    }
+
+    #[test]
+    fn test_chat_length_limit_sums_messages() {
+        let half = "a ".repeat(4097);
+        let history = [Message::user(half.clone()), Message::user(half.clone())];
+        assert!(matches!(
+            LLMClient::new().build_chat_request(&history, "gpt-4", None, None),
+            Err(DSRSError::PromptTooLong(8196, 8192))
+        ));
+
+        // Truncation trims only the last message to fit the remaining room
+        let client = test_builder()
+            .on_too_long(TooLongPolicy::TruncateEnd)
+            .build()
+            .unwrap();
+        let request = client
+            .build_chat_request(&history, "gpt-4", None, None)
+            .unwrap();
+        assert_eq!(request.messages[0].content, half);
+        // 8192 - 4098 tokens is room for 4094 "a"s once the trailing space goes
+        assert_eq!(request.messages[1].content, "a ".repeat(4094).trim_end());
+    }
+
+    #[test]
+    fn test_max_tokens_counts_toward_context_window() {
+        let client = LLMClient::new();
+        let prompt = "a ".repeat(8000);
+        assert!(
+            client
+                .build_request(&prompt, "gpt-4", Some(191), None)
+                .is_ok()
+        );
+        assert!(matches!(
+            client.build_request(&prompt, "gpt-4", Some(500), None),
+            Err(DSRSError::PromptTooLong(8501, 8192))
+        ));
+        // Larger windows accept the same request
+        assert!(
+            client
+                .build_request(&prompt, "gpt-4o", Some(500), None)
+                .is_ok()
+        );
+
+        // Unknown models fall back to ~4 chars per token and the default window
+        let window = tokens::DEFAULT_CONTEXT_WINDOW;
+        let fits = "a".repeat(window * 4);
+        assert!(client.build_request(&fits, "llama3", None, None).is_ok());
+        assert!(matches!(
+            client.build_request(&format!("{fits}a"), "llama3", None, None),
+            Err(DSRSError::PromptTooLong(len, max)) if len == window + 1 && max == window
+        ));
+    }
+
+    #[test]
+    fn test_context_window_overrides() {
+        let client = test_builder()
+            .context_window("llama3", 128_000)
+            .context_window("gpt-4", 4_096)
+            .context_window("gpt-4o-mini", 64_000)
+            .build()
+            .unwrap();
+        assert_eq!(client.context_window("llama3-70b"), 128_000);
+        assert_eq!(client.context_window("gpt-4-0613"), 4_096);
+        // An override beats a longer built-in prefix...
+        assert_eq!(client.context_window("gpt-4o"), 4_096);
+        // ...and the longest override wins
+        assert_eq!(client.context_window("gpt-4o-mini"), 64_000);
+        assert_eq!(client.context_window("gpt-3.5-turbo"), 16_385);
+        assert_eq!(
+            client.context_window("mistral"),
+            tokens::DEFAULT_CONTEXT_WINDOW
+        );
+
+        let prompt = "a".repeat(100_000);
+        assert!(client.build_request(&prompt, "llama3", None, None).is_ok());
+    }
+
+    #[test]
+    fn test_truncation_fits_remaining_tokens() {
+        let client = test_builder()
+            .on_too_long(TooLongPolicy::TruncateStart)
+            .build()
+            .unwrap();
+        let prompt = format!("{}end", "a ".repeat(9000));
+        let request = client
+            .build_request(&prompt, "gpt-4", Some(1000), None)
+            .unwrap();
+        let kept = &request.messages[0].content;
+        assert!(kept.ends_with("a a end"));
+        assert!(client.count_tokens(kept, "gpt-4") <= 7192);
+    }
+
+    #[test]
+    fn test_too_long_policy_respects_char_boundaries() {
+        // Each 'é' is two bytes, so a 3-byte limit must keep a single char
+        let prompt = "ééé";
+        assert_eq!(
+            TooLongPolicy::TruncateStart
+                .apply(prompt, 3, str::len)
+                .unwrap(),
+            "é"
+        );
+        assert_eq!(
+            TooLongPolicy::TruncateEnd
+                .apply(prompt, 3, str::len)
+                .unwrap(),
+            "é"
+        );
+    }
+
+    #[test]
+    fn test_canonical_json_sorts_keys() {
+        let tool = Tool::function("f", "A function", serde_json::json!({"type": "object"}));
+        // Declared as name, description, parameters
+        assert_eq!(
+            canonical_json(&tool),
+            r#"{"function":{"description":"A function","name":"f","parameters":{"type":"object"}},"type":"function"}"#
+        );
+    }
+
+    #[test]
+    fn test_stable_hash_covers_content_and_params() {
+        let base = request("hello", Some(0.7));
+        assert_ne!(
+            base.stable_hash(),
+            request("hello!", Some(0.7)).stable_hash()
+        );
+        assert_ne!(base.stable_hash(), request("hello", None).stable_hash());
+
+        let mut audio = request("hello", Some(0.7));
+        audio.modalities = Some(vec!["text".to_string(), "audio".to_string()]);
+        audio.audio = Some(AudioConfig::new("alloy", "wav"));
+        assert_ne!(base.stable_hash(), audio.stable_hash());
+
+        let mut several = request("hello", Some(0.7));
+        several.n = Some(3);
+        assert_ne!(base.stable_hash(), several.stable_hash());
+
+        let with_tools = || {
+            let mut request = request("hello", Some(0.7));
+            let parameters = serde_json::json!({"type": "object"});
+            request.tools = Some(vec![Tool::function("f", "A function", parameters)]);
+            request
+        };
+        let tools = with_tools();
+        assert_ne!(base.stable_hash(), tools.stable_hash());
+        let mut required = with_tools();
+        required.tool_choice = Some(ToolChoice::Required);
+        assert_ne!(tools.stable_hash(), required.stable_hash());
+
+        let mut nucleus = request("hello", Some(0.7));
+        nucleus.top_p = Some(0.9);
+        assert_ne!(base.stable_hash(), nucleus.stable_hash());
+        let mut penalized = request("hello", Some(0.7));
+        penalized.frequency_penalty = Some(0.9);
+        assert_ne!(nucleus.stable_hash(), penalized.stable_hash());
+        let mut stopped = request("hello", Some(0.7));
+        stopped.stop = Some(vec!["END".to_string()]);
+        assert_ne!(base.stable_hash(), stopped.stable_hash());
+
+        let mut result = request("hello", Some(0.7));
+        result.messages[0] = Message::tool("call_1", "hello");
+        result.messages[0].role = base.messages[0].role.clone();
+        assert_ne!(base.stable_hash(), result.stable_hash());
+    }
+
+    #[test]
+    fn test_audio_fields_are_opt_in() {
+        let json = serde_json::to_value(request("hello", None)).unwrap();
+        assert!(json.get("modalities").is_none());
+        assert!(json.get("audio").is_none());
+
+        let mut audio = request("hello", None);
+        audio.modalities = Some(vec!["text".to_string(), "audio".to_string()]);
+        audio.audio = Some(AudioConfig::new("alloy", "wav"));
+        let json = serde_json::to_value(audio).unwrap();
+        assert_eq!(json["modalities"], serde_json::json!(["text", "audio"]));
+        assert_eq!(json["audio"]["voice"], "alloy");
+    }
+
+    #[test]
+    fn test_response_format_serialization() {
+        let mut structured = request("hello", None);
+        let schema = serde_json::json!({"type": "object"});
+        structured.response_format = Some(ResponseFormat::json_schema("answer", schema));
+        let json = serde_json::to_value(&structured).unwrap();
+        assert_eq!(
+            json["response_format"],
+            serde_json::json!({
+                "type": "json_schema",
+                "json_schema": {"name": "answer", "schema": {"type": "object"}, "strict": true}
+            })
+        );
+        assert_ne!(
+            structured.stable_hash(),
+            request("hello", None).stable_hash()
+        );
+
+        structured.response_format = Some(ResponseFormat::JsonObject);
+        let json = serde_json::to_value(&structured).unwrap();
+        assert_eq!(
+            json["response_format"],
+            serde_json::json!({"type": "json_object"})
+        );
+    }
+
+    #[test]
+    fn test_parse_array_content() {
+        let body = r#"{"choices":[{"message":{"role":"assistant","content":[
+            {"type":"text","text":"The answer "},
+            {"type":"image_url","image_url":{"url":"https://example.com/a.png"}},
+            {"type":"text","text":"is 42."}]}}]}"#;
+        let response: ChatResponse = serde_json::from_str(body).unwrap();
+        let message = &response.choices[0].message;
+        assert_eq!(message.text().as_deref(), Some("The answer is 42."));
+
+        let Some(MessageContent::Parts(parts)) = &message.content else {
+            panic!("expected array content");
+        };
+        assert_eq!(parts[1].part_type, "image_url");
+        assert_eq!(
+            parts[1].extra["image_url"]["url"],
+            "https://example.com/a.png"
+        );
+    }
+
+    #[test]
+    fn test_parse_string_content() {
+        let body = r#"{"choices":[{"message":{"content":"Paris"}}]}"#;
+        let response: ChatResponse = serde_json::from_str(body).unwrap();
+        assert_eq!(response.choices[0].message.text().as_deref(), Some("Paris"));
+    }
 
-        Ok(chat_response.choices[0].message.content.clone())
+    #[test]
+    fn test_parse_audio_response() {
+        let body = r#"{"choices":[{"message":{"content":null,"audio":{
+            "id":"audio_1","data":"UklGRg==","transcript":"Hi there","expires_at":1}}}]}"#;
+        let response: ChatResponse = serde_json::from_str(body).unwrap();
+        let message = &response.choices[0].message;
+        assert!(message.content.is_none());
+        let audio = message.audio.as_ref().unwrap();
+        assert_eq!(audio.transcript.as_deref(), Some("Hi there"));
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(&audio.data)
+            .unwrap();
+        assert_eq!(bytes, b"RIFF");
     }
 }
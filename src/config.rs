@@ -0,0 +1,373 @@
+//! Defaults for the model, endpoint and generation settings, layered from
+//! config files and the environment.
+//!
+//! Config files are TOML with top-level keys only:
+//!
+//! ```toml
+//! model = "gpt-4o"
+//! endpoint = "http://localhost:11434/v1/chat/completions"
+//! max_tokens = 2000
+//! temperature = 0.2
+//! timeout = 60 # seconds
+//! ```
+//!
+//! Unknown keys, including tables, are rejected so typos don't go
+//! unnoticed.
+
+use crate::errors::{DSRSError, Result};
+use serde::Deserialize;
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use toml::Spanned;
+
+/// Name of the project config file, looked up in the working directory.
+pub const LOCAL_CONFIG_FILE: &str = "dsrs.toml";
+
+/// Settings that apply when a call or flag doesn't give its own. Every
+/// field is optional, so configs from several sources can be layered with
+/// [`or`](Config::or) and applied with
+/// [`LLMClientBuilder::config`](crate::client::LLMClientBuilder::config).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Config {
+    pub model: Option<String>,
+    pub endpoint: Option<String>,
+    pub max_tokens: Option<u32>,
+    pub temperature: Option<f32>,
+    pub timeout: Option<Duration>,
+}
+
+impl Config {
+    /// The config from the default files overlaid with the environment:
+    /// the user file, then [`LOCAL_CONFIG_FILE`] in the working directory,
+    /// then the `LLM_*` variables (see [`from_env`](Config::from_env)).
+    pub fn load() -> Result<Self> {
+        Ok(Self::from_env()?.or(Self::load_files(&Self::default_files())?))
+    }
+
+    /// The user config file, `$XDG_CONFIG_HOME/dsrs/config.toml` or
+    /// `~/.config/dsrs/config.toml`, then [`LOCAL_CONFIG_FILE`].
+    pub fn default_files() -> Vec<PathBuf> {
+        let var = |name| std::env::var_os(name).filter(|value| !value.is_empty());
+        var("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| var("HOME").map(|home| Path::new(&home).join(".config")))
+            .map(|config| config.join("dsrs").join("config.toml"))
+            .into_iter()
+            .chain([PathBuf::from(LOCAL_CONFIG_FILE)])
+            .collect()
+    }
+
+    /// Reads each existing file in `paths`, later files taking precedence.
+    /// Missing files are skipped.
+    pub fn load_files(paths: &[PathBuf]) -> Result<Self> {
+        let mut config = Self::default();
+        for path in paths {
+            let text = match std::fs::read_to_string(path) {
+                Ok(text) => text,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => {
+                    return Err(DSRSError::ConfigError(format!(
+                        "Failed to read {}: {err}",
+                        path.display()
+                    )));
+                }
+            };
+            config = Self::parse(&text, path)?.or(config);
+        }
+        Ok(config)
+    }
+
+    /// Parses the text of the config file at `path`, which is only used in
+    /// error messages.
+    pub fn parse(text: &str, path: &Path) -> Result<Self> {
+        let fail = |span: Option<Range<usize>>, message: String| {
+            let Some(span) = span else {
+                return DSRSError::ConfigError(format!(
+                    "Invalid config {}: {message}",
+                    path.display()
+                ));
+            };
+            let offset = span.start.min(text.len());
+            let line = text[..offset].matches('\n').count() + 1;
+            let message = match key_at(text, offset) {
+                Some(key) if !message.contains(&format!("`{key}`")) => {
+                    format!("`{key}`: {message}")
+                }
+                _ => message,
+            };
+            DSRSError::ConfigError(format!(
+                "Invalid config {} (line {line}): {message}",
+                path.display()
+            ))
+        };
+        let file: ConfigFile =
+            toml::from_str(text).map_err(|err| fail(err.span(), err.message().to_string()))?;
+        let invalid = |(span, message)| fail(Some(span), message);
+        Ok(Self {
+            model: file.model,
+            endpoint: file.endpoint,
+            max_tokens: file
+                .max_tokens
+                .map(|value| spanned(value, |number| max_tokens("max_tokens", number as f64)))
+                .transpose()
+                .map_err(invalid)?,
+            temperature: file
+                .temperature
+                .map(|value| spanned(value, |number| finite("temperature", number)))
+                .transpose()
+                .map_err(invalid)?
+                .map(|temperature| temperature as f32),
+            timeout: file
+                .timeout
+                .map(|value| spanned(value, |seconds| timeout("timeout", seconds)))
+                .transpose()
+                .map_err(invalid)?,
+        })
+    }
+
+    /// The config from `LLM_MODEL`, `LLM_ENDPOINT`, `LLM_MAX_TOKENS`,
+    /// `LLM_TEMPERATURE` and `LLM_TIMEOUT` (in seconds), loading a `.env`
+    /// file first if there is one. Empty variables count as unset.
+    pub fn from_env() -> Result<Self> {
+        dotenvy::dotenv().ok();
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let var = |name: &str| var(name).filter(|value| !value.is_empty());
+        let fail = DSRSError::ConfigError;
+        Ok(Self {
+            model: var("LLM_MODEL"),
+            endpoint: var("LLM_ENDPOINT"),
+            max_tokens: var("LLM_MAX_TOKENS")
+                .map(|value| max_tokens("LLM_MAX_TOKENS", number("LLM_MAX_TOKENS", &value)?))
+                .transpose()
+                .map_err(fail)?,
+            temperature: var("LLM_TEMPERATURE")
+                .map(|value| number("LLM_TEMPERATURE", &value))
+                .transpose()
+                .map_err(fail)?
+                .map(|temperature| temperature as f32),
+            timeout: var("LLM_TIMEOUT")
+                .map(|value| timeout("LLM_TIMEOUT", number("LLM_TIMEOUT", &value)?))
+                .transpose()
+                .map_err(fail)?,
+        })
+    }
+
+    /// This config, with settings it leaves unset taken from `fallback`.
+    pub fn or(self, fallback: Self) -> Self {
+        Self {
+            model: self.model.or(fallback.model),
+            endpoint: self.endpoint.or(fallback.endpoint),
+            max_tokens: self.max_tokens.or(fallback.max_tokens),
+            temperature: self.temperature.or(fallback.temperature),
+            timeout: self.timeout.or(fallback.timeout),
+        }
+    }
+}
+
+/// Writes the settings that are set, one `key = value` line each, in the
+/// config file format.
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(model) = &self.model {
+            writeln!(f, "model = {}", toml::Value::from(model.as_str()))?;
+        }
+        if let Some(endpoint) = &self.endpoint {
+            writeln!(f, "endpoint = {}", toml::Value::from(endpoint.as_str()))?;
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            writeln!(f, "max_tokens = {max_tokens}")?;
+        }
+        if let Some(temperature) = self.temperature {
+            writeln!(f, "temperature = {temperature}")?;
+        }
+        if let Some(timeout) = self.timeout {
+            writeln!(f, "timeout = {}", timeout.as_secs_f64())?;
+        }
+        Ok(())
+    }
+}
+
+/// A config file as written; values are checked once it's parsed.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    model: Option<String>,
+    endpoint: Option<String>,
+    max_tokens: Option<Spanned<i64>>,
+    temperature: Option<Spanned<f64>>,
+    /// In seconds.
+    timeout: Option<Spanned<f64>>,
+}
+
+/// A message about the value at a byte range of a config file.
+type SpanError = (Range<usize>, String);
+
+/// Checks a value from a config file with `check`, failing with its span
+/// for the error message.
+fn spanned<T, U>(
+    value: Spanned<T>,
+    check: impl FnOnce(T) -> Result<U, String>,
+) -> Result<U, SpanError> {
+    let span = value.span();
+    check(value.into_inner()).map_err(|message| (span, message))
+}
+
+/// The key set on the line at byte `offset` of `text`, if it's a
+/// `key = value` line.
+fn key_at(text: &str, offset: usize) -> Option<&str> {
+    let start = text[..offset].rfind('\n').map_or(0, |index| index + 1);
+    let line = text[start..].lines().next().unwrap_or_default();
+    let (key, _) = line.split_once('=')?;
+    Some(key.trim())
+}
+
+/// Parses the number in the environment variable `key`.
+fn number(key: &str, text: &str) -> Result<f64, String> {
+    let number = text
+        .replace('_', "")
+        .parse::<f64>()
+        .map_err(|_| format!("`{key}` must be a number, got {text}"))?;
+    finite(key, number)
+}
+
+fn finite(key: &str, number: f64) -> Result<f64, String> {
+    if number.is_finite() {
+        Ok(number)
+    } else {
+        Err(format!("`{key}` must be a number, got {number}"))
+    }
+}
+
+fn max_tokens(key: &str, number: f64) -> Result<u32, String> {
+    if number.fract() != 0.0 || number < 1.0 || number > f64::from(u32::MAX) {
+        return Err(format!(
+            "`{key}` must be a positive whole number, got {number}"
+        ));
+    }
+    Ok(number as u32)
+}
+
+fn timeout(key: &str, seconds: f64) -> Result<Duration, String> {
+    let seconds = finite(key, seconds)?;
+    if seconds <= 0.0 {
+        return Err(format!(
+            "`{key}` must be a positive number of seconds, got {seconds}"
+        ));
+    }
+    Duration::try_from_secs_f64(seconds).map_err(|err| format!("`{key}`: {err}"))
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Config> {
+        Config::parse(text, Path::new("/home/me/.config/dsrs/config.toml"))
+    }
+
+    #[test]
+    fn test_parse_config_file() {
+        let config = parse(
+            "# Defaults for every run\n\
+             model = \"gpt-4o\"\n\
+             endpoint = 'http://localhost:11434/v1/chat/completions' # local\n\
+             \n\
+             max_tokens = 2_000\n\
+             temperature = 0.2\n\
+             timeout = 1.5\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                model: Some("gpt-4o".to_string()),
+                endpoint: Some("http://localhost:11434/v1/chat/completions".to_string()),
+                max_tokens: Some(2000),
+                temperature: Some(0.2),
+                timeout: Some(Duration::from_millis(1500)),
+            }
+        );
+        // What `dsrs config show` prints parses back to the same config
+        assert_eq!(parse(&config.to_string()).unwrap(), config);
+        assert_eq!(
+            parse("model = \"say \\\"hi\\\" # not a comment\"")
+                .unwrap()
+                .model,
+            Some("say \"hi\" # not a comment".to_string())
+        );
+        assert_eq!(parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_invalid_config_names_the_key_and_file() {
+        let error = |text| match parse(text) {
+            Err(DSRSError::ConfigError(message)) => message,
+            other => panic!("expected a ConfigError, got {other:?}"),
+        };
+        assert_eq!(
+            error("model = \"gpt-4o\"\nmax_tokens = \"lots\""),
+            "Invalid config /home/me/.config/dsrs/config.toml (line 2): \
+             `max_tokens`: invalid type: string \"lots\", expected i64"
+        );
+        assert!(error("temperature = hot").contains("`temperature`: string values must be quoted"));
+        assert!(error("temperature = inf").contains("`temperature` must be a number, got inf"));
+        assert!(error("max_tokens = 0").contains("`max_tokens` must be a positive whole number"));
+        assert!(error("max_tokens = 1.5").contains("`max_tokens`: invalid type: floating point"));
+        assert!(error("timeout = -1").contains("`timeout` must be a positive number of seconds"));
+        assert!(error("model = gpt-4o").contains("`model`: string values must be quoted"));
+        assert!(error("model = \"gpt-4o").contains("`model`: invalid basic string"));
+        assert!(error("modle = \"gpt-4o\"").contains("(line 1): unknown field `modle`"));
+        assert!(error("[dsrs]\nmodel = \"gpt-4o\"").contains("unknown field `dsrs`"));
+        assert!(error("model").contains("key with no value"));
+        assert!(error("model = \"a\"\nmodel = \"b\"").contains("(line 2): `model`: duplicate key"));
+    }
+
+    #[test]
+    fn test_later_files_take_precedence() {
+        let dir = std::env::temp_dir().join(format!("dsrs-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (user, local) = (dir.join("config.toml"), dir.join("dsrs.toml"));
+        std::fs::write(&user, "model = \"gpt-4o\"\ntemperature = 0.5\n").unwrap();
+        std::fs::write(&local, "model = \"llama3\"\n").unwrap();
+
+        let config = Config::load_files(&[user.clone(), local, dir.join("missing.toml")]).unwrap();
+        assert_eq!(config.model.as_deref(), Some("llama3"));
+        assert_eq!(config.temperature, Some(0.5));
+
+        std::fs::write(&user, "timeout = never\n").unwrap();
+        assert!(matches!(
+            Config::load_files(std::slice::from_ref(&user)),
+            Err(DSRSError::ConfigError(message))
+                if message.contains(&user.display().to_string()) && message.contains("`timeout`")
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_environment_overrides_files() {
+        let vars = |name: &str| match name {
+            "LLM_MODEL" => Some("gpt-4o-mini".to_string()),
+            "LLM_MAX_TOKENS" => Some("500".to_string()),
+            "LLM_TEMPERATURE" => Some(String::new()),
+            _ => None,
+        };
+        let file = parse("model = \"gpt-4o\"\ntemperature = 0.2\nmax_tokens = 2000").unwrap();
+        let config = Config::from_vars(vars).unwrap().or(file);
+        assert_eq!(config.model.as_deref(), Some("gpt-4o-mini"));
+        assert_eq!(config.max_tokens, Some(500));
+        // Empty variables don't override the file
+        assert_eq!(config.temperature, Some(0.2));
+
+        let invalid = Config::from_vars(|name| (name == "LLM_TIMEOUT").then(|| "soon".to_string()));
+        assert!(matches!(
+            invalid,
+            Err(DSRSError::ConfigError(message)) if message.starts_with("`LLM_TIMEOUT` must be")
+        ));
+    }
+}
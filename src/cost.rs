@@ -0,0 +1,283 @@
+//! Estimated spend, from the token usage providers report and per-model
+//! prices.
+//!
+//! Every [`LLMClient`](crate::client::LLMClient) keeps a [`CostTracker`]
+//! that adds up the cost of each chat response it receives, shared by its
+//! clones; read it with
+//! [`LLMClient::total_cost`](crate::client::LLMClient::total_cost). With a
+//! [`budget_usd`](crate::client::LLMClientBuilder::budget_usd), requests
+//! whose worst case (the counted prompt tokens plus all of `max_tokens`)
+//! would take the total over it are refused with
+//! [`DSRSError::BudgetExceeded`] before they're sent. Streams count too,
+//! priced from the usage the provider reports at the end of the stream, or
+//! at the worst case if it reports none.
+//!
+//! The built-in prices are list prices in US dollars and drift as providers
+//! change them; set your own with [`Pricing::price`]. Cache hits cost
+//! nothing, and embeddings aren't counted.
+
+use crate::client::Usage;
+use crate::errors::{DSRSError, Result};
+use crate::tokens::{self, ByPrefix, Prefixed};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// What a model charges, in US dollars per million tokens.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Price {
+    pub input_per_million: f64,
+    pub output_per_million: f64,
+}
+
+impl Price {
+    /// For models that cost nothing to call, e.g. on a local server.
+    pub const FREE: Price = Price::new(0.0, 0.0);
+
+    pub const fn new(input_per_million: f64, output_per_million: f64) -> Self {
+        Self {
+            input_per_million,
+            output_per_million,
+        }
+    }
+
+    /// The cost in US dollars of `prompt_tokens` in and `completion_tokens`
+    /// out.
+    pub fn cost(&self, prompt_tokens: u64, completion_tokens: u64) -> f64 {
+        (prompt_tokens as f64 * self.input_per_million
+            + completion_tokens as f64 * self.output_per_million)
+            / 1_000_000.0
+    }
+}
+
+/// The price assumed for models missing from the table. It's on the high
+/// side, so budgets err toward refusing requests rather than overspending.
+pub const DEFAULT_PRICE: Price = Price::new(10.0, 30.0);
+
+/// List prices by model name prefix.
+const PRICES: [Prefixed<Price>; 22] = [
+    ("gpt-3.5-turbo", Price::new(0.5, 1.5)),
+    ("gpt-4", Price::new(30.0, 60.0)),
+    ("gpt-4-32k", Price::new(60.0, 120.0)),
+    ("gpt-4-turbo", Price::new(10.0, 30.0)),
+    ("gpt-4-1106", Price::new(10.0, 30.0)),
+    ("gpt-4-0125", Price::new(10.0, 30.0)),
+    ("gpt-4o", Price::new(2.5, 10.0)),
+    ("gpt-4o-mini", Price::new(0.15, 0.6)),
+    ("gpt-4.1", Price::new(2.0, 8.0)),
+    ("gpt-4.1-mini", Price::new(0.4, 1.6)),
+    ("gpt-4.1-nano", Price::new(0.1, 0.4)),
+    ("o1", Price::new(15.0, 60.0)),
+    ("o1-mini", Price::new(1.1, 4.4)),
+    ("o3", Price::new(2.0, 8.0)),
+    ("o3-mini", Price::new(1.1, 4.4)),
+    ("o4-mini", Price::new(1.1, 4.4)),
+    ("claude-3-haiku", Price::new(0.25, 1.25)),
+    ("claude-3-5-haiku", Price::new(0.8, 4.0)),
+    ("claude-3-5-sonnet", Price::new(3.0, 15.0)),
+    ("claude-3-7-sonnet", Price::new(3.0, 15.0)),
+    ("claude-sonnet-4", Price::new(3.0, 15.0)),
+    ("claude-opus-4", Price::new(15.0, 75.0)),
+];
+
+/// Prices by model: overrides set with [`price`](Pricing::price), else the
+/// built-in table, else a default price.
+#[derive(Clone, Debug)]
+pub struct Pricing {
+    overrides: ByPrefix<Price>,
+    default_price: Price,
+}
+
+impl Default for Pricing {
+    fn default() -> Self {
+        Self {
+            overrides: Vec::new(),
+            default_price: DEFAULT_PRICE,
+        }
+    }
+}
+
+impl Pricing {
+    /// The built-in table, with [`DEFAULT_PRICE`] for other models.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Prices models whose name starts with `model` at `price`, e.g. a
+    /// negotiated rate or [`Price::FREE`] for a local model. Takes precedence
+    /// over the built-in table; among overrides the longest matching prefix
+    /// wins.
+    pub fn price(mut self, model: impl Into<String>, price: Price) -> Self {
+        self.overrides.push((model.into(), price));
+        self
+    }
+
+    /// Prices models found neither in the overrides nor in the table at
+    /// `price` instead of [`DEFAULT_PRICE`].
+    pub fn default_price(mut self, price: Price) -> Self {
+        self.default_price = price;
+        self
+    }
+
+    /// The price of `model`. The longest matching prefix wins, so dated
+    /// snapshots such as `gpt-4o-2024-08-06` resolve to their family.
+    pub fn price_of(&self, model: &str) -> Price {
+        let overrides = self
+            .overrides
+            .iter()
+            .map(|(prefix, price)| (prefix.as_str(), *price));
+        tokens::lookup_prefix(overrides, model)
+            .or_else(|| tokens::lookup_prefix(PRICES, model))
+            .unwrap_or(self.default_price)
+    }
+
+    /// The estimated cost in US dollars of a response from `model` that
+    /// billed `usage`.
+    pub fn cost(&self, model: &str, usage: &Usage) -> f64 {
+        self.price_of(model).cost(
+            u64::from(usage.prompt_tokens),
+            u64::from(usage.completion_tokens),
+        )
+    }
+}
+
+/// Adds up what a client has spent and enforces its budget.
+#[derive(Debug)]
+pub struct CostTracker {
+    pricing: Pricing,
+    budget: Option<f64>,
+    spend: Mutex<Spend>,
+}
+
+#[derive(Debug, Default)]
+struct Spend {
+    spent: f64,
+    /// Worst-case costs of the requests in flight, so concurrent requests
+    /// can't overspend together.
+    reserved: f64,
+}
+
+impl CostTracker {
+    /// Tracks spend priced by `pricing`, refusing requests that could take
+    /// it over `budget` US dollars, if given.
+    pub fn new(pricing: Pricing, budget: Option<f64>) -> Self {
+        Self {
+            pricing,
+            budget,
+            spend: Mutex::default(),
+        }
+    }
+
+    pub fn pricing(&self) -> &Pricing {
+        &self.pricing
+    }
+
+    /// The cap on [`total_cost`](Self::total_cost), in US dollars.
+    pub fn budget(&self) -> Option<f64> {
+        self.budget
+    }
+
+    /// The estimated cost in US dollars of every response so far.
+    pub fn total_cost(&self) -> f64 {
+        self.lock().spent
+    }
+
+    /// Sets aside `estimate` for a request about to be sent, failing with
+    /// [`DSRSError::BudgetExceeded`] if that could take the spend over the
+    /// budget. The reservation is released when it's dropped.
+    pub(crate) fn reserve(self: &Arc<Self>, estimate: f64) -> Result<Reservation> {
+        let mut spend = self.lock();
+        if let Some(budget) = self.budget
+            && spend.spent + spend.reserved + estimate > budget
+        {
+            return Err(DSRSError::BudgetExceeded {
+                spent: spend.spent,
+                budget,
+            });
+        }
+        spend.reserved += estimate;
+        Ok(Reservation {
+            tracker: Arc::clone(self),
+            estimate,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Spend> {
+        self.spend.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A request's share of the budget while it's in flight.
+#[derive(Debug)]
+pub(crate) struct Reservation {
+    tracker: Arc<CostTracker>,
+    estimate: f64,
+}
+
+impl Reservation {
+    /// Adds the request's actual `cost` to the spend, or the reserved
+    /// worst case if the provider didn't report usage, and returns it.
+    pub(crate) fn settle(mut self, cost: Option<f64>) -> f64 {
+        let cost = cost.unwrap_or(self.estimate);
+        let mut spend = self.tracker.lock();
+        spend.spent += cost;
+        spend.reserved = (spend.reserved - self.estimate).max(0.0);
+        self.estimate = 0.0;
+        cost
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut spend = self.tracker.lock();
+        spend.reserved = (spend.reserved - self.estimate).max(0.0);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prices_resolve_by_prefix_then_default() {
+        let pricing = Pricing::new()
+            .price("llama", Price::FREE)
+            .price("gpt-4o", Price::new(1.0, 2.0))
+            .default_price(Price::new(3.0, 4.0));
+        assert_eq!(
+            pricing.price_of("gpt-4.1-mini-2025-04-14"),
+            Price::new(0.4, 1.6)
+        );
+        assert_eq!(
+            Pricing::new().price_of("gpt-4o-mini"),
+            Price::new(0.15, 0.6)
+        );
+        // Overrides win over longer prefixes in the table
+        assert_eq!(pricing.price_of("gpt-4o-mini"), Price::new(1.0, 2.0));
+        assert_eq!(pricing.price_of("llama3"), Price::FREE);
+        assert_eq!(pricing.price_of("mistral"), Price::new(3.0, 4.0));
+        assert_eq!(Pricing::new().price_of("mistral"), DEFAULT_PRICE);
+
+        let usage = Usage {
+            prompt_tokens: 1_000_000,
+            completion_tokens: 500_000,
+            total_tokens: 1_500_000,
+        };
+        assert!((Pricing::new().cost("gpt-4o", &usage) - 7.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_reservations_count_against_the_budget_until_settled() {
+        let tracker = Arc::new(CostTracker::new(Pricing::new(), Some(1.0)));
+        let first = tracker.reserve(0.6).unwrap();
+        assert!(matches!(
+            tracker.reserve(0.6),
+            Err(DSRSError::BudgetExceeded { spent, budget }) if spent == 0.0 && budget == 1.0
+        ));
+        assert_eq!(first.settle(Some(0.25)), 0.25);
+        let second = tracker.reserve(0.6).unwrap();
+        drop(second);
+        assert_eq!(tracker.total_cost(), 0.25);
+        assert_eq!(tracker.reserve(0.5).unwrap().settle(None), 0.5);
+        assert_eq!(tracker.total_cost(), 0.75);
+    }
+}
@@ -0,0 +1,120 @@
+//! Request and response types for
+//! [`LLMClient::embed`](crate::LLMClient::embed) and
+//! [`LLMClient::embed_many`](crate::LLMClient::embed_many).
+
+use crate::client::Usage;
+use crate::errors::{DSRSError, Result};
+use serde::{Deserialize, Serialize};
+
+/// Request payload for the embeddings API.
+#[derive(Serialize)]
+pub struct EmbeddingRequest {
+    pub model: String,
+    /// The texts to embed; the API accepts several per request.
+    pub input: Vec<String>,
+}
+
+/// Response from the embeddings API.
+#[derive(Deserialize)]
+pub struct EmbeddingResponse {
+    pub data: Vec<Embedding>,
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Only `prompt_tokens` and `total_tokens` are reported for embeddings.
+    #[serde(default)]
+    pub usage: Option<Usage>,
+}
+
+/// An embedding vector.
+pub type Vector = Vec<f32>;
+
+/// The embedding of one input.
+#[derive(Deserialize)]
+pub struct Embedding {
+    pub embedding: Vector,
+    /// Position of the input in [`EmbeddingRequest::input`].
+    #[serde(default)]
+    pub index: usize,
+}
+
+impl EmbeddingResponse {
+    /// The vectors in input order. Fails with [`DSRSError::ApiError`] unless
+    /// there is exactly one per input.
+    pub fn into_vectors(mut self, inputs: usize) -> Result<Vec<Vector>> {
+        self.data.sort_by_key(|embedding| embedding.index);
+        let in_order = self
+            .data
+            .iter()
+            .enumerate()
+            .all(|(position, embedding)| embedding.index == position);
+        if self.data.len() != inputs || !in_order {
+            return Err(DSRSError::ApiError(format!(
+                "Expected {inputs} embeddings, got {}",
+                self.data.len()
+            )));
+        }
+        Ok(self
+            .data
+            .into_iter()
+            .map(|embedding| embedding.embedding)
+            .collect())
+    }
+}
+
+/// The embeddings URL next to a chat completions endpoint, e.g.
+/// `https://api.openai.com/v1/embeddings` for
+/// `https://api.openai.com/v1/chat/completions`.
+pub(crate) fn embeddings_url(chat_endpoint: &str) -> String {
+    let base = chat_endpoint.trim_end_matches('/');
+    let base = base.strip_suffix("/chat/completions").unwrap_or(base);
+    format!("{}/embeddings", base.trim_end_matches('/'))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_embeddings_url() {
+        assert_eq!(
+            embeddings_url("https://api.openai.com/v1/chat/completions"),
+            "https://api.openai.com/v1/embeddings"
+        );
+        assert_eq!(
+            embeddings_url("http://localhost:8000/v1/chat/completions/"),
+            "http://localhost:8000/v1/embeddings"
+        );
+        assert_eq!(
+            embeddings_url("http://localhost:8000/v1/"),
+            "http://localhost:8000/v1/embeddings"
+        );
+    }
+
+    #[test]
+    fn test_vectors_follow_input_order() {
+        let response: EmbeddingResponse = serde_json::from_value(json!({
+            "object": "list",
+            "data": [
+                {"object": "embedding", "index": 1, "embedding": [0.5, -1.0]},
+                {"object": "embedding", "index": 0, "embedding": [0.25, 2.0]}
+            ],
+            "model": "text-embedding-3-small",
+            "usage": {"prompt_tokens": 4, "total_tokens": 4}
+        }))
+        .unwrap();
+        assert_eq!(response.usage.map(|usage| usage.total_tokens), Some(4));
+        assert_eq!(
+            response.into_vectors(2).unwrap(),
+            [vec![0.25, 2.0], vec![0.5, -1.0]]
+        );
+
+        let short: EmbeddingResponse =
+            serde_json::from_value(json!({"data": [{"index": 0, "embedding": [1.0]}]})).unwrap();
+        assert!(matches!(
+            short.into_vectors(2),
+            Err(DSRSError::ApiError(message)) if message == "Expected 2 embeddings, got 1"
+        ));
+    }
+}
@@ -1,22 +1,328 @@
+use std::time::Duration;
+
+/// How much of an unexpected response body error messages quote.
+const BODY_SNIPPET_CHARS: usize = 200;
+
+/// The crate's result type; most operations fail with a [`DSRSError`].
+pub type Result<T, E = DSRSError> = std::result::Result<T, E>;
+
 #[derive(Debug)]
 pub enum DSRSError {
+    /// The prompt's tokens plus the requested `max_tokens` (first) exceed
+    /// the model's context window (second).
     PromptTooLong(usize, usize),
     ApiError(String),
-    NetworkError(String),
+    /// No connection to the endpoint could be made (DNS, refused, TLS, an
+    /// unreachable proxy); HTTP statuses never map here.
+    NetworkError(reqwest::Error),
     ConfigError(String),
+    /// The provider rejected the credentials (HTTP 401 or 403). The message
+    /// is as for [`HttpError`](DSRSError::HttpError).
+    AuthError(String),
+    /// The request did not complete in time; carries the limit when known.
+    Timeout(Option<Duration>),
+    /// A response could not be parsed into a signature's output; `raw` is
+    /// the response text as received.
+    ParseError {
+        message: String,
+        raw: String,
+    },
+    /// No response parsed, even after asking the model to correct its
+    /// format. `message` is the last parse failure; `responses` holds every
+    /// response received, in order.
+    ParseRetriesExhausted {
+        message: String,
+        responses: Vec<String>,
+    },
+    /// The provider answered with a non-success HTTP status. `message`
+    /// summarizes the status line and any error the body described, or
+    /// quotes the start of a body that isn't a JSON error, such as a
+    /// proxy's HTML page; `retry_after` is the server's `Retry-After` hint.
+    HttpError {
+        status: u16,
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    /// The provider answered 429 Too Many Requests. `message` is as for
+    /// [`HttpError`](DSRSError::HttpError); `retry_after` is how long the
+    /// server asked callers to wait, from its `Retry-After` header.
+    RateLimited {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    /// Every attempt allowed by the retry policy failed; `last` is the error
+    /// from the final one.
+    RetriesExhausted {
+        attempts: u32,
+        last: Box<DSRSError>,
+    },
+    /// The request failed in transit, e.g. the connection dropped while the
+    /// response was being read.
+    Transport(reqwest::Error),
+    /// The response body wasn't the JSON the API promises. A malformed
+    /// payload like [`ApiError`](DSRSError::ApiError), and displayed the same
+    /// way, but kept apart so the `reqwest` error stays available as the
+    /// [`source`](std::error::Error::source).
+    Decode(reqwest::Error),
+    /// The request was stopped through the client's
+    /// [`CancellationToken`](crate::client::CancellationToken).
+    Cancelled,
+    /// The request wasn't sent because its worst-case cost could take the
+    /// client's estimated spend over its
+    /// [`budget_usd`](crate::client::LLMClientBuilder::budget_usd). Both
+    /// amounts are in US dollars; `spent` is the total so far.
+    BudgetExceeded {
+        spent: f64,
+        budget: f64,
+    },
 }
 
 impl std::fmt::Display for DSRSError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             DSRSError::PromptTooLong(len, max) => {
-                write!(f, "Prompt too long: {len} chars (max: {max})")
+                write!(f, "Prompt too long: {len} tokens (max: {max})")
             }
             DSRSError::ApiError(msg) => write!(f, "API error: {msg}"),
-            DSRSError::NetworkError(msg) => write!(f, "Network error: {msg}"),
+            DSRSError::NetworkError(err) => write!(f, "Network error: could not connect: {err}"),
             DSRSError::ConfigError(msg) => write!(f, "Configuration error: {msg}"),
+            DSRSError::AuthError(msg) => write!(f, "Authentication failed: {msg}"),
+            DSRSError::Timeout(Some(limit)) => write!(f, "Request timed out after {limit:?}"),
+            DSRSError::Timeout(None) => write!(f, "Request timed out"),
+            DSRSError::ParseError { message, .. } => {
+                write!(f, "Failed to parse output: {message}")
+            }
+            DSRSError::ParseRetriesExhausted { message, responses } => write!(
+                f,
+                "Failed to parse output after {} attempts: {message}",
+                responses.len()
+            ),
+            DSRSError::HttpError { message, .. } => write!(f, "API error: {message}"),
+            DSRSError::RateLimited {
+                message,
+                retry_after: Some(delay),
+            } => write!(f, "Rate limited: {message}; retry after {delay:?}"),
+            DSRSError::RateLimited { message, .. } => write!(f, "Rate limited: {message}"),
+            DSRSError::RetriesExhausted { attempts, last } => {
+                write!(f, "Failed after {attempts} attempts: {last}")
+            }
+            DSRSError::Transport(err) => write!(f, "Network error: {err}"),
+            DSRSError::Decode(err) => write!(f, "API error: Failed to parse response: {err}"),
+            DSRSError::Cancelled => write!(f, "Request cancelled"),
+            DSRSError::BudgetExceeded { spent, budget } => write!(
+                f,
+                "Budget exceeded: the request could take the ${spent:.4} spent so far over the ${budget:.2} budget"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DSRSError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DSRSError::NetworkError(err) | DSRSError::Transport(err) | DSRSError::Decode(err) => {
+                Some(err)
+            }
+            DSRSError::RetriesExhausted { last, .. } => Some(last.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl DSRSError {
+    /// Whether the request may succeed if simply sent again: connection and
+    /// transport failures, timeouts, rate limits and HTTP 500/502/503/504.
+    /// These are the errors a [`RetryPolicy`](crate::retry::RetryPolicy)
+    /// retries; once it gives up, [`RetriesExhausted`](DSRSError::RetriesExhausted)
+    /// is not retryable.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            DSRSError::NetworkError(_)
+            | DSRSError::Transport(_)
+            | DSRSError::Timeout(_)
+            | DSRSError::RateLimited { .. } => true,
+            DSRSError::HttpError { status, .. } => matches!(status, 500 | 502 | 503 | 504),
+            _ => false,
+        }
+    }
+}
+
+impl DSRSError {
+    /// A response body that isn't the JSON the API promises, e.g. a proxy's
+    /// HTML error page, as an [`ApiError`](DSRSError::ApiError) quoting the
+    /// start of the body.
+    pub(crate) fn undecodable(err: serde_json::Error, body: &[u8]) -> Self {
+        let snippet = body_snippet(body);
+        if snippet.is_empty() {
+            DSRSError::ApiError(format!(
+                "Failed to parse response: {err}; the body was empty"
+            ))
+        } else {
+            DSRSError::ApiError(format!("Failed to parse response: {err}; body: {snippet}"))
+        }
+    }
+}
+
+/// The start of `body` as one line of text: whitespace runs collapse to a
+/// space, and anything past [`BODY_SNIPPET_CHARS`] becomes `...`.
+pub(crate) fn body_snippet(body: &[u8]) -> String {
+    let text = String::from_utf8_lossy(body);
+    let mut words = text.split_whitespace();
+    let mut snippet = words.next().unwrap_or_default().to_string();
+    for word in words {
+        snippet.push(' ');
+        snippet.push_str(word);
+        if snippet.chars().count() > BODY_SNIPPET_CHARS {
+            break;
         }
     }
+    match snippet.char_indices().nth(BODY_SNIPPET_CHARS) {
+        Some((end, _)) => format!("{}...", &snippet[..end]),
+        None => snippet,
+    }
+}
+
+/// Classifies transport errors: timeouts become [`DSRSError::Timeout`],
+/// connection failures [`DSRSError::NetworkError`], undecodable bodies
+/// [`DSRSError::Decode`] rather than [`DSRSError::ApiError`], whose message
+/// string can't carry a source, and other failures
+/// [`DSRSError::Transport`]. The `reqwest` error stays available through
+/// [`source`](std::error::Error::source) for all but timeouts.
+impl From<reqwest::Error> for DSRSError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            DSRSError::Timeout(None)
+        } else if err.is_builder() {
+            DSRSError::ConfigError(format!("Invalid request: {err}"))
+        } else if err.is_connect() {
+            DSRSError::NetworkError(err)
+        } else if err.is_decode() {
+            DSRSError::Decode(err)
+        } else {
+            DSRSError::Transport(err)
+        }
+    }
+}
+
+/// JSON that fails to (de)serialize is treated as a malformed API payload.
+impl From<serde_json::Error> for DSRSError {
+    fn from(err: serde_json::Error) -> Self {
+        DSRSError::ApiError(format!("Failed to parse response: {err}"))
+    }
 }
 
-impl std::error::Error for DSRSError {}
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::test_util::{MockResponse, MockServer};
+    use std::error::Error as _;
+
+    #[tokio::test]
+    async fn test_connect_error_is_network_error() {
+        // Nothing listens on port 1
+        let err = DSRSError::from(reqwest::get("http://127.0.0.1:1").await.unwrap_err());
+        assert!(matches!(err, DSRSError::NetworkError(_)));
+        let source = err.source().unwrap();
+        assert!(
+            source
+                .downcast_ref::<reqwest::Error>()
+                .unwrap()
+                .is_connect()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_timeout_is_timeout() {
+        let server = MockServer::start(vec![
+            MockResponse::new(200, "{}").delay(Duration::from_secs(5)),
+        ])
+        .await;
+        let err = reqwest::Client::new()
+            .get(server.url())
+            .timeout(Duration::from_millis(50))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(matches!(DSRSError::from(err), DSRSError::Timeout(None)));
+    }
+
+    #[tokio::test]
+    async fn test_decode_error_is_decode() {
+        let server = MockServer::start(vec![MockResponse::new(200, "<html>")]).await;
+        let err = reqwest::get(server.url())
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap_err();
+        let err = DSRSError::from(err);
+        assert!(matches!(err, DSRSError::Decode(_)));
+        // Worded like an ApiError
+        assert!(
+            err.to_string()
+                .starts_with("API error: Failed to parse response")
+        );
+        assert!(err.source().unwrap().is::<reqwest::Error>());
+    }
+
+    #[test]
+    fn test_retryable_errors() {
+        let http = |status| DSRSError::HttpError {
+            status,
+            message: format!("HTTP {status}"),
+            retry_after: None,
+        };
+        assert!(http(503).is_retryable());
+        assert!(!http(400).is_retryable());
+        assert!(DSRSError::Timeout(None).is_retryable());
+        assert!(
+            DSRSError::RateLimited {
+                message: "HTTP 429".to_string(),
+                retry_after: None,
+            }
+            .is_retryable()
+        );
+        assert!(!DSRSError::AuthError("HTTP 401".to_string()).is_retryable());
+        assert!(!DSRSError::PromptTooLong(10, 5).is_retryable());
+        let exhausted = DSRSError::RetriesExhausted {
+            attempts: 2,
+            last: Box::new(http(503)),
+        };
+        assert!(!exhausted.is_retryable());
+    }
+
+    #[test]
+    fn test_retries_exhausted_chains_last_error() {
+        let err = DSRSError::RetriesExhausted {
+            attempts: 2,
+            last: Box::new(DSRSError::Timeout(None)),
+        };
+        let last = err.source().unwrap().downcast_ref::<DSRSError>().unwrap();
+        assert!(matches!(last, DSRSError::Timeout(None)));
+        assert!(DSRSError::ConfigError("bad".to_string()).source().is_none());
+    }
+
+    #[test]
+    fn test_body_snippet_is_one_truncated_line() {
+        assert_eq!(
+            body_snippet(b"<h1>Bad\n\n  Gateway</h1>\n"),
+            "<h1>Bad Gateway</h1>"
+        );
+        let page = "word ".repeat(100);
+        let snippet = body_snippet(page.as_bytes());
+        assert_eq!(snippet.len(), BODY_SNIPPET_CHARS + 3);
+        assert!(snippet.starts_with("word word") && snippet.ends_with("..."));
+        assert_eq!(body_snippet(b" \n "), "");
+    }
+
+    #[test]
+    fn test_serde_json_error_is_api_error() {
+        let err = serde_json::from_str::<serde_json::Value>("{").unwrap_err();
+        let err = DSRSError::from(err);
+        assert!(
+            err.to_string()
+                .starts_with("API error: Failed to parse response")
+        );
+    }
+}
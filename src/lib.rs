@@ -1,9 +1,35 @@
+pub mod batch; // Batch scheduling
+pub mod cache; // Response caching
 pub mod client; // LLMClient
+pub mod config; // Config files and layered defaults
+pub mod cost; // Pricing and spend tracking
+pub mod embeddings; // Embedding request and response types
 pub mod errors; // DSRSError
-pub mod modules;
-pub mod signatures; // Signature trait and metas // Predict and other modules
+pub mod lm; // LM trait for modules
+pub mod logging; // Log output for the CLI
+pub mod modules; // Predict and other modules
+pub mod provider; // OpenAI and Anthropic wire formats
+pub mod retry; // RetryPolicy
+pub mod schema; // JSON Schema validation for structured outputs
+pub mod signatures; // Signature trait and metas
+#[cfg(feature = "stream")]
+pub mod stream; // CompletionStream
+pub mod testing; // MockLM
+pub mod tokens; // Token counting
+pub mod tools; // Tool calling types
+pub mod trace; // JSONL request tracing
+pub mod transport; // HttpTransport
 
-pub use client::LLMClient;
-pub use errors::DSRSError;
-// pub use signatures::{DSPySignature, FieldMeta};
-// pub use modules::Predict;
+#[cfg(test)]
+mod test_util; // MockServer and FakeTransport
+
+// Lets `#[derive(Signature)]` expansions name `::dsrs` in this crate's tests
+#[cfg(test)]
+extern crate self as dsrs;
+
+pub use client::{Completion, LLMClient, Message, Usage};
+pub use dsrs_derive::Signature;
+pub use errors::{DSRSError, Result};
+pub use lm::LM;
+pub use modules::{ChainOfThought, Evaluate, Example, Predict, Prediction, Predictor};
+pub use signatures::{DSPySignature, FieldMeta};
@@ -0,0 +1,120 @@
+//! The [`LM`] trait: what modules like [`Predict`](crate::modules::Predict)
+//! need from a language model.
+//!
+//! [`LLMClient`] implements it by calling the provider. Anything else that
+//! can answer a conversation can stand in for it, e.g.
+//! [`MockLM`](crate::testing::MockLM) in tests that shouldn't touch the
+//! network.
+
+use crate::client::{CompletionParams, LLMClient, Message};
+use crate::errors::Result;
+use crate::tokens;
+
+/// A language model that completes conversations.
+pub trait LM {
+    /// The model modules call when they aren't given one.
+    fn default_model(&self) -> &str;
+
+    /// Completes `messages` with `model` and returns the reply text.
+    fn complete(
+        &self,
+        messages: &[Message],
+        model: &str,
+        params: &CompletionParams,
+    ) -> impl Future<Output = Result<String>>;
+
+    /// The context window of `model` in tokens, which modules keep prompts
+    /// within. Defaults to the built-in table, else
+    /// [`tokens::DEFAULT_CONTEXT_WINDOW`].
+    fn context_window(&self, model: &str) -> usize {
+        tokens::context_window(model).unwrap_or(tokens::DEFAULT_CONTEXT_WINDOW)
+    }
+
+    /// Counts the tokens in `text` for `model`. Defaults to
+    /// [`tokens::count_tokens`].
+    fn count_tokens(&self, text: &str, model: &str) -> usize {
+        tokens::count_tokens(text, model)
+    }
+
+    /// The tokens [`complete`](LM::complete) adds to every prompt for
+    /// `model`, such as a system prompt, which modules leave room for.
+    /// Defaults to none.
+    fn prompt_overhead(&self, _model: &str) -> usize {
+        0
+    }
+}
+
+/// Sends `messages` with the client's system prompt ahead of them, unless
+/// they start with their own, and its JSON-mode instruction, as for a
+/// single prompt.
+impl LM for LLMClient {
+    fn default_model(&self) -> &str {
+        LLMClient::default_model(self)
+    }
+
+    async fn complete(
+        &self,
+        messages: &[Message],
+        model: &str,
+        params: &CompletionParams,
+    ) -> Result<String> {
+        self.complete_chat_with_params(&self.conversation(messages), model, params)
+            .await
+            .map(|completion| completion.text)
+    }
+
+    fn context_window(&self, model: &str) -> usize {
+        LLMClient::context_window(self, model)
+    }
+
+    fn count_tokens(&self, text: &str, model: &str) -> usize {
+        LLMClient::count_tokens(self, text, model)
+    }
+
+    /// The system prompt and JSON-mode instruction, as added to an empty
+    /// prompt.
+    fn prompt_overhead(&self, model: &str) -> usize {
+        self.single_turn("")
+            .iter()
+            .map(|message| self.count_tokens(&message.content, model))
+            .sum()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::test_util::FakeTransport;
+    use crate::transport::{HttpResponse, HttpTransport};
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_client_adds_its_system_prompt_unless_the_messages_have_one() {
+        let reply = json!({"choices": [{"message": {"role": "assistant", "content": "Paris"}}]});
+        let transport = FakeTransport::new(vec![HttpResponse::json(200, &reply)]);
+        let client = LLMClient::builder()
+            .api_key("test-key")
+            .transport(Arc::clone(&transport) as Arc<dyn HttpTransport>)
+            .system_prompt("Be brief.")
+            .build()
+            .unwrap();
+        let params = CompletionParams::new().max_tokens(5);
+        let text = LM::complete(&client, &[Message::user("Capital?")], "gpt-4o", &params)
+            .await
+            .unwrap();
+        assert_eq!(text, "Paris");
+        let own = [Message::system("Be precise."), Message::user("Capital?")];
+        LM::complete(&client, &own, "gpt-4o", &params)
+            .await
+            .unwrap();
+
+        let sent = transport.sent();
+        assert_eq!(sent[0].body["messages"][0]["content"], "Be brief.");
+        assert_eq!(sent[0].body["messages"][1]["content"], "Capital?");
+        assert_eq!(sent[0].body["max_tokens"], 5);
+        assert_eq!(sent[1].body["messages"].as_array().unwrap().len(), 2);
+        assert_eq!(sent[1].body["messages"][0]["content"], "Be precise.");
+    }
+}
@@ -0,0 +1,127 @@
+//! The `tracing-subscriber` setup behind the CLI's `-v` flags and
+//! `RUST_LOG`.
+//!
+//! Libraries embedding dsrs will usually install their own subscriber
+//! instead; the events are the same.
+
+use tracing::{Level, Subscriber};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// The filter for dsrs's events at `level` and more severe ones, then the
+/// directives in `rust_log` (`RUST_LOG` syntax, e.g.
+/// `dsrs::client=trace,hyper=debug`), which take precedence for the targets
+/// they name. `None` if neither is given, so nothing is logged.
+pub fn env_filter(level: Option<Level>, rust_log: Option<&str>) -> Option<EnvFilter> {
+    let rust_log = rust_log.filter(|directives| !directives.trim().is_empty());
+    if level.is_none() && rust_log.is_none() {
+        return None;
+    }
+    let directives = level
+        .map(|level| format!("dsrs={level}"))
+        .into_iter()
+        .chain(rust_log.map(str::to_string))
+        .collect::<Vec<_>>()
+        .join(",");
+    // Directives that don't parse are skipped rather than failing the run
+    Some(EnvFilter::builder().parse_lossy(directives))
+}
+
+/// Writes each event `filter` lets through to `writer` as one line:
+/// `LEVEL span{field=value ...}: target: message key=value ...`, with the
+/// spans it's in and their fields.
+pub fn subscriber<W>(filter: EnvFilter, writer: W) -> impl Subscriber + Send + Sync + 'static
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt()
+        .with_writer(writer)
+        .with_env_filter(filter)
+        .without_time()
+        .finish()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex, PoisonError};
+
+    /// The bytes written so far, shared by a buffer's clones.
+    type Written = Arc<Mutex<Vec<u8>>>;
+
+    /// A writer whose output can be read back after it's moved into a
+    /// subscriber.
+    #[derive(Clone, Default)]
+    pub(crate) struct SharedBuffer(Written);
+
+    impl SharedBuffer {
+        pub(crate) fn contents(&self) -> String {
+            let bytes = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+            String::from_utf8_lossy(&bytes).into_owned()
+        }
+    }
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let mut bytes = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+            bytes.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'writer> MakeWriter<'writer> for SharedBuffer {
+        type Writer = SharedBuffer;
+
+        fn make_writer(&'writer self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    /// A subscriber logging dsrs's events at `level` to a buffer, as the CLI
+    /// does to stderr.
+    pub(crate) fn capture(level: Level) -> (impl Subscriber, SharedBuffer) {
+        let buffer = SharedBuffer::default();
+        let filter = env_filter(Some(level), None).unwrap();
+        (subscriber(filter, buffer.clone()), buffer)
+    }
+
+    #[test]
+    fn test_lines_respect_the_level() {
+        let (subscriber, buffer) = capture(Level::INFO);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("chat", model = "gpt-4o");
+            let _entered = span.enter();
+            tracing::info!(tokens = 3, "finished");
+            tracing::debug!("hidden");
+            tracing::warn!(target: "h2", "from another crate");
+        });
+        assert_eq!(
+            buffer.contents(),
+            " INFO chat{model=\"gpt-4o\"}: dsrs::logging::tests: finished tokens=3\n"
+        );
+    }
+
+    #[test]
+    fn test_rust_log_overrides_and_adds_targets() {
+        assert!(env_filter(None, None).is_none());
+        assert!(env_filter(None, Some(" ")).is_none());
+
+        let buffer = SharedBuffer::default();
+        let filter = env_filter(Some(Level::INFO), Some("dsrs=warn,h2=debug")).unwrap();
+        tracing::subscriber::with_default(subscriber(filter, buffer.clone()), || {
+            tracing::info!("hidden");
+            tracing::warn!("shown");
+            tracing::debug!(target: "h2", "from another crate");
+        });
+        assert_eq!(
+            buffer.contents(),
+            " WARN dsrs::logging::tests: shown\nDEBUG h2: from another crate\n"
+        );
+    }
+}
@@ -6,46 +6,1038 @@
 //! - Configurable model selection (defaults to gpt-3.5-turbo)
 //! - Prompt length validation to prevent expensive requests
 //! - Support for .env files and environment variables
+//! - Prompts from a flag, a file or piped stdin
 //!
 //! ## Usage
 //! ```bash
 //! dsrs --prompt "Your question here" --model gpt-4 --max-tokens 500
+//! { echo "Summarize this:"; cat report.md; } | dsrs --prompt -
+//! dsrs --prompt "Capital of France?" --output json | jq -r .text
+//! dsrs run qa --input question="What is the capital of France?" --format json
+//! dsrs chat --model gpt-4o
+//! DSRS_TRACE=trace.jsonl dsrs history show --last 5
 //! ```
 
-use clap::Parser;
-use dsrs::{client::LLMClient, errors::DSRSError};
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use dsrs::{
+    cache::{Cache, DiskCache},
+    client::{
+        Completion, CompletionParams, LLMClient, LLMClientBuilder, Message, ResponseFormat, Usage,
+    },
+    config::Config,
+    errors::{DSRSError, Result},
+    logging,
+    provider::Provider,
+    signatures::{SignatureRegistry, parse_input_pairs},
+    trace::{TraceRecord, read_trace},
+};
+use serde::Serialize;
+use std::io::{BufRead, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::Level;
 
 // Configuration constants
 const DEFAULT_MODEL: &str = "gpt-3.5-turbo";
 const DEFAULT_MAX_TOKENS: u32 = 1000;
+const DEFAULT_CONCURRENCY: usize = 4;
+/// `--prompt` value that reads the whole prompt from stdin.
+const STDIN_PROMPT: &str = "-";
+const TRUNCATED_NOTE: &str = "response was cut off by --max-tokens";
+
+/// Printed when a prompt flag means piped stdin goes unread.
+const IGNORED_STDIN_NOTE: &str = "ignoring stdin; pass --prompt - to read the prompt from it";
 
 /// Command-line arguments for the DSRS application.
 #[derive(Parser)]
+// Flags that provide the same input are mutually exclusive, so clap rejects
+// contradictory combinations instead of silently preferring one of them.
+#[command(group = clap::ArgGroup::new("input").args(["prompt", "prompt_file"]))]
+#[command(args_conflicts_with_subcommands = true)]
 struct Args {
-    /// The prompt to send to the AI model
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// The prompt to send to the AI model, or - to read it from stdin
     #[arg(short, long)]
-    prompt: String,
-    /// Maximum number of tokens in the response
-    #[arg(long, default_value_t = DEFAULT_MAX_TOKENS)]
-    max_tokens: u32,
-    /// AI model to use (e.g., gpt-3.5-turbo, gpt-4)
-    #[arg(long, default_value_t = DEFAULT_MODEL.to_string())]
+    prompt: Option<String>,
+    /// Read the prompt from a file instead of --prompt
+    #[arg(long, value_name = "PATH", alias = "file")]
+    prompt_file: Option<PathBuf>,
+    /// Maximum number of tokens in the response [default: 1000]
+    #[arg(long, global = true)]
+    max_tokens: Option<u32>,
+    /// AI model to use (e.g., gpt-3.5-turbo, gpt-4) [default: gpt-3.5-turbo]
+    #[arg(long, global = true)]
+    model: Option<String>,
+    /// Sampling temperature; lower is more deterministic
+    #[arg(long, global = true, value_name = "T")]
+    temperature: Option<f32>,
+    /// Seconds to wait for the API before giving up [default: 30]
+    #[arg(long, global = true, value_name = "SECS")]
+    timeout: Option<f64>,
+    /// Nucleus sampling: only consider the tokens within this probability
+    /// mass, from 0 to 1
+    #[arg(long, global = true, value_name = "P")]
+    top_p: Option<f32>,
+    /// End the response where this sequence would appear; repeat for several
+    #[arg(long, global = true, value_name = "SEQ")]
+    stop: Vec<String>,
+    /// System prompt sent ahead of the prompt
+    #[arg(long, global = true, conflicts_with = "system_file")]
+    system: Option<String>,
+    /// Read the system prompt from a file instead of --system
+    #[arg(long, global = true, value_name = "PATH")]
+    system_file: Option<PathBuf>,
+    /// API format of the endpoint: openai or anthropic (defaults to $LLM_PROVIDER, then openai)
+    #[arg(long, global = true, value_name = "PROVIDER")]
+    provider: Option<Provider>,
+    /// Chat completions URL to send requests to (defaults to $LLM_ENDPOINT, then the provider's);
+    /// local servers need no API key
+    #[arg(long, global = true, value_name = "URL")]
+    endpoint: Option<String>,
+    /// Request JSON output matching this JSON schema file and validate it
+    #[arg(long, value_name = "PATH")]
+    json_schema: Option<PathBuf>,
+    /// Treat each non-blank line of --prompt-file as a prompt and print one
+    /// response line per prompt, in order; exits with 2 if any failed
+    #[arg(long, requires = "prompt_file", conflicts_with_all = ["prompt", "json_schema"])]
+    batch: bool,
+    /// Requests in flight at once with --batch
+    #[arg(long, value_name = "N", requires = "batch", default_value_t = DEFAULT_CONCURRENCY)]
+    concurrency: usize,
+    /// How to print the response; json prints one object with the text,
+    /// model, usage, timing and finish reason, and errors as JSON on stderr
+    #[arg(
+        long,
+        value_enum,
+        alias = "format",
+        default_value_t = OutputFormat::Text,
+        conflicts_with_all = ["json_schema", "batch"]
+    )]
+    output: OutputFormat,
+    /// Ask for this many candidate responses and print them numbered; use a
+    /// nonzero --temperature so they differ
+    #[arg(
+        long = "n",
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with_all = ["json_schema", "batch", "output"]
+    )]
+    n: Option<u32>,
+    /// Print only the response text, without the "Response: " prefix shown
+    /// in a terminal
+    #[arg(long)]
+    raw: bool,
+    /// Always call the API instead of reusing a cached response; responses
+    /// are only cached at --temperature 0
+    #[arg(long, global = true)]
+    no_cache: bool,
+    /// Refuse requests that could take the estimated spend of this run over
+    /// this many US dollars
+    #[arg(long, global = true, value_name = "USD")]
+    max_cost: Option<f64>,
+    /// Print token usage, estimated cost and the finish reason to stderr, with request
+    /// timings; repeat for the requests, responses and retries (-vv) or
+    /// their bodies, prompts included (-vvv)
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+    /// Print the response token by token as it is generated
+    #[cfg(feature = "stream")]
+    #[arg(long, conflicts_with_all = ["json_schema", "batch", "output", "n"])]
+    stream: bool,
+    /// Defaults from the config files and environment, under the flags
+    #[arg(skip)]
+    config: Config,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a registered signature and print its structured output
+    Run {
+        /// Name of the signature to run (e.g. qa)
+        signature: String,
+        /// An input field as key=value, or key:=json for non-string values
+        #[arg(long = "input", value_name = "KEY=VALUE")]
+        inputs: Vec<String>,
+        /// How to print the output
+        #[arg(long, value_enum, alias = "output", default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Chat interactively, one message per line; type /help for commands
+    Chat,
+    /// Manage the response cache ($DSRS_CACHE_DIR, else ~/.cache/dsrs)
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+    /// Inspect the defaults from ~/.config/dsrs/config.toml, ./dsrs.toml and
+    /// LLM_* variables
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// Inspect the requests traced to $DSRS_TRACE
+    History {
+        #[command(subcommand)]
+        action: HistoryAction,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Subcommand)]
+enum CacheAction {
+    /// Delete every cached response
+    Clear,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Subcommand)]
+enum ConfigAction {
+    /// Print the settings in effect, after files, environment and flags
+    Show,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Subcommand)]
+enum HistoryAction {
+    /// Print traced requests and their responses, oldest first
+    Show {
+        /// Print only the last N requests
+        #[arg(long, value_name = "N")]
+        last: Option<usize>,
+        /// The trace file to read [default: $DSRS_TRACE]
+        #[arg(long, value_name = "PATH")]
+        file: Option<PathBuf>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// The response as is; for `run`, one "field: value" line per output field
+    Text,
+    /// JSON
+    Json,
+}
+
+/// What `--output json` prints for a completion. Every field is always
+/// present, `null` when unknown, so scripts can rely on the shape.
+#[derive(Debug, Serialize)]
+struct CompletionOutput<'a> {
+    text: &'a str,
+    /// The model that served the request, or the requested one if the
+    /// provider didn't say.
+    model: &'a str,
+    finish_reason: Option<&'a str>,
+    usage: Option<Usage>,
+    elapsed_ms: u64,
+}
+
+impl<'a> CompletionOutput<'a> {
+    fn new(completion: &'a Completion, requested_model: &'a str, elapsed: Duration) -> Self {
+        Self {
+            text: &completion.text,
+            model: completion.model.as_deref().unwrap_or(requested_model),
+            finish_reason: completion.finish_reason.as_deref(),
+            usage: completion.usage,
+            elapsed_ms: u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX),
+        }
+    }
+}
+
+impl Args {
+    /// Resolves the prompt from the flags, or from stdin when `--prompt -`
+    /// asks for it or no prompt was given and stdin isn't a terminal. Warns
+    /// when input piped to stdin goes unread.
+    fn prompt(&self) -> Result<String> {
+        let stdin = std::io::stdin();
+        let wanted = match (self.prompt.as_deref(), &self.prompt_file) {
+            (Some(STDIN_PROMPT), _) => true,
+            (None, None) => !stdin.is_terminal(),
+            _ => false,
+        };
+        if !wanted && stdin_has_input() {
+            eprintln!("Warning: {IGNORED_STDIN_NOTE}");
+        }
+        let piped = wanted.then(|| stdin.lock());
+        resolve_prompt(self.prompt.as_deref(), self.prompt_file.as_deref(), piped)
+    }
+
+    /// Resolves the system prompt, if one was given.
+    fn system(&self) -> Result<Option<String>> {
+        match (&self.system, &self.system_file) {
+            (Some(system), _) => Ok(Some(system.clone())),
+            (None, Some(path)) => std::fs::read_to_string(path).map(Some).map_err(|err| {
+                DSRSError::ConfigError(format!("Failed to read {}: {err}", path.display()))
+            }),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// The model to call: `--model`, else the config's, else
+    /// [`DEFAULT_MODEL`].
+    fn model(&self) -> &str {
+        self.model
+            .as_deref()
+            .or(self.config.model.as_deref())
+            .unwrap_or(DEFAULT_MODEL)
+    }
+
+    /// The output token limit: `--max-tokens`, else the config's, else
+    /// [`DEFAULT_MAX_TOKENS`].
+    fn max_tokens(&self) -> u32 {
+        self.max_tokens
+            .or(self.config.max_tokens)
+            .unwrap_or(DEFAULT_MAX_TOKENS)
+    }
+
+    /// The settings in effect: the flags over the loaded config, with the
+    /// CLI's default model and token limit.
+    fn settings(&self) -> Result<Config> {
+        let timeout = self
+            .timeout
+            .map(|seconds| {
+                Duration::try_from_secs_f64(seconds)
+                    .ok()
+                    .filter(|timeout| !timeout.is_zero())
+                    .ok_or_else(|| {
+                        DSRSError::ConfigError(format!(
+                            "--timeout must be a positive number of seconds, got {seconds}"
+                        ))
+                    })
+            })
+            .transpose()?;
+        let flags = Config {
+            model: Some(self.model().to_string()),
+            endpoint: self.endpoint.clone(),
+            max_tokens: Some(self.max_tokens()),
+            temperature: self.temperature,
+            timeout,
+        };
+        Ok(flags.or(self.config.clone()))
+    }
+
+    /// The sampling flags, applied to every request.
+    fn params(&self) -> CompletionParams {
+        let mut params = CompletionParams::new();
+        if let Some(top_p) = self.top_p {
+            params = params.top_p(top_p);
+        }
+        if !self.stop.is_empty() {
+            params = params.stop(self.stop.clone());
+        }
+        params
+    }
+
+    /// Whether to reuse cached responses: only at temperature 0, since at any
+    /// other temperature a rerun should sample a fresh answer.
+    fn caches(&self, settings: &Config) -> bool {
+        !self.no_cache && settings.temperature == Some(0.0)
+    }
+
+    /// Builds a client configured from the flags.
+    fn client(&self) -> Result<LLMClient> {
+        let mut builder = LLMClientBuilder::from_env();
+        if let Some(provider) = self.provider {
+            builder = builder.provider(provider);
+        }
+        if let Some(system) = self.system()? {
+            builder = builder.system_prompt(system);
+        }
+        let settings = self.settings()?;
+        builder = builder.default_params(self.params()).config(&settings);
+        if let Some(dollars) = self.max_cost {
+            builder = builder.budget_usd(dollars);
+        }
+        if self.caches(&settings)
+            && let Some(dir) = DiskCache::default_dir()
+        {
+            builder = builder.cache(Arc::new(DiskCache::new(dir)));
+        }
+        builder.build()
+    }
+}
+
+/// Picks the prompt by precedence: `--prompt` (or `stdin` for `--prompt -`),
+/// then `--prompt-file`, then `stdin`. `stdin` is never combined with
+/// another source.
+fn resolve_prompt(
+    flag: Option<&str>,
+    file: Option<&Path>,
+    stdin: Option<impl Read>,
+) -> Result<String> {
+    match (flag, file) {
+        (Some(STDIN_PROMPT), _) => read_piped(stdin)?.ok_or_else(|| {
+            DSRSError::ConfigError("--prompt - was given but stdin is empty".to_string())
+        }),
+        (Some(flag), _) => Ok(flag.to_string()),
+        (None, Some(path)) => std::fs::read_to_string(path).map_err(|err| {
+            DSRSError::ConfigError(format!("Failed to read {}: {err}", path.display()))
+        }),
+        (None, None) => read_piped(stdin)?.ok_or_else(|| {
+            DSRSError::ConfigError(
+                "No prompt given; pass --prompt or --prompt-file, or pipe it on stdin".to_string(),
+            )
+        }),
+    }
+}
+
+/// Whether stdin is a pipe or a non-empty file, i.e. holds input the user
+/// may have meant as the prompt. Other platforms don't say, so never warn.
+fn stdin_has_input() -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        std::fs::metadata("/dev/stdin")
+            .is_ok_and(|meta| meta.file_type().is_fifo() || (meta.is_file() && meta.len() > 0))
+    }
+    #[cfg(not(unix))]
+    {
+        false
+    }
+}
+
+/// Reads all of `stdin`, if given; whitespace-only input counts as none.
+fn read_piped(stdin: Option<impl Read>) -> Result<Option<String>> {
+    let Some(mut stdin) = stdin else {
+        return Ok(None);
+    };
+    let mut text = String::new();
+    stdin
+        .read_to_string(&mut text)
+        .map_err(|err| DSRSError::ConfigError(format!("Failed to read stdin: {err}")))?;
+    Ok(Some(text).filter(|text| !text.trim().is_empty()))
+}
+
+/// Loads a JSON schema file for `--json-schema`.
+fn load_schema(path: &Path) -> Result<serde_json::Value> {
+    let text = std::fs::read_to_string(path).map_err(|err| {
+        DSRSError::ConfigError(format!("Failed to read {}: {err}", path.display()))
+    })?;
+    serde_json::from_str(&text).map_err(|err| {
+        DSRSError::ConfigError(format!("Invalid JSON schema in {}: {err}", path.display()))
+    })
+}
+
+/// Derives a schema name from the file stem, keeping only the characters
+/// providers accept in `json_schema.name`.
+fn schema_name(path: &Path) -> String {
+    let name: String = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if name.is_empty() {
+        "response".to_string()
+    } else {
+        name
+    }
+}
+
+/// Parses `response` as JSON and checks it against `schema`.
+fn check_structured_output(
+    schema: &serde_json::Value,
+    response: &str,
+) -> Result<serde_json::Value> {
+    let value: serde_json::Value = serde_json::from_str(response.trim()).map_err(|err| {
+        DSRSError::ApiError(format!("Response is not valid JSON: {err}\n{response}"))
+    })?;
+    dsrs::schema::validate(schema, &value).map_err(|errors| {
+        DSRSError::ApiError(format!(
+            "Response does not match the JSON schema:\n  {}",
+            errors.join("\n  ")
+        ))
+    })?;
+    Ok(value)
+}
+
+/// Streams the completion of `prompt` to stdout as tokens arrive.
+#[cfg(feature = "stream")]
+async fn stream_completion(args: &Args, client: &LLMClient, prompt: &str) -> Result<()> {
+    use futures_util::StreamExt;
+
+    let mut stream = client.complete_stream(prompt, args.model(), Some(args.max_tokens()), None);
+    let mut stdout = std::io::stdout();
+    print!("{}", response_prefix(args.raw));
+    while let Some(token) = stream.next().await {
+        print!("{}", token?);
+        // Tokens are small, so flush each one rather than waiting for a newline
+        stdout
+            .flush()
+            .map_err(|err| DSRSError::ConfigError(format!("Failed to write output: {err}")))?;
+    }
+    println!();
+    print_total_cost(args, client);
+    Ok(())
+}
+
+/// For `--verbose`, prints what the client's requests are estimated to have
+/// cost, on the paths that don't describe a single completion.
+fn print_total_cost(args: &Args, client: &LLMClient) {
+    if args.verbose > 0 {
+        eprintln!("Estimated cost: ${:.6}", client.total_cost());
+    }
+}
+
+/// The exit code of a `--batch` run in which some prompts failed, to tell it
+/// from a run that couldn't start (1).
+const BATCH_FAILED_EXIT_CODE: u8 = 2;
+
+/// The prompts of a `--batch` file: its non-blank lines.
+fn batch_prompts(text: &str) -> Vec<&str> {
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect()
+}
+
+/// `--batch`: completes every prompt and prints one line per prompt, with
+/// line breaks in responses replaced by spaces and failures as `Error: ...`,
+/// so output line N answers prompt N. If any prompt failed, says how many
+/// on stderr and exits with [`BATCH_FAILED_EXIT_CODE`].
+async fn run_batch(args: &Args) -> Result<ExitCode> {
+    let text = args.prompt()?;
+    let prompts = batch_prompts(&text);
+    let client = args.client()?;
+    let results = client
+        .complete_batch_with_progress(
+            &prompts,
+            args.model(),
+            Some(args.max_tokens()),
+            None,
+            args.concurrency,
+            |done, total| {
+                if args.verbose > 0 {
+                    eprintln!("Completed {done}/{total}");
+                }
+            },
+        )
+        .await;
+    print_total_cost(args, &client);
+    let mut failed = 0;
+    for result in &results {
+        match result {
+            Ok(response) => println!("{}", response.lines().collect::<Vec<_>>().join(" ")),
+            Err(err) => {
+                failed += 1;
+                println!("Error: {err}");
+            }
+        }
+    }
+    if failed > 0 {
+        eprintln!("{failed} of {} prompts failed", results.len());
+        return Ok(ExitCode::from(BATCH_FAILED_EXIT_CODE));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// `--n`: each candidate after its 1-based number, separated by blank
+/// lines.
+fn number_candidates(candidates: &[String]) -> String {
+    candidates
+        .iter()
+        .enumerate()
+        .map(|(index, candidate)| format!("{}. {candidate}", index + 1))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// `Response: ` when a person is reading stdout; nothing when it is piped
+/// or with `--raw`, so scripts get the bare response.
+fn response_prefix(raw: bool) -> &'static str {
+    if !raw && std::io::stdout().is_terminal() {
+        "Response: "
+    } else {
+        ""
+    }
+}
+
+/// A short machine-readable name for the kind of `err`, for `--output json`.
+fn error_kind(err: &DSRSError) -> &'static str {
+    match err {
+        DSRSError::PromptTooLong(..) => "prompt_too_long",
+        DSRSError::ApiError(_) | DSRSError::Decode(_) => "api",
+        DSRSError::NetworkError(_) | DSRSError::Transport(_) => "network",
+        DSRSError::ConfigError(_) => "config",
+        DSRSError::AuthError(_) => "auth",
+        DSRSError::Timeout(_) => "timeout",
+        DSRSError::ParseError { .. } | DSRSError::ParseRetriesExhausted { .. } => "parse",
+        DSRSError::HttpError { .. } => "http",
+        DSRSError::RateLimited { .. } => "rate_limited",
+        DSRSError::Cancelled => "cancelled",
+        DSRSError::BudgetExceeded { .. } => "budget_exceeded",
+        DSRSError::RetriesExhausted { last, .. } => error_kind(last),
+    }
+}
+
+/// `err` as the JSON object `--output json` prints on stderr.
+fn error_json(err: &DSRSError) -> serde_json::Value {
+    serde_json::json!({
+        "error": {"kind": error_kind(err), "message": err.to_string()}
+    })
+}
+
+/// Summarizes a completion's metadata and its estimated `cost` in US
+/// dollars for `--verbose`.
+fn describe_completion(completion: &Completion, cost: Option<f64>) -> String {
+    let mut lines = Vec::new();
+    if let Some(model) = &completion.model {
+        lines.push(format!("Model: {model}"));
+    }
+    match completion.usage {
+        Some(usage) => lines.push(format!(
+            "Usage: {} prompt + {} completion = {} tokens",
+            usage.prompt_tokens, usage.completion_tokens, usage.total_tokens
+        )),
+        None => lines.push("Usage: not reported".to_string()),
+    }
+    if let Some(cost) = cost {
+        lines.push(format!("Estimated cost: ${cost:.6}"));
+    }
+    if let Some(reason) = &completion.finish_reason {
+        if completion.is_truncated() {
+            lines.push(format!("Finish reason: {reason} ({TRUNCATED_NOTE})"));
+        } else {
+            lines.push(format!("Finish reason: {reason}"));
+        }
+    }
+    lines.join("\n")
+}
+
+/// Renders a signature's output in the requested format.
+fn format_output(output: &serde_json::Value, format: OutputFormat) -> Result<String> {
+    match (format, output) {
+        (OutputFormat::Text, serde_json::Value::Object(fields)) => Ok(fields
+            .iter()
+            .map(|(name, value)| match value {
+                serde_json::Value::String(text) => format!("{name}: {text}"),
+                other => format!("{name}: {other}"),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")),
+        (OutputFormat::Text, other) => Ok(other.to_string()),
+        (OutputFormat::Json, value) => serde_json::to_string_pretty(value)
+            .map_err(|err| DSRSError::ApiError(format!("Failed to format response: {err}"))),
+    }
+}
+
+/// `dsrs run`: looks up `name`, runs it on the given inputs and prints the
+/// output.
+async fn run_signature(
+    args: &Args,
+    name: &str,
+    inputs: &[String],
+    format: OutputFormat,
+) -> Result<()> {
+    let registry = SignatureRegistry::builtin();
+    let signature = registry.get(name).ok_or_else(|| {
+        DSRSError::ConfigError(format!(
+            "Unknown signature '{name}' (available: {})",
+            registry.names().collect::<Vec<_>>().join(", ")
+        ))
+    })?;
+    let prompt = signature.generate_prompt(parse_input_pairs(inputs)?)?;
+
+    let client = args.client()?;
+    let response = client
+        .complete(&prompt, args.model(), Some(args.max_tokens()), None)
+        .await?;
+    let output = signature.parse_output(&response)?;
+    println!("{}", format_output(&output, format)?);
+    Ok(())
+}
+
+/// The `dsrs chat` meta-commands, for `/help`.
+const CHAT_HELP: &str = "/system <text>  set the system prompt
+/reset          clear the conversation
+/model <name>   switch models
+/quit           leave (or press Ctrl-D)";
+
+/// A line typed into `dsrs chat`.
+#[derive(Debug, PartialEq, Eq)]
+enum ChatInput<'a> {
+    /// A message for the model.
+    Message(&'a str),
+    /// `/system <text>`; empty text removes the system prompt.
+    System(&'a str),
+    Reset,
+    Model(&'a str),
+    Help,
+    Quit,
+    /// An unknown `/command`.
+    Unknown(&'a str),
+    /// A blank line, which is ignored.
+    Blank,
+}
+
+impl<'a> ChatInput<'a> {
+    fn parse(line: &'a str) -> Self {
+        let line = line.trim();
+        let Some(command) = line.strip_prefix('/') else {
+            return if line.is_empty() {
+                ChatInput::Blank
+            } else {
+                ChatInput::Message(line)
+            };
+        };
+        let (name, rest) = command
+            .split_once(char::is_whitespace)
+            .map_or((command, ""), |(name, rest)| (name, rest.trim()));
+        match name {
+            "system" => ChatInput::System(rest),
+            "reset" => ChatInput::Reset,
+            "model" if !rest.is_empty() => ChatInput::Model(rest),
+            "help" => ChatInput::Help,
+            "quit" | "exit" => ChatInput::Quit,
+            _ => ChatInput::Unknown(line),
+        }
+    }
+}
+
+/// The state of a `dsrs chat` session.
+struct ChatSession {
+    system: Option<String>,
     model: String,
+    /// User and assistant turns, oldest first; always starts with a user
+    /// message.
+    history: Vec<Message>,
+}
+
+impl ChatSession {
+    /// The conversation to send: the system prompt, then the history.
+    fn messages(&self) -> Vec<Message> {
+        self.system
+            .iter()
+            .map(Message::system)
+            .chain(self.history.iter().cloned())
+            .collect()
+    }
+
+    /// Drops the oldest turns until the conversation and `max_tokens` fit
+    /// the model's context window, keeping the system prompt and the latest
+    /// message. Returns how many messages were dropped.
+    fn fit(&mut self, client: &LLMClient, max_tokens: u32) -> usize {
+        let window = client.context_window(&self.model);
+        let tokens = |messages: &[Message]| -> usize {
+            messages
+                .iter()
+                .map(|message| client.count_tokens(&message.content, &self.model))
+                .sum()
+        };
+        let system = self
+            .system
+            .as_deref()
+            .map_or(0, |system| client.count_tokens(system, &self.model));
+        let mut dropped = 0;
+        while dropped + 1 < self.history.len()
+            && system + tokens(&self.history[dropped..]) + max_tokens as usize > window
+        {
+            dropped += 1;
+            // Keep the history starting with a user turn
+            while dropped + 1 < self.history.len() && self.history[dropped].role != "user" {
+                dropped += 1;
+            }
+        }
+        self.history.drain(..dropped);
+        dropped
+    }
+}
+
+/// `dsrs chat`: reads messages from stdin and prints each reply, keeping
+/// the conversation until `/reset`. Failed turns are reported and dropped
+/// from the history, so the session carries on.
+async fn run_chat(args: &Args) -> Result<()> {
+    let client = args.client()?;
+    let mut session = ChatSession {
+        system: args.system()?,
+        model: args.model().to_string(),
+        history: Vec::new(),
+    };
+    let interactive = std::io::stdin().is_terminal();
+    let mut lines = std::io::stdin().lock().lines();
+    loop {
+        if interactive {
+            print!("> ");
+            std::io::stdout()
+                .flush()
+                .map_err(|err| DSRSError::ConfigError(format!("Failed to write output: {err}")))?;
+        }
+        let Some(line) = lines.next() else {
+            return Ok(());
+        };
+        let line =
+            line.map_err(|err| DSRSError::ConfigError(format!("Failed to read stdin: {err}")))?;
+        match ChatInput::parse(&line) {
+            ChatInput::Message(text) => {
+                session.history.push(Message::user(text));
+                let dropped = session.fit(&client, args.max_tokens());
+                if dropped > 0 {
+                    eprintln!("(left out the {dropped} oldest messages to fit the context window)");
+                }
+                let reply = client
+                    .complete_chat(
+                        &session.messages(),
+                        &session.model,
+                        Some(args.max_tokens()),
+                        None,
+                    )
+                    .await;
+                match reply {
+                    Ok(reply) => {
+                        println!("{reply}");
+                        session.history.push(Message::assistant(reply));
+                    }
+                    Err(err) => {
+                        eprintln!("Error: {err}");
+                        session.history.pop();
+                    }
+                }
+            }
+            ChatInput::System(text) => {
+                session.system = (!text.is_empty()).then(|| text.to_string());
+                eprintln!(
+                    "System prompt {}",
+                    if text.is_empty() { "removed" } else { "set" }
+                );
+            }
+            ChatInput::Reset => {
+                session.history.clear();
+                eprintln!("Conversation cleared");
+            }
+            ChatInput::Model(model) => {
+                session.model = model.to_string();
+                eprintln!("Model: {model}");
+            }
+            ChatInput::Help => eprintln!("{CHAT_HELP}"),
+            ChatInput::Quit => return Ok(()),
+            ChatInput::Unknown(command) => {
+                eprintln!("Unknown command {command}; try /help");
+            }
+            ChatInput::Blank => {}
+        }
+    }
+}
+
+/// `dsrs config show`: prints the settings in effect in the config file
+/// format, after a comment naming the config files that were read.
+fn show_config(args: &Args) -> Result<()> {
+    for path in Config::default_files() {
+        if path.is_file() {
+            println!("# from {}", path.display());
+        }
+    }
+    print!("{}", args.settings()?);
+    Ok(())
+}
+
+/// `dsrs history show`: prints the last `last` records of the trace file.
+fn show_history(last: Option<usize>, file: Option<&Path>) -> Result<()> {
+    let path = match file {
+        Some(path) => path.to_path_buf(),
+        None => std::env::var_os("DSRS_TRACE")
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+            .ok_or_else(|| {
+                DSRSError::ConfigError("No trace file; pass --file or set DSRS_TRACE".to_string())
+            })?,
+    };
+    let records = read_trace(&path)?;
+    let skip = last.map_or(0, |last| records.len().saturating_sub(last));
+    let shown: Vec<String> = records[skip..].iter().map(format_record).collect();
+    print!("{}", shown.join("\n"));
+    Ok(())
+}
+
+/// One traced request for `dsrs history show`: a header line with the time,
+/// model, latency and usage, then each message and response as `role: text`.
+fn format_record(record: &TraceRecord) -> String {
+    let mut header = format!(
+        "== {} {} ({} ms",
+        format_timestamp(record.timestamp_ms),
+        record.model,
+        record.latency_ms
+    );
+    if let Some(usage) = record.usage {
+        header.push_str(&format!(
+            ", {} prompt + {} completion tokens",
+            usage.prompt_tokens, usage.completion_tokens
+        ));
+    }
+    let mut lines = vec![format!("{header})")];
+    for message in &record.messages {
+        lines.push(format!("{}: {}", message.role, message.content));
+    }
+    for response in &record.responses {
+        lines.push(format!("assistant: {response}"));
+    }
+    if let Some(error) = &record.error {
+        lines.push(format!("error: {error}"));
+    }
+    lines.join("\n") + "\n"
+}
+
+/// Formats milliseconds since the Unix epoch as `YYYY-MM-DD HH:MM:SS UTC`.
+fn format_timestamp(ms: u64) -> String {
+    let secs = ms / 1000;
+    let (days, time) = (secs / 86_400, secs % 86_400);
+    // Howard Hinnant's days-to-civil algorithm, with eras starting in March
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// `dsrs cache clear`: empties the default cache directory.
+fn clear_cache() -> Result<()> {
+    let dir = DiskCache::default_dir().ok_or_else(|| {
+        DSRSError::ConfigError("No cache directory; set DSRS_CACHE_DIR or HOME".to_string())
+    })?;
+    DiskCache::new(&dir).clear()?;
+    println!("Cleared {}", dir.display());
+    Ok(())
 }
 
 #[tokio::main]
-async fn main() -> Result<(), DSRSError> {
-    let args = Args::parse();
+async fn main() -> Result<ExitCode> {
+    let mut args = Args::parse();
+    let rust_log = std::env::var("RUST_LOG").ok();
+    if let Some(filter) = logging::env_filter(log_level(args.verbose), rust_log.as_deref()) {
+        // Fails only if a subscriber is already set, which is fine
+        let _ =
+            tracing::subscriber::set_global_default(logging::subscriber(filter, std::io::stderr));
+    }
+    let result = match Config::load() {
+        Ok(config) => {
+            args.config = config;
+            run(&args).await
+        }
+        Err(err) => Err(err),
+    };
+    if args.output == OutputFormat::Json
+        && let Err(err) = &result
+    {
+        eprintln!("{}", error_json(err));
+        std::process::exit(1);
+    }
+    result
+}
 
-    let client = LLMClient::new();
-    let response = client
-        .complete(&args.prompt, &args.model, Some(args.max_tokens), None)
+/// The log level for `-v` given `count` times: info, then debug (with API
+/// keys redacted from the logged requests), then trace, which adds request
+/// and response bodies. `None` without `-v`.
+fn log_level(count: u8) -> Option<Level> {
+    match count {
+        0 => None,
+        1 => Some(Level::INFO),
+        2 => Some(Level::DEBUG),
+        _ => Some(Level::TRACE),
+    }
+}
+
+async fn run(args: &Args) -> Result<ExitCode> {
+    match &args.command {
+        Some(Command::Run {
+            signature,
+            inputs,
+            format,
+        }) => run_signature(args, signature, inputs, *format).await?,
+        Some(Command::Chat) => run_chat(args).await?,
+        Some(Command::Cache {
+            action: CacheAction::Clear,
+        }) => clear_cache()?,
+        Some(Command::Config {
+            action: ConfigAction::Show,
+        }) => show_config(args)?,
+        Some(Command::History {
+            action: HistoryAction::Show { last, file },
+        }) => show_history(*last, file.as_deref())?,
+        None if args.batch => return run_batch(args).await,
+        None => complete_prompt(args).await?,
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Completes the prompt from the flags or stdin and prints the response.
+async fn complete_prompt(args: &Args) -> Result<()> {
+    let prompt = args.prompt()?;
+
+    let client = args.client()?;
+    if let Some(path) = &args.json_schema {
+        let schema = load_schema(path)?;
+        let format = ResponseFormat::json_schema(schema_name(path), schema.clone());
+        let response = client
+            .complete_with_format(&prompt, args.model(), Some(args.max_tokens()), None, format)
+            .await?;
+        let value = check_structured_output(&schema, &response)?;
+        let pretty = serde_json::to_string_pretty(&value)
+            .map_err(|err| DSRSError::ApiError(format!("Failed to format response: {err}")))?;
+        println!("{pretty}");
+        print_total_cost(args, &client);
+        return Ok(());
+    }
+
+    #[cfg(feature = "stream")]
+    if args.stream {
+        return stream_completion(args, &client, &prompt).await;
+    }
+
+    if let Some(n) = args.n {
+        let candidates = client
+            .complete_n(&prompt, args.model(), n, Some(args.max_tokens()), None)
+            .await?;
+        println!("{}", number_candidates(&candidates));
+        print_total_cost(args, &client);
+        return Ok(());
+    }
+
+    let started = Instant::now();
+    let completion = client
+        .complete_detailed(&prompt, args.model(), Some(args.max_tokens()), None)
         .await?;
-    println!("Response: {response}");
+    match args.output {
+        OutputFormat::Text => println!("{}{}", response_prefix(args.raw), completion.text),
+        OutputFormat::Json => {
+            let output = CompletionOutput::new(&completion, args.model(), started.elapsed());
+            let json = serde_json::to_string(&output)
+                .map_err(|err| DSRSError::ApiError(format!("Failed to format response: {err}")))?;
+            println!("{json}");
+        }
+    }
+    if args.verbose > 0 {
+        let cost = completion
+            .usage
+            .map(|usage| client.pricing().cost(args.model(), &usage));
+        eprintln!("{}", describe_completion(&completion, cost));
+    } else if completion.is_truncated() && args.output == OutputFormat::Text {
+        // JSON output carries the finish reason instead
+        eprintln!("Warning: {TRUNCATED_NOTE}");
+    }
     Ok(())
 }
 
 #[cfg(test)]
+#[allow(clippy::expect_used, clippy::panic, clippy::unwrap_used)]
 mod tests {
     use super::*;
 
@@ -58,15 +1050,16 @@ mod tests {
         }
 
         let client = LLMClient::new();
-        let long_prompt = "a".repeat(35000); // Exceeds 32k limit
+        let long_prompt = "a ".repeat(20000); // ~20k tokens, over the 16k window
         let result = client
             .complete(&long_prompt, "gpt-3.5-turbo", Some(100), None)
             .await;
 
         match result {
             Err(DSRSError::PromptTooLong(len, max)) => {
-                assert_eq!(len, 35000);
-                assert_eq!(max, 32000);
+                // 20001 for the prompt, with its trailing space, and 100
+                assert_eq!(len, 20101);
+                assert_eq!(max, 16385);
             }
             other => {
                 panic!("Expected PromptTooLong error, got: {:?}", other);
@@ -79,6 +1072,498 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_prompt_conflicts_with_file() {
+        let err = Args::try_parse_from(["dsrs", "--prompt", "hi", "--prompt-file", "prompt.txt"])
+            .err()
+            .expect("conflicting flags should be rejected");
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+        let message = err.to_string();
+        assert!(message.contains("--prompt ") && message.contains("--prompt-file"));
+    }
+
+    #[test]
+    fn test_system_conflicts_with_system_file() {
+        let err = Args::try_parse_from([
+            "dsrs",
+            "--prompt",
+            "hi",
+            "--system",
+            "Be brief.",
+            "--system-file",
+            "system.txt",
+        ])
+        .err()
+        .expect("conflicting flags should be rejected");
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+        let message = err.to_string();
+        assert!(message.contains("--system") && message.contains("--system-file"));
+
+        let args = Args::try_parse_from(["dsrs", "run", "qa", "--system", "Be brief."]).unwrap();
+        assert_eq!(args.system().unwrap().as_deref(), Some("Be brief."));
+    }
+
+    #[test]
+    fn test_prompt_flags_are_optional() {
+        // Without flags the prompt comes from stdin
+        let args = Args::try_parse_from(["dsrs"]).unwrap();
+        assert!(args.prompt.is_none() && args.prompt_file.is_none());
+
+        // `--file` is kept as an alias
+        for flag in ["--prompt-file", "--file"] {
+            let args = Args::try_parse_from(["dsrs", flag, "prompt.txt"]).unwrap();
+            assert_eq!(args.prompt_file, Some(PathBuf::from("prompt.txt")));
+        }
+    }
+
+    #[test]
+    fn test_resolve_prompt_precedence() {
+        let stdin = |text: &'static str| Some(text.as_bytes());
+        // Stdin is never read alongside a --prompt
+        assert_eq!(
+            resolve_prompt(Some("Summarize:"), None, stdin("a,b\n1,2\n")).unwrap(),
+            "Summarize:"
+        );
+        assert_eq!(
+            resolve_prompt(None, None, stdin("from stdin")).unwrap(),
+            "from stdin"
+        );
+        assert_eq!(
+            resolve_prompt(Some("-"), None, stdin("line 1\nline 2\n")).unwrap(),
+            "line 1\nline 2\n"
+        );
+        assert!(matches!(
+            resolve_prompt(Some("-"), None, stdin("")),
+            Err(DSRSError::ConfigError(message)) if message.contains("stdin is empty")
+        ));
+        assert_eq!(
+            resolve_prompt(Some("hi"), None, None::<&[u8]>).unwrap(),
+            "hi"
+        );
+        for stdin in [None, stdin("")] {
+            assert!(matches!(
+                resolve_prompt(None, None, stdin),
+                Err(DSRSError::ConfigError(message)) if message.starts_with("No prompt given")
+            ));
+        }
+        assert!(matches!(
+            resolve_prompt(None, Some(Path::new("/nonexistent/prompt.txt")), stdin("x")),
+            Err(DSRSError::ConfigError(_))
+        ));
+    }
+
+    #[test]
+    fn test_chat_input() {
+        assert_eq!(
+            ChatInput::parse("  Hello there \n"),
+            ChatInput::Message("Hello there")
+        );
+        assert_eq!(ChatInput::parse("   "), ChatInput::Blank);
+        assert_eq!(
+            ChatInput::parse("/system You are terse.  "),
+            ChatInput::System("You are terse.")
+        );
+        assert_eq!(ChatInput::parse("/system"), ChatInput::System(""));
+        assert_eq!(ChatInput::parse("/reset"), ChatInput::Reset);
+        assert_eq!(
+            ChatInput::parse("/model gpt-4o"),
+            ChatInput::Model("gpt-4o")
+        );
+        assert_eq!(ChatInput::parse("/model"), ChatInput::Unknown("/model"));
+        assert_eq!(ChatInput::parse("/quit"), ChatInput::Quit);
+        assert_eq!(ChatInput::parse("/exit"), ChatInput::Quit);
+        assert_eq!(ChatInput::parse("/undo"), ChatInput::Unknown("/undo"));
+        assert!(Args::try_parse_from(["dsrs", "chat", "--model", "gpt-4o"]).is_ok());
+    }
+
+    #[test]
+    fn test_chat_drops_the_oldest_turns_to_fit() {
+        let client = LLMClient::builder()
+            .api_key("test-key")
+            .context_window("gpt-4", 20)
+            .build()
+            .unwrap();
+        // Each "a" is one token
+        let turn = |role: &str, tokens: usize| Message::new(role, "a ".repeat(tokens).trim_end());
+        let mut session = ChatSession {
+            system: Some("a a".to_string()),
+            model: "gpt-4".to_string(),
+            history: vec![turn("user", 4), turn("assistant", 4), turn("user", 4)],
+        };
+        // 2 + 12 + 5 fits
+        assert_eq!(session.fit(&client, 5), 0);
+        // 2 + 12 + 10 doesn't; dropping the first user turn alone would
+        // leave the history starting with the assistant's reply
+        assert_eq!(session.fit(&client, 10), 2);
+        assert_eq!(session.history, [turn("user", 4)]);
+        assert_eq!(session.messages()[0], Message::system("a a"));
+        // The latest message is kept even if it doesn't fit
+        assert_eq!(session.fit(&client, 100), 0);
+        assert_eq!(session.history.len(), 1);
+    }
+
+    #[test]
+    fn test_run_subcommand_parses() {
+        let args = Args::try_parse_from([
+            "dsrs",
+            "run",
+            "qa",
+            "--input",
+            "question=Why?",
+            "--format",
+            "json",
+            "--model",
+            "gpt-4o",
+        ])
+        .unwrap();
+        assert_eq!(args.model(), "gpt-4o");
+        match args.command {
+            Some(Command::Run {
+                signature,
+                inputs,
+                format,
+            }) => {
+                assert_eq!(signature, "qa");
+                assert_eq!(inputs, ["question=Why?"]);
+                assert_eq!(format, OutputFormat::Json);
+            }
+            _ => panic!("expected the run subcommand"),
+        }
+
+        let err = Args::try_parse_from(["dsrs", "--prompt", "hi", "run", "qa"])
+            .err()
+            .expect("--prompt and run should conflict");
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn test_format_output() {
+        let output = serde_json::json!({"answer": "Paris", "confidence": 0.9});
+        assert_eq!(
+            format_output(&output, OutputFormat::Text).unwrap(),
+            "answer: Paris\nconfidence: 0.9"
+        );
+        assert_eq!(
+            format_output(&output, OutputFormat::Json).unwrap(),
+            "{\n  \"answer\": \"Paris\",\n  \"confidence\": 0.9\n}"
+        );
+    }
+
+    #[test]
+    fn test_batch_flags() {
+        let args =
+            Args::try_parse_from(["dsrs", "--prompt-file", "prompts.txt", "--batch"]).unwrap();
+        assert!(args.batch);
+        assert_eq!(args.concurrency, DEFAULT_CONCURRENCY);
+
+        for invalid in [
+            &["dsrs", "--batch"][..],
+            &["dsrs", "--prompt", "hi", "--batch"],
+            &["dsrs", "--prompt-file", "prompts.txt", "--concurrency", "8"],
+        ] {
+            assert!(Args::try_parse_from(invalid).is_err(), "{invalid:?}");
+        }
+
+        assert_eq!(
+            batch_prompts("What is 2 + 2?\n\n  \nCapital of France?\n"),
+            ["What is 2 + 2?", "Capital of France?"]
+        );
+    }
+
+    #[test]
+    fn test_output_flag() {
+        let args = Args::try_parse_from(["dsrs", "--prompt", "hi"]).unwrap();
+        assert_eq!(args.output, OutputFormat::Text);
+        let args = Args::try_parse_from(["dsrs", "--prompt", "hi", "--output", "json"]).unwrap();
+        assert_eq!(args.output, OutputFormat::Json);
+        let args = Args::try_parse_from(["dsrs", "--prompt", "hi", "--format", "json"]).unwrap();
+        assert_eq!(args.output, OutputFormat::Json);
+        let args = Args::try_parse_from(["dsrs", "--prompt", "hi", "--raw"]).unwrap();
+        assert!(args.raw);
+        assert_eq!(response_prefix(true), "");
+        let args = Args::try_parse_from(["dsrs", "run", "qa", "--output", "json"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Some(Command::Run {
+                format: OutputFormat::Json,
+                ..
+            })
+        ));
+
+        for conflicting in ["--batch", "--json-schema"] {
+            let mut argv = vec!["dsrs", "--prompt-file", "p.txt", "--output", "json"];
+            argv.push(conflicting);
+            if conflicting == "--json-schema" {
+                argv.push("schema.json");
+            }
+            assert!(Args::try_parse_from(&argv).is_err(), "{argv:?}");
+        }
+    }
+
+    #[test]
+    fn test_verbose_levels() {
+        let verbose = |argv: &[&str]| Args::try_parse_from(argv).unwrap().verbose;
+        assert_eq!(verbose(&["dsrs", "--prompt", "hi"]), 0);
+        assert_eq!(verbose(&["dsrs", "-v", "--prompt", "hi"]), 1);
+        assert_eq!(verbose(&["dsrs", "-vv", "--prompt", "hi"]), 2);
+        assert_eq!(verbose(&["dsrs", "run", "qa", "-v", "--verbose"]), 2);
+
+        assert_eq!(log_level(0), None);
+        assert_eq!(log_level(1), Some(Level::INFO));
+        assert_eq!(log_level(2), Some(Level::DEBUG));
+        assert_eq!(log_level(5), Some(Level::TRACE));
+    }
+
+    #[test]
+    fn test_completion_output_shape() {
+        let completion = Completion {
+            text: "Paris".to_string(),
+            model: None,
+            usage: Some(Usage {
+                prompt_tokens: 5,
+                completion_tokens: 1,
+                total_tokens: 6,
+            }),
+            finish_reason: None,
+        };
+        let output = CompletionOutput::new(&completion, "gpt-4o", Duration::from_millis(1500));
+        assert_eq!(
+            serde_json::to_value(&output).unwrap(),
+            serde_json::json!({
+                "text": "Paris",
+                "model": "gpt-4o",
+                "finish_reason": null,
+                "usage": {"prompt_tokens": 5, "completion_tokens": 1, "total_tokens": 6},
+                "elapsed_ms": 1500
+            })
+        );
+
+        let err = DSRSError::RetriesExhausted {
+            attempts: 3,
+            last: Box::new(DSRSError::RateLimited {
+                message: "HTTP 429".to_string(),
+                retry_after: None,
+            }),
+        };
+        assert_eq!(error_json(&err)["error"]["kind"], "rate_limited");
+        assert_eq!(
+            error_json(&err)["error"]["message"],
+            "Failed after 3 attempts: Rate limited: HTTP 429"
+        );
+    }
+
+    #[test]
+    fn test_cache_flags() {
+        let caches = |flags: &[&str]| {
+            let args =
+                Args::try_parse_from(["dsrs", "--prompt", "hi"].iter().chain(flags)).unwrap();
+            args.caches(&args.settings().unwrap())
+        };
+        assert!(!caches(&[]));
+        assert!(!caches(&["--temperature", "0.7"]));
+        assert!(caches(&["--temperature", "0"]));
+        assert!(!caches(&["--temperature", "0", "--no-cache"]));
+
+        let args = Args::try_parse_from(["dsrs", "cache", "clear"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Some(Command::Cache {
+                action: CacheAction::Clear
+            })
+        ));
+        assert!(Args::try_parse_from(["dsrs", "cache"]).is_err());
+    }
+
+    #[test]
+    fn test_provider_flag() {
+        let args = Args::try_parse_from(["dsrs", "--prompt", "hi"]).unwrap();
+        assert_eq!(args.provider, None);
+        let args =
+            Args::try_parse_from(["dsrs", "--provider", "anthropic", "--prompt", "hi"]).unwrap();
+        assert_eq!(args.provider, Some(Provider::Anthropic));
+        assert!(Args::try_parse_from(["dsrs", "--provider", "gemini", "--prompt", "hi"]).is_err());
+    }
+
+    #[test]
+    fn test_endpoint_flag() {
+        let args = Args::try_parse_from(["dsrs", "--prompt", "hi"]).unwrap();
+        assert_eq!(args.endpoint, None);
+        let url = "http://localhost:11434/v1/chat/completions";
+        let args = Args::try_parse_from(["dsrs", "run", "qa", "--endpoint", url]).unwrap();
+        assert_eq!(args.endpoint.as_deref(), Some(url));
+    }
+
+    #[test]
+    fn test_sampling_flags() {
+        let args = Args::try_parse_from(["dsrs", "--prompt", "hi"]).unwrap();
+        assert_eq!(args.params(), CompletionParams::new());
+        let args = Args::try_parse_from([
+            "dsrs", "--prompt", "hi", "--top-p", "0.9", "--stop", "\n\n", "--stop", "END",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.params(),
+            CompletionParams::new().top_p(0.9).stop(["\n\n", "END"])
+        );
+
+        let args = Args::try_parse_from(["dsrs", "--prompt", "hi", "--top-p", "1.5"]).unwrap();
+        let err = args.client().unwrap_err();
+        assert!(matches!(err, DSRSError::ConfigError(_)), "{err}");
+    }
+
+    #[test]
+    fn test_n_flag() {
+        let args = Args::try_parse_from(["dsrs", "--prompt", "hi", "--n", "3"]).unwrap();
+        assert_eq!(args.n, Some(3));
+        assert!(Args::try_parse_from(["dsrs", "--prompt", "hi", "--n", "0"]).is_err());
+        for conflicting in [["--output", "json"], ["--json-schema", "person.json"]] {
+            let mut argv = vec!["dsrs", "--prompt", "hi", "--n", "2"];
+            argv.extend(conflicting);
+            let err = Args::try_parse_from(argv).err().unwrap();
+            assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+        }
+        let candidates = ["4".to_string(), "Four\n(2 + 2)".to_string()];
+        assert_eq!(number_candidates(&candidates), "1. 4\n\n2. Four\n(2 + 2)");
+    }
+
+    #[test]
+    fn test_flags_override_the_config() {
+        let config = Config {
+            model: Some("llama3".to_string()),
+            max_tokens: Some(2000),
+            temperature: Some(0.2),
+            timeout: Some(Duration::from_secs(60)),
+            ..Config::default()
+        };
+        // Built-in defaults apply without a config
+        let args = Args::try_parse_from(["dsrs", "--prompt", "hi"]).unwrap();
+        assert_eq!(
+            (args.model(), args.max_tokens()),
+            (DEFAULT_MODEL, DEFAULT_MAX_TOKENS)
+        );
+
+        let mut args = Args::try_parse_from(["dsrs", "--prompt", "hi"]).unwrap();
+        args.config = config.clone();
+        assert_eq!((args.model(), args.max_tokens()), ("llama3", 2000));
+        assert_eq!(args.settings().unwrap(), config);
+
+        let mut args = Args::try_parse_from([
+            "dsrs",
+            "config",
+            "show",
+            "--model",
+            "gpt-4o",
+            "--temperature",
+            "0.7",
+            "--timeout",
+            "2.5",
+        ])
+        .unwrap();
+        args.config = config;
+        let settings = args.settings().unwrap();
+        assert_eq!(settings.model.as_deref(), Some("gpt-4o"));
+        assert_eq!(settings.max_tokens, Some(2000));
+        assert_eq!(settings.temperature, Some(0.7));
+        assert_eq!(settings.timeout, Some(Duration::from_millis(2500)));
+
+        let args = Args::try_parse_from(["dsrs", "--prompt", "hi", "--timeout", "0"]).unwrap();
+        assert!(matches!(args.settings(), Err(DSRSError::ConfigError(_))));
+    }
+
+    #[cfg(feature = "stream")]
+    #[test]
+    fn test_stream_flag() {
+        let args = Args::try_parse_from(["dsrs", "--prompt", "hi", "--stream"]).unwrap();
+        assert!(args.stream);
+
+        let err = Args::try_parse_from([
+            "dsrs",
+            "--prompt",
+            "hi",
+            "--stream",
+            "--json-schema",
+            "schema.json",
+        ])
+        .err()
+        .expect("--stream and --json-schema should conflict");
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn test_describe_completion() {
+        let completion = Completion {
+            text: "The capital".to_string(),
+            model: Some("gpt-4o-2024-08-06".to_string()),
+            usage: Some(Usage {
+                prompt_tokens: 12,
+                completion_tokens: 2,
+                total_tokens: 14,
+            }),
+            finish_reason: Some("length".to_string()),
+        };
+        assert_eq!(
+            describe_completion(&completion, Some(0.00005)),
+            "Model: gpt-4o-2024-08-06\n\
+             Usage: 12 prompt + 2 completion = 14 tokens\n\
+             Estimated cost: $0.000050\n\
+             Finish reason: length (response was cut off by --max-tokens)"
+        );
+
+        let bare = Completion {
+            usage: None,
+            model: None,
+            finish_reason: Some("stop".to_string()),
+            ..completion
+        };
+        assert_eq!(
+            describe_completion(&bare, None),
+            "Usage: not reported\nFinish reason: stop"
+        );
+    }
+
+    #[test]
+    fn test_schema_name_is_sanitized() {
+        assert_eq!(
+            schema_name(Path::new("schemas/person.v2.json")),
+            "person_v2"
+        );
+        assert_eq!(schema_name(Path::new("my schema.json")), "my_schema");
+        assert_eq!(schema_name(Path::new("")), "response");
+    }
+
+    #[test]
+    fn test_check_structured_output() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {"answer": {"type": "string"}},
+            "required": ["answer"]
+        });
+        let value = check_structured_output(&schema, "{\"answer\": \"Paris\"}\n").unwrap();
+        assert_eq!(value["answer"], "Paris");
+
+        let err = check_structured_output(&schema, "{\"answer\": 42}").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("/answer: expected string, got number")
+        );
+
+        let err = check_structured_output(&schema, "Paris").unwrap_err();
+        assert!(err.to_string().contains("not valid JSON"));
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(
+            format_timestamp(1_709_210_096_789),
+            "2024-02-29 12:34:56 UTC"
+        );
+        assert_eq!(
+            format_timestamp(1_735_689_599_000),
+            "2024-12-31 23:59:59 UTC"
+        );
+    }
+
     #[test]
     fn test_error_display() {
         // We can't easily test the actual API response parsing without mocking,
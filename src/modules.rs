@@ -1,28 +1,1081 @@
-// use crate::client::LLMClient;
-// use crate::errors::DSRSError;
-// use crate::signatures::DSPySignature;
-
-// // Basic Predict module
-// pub struct Predict<S: DSPySignature> {
-//     signature: S,
-// }
-
-// impl<S: DSPySignature> Predict<S> {
-//     pub fn new(signature: S) -> Self {
-//         Self { signature }
-//     }
-
-//     pub async fn forward(
-//         &self,
-//         client: &LLMClient,
-//         input: S::Input,
-//     ) -> Result<S::Output, DSRSError> {
-//         let prompt = self.signature.generate_prompt(&input);
-//         let response = client
-//             .complete(&prompt, "gpt-3.5-turbo", Some(1000), None)
-//             .await?;
-//         self.signature
-//             .parse_output(&response)
-//             .map_err(|e| DSRSError::ApiError(e.to_string()))
-//     }
-// }
+use crate::batch::run_prioritized;
+use crate::client::{CompletionParams, Message};
+use crate::errors::{DSRSError, Result};
+use crate::lm::LM;
+use crate::signatures::DSPySignature;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+const DEFAULT_MAX_TOKENS: u32 = 1000;
+const DEFAULT_EVAL_CONCURRENCY: usize = 4;
+const REASONING_LABEL: &str = "Reasoning:";
+const FINAL_ANSWER_LABEL: &str = "Final answer:";
+const CHAIN_OF_THOUGHT_INSTRUCTION: &str = "Think step by step. Write \"Reasoning:\" followed by \
+your reasoning, then \"Final answer:\" followed by the response in the format \
+requested above.";
+
+/// A worked example for a [`Predict`]: an input and the output wanted for
+/// it, shown to the model ahead of the live input.
+#[derive(Serialize, Deserialize)]
+#[serde(bound(serialize = "", deserialize = "S::Input: DeserializeOwned"))]
+pub struct Example<S: DSPySignature> {
+    pub input: S::Input,
+    pub output: S::Output,
+}
+
+impl<S: DSPySignature> Example<S> {
+    pub fn new(input: S::Input, output: S::Output) -> Self {
+        Self { input, output }
+    }
+}
+
+impl<S: DSPySignature> From<(S::Input, S::Output)> for Example<S> {
+    fn from((input, output): (S::Input, S::Output)) -> Self {
+        Self::new(input, output)
+    }
+}
+
+impl<S: DSPySignature> Clone for Example<S>
+where
+    S::Input: Clone,
+{
+    fn clone(&self) -> Self {
+        Self::new(self.input.clone(), self.output.clone())
+    }
+}
+
+impl<S: DSPySignature> fmt::Debug for Example<S>
+where
+    S::Input: fmt::Debug,
+    S::Output: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Example")
+            .field("input", &self.input)
+            .field("output", &self.output)
+            .finish()
+    }
+}
+
+// Basic Predict module
+pub struct Predict<S: DSPySignature> {
+    signature: S,
+    demos: Vec<Example<S>>,
+    model: Option<String>,
+    max_tokens: Option<u32>,
+    temperature: Option<f32>,
+    parse_retries: u32,
+    retry_temperature: Option<f32>,
+}
+
+impl<S: DSPySignature> Predict<S> {
+    /// Creates a predictor using the LM's
+    /// [default model](LM::default_model) with up to 1000 output tokens.
+    pub fn new(signature: S) -> Self {
+        Self {
+            signature,
+            demos: Vec::new(),
+            model: None,
+            max_tokens: Some(DEFAULT_MAX_TOKENS),
+            temperature: None,
+            parse_retries: 0,
+            retry_temperature: None,
+        }
+    }
+
+    /// Sets the model to call instead of the client's default.
+    pub fn with_model(mut self, model: impl Into<String>) -> Self {
+        self.model = Some(model.into());
+        self
+    }
+
+    /// Sets the output token limit; `None` leaves it to the provider.
+    pub fn with_max_tokens(mut self, max_tokens: Option<u32>) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    /// Sets the sampling temperature; `None` uses the client default.
+    pub fn with_temperature(mut self, temperature: Option<f32>) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    /// Sets how many times to re-ask the model when its response doesn't
+    /// parse. Each retry shows the model its previous response and the parse
+    /// error and asks it to answer again in the expected format.
+    pub fn with_parse_retries(mut self, retries: u32) -> Self {
+        self.parse_retries = retries;
+        self
+    }
+
+    /// Sets the sampling temperature for parse retries, e.g. slightly above
+    /// [`with_temperature`](Self::with_temperature) so the model doesn't
+    /// repeat itself; `None` keeps the predictor's temperature.
+    pub fn with_retry_temperature(mut self, temperature: Option<f32>) -> Self {
+        self.retry_temperature = temperature;
+        self
+    }
+
+    /// Sets the demonstrations shown above every input, oldest first. When
+    /// the prompt would overflow the model's context window (less
+    /// `max_tokens` and the LM's [`prompt_overhead`](LM::prompt_overhead)),
+    /// the oldest demos are left out. Takes [`Example`]s or
+    /// `(input, output)` pairs.
+    pub fn with_demos<E: Into<Example<S>>>(mut self, demos: impl IntoIterator<Item = E>) -> Self {
+        self.demos = demos.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// The wrapped signature.
+    pub fn signature(&self) -> &S {
+        &self.signature
+    }
+
+    /// The demonstrations set with [`with_demos`](Self::with_demos).
+    #[allow(
+        clippy::type_complexity,
+        reason = "a borrowed slice; the lint scores the reference and slice as nesting"
+    )]
+    pub fn demos(&self) -> &[Example<S>] {
+        &self.demos
+    }
+
+    /// Builds the prompt for `input`, completes it with `lm`, usually an
+    /// [`LLMClient`](crate::client::LLMClient), and parses the typed output.
+    /// Responses that don't parse fail with [`DSRSError::ParseError`], or,
+    /// with [parse retries](Self::with_parse_retries), with
+    /// [`DSRSError::ParseRetriesExhausted`] once every retry failed too.
+    pub async fn forward<L: LM>(&self, lm: &L, input: S::Input) -> Result<S::Output> {
+        let model = self.model(lm);
+        let budget = lm
+            .context_window(model)
+            .saturating_sub(self.max_tokens.unwrap_or(0) as usize)
+            .saturating_sub(lm.prompt_overhead(model));
+        let prompt = self.prompt(&input, |prompt| lm.count_tokens(prompt, model) <= budget);
+        let mut response = self.complete(lm, &prompt).await?;
+        let mut responses = Vec::new();
+        loop {
+            let err = match self.parse(&response) {
+                Ok(output) => return Ok(output),
+                Err(err) if self.parse_retries == 0 => return Err(err),
+                Err(err) => err,
+            };
+            let DSRSError::ParseError { message, raw } = err else {
+                return Err(err);
+            };
+            responses.push(raw);
+            if responses.len() > self.parse_retries as usize {
+                return Err(DSRSError::ParseRetriesExhausted { message, responses });
+            }
+            let previous = responses.last().map(String::as_str).unwrap_or_default();
+            tracing::debug!(
+                attempt = responses.len(),
+                error = %message,
+                "response didn't parse; asking the model to correct it"
+            );
+            response = self.correct(lm, &prompt, previous, &message).await?;
+        }
+    }
+
+    /// Asks the model to answer `prompt` again after `previous` failed to
+    /// parse with `error`.
+    async fn correct<L: LM>(
+        &self,
+        lm: &L,
+        prompt: &str,
+        previous: &str,
+        error: &str,
+    ) -> Result<String> {
+        let messages = [
+            Message::user(prompt),
+            Message::assistant(previous),
+            Message::user(format!(
+                "Your response could not be parsed: {error}\n\nAnswer again, following the \
+                 output format above exactly."
+            )),
+        ];
+        let params = CompletionParams {
+            temperature: self.retry_temperature.or(self.temperature),
+            ..self.params()
+        };
+        lm.complete(&messages, self.model(lm), &params).await
+    }
+
+    /// The prompt for `input` with as many of the newest demos as `fits`
+    /// accepts. If none fit, the prompt has no demos (and may still be too
+    /// long, which the client reports).
+    fn prompt(&self, input: &S::Input, fits: impl Fn(&str) -> bool) -> String {
+        let demos: Vec<String> = self
+            .demos
+            .iter()
+            .map(|demo| self.signature.format_demo(&demo.input, &demo.output))
+            .collect();
+        let mut dropped = 0;
+        let prompt = loop {
+            let prompt = self
+                .signature
+                .generate_prompt_with_demos(input, &demos[dropped..]);
+            if dropped == demos.len() || fits(&prompt) {
+                break prompt;
+            }
+            dropped += 1;
+        };
+        if dropped > 0 {
+            tracing::warn!(
+                dropped,
+                kept = demos.len() - dropped,
+                "demos don't fit in the context window; leaving the oldest out"
+            );
+        }
+        prompt
+    }
+
+    /// The model this predictor calls.
+    fn model<'a, L: LM>(&'a self, lm: &'a L) -> &'a str {
+        self.model.as_deref().unwrap_or(lm.default_model())
+    }
+
+    /// This predictor's generation settings.
+    fn params(&self) -> CompletionParams {
+        CompletionParams {
+            max_tokens: self.max_tokens,
+            temperature: self.temperature,
+            ..CompletionParams::default()
+        }
+    }
+
+    /// Completes `prompt` with this predictor's model and sampling settings.
+    async fn complete<L: LM>(&self, lm: &L, prompt: &str) -> Result<String> {
+        lm.complete(&[Message::user(prompt)], self.model(lm), &self.params())
+            .await
+    }
+
+    fn parse(&self, response: &str) -> Result<S::Output> {
+        self.signature
+            .parse_output(response)
+            .map_err(|err| DSRSError::ParseError {
+                message: err.to_string(),
+                raw: response.to_string(),
+            })
+    }
+}
+
+/// A typed output together with the reasoning that led to it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Prediction<T> {
+    pub reasoning: String,
+    pub output: T,
+}
+
+/// A parsed [`Prediction`], or why the response didn't parse.
+type Parsed<T> = Result<Prediction<T>>;
+
+/// Like [`Predict`], but asks the model to reason step by step before
+/// answering and returns that reasoning alongside the output.
+pub struct ChainOfThought<S: DSPySignature> {
+    predict: Predict<S>,
+}
+
+impl<S: DSPySignature> ChainOfThought<S> {
+    /// Creates a module with the same defaults as [`Predict::new`].
+    pub fn new(signature: S) -> Self {
+        Self {
+            predict: Predict::new(signature),
+        }
+    }
+
+    /// Sets the model to call instead of the client's default.
+    pub fn with_model(mut self, model: impl Into<String>) -> Self {
+        self.predict = self.predict.with_model(model);
+        self
+    }
+
+    /// Sets the output token limit, which has to cover the reasoning too;
+    /// `None` leaves it to the provider.
+    pub fn with_max_tokens(mut self, max_tokens: Option<u32>) -> Self {
+        self.predict = self.predict.with_max_tokens(max_tokens);
+        self
+    }
+
+    /// Sets the sampling temperature; `None` uses the client default.
+    pub fn with_temperature(mut self, temperature: Option<f32>) -> Self {
+        self.predict = self.predict.with_temperature(temperature);
+        self
+    }
+
+    /// The wrapped signature.
+    pub fn signature(&self) -> &S {
+        self.predict.signature()
+    }
+
+    /// The signature's prompt for `input`, with the reasoning instruction
+    /// appended.
+    pub fn generate_prompt(&self, input: &S::Input) -> String {
+        let prompt = self.signature().generate_prompt(input);
+        format!("{prompt}\n\n{CHAIN_OF_THOUGHT_INSTRUCTION}")
+    }
+
+    /// Builds the prompt for `input`, completes it and splits the response
+    /// into the reasoning and the typed output. Responses whose answer
+    /// doesn't parse fail with [`DSRSError::ParseError`].
+    pub async fn forward<L: LM>(&self, lm: &L, input: S::Input) -> Result<Prediction<S::Output>> {
+        let prompt = self.generate_prompt(&input);
+        let response = self.predict.complete(lm, &prompt).await?;
+        self.parse(&response)
+    }
+
+    /// Splits at the last `Final answer:` label. Models don't always use the
+    /// labels they were asked for, so without one this tries looser splits:
+    /// at the last blank line, then the whole response as the answer. The
+    /// first answer the signature accepts wins.
+    fn parse(&self, response: &str) -> Parsed<S::Output> {
+        let mut candidates = Vec::new();
+        if let Some(index) = find_label(response, FINAL_ANSWER_LABEL) {
+            candidates.push((
+                &response[..index],
+                &response[index + FINAL_ANSWER_LABEL.len()..],
+            ));
+        } else {
+            if let Some(index) = response.trim_end().rfind("\n\n") {
+                candidates.push((&response[..index], &response[index..]));
+            }
+            candidates.push(("", response));
+        }
+
+        let mut first_error = None;
+        for (reasoning, answer) in candidates {
+            match self.predict.parse(answer) {
+                Ok(output) => {
+                    return Ok(Prediction {
+                        reasoning: clean_reasoning(reasoning),
+                        output,
+                    });
+                }
+                Err(err) => {
+                    first_error.get_or_insert(err);
+                }
+            }
+        }
+        Err(match first_error {
+            Some(DSRSError::ParseError { message, .. }) => DSRSError::ParseError {
+                message,
+                raw: response.to_string(),
+            },
+            Some(err) => err,
+            None => DSRSError::ParseError {
+                message: "response contains no answer".to_string(),
+                raw: response.to_string(),
+            },
+        })
+    }
+}
+
+/// Anything that predicts outputs of `S`, like [`Predict`] and
+/// [`ChainOfThought`] (whose reasoning is dropped); what [`Evaluate`] runs.
+pub trait Predictor<S: DSPySignature> {
+    fn predict<L: LM>(&self, lm: &L, input: S::Input) -> impl Future<Output = Result<S::Output>>;
+}
+
+impl<S: DSPySignature> Predictor<S> for Predict<S> {
+    async fn predict<L: LM>(&self, lm: &L, input: S::Input) -> Result<S::Output> {
+        self.forward(lm, input).await
+    }
+}
+
+impl<S: DSPySignature> Predictor<S> for ChainOfThought<S> {
+    async fn predict<L: LM>(&self, lm: &L, input: S::Input) -> Result<S::Output> {
+        Ok(self.forward(lm, input).await?.output)
+    }
+}
+
+/// Scores a `(prediction, expected)` pair for [`Evaluate`].
+type Metric<T> = Box<dyn Fn(&T, &T) -> f64 + Send + Sync>;
+
+/// Scores a [`Predictor`] on a dataset of examples: each input is
+/// predicted and the prediction compared with the expected output by a
+/// metric returning a score, usually between 0 and 1. See [`metrics`] for
+/// built-in ones.
+pub struct Evaluate<S: DSPySignature> {
+    dataset: Vec<Example<S>>,
+    metric: Metric<S::Output>,
+    concurrency: usize,
+}
+
+impl<S: DSPySignature> Evaluate<S> {
+    /// Evaluates on `dataset`, e.g. `(input, output)` pairs, scoring each
+    /// `(prediction, expected)` with `metric`.
+    pub fn new<E: Into<Example<S>>>(
+        dataset: impl IntoIterator<Item = E>,
+        metric: impl Fn(&S::Output, &S::Output) -> f64 + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            dataset: dataset.into_iter().map(Into::into).collect(),
+            metric: Box::new(metric),
+            concurrency: DEFAULT_EVAL_CONCURRENCY,
+        }
+    }
+
+    /// Sets how many examples are predicted at once. Defaults to 4.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// Runs `predictor` over the dataset. An example whose prediction fails
+    /// (e.g. a network or parse error) scores 0 and is counted in
+    /// [`EvalReport::failures`]; the rest still run.
+    pub async fn run<P, L>(&self, predictor: &P, lm: &L) -> EvalReport<S::Output>
+    where
+        P: Predictor<S>,
+        L: LM,
+        S::Input: Clone,
+    {
+        let priorities = vec![0; self.dataset.len()];
+        let predictions = run_prioritized(&priorities, self.concurrency, |index| {
+            predictor.predict(lm, self.dataset[index].input.clone())
+        })
+        .await;
+        let scores: Vec<f64> = predictions
+            .iter()
+            .zip(&self.dataset)
+            .map(|(prediction, example)| match prediction {
+                Ok(output) => (self.metric)(output, &example.output),
+                Err(_) => 0.0,
+            })
+            .collect();
+        let mean_score = if scores.is_empty() {
+            0.0
+        } else {
+            scores.iter().sum::<f64>() / scores.len() as f64
+        };
+        EvalReport {
+            failures: predictions.iter().filter(|result| result.is_err()).count(),
+            scores,
+            mean_score,
+            predictions,
+        }
+    }
+}
+
+/// The outcome of [`Evaluate::run`]; `scores` and `predictions` are in
+/// dataset order.
+#[derive(Debug)]
+pub struct EvalReport<T> {
+    pub scores: Vec<f64>,
+    /// Mean over every example, with failures scoring 0; 0 for an empty
+    /// dataset.
+    pub mean_score: f64,
+    /// How many predictions failed.
+    pub failures: usize,
+    pub predictions: Vec<Result<T>>,
+}
+
+/// Metrics for [`Evaluate`].
+pub mod metrics {
+    use serde::Serialize;
+    use serde_json::Value;
+
+    /// 1 if the prediction equals the expected output, else 0.
+    pub fn exact_match<T: PartialEq>(predicted: &T, expected: &T) -> f64 {
+        if predicted == expected { 1.0 } else { 0.0 }
+    }
+
+    /// Like [`exact_match`], but strings anywhere in the outputs are
+    /// compared normalized: lowercased, without punctuation, and with runs of
+    /// whitespace collapsed, so `"Paris."` matches `" paris"`.
+    pub fn normalized_match<T: Serialize>(predicted: &T, expected: &T) -> f64 {
+        let normalized = |value: &T| serde_json::to_value(value).ok().map(normalize);
+        match (normalized(predicted), normalized(expected)) {
+            (Some(predicted), Some(expected)) if predicted == expected => 1.0,
+            _ => 0.0,
+        }
+    }
+
+    fn normalize(value: Value) -> Value {
+        match value {
+            Value::String(text) => Value::String(normalize_text(&text)),
+            Value::Array(items) => Value::Array(items.into_iter().map(normalize).collect()),
+            Value::Object(fields) => Value::Object(
+                fields
+                    .into_iter()
+                    .map(|(name, value)| (name, normalize(value)))
+                    .collect(),
+            ),
+            other => other,
+        }
+    }
+
+    fn normalize_text(text: &str) -> String {
+        let text: String = text
+            .to_lowercase()
+            .chars()
+            .filter(|c| c.is_alphanumeric() || c.is_whitespace())
+            .collect();
+        text.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+}
+
+/// The byte offset of the last occurrence of `label`, ignoring ASCII case.
+fn find_label(text: &str, label: &str) -> Option<usize> {
+    // ASCII lowercasing keeps byte offsets unchanged
+    text.to_ascii_lowercase().rfind(&label.to_ascii_lowercase())
+}
+
+/// Trims the reasoning and drops a leading `Reasoning:` label.
+fn clean_reasoning(reasoning: &str) -> String {
+    let reasoning = reasoning.trim();
+    let reasoning = match reasoning.get(..REASONING_LABEL.len()) {
+        Some(label) if label.eq_ignore_ascii_case(REASONING_LABEL) => {
+            &reasoning[REASONING_LABEL.len()..]
+        }
+        _ => reasoning,
+    };
+    reasoning.trim().to_string()
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::Signature;
+    use crate::client::LLMClient;
+    use crate::signatures::{QuestionAnswer, QuestionAnswerInput, QuestionAnswerOutput};
+    use crate::test_util::{MockResponse, MockServer};
+    use crate::testing::MockLM;
+
+    fn client_for(server: &MockServer) -> LLMClient {
+        LLMClient::builder()
+            .api_key("test-key")
+            .endpoint(server.url())
+            .build()
+            .unwrap()
+    }
+
+    /// Answer the question using the context.
+    #[derive(Default, Signature)]
+    struct ContextQa {
+        #[input(desc = "facts that may help")]
+        context: String,
+        #[input(desc = "the question to answer")]
+        question: String,
+        /// a few words at most
+        #[output]
+        answer: String,
+    }
+
+    #[tokio::test]
+    async fn test_derived_signature_round_trip() {
+        let server = MockServer::start(vec![MockResponse::completion(" Berlin\n")]).await;
+        let demo = ContextQa {
+            context: "Paris is the capital of France.".to_string(),
+            question: "Capital of France?".to_string(),
+            answer: "Paris".to_string(),
+        };
+        let predict = Predict::new(ContextQa::default()).with_demos([demo]);
+        let output = predict
+            .forward(
+                &client_for(&server),
+                ContextQaInput {
+                    context: "Berlin is the capital of Germany.".to_string(),
+                    question: "Capital of Germany?".to_string(),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            output,
+            ContextQaOutput {
+                answer: "Berlin".to_string()
+            }
+        );
+
+        assert_eq!(
+            server.requests()[0].json()["messages"][0]["content"],
+            "Answer the question using the context.\n\n\
+             Input fields:\n\
+             - Context: facts that may help\n\
+             - Question: the question to answer\n\
+             Output fields:\n\
+             - Answer: a few words at most\n\n\
+             Answer with each output field on its own line as \"Field: value\".\n\n\
+             Context: Paris is the capital of France.\n\
+             Question: Capital of France?\n\
+             Answer: Paris\n\n\
+             Context: Berlin is the capital of Germany.\n\
+             Question: Capital of Germany?\n\
+             Answer:"
+        );
+        let names: Vec<&str> = ContextQa::input_fields()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, ["context", "question"]);
+    }
+
+    /// Questions and their answers.
+    type Answers = &'static [(&'static str, &'static str)];
+
+    /// Answers from a fixed table, failing on unknown questions.
+    struct Lookup(Answers);
+
+    impl Predictor<QuestionAnswer> for Lookup {
+        async fn predict<L: LM>(
+            &self,
+            _lm: &L,
+            input: QuestionAnswerInput,
+        ) -> Result<QuestionAnswerOutput> {
+            tokio::task::yield_now().await;
+            let answer = self
+                .0
+                .iter()
+                .find(|(question, _)| *question == input.question);
+            match answer {
+                Some((_, answer)) => Ok(QuestionAnswerOutput {
+                    answer: answer.to_string(),
+                }),
+                None => Err(DSRSError::ParseError {
+                    message: "response contains no Answer".to_string(),
+                    raw: String::new(),
+                }),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_evaluate_records_scores_and_failures() {
+        let predictor = Lookup(&[
+            ("Capital of France?", "Paris."),
+            ("Capital of Italy?", "Rome"),
+            ("Capital of Spain?", "Lisbon"),
+        ]);
+        let dataset = [
+            qa("Capital of France?", "paris"),
+            qa("Capital of Italy?", "Rome"),
+            qa("Capital of Spain?", "Madrid"),
+            qa("Capital of Peru?", "Lima"),
+        ];
+        // Never called: the stub needs no model
+        let lm = MockLM::default();
+
+        let report = Evaluate::new(dataset.clone(), metrics::normalized_match)
+            .with_concurrency(2)
+            .run(&predictor, &lm)
+            .await;
+        assert_eq!(report.scores, [1.0, 1.0, 0.0, 0.0]);
+        assert_eq!(report.mean_score, 0.5);
+        assert_eq!(report.failures, 1);
+        assert_eq!(report.predictions[0].as_ref().unwrap().answer, "Paris.");
+        assert!(matches!(
+            report.predictions[3],
+            Err(DSRSError::ParseError { .. })
+        ));
+
+        // Plain (input, output) pairs work too
+        let pairs = dataset.map(|example| (example.input, example.output));
+        let report = Evaluate::new(pairs, metrics::exact_match)
+            .run(&predictor, &lm)
+            .await;
+        assert_eq!(report.scores, [0.0, 1.0, 0.0, 0.0]);
+
+        let empty: Vec<Example<QuestionAnswer>> = Vec::new();
+        let report = Evaluate::new(empty, metrics::exact_match)
+            .run(&predictor, &lm)
+            .await;
+        assert_eq!((report.mean_score, report.failures), (0.0, 0));
+    }
+
+    #[test]
+    fn test_normalized_match() {
+        assert_eq!(
+            metrics::normalized_match(&"  The  Answer!", &"the answer"),
+            1.0
+        );
+        assert_eq!(metrics::normalized_match(&"Paris", &"Rome"), 0.0);
+        // Strings are normalized inside structured outputs too
+        let expected = qa("", "New York").output;
+        let predicted = qa("", "new york.").output;
+        assert_eq!(metrics::normalized_match(&predicted, &expected), 1.0);
+        assert_eq!(metrics::exact_match(&predicted, &expected), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_forward_end_to_end() {
+        let server = MockServer::start(vec![MockResponse::completion("Answer: Paris")]).await;
+        let predict = Predict::new(QuestionAnswer)
+            .with_model("gpt-4o")
+            .with_temperature(Some(0.0));
+        let output = predict
+            .forward(
+                &client_for(&server),
+                QuestionAnswerInput {
+                    question: "Capital of France?".to_string(),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(output.answer, "Paris");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].header("Authorization"), Some("Bearer test-key"));
+        let body = requests[0].json();
+        assert_eq!(body["model"], "gpt-4o");
+        assert_eq!(body["temperature"], 0.0);
+        assert_eq!(
+            body["messages"][0]["content"],
+            "Answer the question concisely.\n\nQuestion: Capital of France?\nAnswer:"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forward_reports_unparseable_responses() {
+        let server = MockServer::start(vec![MockResponse::completion("  ")]).await;
+        let result = Predict::new(QuestionAnswer)
+            .forward(
+                &client_for(&server),
+                QuestionAnswerInput {
+                    question: "?".to_string(),
+                },
+            )
+            .await;
+        assert!(matches!(result, Err(DSRSError::ParseError { raw, .. }) if raw == "  "));
+        // Without a model of its own, Predict uses the client's default
+        assert_eq!(server.requests()[0].json()["model"], "gpt-3.5-turbo");
+    }
+
+    #[tokio::test]
+    async fn test_forward_retries_unparseable_responses() {
+        let server = MockServer::start(vec![
+            MockResponse::completion("  "),
+            MockResponse::completion("Answer: Paris"),
+        ])
+        .await;
+        let predict = Predict::new(QuestionAnswer)
+            .with_temperature(Some(0.0))
+            .with_parse_retries(2)
+            .with_retry_temperature(Some(0.5));
+        let output = predict
+            .forward(&client_for(&server), france())
+            .await
+            .unwrap();
+        assert_eq!(output.answer, "Paris");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        let retry = requests[1].json();
+        assert_eq!(retry["temperature"], 0.5);
+        let messages = retry["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0], requests[0].json()["messages"][0]);
+        assert_eq!(messages[1]["role"], "assistant");
+        assert_eq!(messages[1]["content"], "  ");
+        assert_eq!(messages[2]["role"], "user");
+        let correction = messages[2]["content"].as_str().unwrap();
+        assert!(correction.starts_with("Your response could not be parsed: "));
+    }
+
+    #[tokio::test]
+    async fn test_forward_reports_every_response_once_retries_run_out() {
+        let server = MockServer::start(vec![
+            MockResponse::completion("  "),
+            MockResponse::completion("\n"),
+            MockResponse::completion("Answer: Paris"),
+        ])
+        .await;
+        let result = Predict::new(QuestionAnswer)
+            .with_parse_retries(1)
+            .forward(&client_for(&server), france())
+            .await;
+        match result {
+            Err(DSRSError::ParseRetriesExhausted { responses, .. }) => {
+                assert_eq!(responses, ["  ", "\n"]);
+            }
+            other => panic!("expected ParseRetriesExhausted, got {other:?}"),
+        }
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_forward_on_a_mock_lm() {
+        let lm = MockLM::new(["  ", "Answer: Paris"]);
+        let predict = Predict::new(QuestionAnswer)
+            .with_temperature(Some(0.25))
+            .with_parse_retries(1);
+        let output = predict.forward(&lm, france()).await.unwrap();
+        assert_eq!(output.answer, "Paris");
+
+        let requests = lm.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].model, "mock");
+        assert_eq!(
+            requests[0].params,
+            CompletionParams::new().max_tokens(1000).temperature(0.25)
+        );
+        assert_eq!(
+            requests[0].messages,
+            [Message::user(
+                predict.signature().generate_prompt(&france())
+            )]
+        );
+        assert_eq!(requests[1].messages.len(), 3);
+        assert!(lm.prompts()[1].starts_with("Your response could not be parsed: "));
+    }
+
+    #[test]
+    fn test_predict_generates_signature_prompt() {
+        let predict = Predict::new(QuestionAnswer).with_model("gpt-4o");
+        let input = QuestionAnswerInput {
+            question: "What is 2 + 2?".to_string(),
+        };
+        assert_eq!(
+            predict.signature().generate_prompt(&input),
+            "Answer the question concisely.\n\nQuestion: What is 2 + 2?\nAnswer:"
+        );
+        assert_eq!(predict.model.as_deref(), Some("gpt-4o"));
+    }
+
+    fn qa(question: &str, answer: &str) -> Example<QuestionAnswer> {
+        Example::new(
+            QuestionAnswerInput {
+                question: question.to_string(),
+            },
+            QuestionAnswerOutput {
+                answer: answer.to_string(),
+            },
+        )
+    }
+
+    fn demos() -> Vec<Example<QuestionAnswer>> {
+        vec![
+            qa("Capital of Italy?", "Rome"),
+            qa("Capital of Spain?", "Madrid"),
+            qa("Capital of Japan?", "Tokyo"),
+        ]
+    }
+
+    fn france() -> QuestionAnswerInput {
+        QuestionAnswerInput {
+            question: "Capital of France?".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_demos_render_above_the_input() {
+        let prompt = |demos: &[Example<_>]| {
+            Predict::new(QuestionAnswer)
+                .with_demos(demos.to_vec())
+                .prompt(&france(), |_| true)
+        };
+        let all = demos();
+        assert_eq!(
+            prompt(&[]),
+            "Answer the question concisely.\n\nQuestion: Capital of France?\nAnswer:"
+        );
+        assert_eq!(
+            prompt(&all[..1]),
+            "Answer the question concisely.\n\n\
+             Question: Capital of Italy?\nAnswer: Rome\n\n\
+             Question: Capital of France?\nAnswer:"
+        );
+        assert_eq!(
+            prompt(&all),
+            "Answer the question concisely.\n\n\
+             Question: Capital of Italy?\nAnswer: Rome\n\n\
+             Question: Capital of Spain?\nAnswer: Madrid\n\n\
+             Question: Capital of Japan?\nAnswer: Tokyo\n\n\
+             Question: Capital of France?\nAnswer:"
+        );
+        let pairs = all
+            .iter()
+            .map(|demo| (demo.input.clone(), demo.output.clone()));
+        assert_eq!(
+            Predict::new(QuestionAnswer)
+                .with_demos(pairs)
+                .prompt(&france(), |_| true),
+            prompt(&all)
+        );
+    }
+
+    #[test]
+    fn test_oldest_demos_are_dropped_to_fit() {
+        let predict = Predict::new(QuestionAnswer).with_demos(demos());
+        let full = predict.prompt(&france(), |_| true);
+        let without_oldest = predict.signature().generate_prompt_with_demos(
+            &france(),
+            &[
+                "Question: Capital of Spain?\nAnswer: Madrid".to_string(),
+                "Question: Capital of Japan?\nAnswer: Tokyo".to_string(),
+            ],
+        );
+
+        // One character short of the full prompt: only the oldest demo goes
+        let limit = full.len() - 1;
+        let prompt = predict.prompt(&france(), |prompt| prompt.len() <= limit);
+        assert_eq!(prompt, without_oldest);
+
+        // Exactly at the limit nothing is dropped
+        let prompt = predict.prompt(&france(), |prompt| prompt.len() <= full.len());
+        assert_eq!(prompt, full);
+
+        // Nothing fits: the bare prompt is sent
+        let prompt = predict.prompt(&france(), |_| false);
+        assert_eq!(prompt, QuestionAnswer.generate_prompt(&france()));
+    }
+
+    #[test]
+    fn test_examples_clone_and_serialize() {
+        let example = qa("Capital of Italy?", "Rome");
+        let json = serde_json::to_value(example.clone()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "input": {"question": "Capital of Italy?"},
+                "output": {"answer": "Rome"}
+            })
+        );
+        let back: Example<QuestionAnswer> = serde_json::from_value(json).unwrap();
+        assert_eq!(back.output, example.output);
+    }
+
+    #[tokio::test]
+    async fn test_forward_drops_demos_beyond_the_context_window() {
+        let server = MockServer::start(vec![MockResponse::completion("Answer: Paris")]).await;
+        let client = LLMClient::builder()
+            .api_key("test-key")
+            .endpoint(server.url())
+            .context_window("tiny", 60)
+            .build()
+            .unwrap();
+        Predict::new(QuestionAnswer)
+            .with_model("tiny-model")
+            .with_max_tokens(Some(20))
+            .with_demos(demos())
+            .forward(&client, france())
+            .await
+            .unwrap();
+
+        let body = server.requests()[0].json();
+        let prompt = body["messages"][0]["content"].as_str().unwrap().to_string();
+        assert!(client.count_tokens(&prompt, "tiny-model") <= 40);
+        assert!(prompt.contains("Capital of Japan?"));
+        assert!(!prompt.contains("Capital of Italy?"));
+
+        // The client's system prompt takes room from the demos too
+        let server = MockServer::start(vec![MockResponse::completion("Answer: Paris")]).await;
+        let system = "You answer geography questions for a quiz show.";
+        let client = LLMClient::builder()
+            .api_key("test-key")
+            .endpoint(server.url())
+            .context_window("tiny", 60)
+            .system_prompt(system)
+            .build()
+            .unwrap();
+        assert!(client.prompt_overhead("tiny-model") > 0);
+        Predict::new(QuestionAnswer)
+            .with_model("tiny-model")
+            .with_max_tokens(Some(20))
+            .with_demos(demos())
+            .forward(&client, france())
+            .await
+            .unwrap();
+
+        let body = server.requests()[0].json();
+        assert_eq!(body["messages"][0]["content"], system);
+        let prompt = body["messages"][1]["content"].as_str().unwrap().to_string();
+        let sent =
+            client.count_tokens(system, "tiny-model") + client.count_tokens(&prompt, "tiny-model");
+        assert!(sent <= 40, "{sent}");
+        assert!(prompt.contains("Capital of Japan?"));
+        assert!(!prompt.contains("Capital of Spain?"));
+    }
+
+    #[test]
+    fn test_predict_parses_typed_output() {
+        let predict = Predict::new(QuestionAnswer);
+        assert_eq!(predict.parse("Answer: 4").unwrap().answer, "4");
+
+        match predict.parse("") {
+            Err(DSRSError::ParseError { message, raw }) => {
+                assert_eq!(message, "response contains no answer");
+                assert_eq!(raw, "");
+            }
+            other => panic!("expected a parse error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_chain_of_thought_splits_reasoning() {
+        let cot = ChainOfThought::new(QuestionAnswer);
+        let prediction = cot
+            .parse("Reasoning: 2 + 2 is 4.\nChecked twice.\nFinal answer: 4")
+            .unwrap();
+        assert_eq!(prediction.reasoning, "2 + 2 is 4.\nChecked twice.");
+        assert_eq!(prediction.output.answer, "4");
+
+        // Labels in a different case, with the signature's own label repeated
+        let prediction = cot
+            .parse("reasoning: Paris is the capital.\nFINAL ANSWER: Answer: Paris")
+            .unwrap();
+        assert_eq!(prediction.reasoning, "Paris is the capital.");
+        assert_eq!(prediction.output.answer, "Paris");
+    }
+
+    #[test]
+    fn test_chain_of_thought_falls_back_without_labels() {
+        let cot = ChainOfThought::new(QuestionAnswer);
+        // Reasoning blended in, separated only by a blank line
+        let prediction = cot
+            .parse("France's capital has been Paris for centuries.\n\nAnswer: Paris\n")
+            .unwrap();
+        assert_eq!(
+            prediction.reasoning,
+            "France's capital has been Paris for centuries."
+        );
+        assert_eq!(prediction.output.answer, "Paris");
+
+        // No reasoning at all
+        let prediction = cot.parse("Paris").unwrap();
+        assert_eq!(prediction.reasoning, "");
+        assert_eq!(prediction.output.answer, "Paris");
+    }
+
+    #[test]
+    fn test_chain_of_thought_reports_malformed_responses() {
+        let cot = ChainOfThought::new(QuestionAnswer);
+        for response in ["", "Reasoning: hmm.\nFinal answer:   "] {
+            match cot.parse(response) {
+                Err(DSRSError::ParseError { message, raw }) => {
+                    assert_eq!(message, "response contains no answer");
+                    assert_eq!(raw, response);
+                }
+                other => panic!("expected a parse error, got {other:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chain_of_thought_end_to_end() {
+        let server = MockServer::start(vec![MockResponse::completion(
+            "Reasoning: It is the seat of government.\nFinal answer: Paris",
+        )])
+        .await;
+        let prediction = ChainOfThought::new(QuestionAnswer)
+            .forward(
+                &client_for(&server),
+                QuestionAnswerInput {
+                    question: "Capital of France?".to_string(),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(prediction.reasoning, "It is the seat of government.");
+        assert_eq!(prediction.output.answer, "Paris");
+
+        let prompt = server.requests()[0].json()["messages"][0]["content"].clone();
+        let prompt = prompt.as_str().unwrap();
+        assert!(prompt.starts_with("Answer the question concisely."));
+        assert!(prompt.ends_with(CHAIN_OF_THOUGHT_INSTRUCTION));
+    }
+}
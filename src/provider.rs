@@ -0,0 +1,462 @@
+//! Request and response formats of the supported LLM APIs.
+//!
+//! [`LLMClient`](crate::LLMClient) builds every request as a [`ChatRequest`]
+//! and reads every response into the same reply shape; the [`Provider`]
+//! translates both to and from the wire format of the API it talks to.
+
+use crate::client::{
+    ChatRequest, ChatResponse, Message, MessageContent, MessageResponse, Reply, Usage,
+};
+use crate::errors::{DSRSError, Result};
+use crate::transport::Header;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+const OPENAI_ENDPOINT: &str = "https://api.openai.com/v1/chat/completions";
+const ANTHROPIC_ENDPOINT: &str = "https://api.anthropic.com/v1/messages";
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+/// Anthropic requires `max_tokens`; this is sent when the caller gives none.
+const ANTHROPIC_DEFAULT_MAX_TOKENS: u32 = 1024;
+
+/// The API an [`LLMClient`](crate::LLMClient) talks to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Provider {
+    /// OpenAI-compatible chat completions, also served by Together,
+    /// OpenRouter, LM Studio, vLLM and others.
+    #[default]
+    OpenAI,
+    /// Anthropic's Messages API.
+    Anthropic,
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Provider::OpenAI => "openai",
+            Provider::Anthropic => "anthropic",
+        })
+    }
+}
+
+/// Parses `openai` or `anthropic`, ignoring case, as used by `LLM_PROVIDER`
+/// and `--provider`.
+impl FromStr for Provider {
+    type Err = DSRSError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "openai" => Ok(Provider::OpenAI),
+            "anthropic" => Ok(Provider::Anthropic),
+            _ => Err(DSRSError::ConfigError(format!(
+                "Unknown provider '{name}': expected openai or anthropic"
+            ))),
+        }
+    }
+}
+
+impl Provider {
+    /// The provider's own endpoint, used when none is configured.
+    pub fn default_endpoint(self) -> &'static str {
+        match self {
+            Provider::OpenAI => OPENAI_ENDPOINT,
+            Provider::Anthropic => ANTHROPIC_ENDPOINT,
+        }
+    }
+
+    /// The header this provider expects the API key in.
+    pub(crate) fn auth_header(self) -> &'static str {
+        match self {
+            Provider::OpenAI => "Authorization",
+            Provider::Anthropic => "x-api-key",
+        }
+    }
+
+    /// This provider's request headers; the authentication ones only with
+    /// an `api_key`.
+    pub(crate) fn headers(self, api_key: Option<&str>) -> Vec<Header> {
+        let header = |name: &str, value: String| (name.to_string(), value);
+        match (self, api_key) {
+            (Provider::OpenAI, Some(key)) => {
+                vec![header(self.auth_header(), format!("Bearer {key}"))]
+            }
+            (Provider::OpenAI, None) => Vec::new(),
+            (Provider::Anthropic, key) => key
+                .map(|key| header(self.auth_header(), key.to_string()))
+                .into_iter()
+                .chain([header("anthropic-version", ANTHROPIC_VERSION.to_string())])
+                .collect(),
+        }
+    }
+
+    /// The JSON body for `request` in this provider's format.
+    pub(crate) fn request_body(self, request: &ChatRequest) -> Result<serde_json::Value> {
+        match self {
+            Provider::OpenAI => Ok(serde_json::to_value(request)?),
+            Provider::Anthropic => Ok(serde_json::to_value(AnthropicRequest::new(request)?)?),
+        }
+    }
+
+    /// Reads the body of a successful response into its replies, in choice
+    /// order. Fails with [`DSRSError::ApiError`] if there are none.
+    pub(crate) fn read_replies(self, body: &[u8]) -> Result<Vec<Reply>> {
+        match self {
+            Provider::OpenAI => openai_replies(
+                serde_json::from_slice(body).map_err(|err| DSRSError::undecodable(err, body))?,
+            ),
+            Provider::Anthropic => Ok(vec![anthropic_reply(
+                serde_json::from_slice(body).map_err(|err| DSRSError::undecodable(err, body))?,
+            )]),
+        }
+    }
+}
+
+fn openai_replies(response: ChatResponse) -> Result<Vec<Reply>> {
+    // Check for embedded error in JSON
+    if let Some(err) = response.error {
+        return Err(DSRSError::ApiError(err.to_string()));
+    }
+
+    let mut choices = response.choices;
+    if choices.is_empty() {
+        return Err(DSRSError::ApiError(
+            "No response choices returned".to_string(),
+        ));
+    }
+    choices.sort_by_key(|choice| choice.index);
+    Ok(choices
+        .into_iter()
+        .map(|choice| Reply {
+            message: choice.message,
+            finish_reason: choice.finish_reason,
+            model: response.model.clone(),
+            usage: response.usage,
+        })
+        .collect())
+}
+
+/// A Messages API request. System messages move to the top-level `system`
+/// field, since Anthropic doesn't accept them in `messages`.
+#[derive(Serialize)]
+struct AnthropicRequest<'a> {
+    model: &'a str,
+    max_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<&'a Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    stop_sequences: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream: Option<bool>,
+}
+
+impl<'a> AnthropicRequest<'a> {
+    fn new(request: &'a ChatRequest) -> Result<Self> {
+        let unsupported = [
+            (
+                "audio output",
+                request.modalities.is_some() || request.audio.is_some(),
+            ),
+            ("response_format", request.response_format.is_some()),
+            ("n", request.n.is_some_and(|n| n > 1)),
+            (
+                "frequency or presence penalties",
+                request.frequency_penalty.is_some() || request.presence_penalty.is_some(),
+            ),
+            (
+                "tools",
+                request.tools.is_some()
+                    || request.tool_choice.is_some()
+                    || request.messages.iter().any(|message| {
+                        message.tool_calls.is_some() || message.tool_call_id.is_some()
+                    }),
+            ),
+        ];
+        if let Some((feature, _)) = unsupported.iter().find(|(_, used)| *used) {
+            return Err(DSRSError::ConfigError(format!(
+                "The anthropic provider doesn't support {feature}"
+            )));
+        }
+
+        let (system, messages): (Vec<_>, Vec<_>) = request
+            .messages
+            .iter()
+            .partition(|message| message.role == "system");
+        let system = (!system.is_empty()).then(|| {
+            system
+                .iter()
+                .map(|message| message.content.as_str())
+                .collect::<Vec<_>>()
+                .join("\n\n")
+        });
+        Ok(Self {
+            model: &request.model,
+            max_tokens: request.max_tokens.unwrap_or(ANTHROPIC_DEFAULT_MAX_TOKENS),
+            system,
+            messages,
+            temperature: request.temperature,
+            top_p: request.top_p,
+            stop_sequences: request.stop.as_deref().unwrap_or_default(),
+            stream: request.stream,
+        })
+    }
+}
+
+/// A Messages API response.
+#[derive(Deserialize)]
+struct AnthropicResponse {
+    #[serde(default)]
+    content: Vec<AnthropicBlock>,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    stop_reason: Option<String>,
+    #[serde(default)]
+    usage: Option<AnthropicUsage>,
+}
+
+/// One content block; only `text` blocks carry completion text.
+#[derive(Deserialize)]
+struct AnthropicBlock {
+    #[serde(rename = "type")]
+    block_type: String,
+    #[serde(default)]
+    text: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AnthropicUsage {
+    #[serde(default)]
+    input_tokens: u32,
+    #[serde(default)]
+    output_tokens: u32,
+}
+
+/// Joins the text blocks and maps the stop reason and usage onto their
+/// OpenAI equivalents, so `finish_reason == "length"` means truncated for
+/// both providers.
+fn anthropic_reply(response: AnthropicResponse) -> Reply {
+    let text: String = response
+        .content
+        .into_iter()
+        .filter(|block| block.block_type == "text")
+        .filter_map(|block| block.text)
+        .collect();
+    let finish_reason = response.stop_reason.map(|reason| match reason.as_str() {
+        "max_tokens" => "length".to_string(),
+        "end_turn" | "stop_sequence" => "stop".to_string(),
+        _ => reason,
+    });
+    Reply {
+        message: MessageResponse {
+            content: Some(MessageContent::Text(text)),
+            audio: None,
+            tool_calls: None,
+        },
+        finish_reason,
+        model: response.model,
+        usage: response.usage.map(|usage| Usage {
+            prompt_tokens: usage.input_tokens,
+            completion_tokens: usage.output_tokens,
+            total_tokens: usage.input_tokens + usage.output_tokens,
+        }),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request() -> ChatRequest {
+        ChatRequest {
+            model: "model".to_string(),
+            messages: vec![
+                Message::system("Be brief."),
+                Message::user("Capital of France?"),
+                Message::assistant("Paris."),
+                Message::user("And Germany?"),
+            ],
+            max_tokens: None,
+            temperature: Some(0.5),
+            stream: None,
+            modalities: None,
+            audio: None,
+            response_format: None,
+            n: None,
+            tools: None,
+            tool_choice: None,
+            top_p: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            stop: None,
+        }
+    }
+
+    #[test]
+    fn test_openai_request_shape() {
+        let body = Provider::OpenAI.request_body(&request()).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "model": "model",
+                "messages": [
+                    {"role": "system", "content": "Be brief."},
+                    {"role": "user", "content": "Capital of France?"},
+                    {"role": "assistant", "content": "Paris."},
+                    {"role": "user", "content": "And Germany?"}
+                ],
+                "temperature": 0.5
+            })
+        );
+    }
+
+    #[test]
+    fn test_anthropic_request_shape() {
+        let body = Provider::Anthropic.request_body(&request()).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "model": "model",
+                "max_tokens": 1024,
+                "system": "Be brief.",
+                "messages": [
+                    {"role": "user", "content": "Capital of France?"},
+                    {"role": "assistant", "content": "Paris."},
+                    {"role": "user", "content": "And Germany?"}
+                ],
+                "temperature": 0.5
+            })
+        );
+
+        let mut json_mode = request();
+        json_mode.response_format = Some(crate::client::ResponseFormat::JsonObject);
+        assert!(matches!(
+            Provider::Anthropic.request_body(&json_mode),
+            Err(DSRSError::ConfigError(_))
+        ));
+
+        let mut sampled = request();
+        sampled.top_p = Some(0.9);
+        sampled.stop = Some(vec!["END".to_string()]);
+        let body = Provider::Anthropic.request_body(&sampled).unwrap();
+        assert_eq!(body["top_p"], json!(0.9f32));
+        assert_eq!(body["stop_sequences"], json!(["END"]));
+        sampled.presence_penalty = Some(0.5);
+        assert!(matches!(
+            Provider::Anthropic.request_body(&sampled),
+            Err(DSRSError::ConfigError(message)) if message.ends_with("presence penalties")
+        ));
+
+        let mut tool_result = request();
+        tool_result.messages.push(Message::tool("call_1", "21°C"));
+        assert!(matches!(
+            Provider::Anthropic.request_body(&tool_result),
+            Err(DSRSError::ConfigError(message)) if message.ends_with("support tools")
+        ));
+    }
+
+    #[test]
+    fn test_openai_response_parsing() {
+        let response = serde_json::from_value(json!({
+            "model": "gpt-4o-2024-08-06",
+            "choices": [{
+                "message": {"role": "assistant", "content": "Berlin."},
+                "finish_reason": "stop"
+            }],
+            "usage": {"prompt_tokens": 20, "completion_tokens": 2, "total_tokens": 22}
+        }))
+        .unwrap();
+        let replies = openai_replies(response).unwrap();
+        let reply = &replies[0];
+        assert_eq!(reply.message.text().as_deref(), Some("Berlin."));
+        assert_eq!(reply.finish_reason.as_deref(), Some("stop"));
+        assert_eq!(reply.usage.map(|usage| usage.total_tokens), Some(22));
+
+        let empty = serde_json::from_value(json!({"choices": []})).unwrap();
+        assert!(matches!(openai_replies(empty), Err(DSRSError::ApiError(_))));
+
+        // A 200 carrying only an error still reports it
+        let error = Provider::OpenAI.read_replies(br#"{"error": {"message": "model overloaded"}}"#);
+        assert!(
+            matches!(&error, Err(DSRSError::ApiError(message)) if message == "model overloaded")
+        );
+
+        // Several choices come back in index order
+        let several = serde_json::from_value(json!({
+            "choices": [
+                {"index": 1, "message": {"role": "assistant", "content": "B"}},
+                {"index": 0, "message": {"role": "assistant", "content": "A"}}
+            ]
+        }))
+        .unwrap();
+        let texts: Vec<Option<String>> = openai_replies(several)
+            .unwrap()
+            .iter()
+            .map(|reply| reply.message.text())
+            .collect();
+        assert_eq!(texts, [Some("A".to_string()), Some("B".to_string())]);
+    }
+
+    #[test]
+    fn test_anthropic_response_parsing() {
+        let response = serde_json::from_value(json!({
+            "id": "msg_01",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-sonnet-4-20250514",
+            "content": [
+                {"type": "text", "text": "Berlin"},
+                {"type": "tool_use", "id": "t", "name": "n", "input": {}},
+                {"type": "text", "text": "."}
+            ],
+            "stop_reason": "max_tokens",
+            "usage": {"input_tokens": 20, "output_tokens": 2}
+        }))
+        .unwrap();
+        let reply = anthropic_reply(response);
+        assert_eq!(reply.message.text().as_deref(), Some("Berlin."));
+        assert_eq!(reply.finish_reason.as_deref(), Some("length"));
+        assert_eq!(reply.model.as_deref(), Some("claude-sonnet-4-20250514"));
+        assert_eq!(
+            reply.usage,
+            Some(Usage {
+                prompt_tokens: 20,
+                completion_tokens: 2,
+                total_tokens: 22
+            })
+        );
+    }
+
+    #[test]
+    fn test_headers_without_key() {
+        assert!(Provider::OpenAI.headers(None).is_empty());
+        assert_eq!(
+            Provider::Anthropic.headers(None),
+            [(
+                "anthropic-version".to_string(),
+                ANTHROPIC_VERSION.to_string()
+            )]
+        );
+        assert_eq!(Provider::Anthropic.headers(Some("key")).len(), 2);
+    }
+
+    #[test]
+    fn test_provider_names() {
+        assert_eq!(
+            "Anthropic".parse::<Provider>().unwrap(),
+            Provider::Anthropic
+        );
+        assert_eq!(" openai ".parse::<Provider>().unwrap(), Provider::OpenAI);
+        assert!(matches!(
+            "gemini".parse::<Provider>(),
+            Err(DSRSError::ConfigError(_))
+        ));
+        assert_eq!(Provider::Anthropic.to_string(), "anthropic");
+    }
+}
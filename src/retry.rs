@@ -0,0 +1,320 @@
+//! Retry policy for transient request failures.
+
+use crate::errors::{DSRSError, Result};
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_BASE_DELAY_MS: u64 = 500;
+const DEFAULT_MAX_DELAY_SECS: u64 = 30;
+
+/// Called before each retry sleep with the 1-based number of the attempt
+/// that just failed, its error, and the delay before the next attempt.
+pub type RetryCallback = Arc<dyn Fn(u32, &DSRSError, Duration) + Send + Sync>;
+
+/// How a client retries requests that fail with a transient error.
+///
+/// Errors for which [`DSRSError::is_retryable`] holds (connection failures,
+/// timeouts, rate limits and HTTP 500/502/503/504) are retried; anything
+/// else (e.g. 400, 401, 404, an over-long prompt) fails immediately.
+///
+/// Delays grow exponentially from `base_delay`, are capped at `max_delay`,
+/// and are randomized to between half and all of that so clients that failed
+/// together don't retry in lockstep. A `Retry-After` header overrides the
+/// backoff, still capped at `max_delay`.
+///
+/// If the last allowed attempt fails, the error is
+/// [`DSRSError::RetriesExhausted`] wrapping that attempt's error. The default
+/// policy retries up to 3 times; [`RetryPolicy::none`] disables retrying and
+/// returns errors unchanged.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_retries: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: bool,
+    on_retry: Option<RetryCallback>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_RETRIES)
+    }
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_retries", &self.max_retries)
+            .field("base_delay", &self.base_delay)
+            .field("max_delay", &self.max_delay)
+            .field("jitter", &self.jitter)
+            .field("on_retry", &self.on_retry.is_some())
+            .finish()
+    }
+}
+
+impl RetryPolicy {
+    /// Retries up to `max_retries` times after the first attempt.
+    pub fn new(max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            base_delay: Duration::from_millis(DEFAULT_BASE_DELAY_MS),
+            max_delay: Duration::from_secs(DEFAULT_MAX_DELAY_SECS),
+            jitter: true,
+            on_retry: None,
+        }
+    }
+
+    /// Never retries.
+    pub fn none() -> Self {
+        Self::new(0)
+    }
+
+    /// Changes the number of retries, keeping the other settings.
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Sets the delay before the first retry.
+    pub fn base_delay(mut self, delay: Duration) -> Self {
+        self.base_delay = delay;
+        self
+    }
+
+    /// Sets the upper bound on any single delay.
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// Turns delay randomization on or off. On by default.
+    pub fn jitter(mut self, enabled: bool) -> Self {
+        self.jitter = enabled;
+        self
+    }
+
+    /// Observes each retry, e.g. for logging why a call is slow.
+    pub fn on_retry(
+        mut self,
+        callback: impl Fn(u32, &DSRSError, Duration) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_retry = Some(Arc::new(callback));
+        self
+    }
+
+    /// The number of retries allowed after the first attempt.
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries
+    }
+
+    /// The backoff after the given 1-based failed attempt, before jitter.
+    fn delay_for(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.base_delay.saturating_mul(factor).min(self.max_delay)
+    }
+
+    /// How long to wait after `attempt` failed with `err`.
+    fn delay_after(&self, attempt: u32, err: &DSRSError) -> Duration {
+        if let DSRSError::HttpError {
+            retry_after: Some(delay),
+            ..
+        }
+        | DSRSError::RateLimited {
+            retry_after: Some(delay),
+            ..
+        } = err
+        {
+            return (*delay).min(self.max_delay);
+        }
+        let delay = self.delay_for(attempt);
+        if self.jitter {
+            let half = delay / 2;
+            half + half.mul_f64(random_unit())
+        } else {
+            delay
+        }
+    }
+
+    /// Runs `operation`, retrying transient failures per the policy.
+    pub(crate) async fn run<T, F, Fut>(&self, mut operation: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match operation().await {
+                Err(err) if attempt <= self.max_retries && err.is_retryable() => {
+                    let delay = self.delay_after(attempt, &err);
+                    // The error itself is logged, redacted, where it arose
+                    tracing::debug!(
+                        attempt,
+                        delay_ms = u64::try_from(delay.as_millis()).unwrap_or(u64::MAX),
+                        "retrying after a transient failure"
+                    );
+                    if let Some(callback) = &self.on_retry {
+                        callback(attempt, &err, delay);
+                    }
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(err) if attempt > 1 && err.is_retryable() => {
+                    tracing::debug!(attempts = attempt, "giving up after retrying");
+                    return Err(DSRSError::RetriesExhausted {
+                        attempts: attempt,
+                        last: Box::new(err),
+                    });
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// A random number in `[0, 1)`, from the standard library's per-instance
+/// random hash keys (good enough for jitter without a `rand` dependency).
+fn random_unit() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn fast(max_retries: u32) -> RetryPolicy {
+        RetryPolicy::new(max_retries)
+            .base_delay(Duration::from_millis(1))
+            .jitter(false)
+    }
+
+    /// A retryable failure whose message names the `attempt`.
+    fn unavailable(attempt: u32) -> DSRSError {
+        DSRSError::HttpError {
+            status: 503,
+            message: format!("HTTP 503 (attempt {attempt})"),
+            retry_after: None,
+        }
+    }
+
+    #[test]
+    fn test_delays_grow_exponentially_and_cap() {
+        let policy = RetryPolicy::new(5)
+            .base_delay(Duration::from_millis(100))
+            .max_delay(Duration::from_millis(350));
+        let delays: Vec<u128> = (1..=4).map(|n| policy.delay_for(n).as_millis()).collect();
+        assert_eq!(delays, [100, 200, 350, 350]);
+    }
+
+    #[test]
+    fn test_default_retries_three_times() {
+        assert_eq!(RetryPolicy::default().max_retries(), 3);
+        assert_eq!(RetryPolicy::default().with_max_retries(0).max_retries(), 0);
+        assert_eq!(RetryPolicy::none().max_retries(), 0);
+    }
+
+    #[test]
+    fn test_jitter_stays_within_half_to_full_delay() {
+        let policy = RetryPolicy::new(3).base_delay(Duration::from_millis(100));
+        let err = DSRSError::Timeout(None);
+        for _ in 0..50 {
+            let delay = policy.delay_after(1, &err);
+            assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn test_rate_limit_wait_overrides_backoff() {
+        let policy = fast(3).max_delay(Duration::from_secs(10));
+        let limited = |retry_after| DSRSError::RateLimited {
+            message: "HTTP 429 Too Many Requests".to_string(),
+            retry_after,
+        };
+        assert!(limited(None).is_retryable());
+        assert_eq!(
+            policy.delay_after(1, &limited(Some(Duration::from_secs(4)))),
+            Duration::from_secs(4)
+        );
+        assert_eq!(
+            policy.delay_after(1, &limited(Some(Duration::from_secs(60)))),
+            Duration::from_secs(10)
+        );
+        assert_eq!(
+            policy.delay_after(2, &limited(None)),
+            Duration::from_millis(2)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_on_retry_fires_per_attempt() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorder = Arc::clone(&seen);
+        let policy = fast(3).on_retry(move |attempt, err, delay| {
+            recorder
+                .lock()
+                .unwrap()
+                .push((attempt, err.to_string(), delay));
+        });
+
+        let mut calls = 0;
+        let result = policy
+            .run(|| {
+                calls += 1;
+                let outcome = if calls < 3 {
+                    Err(unavailable(calls))
+                } else {
+                    Ok(calls)
+                };
+                async move { outcome }
+            })
+            .await;
+
+        assert_eq!(result.unwrap(), 3);
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 2);
+        assert_eq!(seen[0].0, 1);
+        assert_eq!(seen[0].1, "API error: HTTP 503 (attempt 1)");
+        assert_eq!(seen[1].0, 2);
+        assert_eq!(seen[1].2, Duration::from_millis(2));
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_retries() {
+        let mut calls = 0;
+        let result: Result<(), _> = fast(2)
+            .run(|| {
+                calls += 1;
+                async { Err(DSRSError::Timeout(None)) }
+            })
+            .await;
+        match result {
+            Err(DSRSError::RetriesExhausted { attempts, last }) => {
+                assert_eq!(attempts, 3);
+                assert!(matches!(*last, DSRSError::Timeout(None)));
+            }
+            other => panic!("expected RetriesExhausted, got {other:?}"),
+        }
+        assert_eq!(calls, 3);
+    }
+
+    #[tokio::test]
+    async fn test_non_transient_errors_fail_immediately() {
+        let mut calls = 0;
+        let result: Result<(), _> = fast(3)
+            .run(|| {
+                calls += 1;
+                async { Err(DSRSError::PromptTooLong(10, 5)) }
+            })
+            .await;
+        assert!(matches!(result, Err(DSRSError::PromptTooLong(10, 5))));
+        assert_eq!(calls, 1);
+    }
+}
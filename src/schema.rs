@@ -0,0 +1,171 @@
+//! Minimal JSON Schema validation for structured outputs.
+//!
+//! This checks the subset of JSON Schema that strict structured outputs
+//! actually use: `type` (a single type or a list), `properties`, `required`,
+//! `additionalProperties: false`, `items`, `enum` and `const`. Anything else
+//! (`$ref`, `anyOf`, formats, numeric bounds, ...) is accepted without being
+//! checked, so a passing result means "no violations of the supported
+//! keywords", not full conformance.
+
+use serde_json::Value;
+
+/// The messages describing each way a value breaks its schema.
+pub type Violations = Vec<String>;
+
+/// Validates `value` against `schema`, returning every violation found.
+///
+/// Each violation is prefixed with the JSON pointer of the offending value
+/// (`/` for the root), e.g. `/items/0/name: expected string, got number`.
+pub fn validate(schema: &Value, value: &Value) -> Result<(), Violations> {
+    let mut errors = Vec::new();
+    validate_at(schema, value, "", &mut errors);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn validate_at(schema: &Value, value: &Value, path: &str, errors: &mut Vec<String>) {
+    let Some(schema) = schema.as_object() else {
+        // `true`/`{}`-style schemas accept anything
+        return;
+    };
+    let at = if path.is_empty() { "/" } else { path };
+
+    if let Some(expected) = schema.get("type") {
+        let allowed: Vec<&str> = match expected {
+            Value::String(name) => vec![name.as_str()],
+            Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !allowed.is_empty() && !allowed.iter().any(|name| matches_type(name, value)) {
+            errors.push(format!(
+                "{at}: expected {}, got {}",
+                allowed.join(" or "),
+                type_name(value)
+            ));
+            return;
+        }
+    }
+
+    if let Some(Value::Array(options)) = schema.get("enum")
+        && !options.contains(value)
+    {
+        errors.push(format!("{at}: {value} is not one of the allowed values"));
+    }
+    if let Some(constant) = schema.get("const")
+        && constant != value
+    {
+        errors.push(format!("{at}: expected {constant}, got {value}"));
+    }
+
+    match value {
+        Value::Object(object) => {
+            let properties = schema.get("properties").and_then(Value::as_object);
+            if let Some(Value::Array(required)) = schema.get("required") {
+                for key in required.iter().filter_map(Value::as_str) {
+                    if !object.contains_key(key) {
+                        errors.push(format!("{at}: missing required property \"{key}\""));
+                    }
+                }
+            }
+            for (key, field) in object {
+                let field_path = format!("{path}/{key}");
+                match properties.and_then(|properties| properties.get(key)) {
+                    Some(field_schema) => validate_at(field_schema, field, &field_path, errors),
+                    None if schema.get("additionalProperties") == Some(&Value::Bool(false)) => {
+                        errors.push(format!("{field_path}: unexpected property"));
+                    }
+                    None => {}
+                }
+            }
+        }
+        Value::Array(items) => {
+            if let Some(item_schema) = schema.get("items") {
+                for (index, item) in items.iter().enumerate() {
+                    validate_at(item_schema, item, &format!("{path}/{index}"), errors);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn matches_type(name: &str, value: &Value) -> bool {
+    match name {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        // Unknown type names can't be checked, so don't reject on them
+        _ => true,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn person_schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+                "age": {"type": "integer"},
+                "tags": {"type": "array", "items": {"type": "string"}},
+                "role": {"enum": ["admin", "user"]}
+            },
+            "required": ["name", "age"],
+            "additionalProperties": false
+        })
+    }
+
+    #[test]
+    fn test_valid_value_passes() {
+        let value = json!({"name": "Ada", "age": 36, "tags": ["math"], "role": "admin"});
+        assert_eq!(validate(&person_schema(), &value), Ok(()));
+    }
+
+    #[test]
+    fn test_violations_are_reported_with_paths() {
+        let value = json!({"name": 1, "tags": ["ok", 2], "role": "root", "extra": true});
+        let errors = validate(&person_schema(), &value).unwrap_err();
+        assert_eq!(
+            errors,
+            [
+                "/: missing required property \"age\"".to_string(),
+                "/extra: unexpected property".to_string(),
+                "/name: expected string, got number".to_string(),
+                "/role: \"root\" is not one of the allowed values".to_string(),
+                "/tags/1: expected string, got number".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_type_lists_and_root_mismatch() {
+        let schema = json!({"type": ["string", "null"]});
+        assert!(validate(&schema, &json!(null)).is_ok());
+        assert_eq!(
+            validate(&schema, &json!(3)),
+            Err(vec!["/: expected string or null, got number".to_string()])
+        );
+    }
+}
@@ -1,18 +1,689 @@
-// use serde::{Deserialize, Serialize};
-// use std::error::Error as StdError;
-
-// // Field metadata for input/output fields (like dspy.InputField/OutputField)
-// #[derive(Clone, Debug)]
-// pub struct FieldMeta {
-//     pub desc: Option<&'static str>,
-//     pub constraints: Option<Vec<&'static str>>, // For Literal-like enums
-// }
-
-// // Trait for DSPy-style signatures
-// pub trait DSPySignature {
-//     type Input: Serialize;  // Input data structure
-//     type Output: for<'de> Deserialize<'de> + Clone;  // Output for parsing from LM
-
-//     fn generate_prompt(&self, input: &Self::Input) -> String;
-//     fn parse_output(&self, response: &str) -> Result<Self::Output, Box<dyn StdError>>;
-// }
+use crate::errors::{DSRSError, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::sync::Arc;
+
+/// What [`DSPySignature::parse_output`] returns.
+pub type ParseResult<T> = Result<T, Box<dyn StdError>>;
+
+// Field metadata for input/output fields (like dspy.InputField/OutputField)
+#[derive(Clone, Debug)]
+pub struct FieldMeta {
+    pub desc: Option<&'static str>,
+    #[allow(
+        clippy::type_complexity,
+        reason = "a list of string literals; the lint scores each `&'static` as nesting"
+    )]
+    pub constraints: Option<Vec<&'static str>>, // For Literal-like enums
+}
+
+// Trait for DSPy-style signatures
+pub trait DSPySignature {
+    type Input: Serialize; // Input data structure
+    type Output: Serialize + for<'de> Deserialize<'de> + Clone; // Output for parsing from LM
+
+    fn generate_prompt(&self, input: &Self::Input) -> String;
+    fn parse_output(&self, response: &str) -> ParseResult<Self::Output>;
+
+    /// Formats a demonstration: `input` and the `output` wanted for it, laid
+    /// out like the prompt's own fields. The default writes every field of
+    /// both as a `Label: value` line, in JSON key order.
+    fn format_demo(&self, input: &Self::Input, output: &Self::Output) -> String {
+        let fields = |value: &Value| match value {
+            Value::Object(fields) => fields
+                .iter()
+                .filter_map(|(name, value)| __private::labeled_line(name, value))
+                .collect(),
+            _ => Vec::new(),
+        };
+        let mut lines = fields(&__private::to_value(input));
+        lines.extend(fields(&__private::to_value(output)));
+        lines.join("\n")
+    }
+
+    /// The prompt for `input` with formatted demonstrations, oldest first.
+    /// The default puts them above the prompt from
+    /// [`generate_prompt`](DSPySignature::generate_prompt).
+    fn generate_prompt_with_demos(&self, input: &Self::Input, demos: &[String]) -> String {
+        let prompt = self.generate_prompt(input);
+        if demos.is_empty() {
+            return prompt;
+        }
+        format!("{}\n\n{prompt}", demos.join("\n\n"))
+    }
+}
+
+/// A signature with its input and output types erased to JSON, so signatures
+/// of different types can be looked up by name (e.g. from the CLI).
+pub trait DynSignature: Send + Sync {
+    /// Builds the prompt from a JSON object of input fields.
+    fn generate_prompt(&self, input: Value) -> Result<String>;
+    /// Parses a response into a JSON value of the output type.
+    fn parse_output(&self, response: &str) -> Result<Value>;
+}
+
+impl<S> DynSignature for S
+where
+    S: DSPySignature + Send + Sync,
+    S::Input: DeserializeOwned,
+    S::Output: Serialize,
+{
+    fn generate_prompt(&self, input: Value) -> Result<String> {
+        let input: S::Input = serde_json::from_value(input)
+            .map_err(|err| DSRSError::ConfigError(format!("Invalid signature input: {err}")))?;
+        Ok(DSPySignature::generate_prompt(self, &input))
+    }
+
+    fn parse_output(&self, response: &str) -> Result<Value> {
+        let output =
+            DSPySignature::parse_output(self, response).map_err(|err| DSRSError::ParseError {
+                message: err.to_string(),
+                raw: response.to_string(),
+            })?;
+        Ok(serde_json::to_value(output)?)
+    }
+}
+
+/// A registered signature, shared with the registry's clones.
+type SharedSignature = Arc<dyn DynSignature>;
+
+/// Signatures registered by name.
+#[derive(Clone, Default)]
+pub struct SignatureRegistry {
+    signatures: BTreeMap<String, SharedSignature>,
+}
+
+impl SignatureRegistry {
+    /// A registry holding the signatures that ship with dsrs: `qa`
+    /// ([`QuestionAnswer`]), `summarize` ([`Summarize`]) and `sentiment`
+    /// ([`Sentiment`]).
+    pub fn builtin() -> Self {
+        let mut registry = Self::default();
+        registry.register("qa", QuestionAnswer);
+        registry.register("summarize", Summarize);
+        registry.register("sentiment", Sentiment);
+        registry
+    }
+
+    /// Registers `signature` under `name`, replacing any previous entry.
+    pub fn register<S>(&mut self, name: impl Into<String>, signature: S)
+    where
+        S: DynSignature + 'static,
+    {
+        self.signatures.insert(name.into(), Arc::new(signature));
+    }
+
+    /// Looks up a signature by name.
+    pub fn get(&self, name: &str) -> Option<&dyn DynSignature> {
+        self.signatures
+            .get(name)
+            .map(|signature| signature.as_ref())
+    }
+
+    /// The registered names, in sorted order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.signatures.keys().map(String::as_str)
+    }
+}
+
+/// Declares a signature from field specs: a unit struct implementing
+/// [`DSPySignature`], plus its input and output structs.
+///
+/// ```
+/// dsrs::signature! {
+///     /// Translates text to French.
+///     pub Translate(TranslateInput, TranslateOutput), "Translate the text to French.",
+///     inputs: { text: String => "English text" },
+///     outputs: {
+///         translation: String => "the French translation",
+///         notes: Option<String> => "anything lost in translation",
+///     },
+/// }
+/// ```
+///
+/// The prompt lists the instruction and every field's description, then the
+/// input values as `Label: value` lines (labels are the field names with
+/// underscores as spaces, capitalized), and ends with the first output
+/// label. `parse_output` reads each output from its `Label:` line up to the
+/// next label; text before any label is taken as the first output, like the
+/// model's continuation of the prompt. `Option` fields may be missing, and
+/// non-string fields are parsed as JSON (e.g. `0.9` or `["a", "b"]`).
+///
+/// The structs derive `serde`'s traits, so the calling crate needs a
+/// `serde` dependency with the `derive` feature. [`Signature`](crate::Signature)
+/// derives the same from the fields of a struct.
+#[macro_export]
+macro_rules! signature {
+    (
+        $(#[$attr:meta])*
+        $vis:vis $name:ident($input:ident, $output:ident), $instruction:literal,
+        inputs: { $($in_field:ident: $in_ty:ty => $in_desc:literal),+ $(,)? },
+        outputs: { $($out_field:ident: $out_ty:ty => $out_desc:literal),+ $(,)? } $(,)?
+    ) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, Debug, Default)]
+        $vis struct $name;
+
+        #[doc = concat!("Input of [`", stringify!($name), "`].")]
+        #[derive(Clone, Debug, PartialEq, ::serde::Serialize, ::serde::Deserialize)]
+        $vis struct $input {
+            $(#[doc = $in_desc] pub $in_field: $in_ty,)+
+        }
+
+        #[doc = concat!("Output of [`", stringify!($name), "`].")]
+        #[derive(Clone, Debug, PartialEq, ::serde::Serialize, ::serde::Deserialize)]
+        $vis struct $output {
+            $(#[doc = $out_desc] pub $out_field: $out_ty,)+
+        }
+
+        impl $name {
+            /// The task instruction at the top of the prompt.
+            pub const INSTRUCTION: &'static str = $instruction;
+
+            /// The input fields by name, in prompt order.
+            pub fn input_fields() -> ::std::vec::Vec<(&'static str, $crate::signatures::FieldMeta)> {
+                ::std::vec![$((stringify!($in_field), $crate::signatures::FieldMeta {
+                    desc: Some($in_desc),
+                    constraints: None,
+                })),+]
+            }
+
+            /// The output fields by name, in prompt order.
+            pub fn output_fields() -> ::std::vec::Vec<(&'static str, $crate::signatures::FieldMeta)> {
+                ::std::vec![$((stringify!($out_field), $crate::signatures::FieldMeta {
+                    desc: Some($out_desc),
+                    constraints: None,
+                })),+]
+            }
+        }
+
+        impl $crate::signatures::DSPySignature for $name {
+            type Input = $input;
+            type Output = $output;
+
+            fn generate_prompt(&self, input: &Self::Input) -> ::std::string::String {
+                self.generate_prompt_with_demos(input, &[])
+            }
+
+            fn format_demo(&self, input: &Self::Input, output: &Self::Output) -> ::std::string::String {
+                use $crate::signatures::__private as private;
+                private::format_demo(
+                    &[$(stringify!($in_field)),+],
+                    &private::to_value(input),
+                    &[$(stringify!($out_field)),+],
+                    &private::to_value(output),
+                )
+            }
+
+            fn generate_prompt_with_demos(
+                &self,
+                input: &Self::Input,
+                demos: &[::std::string::String],
+            ) -> ::std::string::String {
+                use $crate::signatures::__private as private;
+                private::render_prompt(
+                    $instruction,
+                    &[$(private::FieldSpec {
+                        name: stringify!($in_field),
+                        desc: $in_desc,
+                        optional: private::is_optional::<$in_ty>(),
+                    }),+],
+                    &[$(private::FieldSpec {
+                        name: stringify!($out_field),
+                        desc: $out_desc,
+                        optional: private::is_optional::<$out_ty>(),
+                    }),+],
+                    demos,
+                    &private::to_value(input),
+                )
+            }
+
+            fn parse_output(
+                &self,
+                response: &str,
+            ) -> ::std::result::Result<Self::Output, ::std::boxed::Box<dyn ::std::error::Error>> {
+                use $crate::signatures::__private as private;
+                let fields = private::split_fields(response, &[$(stringify!($out_field)),+]);
+                Ok($output {
+                    $($out_field: private::field(&fields, stringify!($out_field))?,)+
+                })
+            }
+        }
+    };
+}
+
+/// Support code for [`signature!`](crate::signature); not a stable API.
+#[doc(hidden)]
+pub mod __private {
+    use serde::Serialize;
+    use serde::de::DeserializeOwned;
+    use serde_json::Value;
+    use std::collections::BTreeMap;
+
+    /// A field as the generated code describes it.
+    pub struct FieldSpec {
+        pub name: &'static str,
+        pub desc: &'static str,
+        pub optional: bool,
+    }
+
+    /// Whether `T` may be missing from a response, i.e. accepts `null`.
+    pub fn is_optional<T: DeserializeOwned>() -> bool {
+        serde_json::from_value::<T>(Value::Null).is_ok()
+    }
+
+    pub fn to_value<T: Serialize>(input: &T) -> Value {
+        serde_json::to_value(input).unwrap_or(Value::Null)
+    }
+
+    /// `key_points` → `Key points`.
+    pub fn field_label(name: &str) -> String {
+        let spaced = name.replace('_', " ");
+        let mut chars = spaced.chars();
+        match chars.next() {
+            Some(first) => first.to_uppercase().chain(chars).collect(),
+            None => spaced,
+        }
+    }
+
+    /// `Label: value` for a field, or `None` for a `null` one. Strings are
+    /// written as is, anything else as JSON.
+    pub fn labeled_line(name: &str, value: &Value) -> Option<String> {
+        match value {
+            Value::Null => None,
+            Value::String(text) => Some(format!("{}: {text}", field_label(name))),
+            other => Some(format!("{}: {other}", field_label(name))),
+        }
+    }
+
+    /// The named fields of `values`, one `Label: value` line each.
+    fn labeled_lines<'a>(
+        names: &'a [&str],
+        values: &'a Value,
+    ) -> impl Iterator<Item = String> + 'a {
+        names
+            .iter()
+            .filter_map(|name| values.get(name).and_then(|value| labeled_line(name, value)))
+    }
+
+    pub fn format_demo(
+        input_names: &[&str],
+        input: &Value,
+        output_names: &[&str],
+        output: &Value,
+    ) -> String {
+        labeled_lines(input_names, input)
+            .chain(labeled_lines(output_names, output))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn render_prompt(
+        instruction: &str,
+        inputs: &[FieldSpec],
+        outputs: &[FieldSpec],
+        demos: &[String],
+        values: &Value,
+    ) -> String {
+        let describe = |field: &FieldSpec| {
+            let optional = if field.optional { " (optional)" } else { "" };
+            format!("- {}{optional}: {}\n", field_label(field.name), field.desc)
+        };
+        let mut prompt = format!("{instruction}\n\nInput fields:\n");
+        prompt.extend(inputs.iter().map(describe));
+        prompt.push_str("Output fields:\n");
+        prompt.extend(outputs.iter().map(describe));
+        prompt.push_str("\nAnswer with each output field on its own line as \"Field: value\".\n\n");
+        for demo in demos {
+            prompt.push_str(&format!("{demo}\n\n"));
+        }
+        let names: Vec<&str> = inputs.iter().map(|field| field.name).collect();
+        for line in labeled_lines(&names, values) {
+            prompt.push_str(&format!("{line}\n"));
+        }
+        if let Some(first) = outputs.first() {
+            prompt.push_str(&format!("{}:", field_label(first.name)));
+        }
+        prompt
+    }
+
+    /// Splits `response` into the text of each labeled output field, by name.
+    pub fn split_fields(response: &str, names: &[&str]) -> BTreeMap<String, String> {
+        let labels: Vec<_> = names
+            .iter()
+            .map(|name| (*name, field_label(name).to_ascii_lowercase()))
+            .collect();
+        let mut fields: BTreeMap<String, String> = BTreeMap::new();
+        // Text before the first label continues the prompt's last line
+        let mut current = names.first().copied();
+        for line in response.lines() {
+            let trimmed = line.trim_start();
+            let lowered = trimmed.to_ascii_lowercase();
+            let labeled = labels.iter().find_map(|(name, label)| {
+                [label.as_str(), *name].into_iter().find_map(|prefix| {
+                    lowered
+                        .strip_prefix(prefix)
+                        .filter(|rest| rest.starts_with(':'))
+                        .map(|rest| (*name, trimmed.len() - rest.len() + 1))
+                })
+            });
+            let text = match labeled {
+                Some((name, value_start)) => {
+                    current = Some(name);
+                    fields.remove(name);
+                    &trimmed[value_start..]
+                }
+                None => line,
+            };
+            if let Some(name) = current {
+                let value = fields.entry(name.to_string()).or_default();
+                if !value.is_empty() {
+                    value.push('\n');
+                }
+                value.push_str(text);
+            }
+        }
+        fields
+    }
+
+    /// Reads output field `name` from `fields` into `T`: as a string first,
+    /// then as JSON.
+    pub fn field<T: DeserializeOwned>(
+        fields: &BTreeMap<String, String>,
+        name: &str,
+    ) -> Result<T, String> {
+        let text = fields.get(name).map(|text| text.trim()).unwrap_or_default();
+        if text.is_empty() {
+            return serde_json::from_value(Value::Null)
+                .map_err(|_| format!("response contains no {}", field_label(name)));
+        }
+        serde_json::from_value(Value::String(text.to_string()))
+            .or_else(|_| serde_json::from_str(text))
+            .map_err(|err| format!("invalid {}: {err}", field_label(name)))
+    }
+}
+
+signature! {
+    /// Summarizes a passage: `passage` in, `summary` and optional `keywords`
+    /// out.
+    pub Summarize(SummarizeInput, SummarizeOutput), "Summarize the passage.",
+    inputs: { passage: String => "the text to summarize" },
+    outputs: {
+        summary: String => "a one-paragraph summary",
+        keywords: Option<String> => "comma-separated keywords",
+    },
+}
+
+signature! {
+    /// Classifies the sentiment of a text: `text` in, `sentiment` and
+    /// optional `confidence` out.
+    pub Sentiment(SentimentInput, SentimentOutput), "Classify the sentiment of the text.",
+    inputs: { text: String => "the text to classify" },
+    outputs: {
+        sentiment: String => "positive, negative or neutral",
+        confidence: Option<f32> => "how sure you are, from 0 to 1",
+    },
+}
+
+/// An input field's label and value.
+type Field<'a> = (&'a str, &'a str);
+
+/// Builds a `"Label: value"` style prompt: the instruction, any
+/// demonstrations, then one line per input field and the first output label
+/// for the model to complete.
+fn labeled_prompt(instruction: &str, demos: &[String], inputs: &[Field], output: &str) -> String {
+    let mut prompt = format!("{instruction}\n\n");
+    for demo in demos {
+        prompt.push_str(&format!("{demo}\n\n"));
+    }
+    for (label, value) in inputs {
+        prompt.push_str(&format!("{label}: {value}\n"));
+    }
+    prompt.push_str(&format!("{output}:"));
+    prompt
+}
+
+/// Answers a question: `question` in, `answer` out.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QuestionAnswer;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QuestionAnswerInput {
+    pub question: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QuestionAnswerOutput {
+    pub answer: String,
+}
+
+impl DSPySignature for QuestionAnswer {
+    type Input = QuestionAnswerInput;
+    type Output = QuestionAnswerOutput;
+
+    fn generate_prompt(&self, input: &Self::Input) -> String {
+        self.generate_prompt_with_demos(input, &[])
+    }
+
+    fn generate_prompt_with_demos(&self, input: &Self::Input, demos: &[String]) -> String {
+        labeled_prompt(
+            "Answer the question concisely.",
+            demos,
+            &[("Question", &input.question)],
+            "Answer",
+        )
+    }
+
+    fn parse_output(&self, response: &str) -> Result<Self::Output, Box<dyn StdError>> {
+        let response = response.trim();
+        // Models often repeat the label they were asked to complete
+        let answer = response.strip_prefix("Answer:").unwrap_or(response).trim();
+        if answer.is_empty() {
+            return Err("response contains no answer".into());
+        }
+        Ok(QuestionAnswerOutput {
+            answer: answer.to_string(),
+        })
+    }
+}
+
+/// Builds a JSON input object from `key=value` pairs, as used by `dsrs run`.
+///
+/// Values are strings; `key:=value` parses the value as JSON instead, for
+/// numbers, booleans, lists and nested objects.
+pub fn parse_input_pairs<S: AsRef<str>>(pairs: &[S]) -> Result<Value> {
+    let mut input = Map::new();
+    for pair in pairs {
+        let pair = pair.as_ref();
+        let Some((key, value)) = pair.split_once('=') else {
+            return Err(DSRSError::ConfigError(format!(
+                "Invalid input '{pair}': expected key=value"
+            )));
+        };
+        let (key, value) = match key.strip_suffix(':') {
+            Some(key) => {
+                let value = serde_json::from_str(value).map_err(|err| {
+                    DSRSError::ConfigError(format!("Invalid JSON for input '{key}': {err}"))
+                })?;
+                (key, value)
+            }
+            None => (key, Value::String(value.to_string())),
+        };
+        if key.is_empty() {
+            return Err(DSRSError::ConfigError(format!(
+                "Invalid input '{pair}': missing key"
+            )));
+        }
+        input.insert(key.to_string(), value);
+    }
+    Ok(Value::Object(input))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_registry_runs_signatures_by_name() {
+        let registry = SignatureRegistry::builtin();
+        assert_eq!(
+            registry.names().collect::<Vec<_>>(),
+            ["qa", "sentiment", "summarize"]
+        );
+        assert!(registry.get("translate").is_none());
+
+        let qa = registry.get("qa").unwrap();
+        let prompt = qa
+            .generate_prompt(json!({"question": "Capital of France?"}))
+            .unwrap();
+        assert_eq!(
+            prompt,
+            "Answer the question concisely.\n\nQuestion: Capital of France?\nAnswer:"
+        );
+        assert_eq!(
+            qa.parse_output(" Answer: Paris\n").unwrap(),
+            json!({"answer": "Paris"})
+        );
+        assert!(matches!(
+            qa.generate_prompt(json!({"q": "?"})),
+            Err(DSRSError::ConfigError(_))
+        ));
+        assert!(matches!(
+            qa.parse_output("Answer:"),
+            Err(DSRSError::ParseError { raw, .. }) if raw == "Answer:"
+        ));
+    }
+
+    #[test]
+    fn test_question_answer_output_parsing() {
+        let parse = |response| DSPySignature::parse_output(&QuestionAnswer, response);
+        assert_eq!(parse("Paris").unwrap().answer, "Paris");
+        assert_eq!(
+            parse("Answer:  Paris, France\n").unwrap().answer,
+            "Paris, France"
+        );
+        assert!(parse("  \n").is_err());
+    }
+
+    #[test]
+    fn test_declared_signature_prompt() {
+        let input = SummarizeInput {
+            passage: "Rust is fast.\nIt is also safe.".to_string(),
+        };
+        assert_eq!(
+            DSPySignature::generate_prompt(&Summarize, &input),
+            "Summarize the passage.\n\n\
+             Input fields:\n\
+             - Passage: the text to summarize\n\
+             Output fields:\n\
+             - Summary: a one-paragraph summary\n\
+             - Keywords (optional): comma-separated keywords\n\n\
+             Answer with each output field on its own line as \"Field: value\".\n\n\
+             Passage: Rust is fast.\nIt is also safe.\n\
+             Summary:"
+        );
+        let names: Vec<&str> = Summarize::output_fields()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, ["summary", "keywords"]);
+        assert_eq!(
+            Summarize::input_fields()[0].1.desc,
+            Some("the text to summarize")
+        );
+    }
+
+    #[test]
+    fn test_declared_signature_demos() {
+        // Fields follow the declaration order, and unset optional ones are left out
+        let demo = Sentiment.format_demo(
+            &SentimentInput {
+                text: "Loved it".to_string(),
+            },
+            &SentimentOutput {
+                sentiment: "positive".to_string(),
+                confidence: Some(0.5),
+            },
+        );
+        assert_eq!(demo, "Text: Loved it\nSentiment: positive\nConfidence: 0.5");
+        let demo = Sentiment.format_demo(
+            &SentimentInput {
+                text: "Meh".to_string(),
+            },
+            &SentimentOutput {
+                sentiment: "neutral".to_string(),
+                confidence: None,
+            },
+        );
+        assert_eq!(demo, "Text: Meh\nSentiment: neutral");
+
+        let prompt = Sentiment.generate_prompt_with_demos(
+            &SentimentInput {
+                text: "Great".to_string(),
+            },
+            &[demo],
+        );
+        assert!(prompt.ends_with(
+            "\"Field: value\".\n\nText: Meh\nSentiment: neutral\n\nText: Great\nSentiment:"
+        ));
+    }
+
+    #[test]
+    fn test_declared_signature_round_trip() {
+        let parse = |response| DSPySignature::parse_output(&Summarize, response);
+        // The model continues after the prompt's "Summary:"
+        assert_eq!(
+            parse(" Rust is fast\nand safe.\nKeywords: rust, speed\n").unwrap(),
+            SummarizeOutput {
+                summary: "Rust is fast\nand safe.".to_string(),
+                keywords: Some("rust, speed".to_string()),
+            }
+        );
+        // Repeated labels, in any case, and a missing optional field
+        assert_eq!(
+            parse("SUMMARY: Rust is fast and safe.").unwrap(),
+            SummarizeOutput {
+                summary: "Rust is fast and safe.".to_string(),
+                keywords: None,
+            }
+        );
+        assert!(parse("Keywords: rust").is_err());
+
+        let parse = |response| DSPySignature::parse_output(&Sentiment, response);
+        assert_eq!(
+            parse("Sentiment: positive\nConfidence: 0.9").unwrap(),
+            SentimentOutput {
+                sentiment: "positive".to_string(),
+                confidence: Some(0.9),
+            }
+        );
+        let err = parse("Sentiment: positive\nConfidence: high").unwrap_err();
+        assert!(err.to_string().starts_with("invalid Confidence"));
+
+        // Declared signatures also run by name
+        let sentiment = SignatureRegistry::builtin();
+        let sentiment = sentiment.get("sentiment").unwrap();
+        assert_eq!(
+            sentiment.parse_output(" negative").unwrap(),
+            json!({"sentiment": "negative", "confidence": null})
+        );
+    }
+
+    #[test]
+    fn test_parse_input_pairs() {
+        let input = parse_input_pairs(&["question=a=b", "n:=3", "tags:=[\"x\"]"]).unwrap();
+        assert_eq!(input, json!({"question": "a=b", "n": 3, "tags": ["x"]}));
+
+        for bad in ["question", "=value", "n:=three"] {
+            assert!(matches!(
+                parse_input_pairs(&[bad]),
+                Err(DSRSError::ConfigError(_))
+            ));
+        }
+    }
+}
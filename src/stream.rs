@@ -0,0 +1,506 @@
+//! Streaming completions over server-sent events.
+//!
+//! [`CompletionStream`] is a hand-written [`Stream`] state machine rather than
+//! an `async-stream` generator, and it reads the body with
+//! [`reqwest::Response::chunk`] instead of enabling `reqwest`'s `stream`
+//! feature. That costs a little more code here, but the only dependencies it
+//! adds are `futures-core` and `bytes`, both of which `reqwest` already pulls
+//! in. The whole module is behind the `stream` feature
+//! (enabled by default) so builds that don't need streaming can drop it.
+
+use crate::client::Usage;
+use crate::cost::{Price, Reservation};
+use crate::errors::{DSRSError, Result};
+use bytes::Bytes;
+use futures_core::Stream;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::time::Sleep;
+
+/// A connected response plus the concurrency slot it occupies, if any.
+type Connected = (reqwest::Response, Option<OwnedSemaphorePermit>);
+type ResponseFuture = Pin<Box<dyn Future<Output = Result<Connected>> + Send>>;
+type ChunkFuture =
+    Pin<Box<dyn Future<Output = (reqwest::Response, reqwest::Result<Option<Bytes>>)> + Send>>;
+
+/// A content token, or the error that ended the stream.
+pub type StreamItem = Result<String>;
+type Deadline = Pin<Box<Sleep>>;
+
+/// Sentinel payload the server sends as its final event.
+const DONE_SENTINEL: &str = "[DONE]";
+
+/// One `data:` event of a streamed chat completion. OpenAI events carry
+/// `choices`; Anthropic's are typed, with text in `content_block_delta`
+/// events and a final `message_stop`.
+#[derive(Deserialize)]
+struct StreamChunk {
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+    #[serde(default, rename = "type")]
+    event_type: Option<String>,
+    #[serde(default)]
+    delta: Option<TextDelta>,
+    #[serde(default)]
+    usage: Option<ChunkUsage>,
+    #[serde(default)]
+    message: Option<StreamMessage>,
+}
+
+/// Anthropic's `message_start` event, which carries the prompt's usage.
+#[derive(Deserialize)]
+struct StreamMessage {
+    #[serde(default)]
+    usage: Option<ChunkUsage>,
+}
+
+/// Token counts from a stream event: OpenAI's last chunk has the totals,
+/// while Anthropic reports `input_tokens` when the message starts and a
+/// running `output_tokens` as it goes.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct ChunkUsage {
+    prompt_tokens: Option<u32>,
+    completion_tokens: Option<u32>,
+    input_tokens: Option<u32>,
+    output_tokens: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct TextDelta {
+    #[serde(default)]
+    text: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StreamChoice {
+    delta: Delta,
+}
+
+#[derive(Deserialize)]
+struct Delta {
+    content: Option<String>,
+}
+
+enum State {
+    Connecting(ResponseFuture),
+    // The permit is held until the stream ends
+    Streaming(ChunkFuture, Option<OwnedSemaphorePermit>),
+    Done,
+}
+
+/// A stream of incremental content tokens from a streamed completion.
+///
+/// Created by [`LLMClient::complete_stream`](crate::LLMClient::complete_stream).
+/// The request is sent on first poll; the stream ends after the server's
+/// `[DONE]` event (or when the connection closes).
+pub struct CompletionStream {
+    state: State,
+    buffer: Vec<u8>,
+    pending: VecDeque<StreamItem>,
+    raw: Option<Vec<u8>>,
+    text: String,
+    max_duration: Option<Duration>,
+    // Armed on first poll so the limit covers connecting as well
+    deadline: Option<Deadline>,
+    prompt_tokens: Option<u32>,
+    completion_tokens: Option<u32>,
+    cost: Option<StreamCost>,
+}
+
+/// The stream's share of the client's budget, settled when it ends.
+struct StreamCost {
+    reservation: Reservation,
+    price: Price,
+}
+
+impl CompletionStream {
+    pub(crate) fn new(response: ResponseFuture) -> Self {
+        Self::with_state(State::Connecting(response))
+    }
+
+    /// A stream that yields `err` once and then ends.
+    pub(crate) fn failed(err: DSRSError) -> Self {
+        let mut stream = Self::with_state(State::Done);
+        stream.pending.push_back(Err(err));
+        stream
+    }
+
+    fn with_state(state: State) -> Self {
+        Self {
+            state,
+            buffer: Vec::new(),
+            pending: VecDeque::new(),
+            raw: None,
+            text: String::new(),
+            max_duration: None,
+            deadline: None,
+            prompt_tokens: None,
+            completion_tokens: None,
+            cost: None,
+        }
+    }
+
+    /// Holds `reservation` against the client's budget until the stream
+    /// ends, then settles it at `price` for the usage the provider reported,
+    /// or at the reserved worst case without one. A stream that fails before
+    /// the response starts releases it instead.
+    pub(crate) fn cost(mut self, reservation: Reservation, price: Price) -> Self {
+        self.cost = Some(StreamCost { reservation, price });
+        self
+    }
+
+    /// Ends the stream with [`DSRSError::Timeout`] once it has run for
+    /// `limit`, counted from the first poll.
+    pub(crate) fn max_duration(mut self, limit: Option<Duration>) -> Self {
+        self.max_duration = limit;
+        self
+    }
+
+    /// All content received so far. If the stream ended early with an
+    /// error, such as hitting `max_stream_duration`, this is the partial
+    /// completion.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The tokens the provider billed, once it has reported both the
+    /// prompt's and the completion's, usually at the end of the stream.
+    pub fn usage(&self) -> Option<Usage> {
+        let prompt_tokens = self.prompt_tokens?;
+        let completion_tokens = self.completion_tokens?;
+        Some(Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        })
+    }
+
+    /// Keeps a copy of every raw SSE byte received, for replaying or
+    /// debugging provider quirks. Off by default to avoid holding the whole
+    /// response in memory.
+    pub fn capture_raw(mut self) -> Self {
+        self.raw.get_or_insert_with(Vec::new);
+        self
+    }
+
+    /// The raw bytes received so far, if [`CompletionStream::capture_raw`]
+    /// was enabled. Complete once the stream has ended.
+    #[allow(
+        clippy::type_complexity,
+        reason = "an optional byte slice; the lint scores the reference and slice as nesting"
+    )]
+    pub fn raw_bytes(&self) -> Option<&[u8]> {
+        self.raw.as_deref()
+    }
+
+    /// Takes the captured raw bytes, leaving an empty capture in place.
+    pub fn take_raw_bytes(&mut self) -> Option<Vec<u8>> {
+        self.raw.as_mut().map(std::mem::take)
+    }
+
+    /// Ends the stream, settling its cost if the response had started.
+    fn end(&mut self) {
+        let billed = matches!(self.state, State::Streaming(..));
+        self.state = State::Done;
+        if let Some(cost) = self.cost.take()
+            && billed
+        {
+            let usage = self.usage();
+            cost.reservation.settle(usage.map(|usage| {
+                cost.price.cost(
+                    u64::from(usage.prompt_tokens),
+                    u64::from(usage.completion_tokens),
+                )
+            }));
+        }
+    }
+
+    /// Returns a timeout error once the stream has outlived `max_duration`.
+    fn poll_deadline(&mut self, cx: &mut Context<'_>) -> Option<DSRSError> {
+        let limit = self.max_duration?;
+        if matches!(self.state, State::Done) {
+            return None;
+        }
+        let deadline = self
+            .deadline
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(limit)));
+        deadline
+            .as_mut()
+            .poll(cx)
+            .is_ready()
+            .then_some(DSRSError::Timeout(Some(limit)))
+    }
+
+    /// Reads the next body chunk, handing the response back with it.
+    fn next_chunk(mut response: reqwest::Response) -> ChunkFuture {
+        Box::pin(async move {
+            let chunk = response.chunk().await;
+            (response, chunk)
+        })
+    }
+
+    /// Splits buffered bytes into complete lines and queues their tokens.
+    fn feed(&mut self, bytes: &[u8]) {
+        if let Some(raw) = &mut self.raw {
+            raw.extend_from_slice(bytes);
+        }
+        self.buffer.extend_from_slice(bytes);
+        while let Some(newline) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=newline).collect();
+            self.handle_line(&line);
+            if matches!(self.state, State::Done) {
+                return;
+            }
+        }
+    }
+
+    fn handle_line(&mut self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        let Some(data) = line.trim_end().strip_prefix("data:") else {
+            // Comments, `event:`/`id:` fields and blank separators carry no tokens
+            return;
+        };
+        let data = data.trim();
+        if data == DONE_SENTINEL {
+            self.end();
+            return;
+        }
+        match serde_json::from_str::<StreamChunk>(data) {
+            Ok(chunk) if chunk.event_type.as_deref() == Some("message_stop") => self.end(),
+            Ok(chunk) => {
+                let message_usage = chunk.message.and_then(|message| message.usage);
+                for usage in chunk.usage.into_iter().chain(message_usage) {
+                    if let Some(tokens) = usage.prompt_tokens.or(usage.input_tokens) {
+                        self.prompt_tokens = Some(tokens);
+                    }
+                    if let Some(tokens) = usage.completion_tokens.or(usage.output_tokens) {
+                        self.completion_tokens = Some(tokens);
+                    }
+                }
+                let content = match chunk.choices.into_iter().next() {
+                    Some(choice) => choice.delta.content,
+                    None => chunk.delta.and_then(|delta| delta.text),
+                };
+                if let Some(content) = content.filter(|c| !c.is_empty()) {
+                    self.text.push_str(&content);
+                    self.pending.push_back(Ok(content));
+                }
+            }
+            Err(err) => self.pending.push_back(Err(DSRSError::ApiError(format!(
+                "Failed to parse stream chunk: {err}"
+            )))),
+        }
+    }
+}
+
+impl Stream for CompletionStream {
+    type Item = StreamItem;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(item) = this.pending.pop_front() {
+                return Poll::Ready(Some(item));
+            }
+            if let Some(err) = this.poll_deadline(cx) {
+                this.end();
+                return Poll::Ready(Some(Err(err)));
+            }
+            match &mut this.state {
+                State::Connecting(response) => match response.as_mut().poll(cx) {
+                    Poll::Ready(Ok((response, permit))) => {
+                        this.state = State::Streaming(Self::next_chunk(response), permit);
+                    }
+                    Poll::Ready(Err(err)) => {
+                        this.end();
+                        return Poll::Ready(Some(Err(err)));
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                State::Streaming(chunk, _permit) => match chunk.as_mut().poll(cx) {
+                    Poll::Ready((response, Ok(Some(bytes)))) => {
+                        *chunk = Self::next_chunk(response);
+                        this.feed(&bytes);
+                    }
+                    Poll::Ready((_, Err(err))) => {
+                        this.end();
+                        return Poll::Ready(Some(Err(err.into())));
+                    }
+                    Poll::Ready((_, Ok(None))) => {
+                        // A final event may arrive without a trailing newline
+                        let rest = std::mem::take(&mut this.buffer);
+                        this.handle_line(&rest);
+                        this.end();
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                State::Done => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+/// A stream dropped mid-response was still billed, so it counts at the
+/// worst case unless usage already arrived.
+impl Drop for CompletionStream {
+    fn drop(&mut self) {
+        self.end();
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    async fn collect(mut stream: CompletionStream) -> Vec<StreamItem> {
+        let mut items = Vec::new();
+        while let Some(item) = std::future::poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await
+        {
+            items.push(item);
+        }
+        items
+    }
+
+    fn fed(chunks: &[&str]) -> CompletionStream {
+        fed_into(unread(), chunks)
+    }
+
+    fn unread() -> CompletionStream {
+        let body: ResponseFuture = Box::pin(std::future::pending());
+        CompletionStream::with_state(State::Connecting(body))
+    }
+
+    fn fed_into(mut stream: CompletionStream, chunks: &[&str]) -> CompletionStream {
+        for chunk in chunks {
+            stream.feed(chunk.as_bytes());
+        }
+        // Pretend the body has been fully read
+        if !matches!(stream.state, State::Done) {
+            let rest = std::mem::take(&mut stream.buffer);
+            stream.handle_line(&rest);
+            stream.state = State::Done;
+        }
+        stream
+    }
+
+    #[tokio::test]
+    async fn test_stream_yields_deltas_until_done() {
+        let stream = fed(&[
+            ": keep-alive\n\n",
+            "data: {\"choices\":[{\"delta\":{\"role\":\"assistant\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hel\"}}]}\n\ndata: {\"choi",
+            "ces\":[{\"delta\":{\"content\":\"lo\"}}]}\r\n\r\n",
+            "data: [DONE]\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"ignored\"}}]}\n\n",
+        ]);
+        let tokens: Vec<String> = collect(stream)
+            .await
+            .into_iter()
+            .map(|item| item.unwrap())
+            .collect();
+        assert_eq!(tokens, ["Hel", "lo"]);
+    }
+
+    #[tokio::test]
+    async fn test_stream_reads_anthropic_events() {
+        let stream = fed(&[
+            "event: message_start\ndata: {\"type\":\"message_start\",\"message\":{}}\n\n",
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":0,",
+            "\"delta\":{\"type\":\"text_delta\",\"text\":\"Hi\"}}\n\n",
+            "data: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"end_turn\"}}\n\n",
+            "data: {\"type\":\"message_stop\"}\n\n",
+            "data: {\"type\":\"content_block_delta\",\"delta\":{\"text\":\"ignored\"}}\n\n",
+        ]);
+        let tokens: Vec<String> = collect(stream)
+            .await
+            .into_iter()
+            .map(|item| item.unwrap())
+            .collect();
+        assert_eq!(tokens, ["Hi"]);
+    }
+
+    #[test]
+    fn test_stream_reads_usage() {
+        let stream = fed(&[
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}],\"usage\":null}\n\n",
+            "data: {\"choices\":[],\"usage\":{\"prompt_tokens\":9,\"completion_tokens\":2,\"total_tokens\":11}}\n\n",
+            "data: [DONE]\n\n",
+        ]);
+        assert_eq!(
+            stream.usage(),
+            Some(Usage {
+                prompt_tokens: 9,
+                completion_tokens: 2,
+                total_tokens: 11
+            })
+        );
+
+        let stream = fed(&[
+            "data: {\"type\":\"message_start\",\"message\":{\"usage\":{\"input_tokens\":25,\"output_tokens\":1}}}\n\n",
+            "data: {\"type\":\"message_delta\",\"delta\":{},\"usage\":{\"output_tokens\":15}}\n\n",
+        ]);
+        assert_eq!(stream.usage().map(|usage| usage.total_tokens), Some(40));
+        assert_eq!(fed(&["data: [DONE]\n\n"]).usage(), None);
+    }
+
+    #[tokio::test]
+    async fn test_stream_reports_malformed_chunks() {
+        let stream = fed(&["data: {not json}\n\n"]);
+        let items = collect(stream).await;
+        assert!(matches!(items.as_slice(), [Err(DSRSError::ApiError(_))]));
+    }
+
+    #[tokio::test]
+    async fn test_raw_capture_is_opt_in() {
+        let chunks = ["data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}]}\n\n"];
+        assert!(fed(&chunks).raw_bytes().is_none());
+
+        let mut stream = fed_into(unread().capture_raw(), &chunks);
+        assert_eq!(stream.raw_bytes(), Some(chunks[0].as_bytes()));
+        assert_eq!(stream.take_raw_bytes().unwrap(), chunks[0].as_bytes());
+        assert_eq!(stream.raw_bytes(), Some(&[][..]));
+    }
+
+    #[tokio::test]
+    async fn test_max_duration_keeps_partial_text() {
+        let mut stream = unread().max_duration(Some(Duration::from_millis(20)));
+        stream.feed(b"data: {\"choices\":[{\"delta\":{\"content\":\"Once \"}}]}\n\n");
+        stream.feed(b"data: {\"choices\":[{\"delta\":{\"content\":\"upon\"}}]}\n\n");
+
+        let mut items = Vec::new();
+        while let Some(item) = std::future::poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await
+        {
+            items.push(item);
+        }
+        assert!(matches!(
+            items.as_slice(),
+            [Ok(_), Ok(_), Err(DSRSError::Timeout(Some(limit)))] if *limit == Duration::from_millis(20)
+        ));
+        assert_eq!(stream.text(), "Once upon");
+    }
+
+    #[tokio::test]
+    async fn test_completed_stream_ignores_max_duration() {
+        let chunks = ["data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}]}\n\n"];
+        let stream = fed_into(unread().max_duration(Some(Duration::ZERO)), &chunks);
+        let items = collect(stream).await;
+        assert!(matches!(items.as_slice(), [Ok(token)] if token == "Hi"));
+    }
+
+    #[tokio::test]
+    async fn test_failed_stream_yields_error_once() {
+        let items = collect(CompletionStream::failed(DSRSError::ConfigError(
+            "missing key".to_string(),
+        )))
+        .await;
+        assert!(matches!(items.as_slice(), [Err(DSRSError::ConfigError(_))]));
+    }
+}
@@ -0,0 +1,280 @@
+//! A minimal HTTP/1.1 server and a fake transport for exercising the client
+//! in tests.
+
+// Test scaffolding, which panics to report failures
+#![allow(clippy::unwrap_used)]
+
+use crate::transport::{Header, HttpResponse, HttpTransport, PostFuture};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A canned response served by [`MockServer`].
+#[derive(Clone, Debug)]
+pub(crate) struct MockResponse {
+    status: u16,
+    headers: Vec<Header>,
+    body: Vec<u8>,
+    delay: Duration,
+}
+
+impl MockResponse {
+    pub(crate) fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: body.into(),
+            delay: Duration::ZERO,
+        }
+    }
+
+    /// A `200 OK` chat completion whose only choice has `content`.
+    pub(crate) fn completion(content: &str) -> Self {
+        let body = serde_json::json!({
+            "choices": [{"message": {"role": "assistant", "content": content}}]
+        });
+        Self::new(200, body.to_string()).header("Content-Type", "application/json")
+    }
+
+    /// Adds a response header.
+    pub(crate) fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Waits this long before responding.
+    pub(crate) fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+/// A request received by [`MockServer`].
+#[derive(Clone, Debug)]
+pub(crate) struct RecordedRequest {
+    headers: Vec<Header>,
+    body: Vec<u8>,
+}
+
+impl RecordedRequest {
+    /// The first value of header `name`, compared case-insensitively.
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The body parsed as JSON.
+    pub(crate) fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).unwrap()
+    }
+}
+
+/// The requests received so far, shared with the server's tasks.
+type Recorded = Arc<Mutex<Vec<RecordedRequest>>>;
+
+/// Picks the response to a request from its 0-based connection index.
+type Responder = dyn Fn(usize, &RecordedRequest) -> MockResponse + Send + Sync;
+
+/// Serves mock responses, one per connection, recording each request.
+pub(crate) struct MockServer {
+    base_url: String,
+    requests: Recorded,
+    max_in_flight: Arc<AtomicUsize>,
+}
+
+/// Tracks how many requests are being answered at once.
+#[derive(Default)]
+struct InFlight {
+    current: AtomicUsize,
+    max: Arc<AtomicUsize>,
+}
+
+impl MockServer {
+    /// Serves `responses` in order, repeating the last one once they run out.
+    pub(crate) async fn start(responses: Vec<MockResponse>) -> Self {
+        Self::respond_with(move |index, _| {
+            responses
+                .get(index)
+                .or_else(|| responses.last())
+                .cloned()
+                .unwrap_or_else(|| MockResponse::new(500, "no mock response"))
+        })
+        .await
+    }
+
+    /// Serves whatever `respond` returns for each request.
+    pub(crate) async fn respond_with(
+        respond: impl Fn(usize, &RecordedRequest) -> MockResponse + Send + Sync + 'static,
+    ) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let served = AtomicUsize::new(0);
+        let respond: Arc<Responder> = Arc::new(respond);
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&requests);
+        let in_flight = Arc::new(InFlight::default());
+        let max_in_flight = Arc::clone(&in_flight.max);
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let index = served.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(handle(
+                    stream,
+                    index,
+                    Arc::clone(&respond),
+                    Arc::clone(&recorded),
+                    Arc::clone(&in_flight),
+                ));
+            }
+        });
+
+        Self {
+            base_url,
+            requests,
+            max_in_flight,
+        }
+    }
+
+    /// The chat-completions URL on this server.
+    pub(crate) fn url(&self) -> String {
+        format!("{}/v1/chat/completions", self.base_url)
+    }
+
+    /// The requests received so far, in arrival order.
+    pub(crate) fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// The most requests that were being answered at the same time.
+    pub(crate) fn max_in_flight(&self) -> usize {
+        self.max_in_flight.load(Ordering::SeqCst)
+    }
+}
+
+async fn handle(
+    mut stream: TcpStream,
+    index: usize,
+    respond: Arc<Responder>,
+    requests: Recorded,
+    in_flight: Arc<InFlight>,
+) {
+    let Some(request) = read_request(&mut stream).await else {
+        return;
+    };
+    let response = respond(index, &request);
+    requests.lock().unwrap().push(request);
+
+    let current = in_flight.current.fetch_add(1, Ordering::SeqCst) + 1;
+    in_flight.max.fetch_max(current, Ordering::SeqCst);
+    tokio::time::sleep(response.delay).await;
+    let mut head = format!(
+        "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        response.body.len()
+    );
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    let _ = stream.write_all(head.as_bytes()).await;
+    let _ = stream.write_all(&response.body).await;
+    let _ = stream.shutdown().await;
+    in_flight.current.fetch_sub(1, Ordering::SeqCst);
+}
+
+/// Reads one request (head and body).
+async fn read_request(stream: &mut TcpStream) -> Option<RecordedRequest> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    let head_end = loop {
+        if let Some(pos) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return None,
+            Ok(read) => data.extend_from_slice(&buf[..read]),
+        }
+    };
+
+    let head = String::from_utf8_lossy(&data[..head_end]).into_owned();
+    let headers: Vec<Header> = head
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    let length = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.parse::<usize>().ok())
+        .unwrap_or(0);
+    while data.len() < head_end + length {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return None,
+            Ok(read) => data.extend_from_slice(&buf[..read]),
+        }
+    }
+    Some(RecordedRequest {
+        headers,
+        body: data[head_end..head_end + length].to_vec(),
+    })
+}
+
+/// A request sent through [`FakeTransport`].
+#[derive(Clone, Debug)]
+pub(crate) struct SentRequest {
+    pub(crate) url: String,
+    pub(crate) headers: Vec<Header>,
+    pub(crate) body: serde_json::Value,
+}
+
+/// An [`HttpTransport`] that answers from canned responses without any
+/// network, recording each request.
+#[derive(Default)]
+pub(crate) struct FakeTransport {
+    responses: Mutex<Vec<HttpResponse>>,
+    sent: Mutex<Vec<SentRequest>>,
+}
+
+impl FakeTransport {
+    /// Answers with `responses` in order, repeating the last one once they
+    /// run out.
+    pub(crate) fn new(responses: Vec<HttpResponse>) -> Arc<Self> {
+        Arc::new(Self {
+            responses: Mutex::new(responses),
+            sent: Mutex::default(),
+        })
+    }
+
+    /// The requests sent so far, in order.
+    pub(crate) fn sent(&self) -> Vec<SentRequest> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+impl HttpTransport for FakeTransport {
+    fn post_json<'a>(
+        &'a self,
+        url: &'a str,
+        headers: &'a [Header],
+        body: &'a serde_json::Value,
+    ) -> PostFuture<'a> {
+        let mut sent = self.sent.lock().unwrap();
+        let responses = self.responses.lock().unwrap();
+        let response = responses
+            .get(sent.len())
+            .or_else(|| responses.last())
+            .cloned()
+            .unwrap_or_else(|| HttpResponse::new(500, "no fake response"));
+        sent.push(SentRequest {
+            url: url.to_string(),
+            headers: headers.to_vec(),
+            body: body.clone(),
+        });
+        Box::pin(async move { Ok(response) })
+    }
+}
@@ -0,0 +1,153 @@
+//! Test doubles for code built on dsrs modules.
+//!
+//! ```
+//! use dsrs::modules::Predict;
+//! use dsrs::signatures::{QuestionAnswer, QuestionAnswerInput};
+//! use dsrs::testing::MockLM;
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let lm = MockLM::new(["Answer: Paris"]);
+//! let input = QuestionAnswerInput { question: "Capital of France?".to_string() };
+//! let output = Predict::new(QuestionAnswer).forward(&lm, input).await.unwrap();
+//! assert_eq!(output.answer, "Paris");
+//! assert!(lm.prompts()[0].ends_with("Question: Capital of France?\nAnswer:"));
+//! # }
+//! ```
+
+use crate::client::{CompletionParams, Message};
+use crate::errors::{DSRSError, Result};
+use crate::lm::LM;
+use std::collections::VecDeque;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+const MOCK_MODEL: &str = "mock";
+
+/// A scripted response, or the error to fail with.
+type Scripted = Result<String>;
+
+/// An [`LM`] that replies with scripted responses, in order, and records
+/// every request it gets. Once the script runs out, requests fail with
+/// [`DSRSError::ApiError`].
+#[derive(Debug, Default)]
+pub struct MockLM {
+    responses: Mutex<VecDeque<Scripted>>,
+    requests: Mutex<Vec<MockRequest>>,
+}
+
+/// A request a [`MockLM`] received.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MockRequest {
+    pub messages: Vec<Message>,
+    pub model: String,
+    pub params: CompletionParams,
+}
+
+impl MockLM {
+    /// Replies with `responses`, one per request.
+    pub fn new<R: Into<String>>(responses: impl IntoIterator<Item = R>) -> Self {
+        Self {
+            responses: Mutex::new(responses.into_iter().map(|text| Ok(text.into())).collect()),
+            requests: Mutex::default(),
+        }
+    }
+
+    /// Fails the request after the responses scripted so far with `err`.
+    pub fn with_error(self, err: DSRSError) -> Self {
+        lock(&self.responses).push_back(Err(err));
+        self
+    }
+
+    /// Replies with `response` after the ones scripted so far.
+    pub fn with_response(self, response: impl Into<String>) -> Self {
+        lock(&self.responses).push_back(Ok(response.into()));
+        self
+    }
+
+    /// Every request received, oldest first.
+    pub fn requests(&self) -> Vec<MockRequest> {
+        lock(&self.requests).clone()
+    }
+
+    /// The content of each request's last message, usually the prompt a
+    /// module generated, oldest first.
+    pub fn prompts(&self) -> Vec<String> {
+        lock(&self.requests)
+            .iter()
+            .map(|request| {
+                request
+                    .messages
+                    .last()
+                    .map(|message| message.content.clone())
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+}
+
+impl LM for MockLM {
+    /// Always `mock`.
+    fn default_model(&self) -> &str {
+        MOCK_MODEL
+    }
+
+    async fn complete(
+        &self,
+        messages: &[Message],
+        model: &str,
+        params: &CompletionParams,
+    ) -> Result<String> {
+        lock(&self.requests).push(MockRequest {
+            messages: messages.to_vec(),
+            model: model.to_string(),
+            params: params.clone(),
+        });
+        lock(&self.responses).pop_front().unwrap_or_else(|| {
+            Err(DSRSError::ApiError(
+                "MockLM has no responses left".to_string(),
+            ))
+        })
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mock_lm_replays_the_script_and_records_requests() {
+        let lm = MockLM::new(["first"])
+            .with_error(DSRSError::Timeout(None))
+            .with_response("third");
+        let params = CompletionParams::new().temperature(0.5);
+        let messages = [Message::system("Be brief."), Message::user("Hi")];
+        assert_eq!(
+            lm.complete(&messages, "mock", &params).await.unwrap(),
+            "first"
+        );
+        assert!(matches!(
+            lm.complete(&messages, "mock", &params).await,
+            Err(DSRSError::Timeout(None))
+        ));
+        assert_eq!(
+            lm.complete(&messages, "other", &params).await.unwrap(),
+            "third"
+        );
+        assert!(matches!(
+            lm.complete(&messages, "mock", &params).await,
+            Err(DSRSError::ApiError(_))
+        ));
+
+        let requests = lm.requests();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests[2].model, "other");
+        assert_eq!(requests[0].messages, messages);
+        assert_eq!(requests[0].params, params);
+        assert_eq!(lm.prompts(), ["Hi", "Hi", "Hi", "Hi"]);
+    }
+}
@@ -0,0 +1,238 @@
+//! Token counting used for prompt validation, budgeting and throughput
+//! metrics.
+//!
+//! [`count_tokens`] picks a counter per model:
+//!
+//! | Models | Counter | Accuracy |
+//! |---|---|---|
+//! | OpenAI models `tiktoken` knows (`gpt-4o`, `gpt-4.1`, `o1`/`o3`/`o4`, `gpt-4`, `gpt-3.5-turbo`, `text-embedding-*`, ...) | [`Tiktoken`]: the model's BPE encoding (`o200k_base`, `cl100k_base`, ...) via `tiktoken-rs` | exact for the text; chat formatting adds a few tokens per message |
+//! | Anything else | [`CharHeuristic`]: ~4 chars per token, 1 per CJK char | within ~25% for English; tokenizers vary widely by provider |
+//!
+//! Prompts are checked against the model's context window from
+//! [`context_window`], counting the prompt and the requested `max_tokens`
+//! together.
+//!
+//! For other providers' models, wrap their tokenizer in a [`TokenCounter`]
+//! and install it with [`LLMClientBuilder::token_counter`](crate::client::LLMClientBuilder::token_counter).
+
+use std::fmt;
+use tiktoken_rs::CoreBPE;
+use tiktoken_rs::tokenizer::{self, Tokenizer};
+
+/// Counts tokens in text for a particular tokenizer.
+pub trait TokenCounter: Send + Sync {
+    fn count_tokens(&self, text: &str) -> usize;
+}
+
+impl fmt::Debug for dyn TokenCounter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("TokenCounter")
+    }
+}
+
+/// One of OpenAI's BPE encodings. Each is loaded the first time it counts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tiktoken {
+    /// GPT-4o, GPT-4.1 and the o-series.
+    O200kBase,
+    /// GPT-4, GPT-3.5 Turbo and the embedding models.
+    Cl100kBase,
+    P50kBase,
+    P50kEdit,
+    R50kBase,
+}
+
+impl Tiktoken {
+    /// The encoding of `model`, if it's an OpenAI model `tiktoken` knows.
+    pub fn for_model(model: &str) -> Option<Self> {
+        match tokenizer::get_tokenizer(model)? {
+            Tokenizer::O200kBase => Some(Tiktoken::O200kBase),
+            Tokenizer::Cl100kBase => Some(Tiktoken::Cl100kBase),
+            Tokenizer::P50kBase => Some(Tiktoken::P50kBase),
+            Tokenizer::P50kEdit => Some(Tiktoken::P50kEdit),
+            Tokenizer::R50kBase | Tokenizer::Gpt2 => Some(Tiktoken::R50kBase),
+        }
+    }
+
+    fn bpe(self) -> &'static CoreBPE {
+        match self {
+            Tiktoken::O200kBase => tiktoken_rs::o200k_base_singleton(),
+            Tiktoken::Cl100kBase => tiktoken_rs::cl100k_base_singleton(),
+            Tiktoken::P50kBase => tiktoken_rs::p50k_base_singleton(),
+            Tiktoken::P50kEdit => tiktoken_rs::p50k_edit_singleton(),
+            Tiktoken::R50kBase => tiktoken_rs::r50k_base_singleton(),
+        }
+    }
+}
+
+impl TokenCounter for Tiktoken {
+    /// Special tokens such as `<|endoftext|>` count as the text they're
+    /// written as, as they do in a prompt.
+    fn count_tokens(&self, text: &str) -> usize {
+        self.bpe().encode_ordinary(text).len()
+    }
+}
+
+/// Provider-agnostic fallback: about four characters per token.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CharHeuristic;
+
+impl TokenCounter for CharHeuristic {
+    fn count_tokens(&self, text: &str) -> usize {
+        let (cjk, other) = text.chars().fold((0, 0), |(cjk, other), c| {
+            if is_cjk(c) {
+                (cjk + 1, other)
+            } else {
+                (cjk, other + 1)
+            }
+        });
+        cjk + usize::div_ceil(other, 4)
+    }
+}
+
+/// Returns the counter used for `model`: its [`Tiktoken`] encoding, else
+/// [`CharHeuristic`].
+pub fn counter_for_model(model: &str) -> &'static dyn TokenCounter {
+    // Strip router prefixes such as `openai/gpt-4o`
+    let name = model.strip_prefix("openai/").unwrap_or(model);
+    match Tiktoken::for_model(name) {
+        Some(Tiktoken::O200kBase) => &Tiktoken::O200kBase,
+        Some(Tiktoken::Cl100kBase) => &Tiktoken::Cl100kBase,
+        Some(Tiktoken::P50kBase) => &Tiktoken::P50kBase,
+        Some(Tiktoken::P50kEdit) => &Tiktoken::P50kEdit,
+        Some(Tiktoken::R50kBase) => &Tiktoken::R50kBase,
+        None => &CharHeuristic,
+    }
+}
+
+/// The number of tokens `text` uses with `model`'s tokenizer, estimated for
+/// models without a known one.
+pub fn count_tokens(text: &str, model: &str) -> usize {
+    counter_for_model(model).count_tokens(text)
+}
+
+/// The window assumed for models missing from the table; roughly the 32k
+/// characters the client allowed before limits were counted in tokens.
+pub const DEFAULT_CONTEXT_WINDOW: usize = 8192;
+
+/// Context windows in tokens (prompt plus completion), by model name prefix.
+const CONTEXT_WINDOWS: [Prefixed<usize>; 14] = [
+    ("gpt-3.5-turbo", 16_385),
+    ("gpt-3.5-turbo-instruct", 4_096),
+    ("gpt-4", 8_192),
+    ("gpt-4-32k", 32_768),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4-1106", 128_000),
+    ("gpt-4-0125", 128_000),
+    ("gpt-4o", 128_000),
+    ("gpt-4.1", 1_047_576),
+    ("o1", 200_000),
+    ("o1-mini", 128_000),
+    ("o3", 200_000),
+    ("o4-mini", 200_000),
+    ("claude-", 200_000),
+];
+
+/// The context window of `model` in tokens, if it's a known model. The
+/// longest matching prefix wins, so dated snapshots such as
+/// `gpt-4o-2024-08-06` resolve to their family.
+pub fn context_window(model: &str) -> Option<usize> {
+    lookup_prefix(CONTEXT_WINDOWS, model)
+}
+
+/// A model name prefix and its value in a built-in table.
+pub(crate) type Prefixed<T> = (&'static str, T);
+
+/// Values set by model name prefix, e.g. with
+/// [`LLMClientBuilder::context_window`](crate::client::LLMClientBuilder::context_window).
+pub(crate) type ByPrefix<T> = Vec<(String, T)>;
+
+/// The value of the longest prefix of `model` in `table`.
+pub(crate) fn lookup_prefix<'a, T>(
+    table: impl IntoIterator<Item = (&'a str, T)>,
+    model: &str,
+) -> Option<T> {
+    let name = model.strip_prefix("openai/").unwrap_or(model);
+    table
+        .into_iter()
+        .filter(|(prefix, _)| name.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, value)| value)
+}
+
+/// Chinese, Japanese and Korean characters, which BPE tokenizers encode as
+/// roughly one token each.
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}'   // Hiragana, Katakana
+        | '\u{3400}'..='\u{4dbf}' // CJK Extension A
+        | '\u{4e00}'..='\u{9fff}' // CJK Unified Ideographs
+        | '\u{ac00}'..='\u{d7af}' // Hangul syllables
+        | '\u{f900}'..='\u{faff}' // CJK Compatibility Ideographs
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tiktoken_counts_exactly() {
+        let text = "The quick brown fox jumps over the lazy dog.";
+        assert_eq!(Tiktoken::Cl100kBase.count_tokens(text), 10);
+        assert_eq!(Tiktoken::O200kBase.count_tokens(text), 10);
+        assert_eq!(Tiktoken::Cl100kBase.count_tokens(""), 0);
+        assert_eq!(Tiktoken::Cl100kBase.count_tokens("1234567"), 3);
+        assert_eq!(Tiktoken::Cl100kBase.count_tokens("<|endoftext|>"), 7);
+    }
+
+    #[test]
+    fn test_cjk_counts_per_character() {
+        assert_eq!(CharHeuristic.count_tokens("你好世界"), 4);
+    }
+
+    #[test]
+    fn test_char_heuristic() {
+        assert_eq!(CharHeuristic.count_tokens("abcdefgh"), 2);
+        assert_eq!(CharHeuristic.count_tokens("abcdefghi"), 3);
+    }
+
+    #[test]
+    fn test_context_window_lookup() {
+        assert_eq!(context_window("gpt-3.5-turbo"), Some(16_385));
+        assert_eq!(context_window("gpt-4"), Some(8_192));
+        assert_eq!(context_window("gpt-4-32k-0613"), Some(32_768));
+        assert_eq!(context_window("gpt-4o-2024-08-06"), Some(128_000));
+        assert_eq!(context_window("openai/o1-mini"), Some(128_000));
+        assert_eq!(context_window("llama3"), None);
+    }
+
+    #[test]
+    fn test_counter_is_picked_per_model() {
+        assert_eq!(
+            Tiktoken::for_model("gpt-4o-2024-08-06"),
+            Some(Tiktoken::O200kBase)
+        );
+        assert_eq!(Tiktoken::for_model("o3-mini"), Some(Tiktoken::O200kBase));
+        assert_eq!(Tiktoken::for_model("gpt-4"), Some(Tiktoken::Cl100kBase));
+        assert_eq!(
+            Tiktoken::for_model("text-embedding-3-small"),
+            Some(Tiktoken::Cl100kBase)
+        );
+        assert_eq!(Tiktoken::for_model("llama3"), None);
+
+        let text = "tokenization-heavy-identifier";
+        assert_eq!(
+            count_tokens(text, "openai/o3-mini"),
+            Tiktoken::O200kBase.count_tokens(text)
+        );
+        assert_eq!(
+            count_tokens(text, "gpt-3.5-turbo"),
+            Tiktoken::Cl100kBase.count_tokens(text)
+        );
+        assert_eq!(
+            count_tokens(text, "llama3"),
+            CharHeuristic.count_tokens(text)
+        );
+    }
+}
@@ -0,0 +1,187 @@
+//! Request and response types for tool (function) calling with
+//! [`LLMClient::complete_with_tools`](crate::LLMClient::complete_with_tools).
+//!
+//! The crate doesn't run tools itself: the model asks for calls, the caller
+//! runs them and sends the results back as [`Message::tool`](crate::Message::tool)
+//! turns until the model answers with text.
+
+use crate::errors::{DSRSError, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::json;
+
+/// A tool the model may call, for [`ChatRequest::tools`](crate::client::ChatRequest::tools).
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Tool {
+    Function { function: FunctionDefinition },
+}
+
+impl Tool {
+    /// A function taking a JSON object matching the `parameters` schema.
+    pub fn function(
+        name: impl Into<String>,
+        description: impl Into<String>,
+        parameters: serde_json::Value,
+    ) -> Self {
+        Tool::Function {
+            function: FunctionDefinition {
+                name: name.into(),
+                description: Some(description.into()),
+                parameters,
+            },
+        }
+    }
+}
+
+/// The name, description and JSON-schema parameters of a [`Tool::Function`].
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FunctionDefinition {
+    /// Function name; letters, digits, underscores and dashes only.
+    pub name: String,
+    /// What the function does, which the model uses to decide when to call it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// JSON schema of the arguments object.
+    pub parameters: serde_json::Value,
+}
+
+/// Whether and which tools the model must call, for
+/// [`ChatRequest::tool_choice`](crate::client::ChatRequest::tool_choice).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ToolChoice {
+    /// The model decides (the provider default when tools are given).
+    Auto,
+    /// Never call a tool.
+    None,
+    /// Call at least one tool.
+    Required,
+    /// Call the named function.
+    Function(String),
+}
+
+impl Serialize for ToolChoice {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            ToolChoice::Auto => serializer.serialize_str("auto"),
+            ToolChoice::None => serializer.serialize_str("none"),
+            ToolChoice::Required => serializer.serialize_str("required"),
+            ToolChoice::Function(name) => {
+                json!({"type": "function", "function": {"name": name}}).serialize(serializer)
+            }
+        }
+    }
+}
+
+/// A call the model asked for.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolCall {
+    /// Identifies the call; answer it with a [`Message::tool`](crate::Message::tool)
+    /// carrying the same id.
+    pub id: String,
+    /// Always `"function"` for now.
+    #[serde(rename = "type", default = "function_type")]
+    pub call_type: String,
+    pub function: FunctionCall,
+}
+
+fn function_type() -> String {
+    "function".to_string()
+}
+
+impl ToolCall {
+    /// Parses the arguments into `T`. Fails with [`DSRSError::ParseError`]
+    /// holding the raw arguments, since models sometimes produce invalid
+    /// JSON here.
+    pub fn arguments<T: DeserializeOwned>(&self) -> Result<T> {
+        serde_json::from_str(&self.function.arguments).map_err(|err| DSRSError::ParseError {
+            message: err.to_string(),
+            raw: self.function.arguments.clone(),
+        })
+    }
+}
+
+/// The function and arguments of a [`ToolCall`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    /// The arguments as a JSON-encoded object, exactly as the model wrote
+    /// them.
+    pub arguments: String,
+}
+
+/// The outcome of [`LLMClient::complete_with_tools`](crate::LLMClient::complete_with_tools).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ToolCallResult {
+    /// The model answered directly.
+    Text(String),
+    /// The model wants these tools run, in order, before it answers.
+    ToolCalls(Vec<ToolCall>),
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tool_serialization() {
+        let tool = Tool::function(
+            "get_weather",
+            "Current weather for a city",
+            json!({"type": "object", "properties": {"city": {"type": "string"}}}),
+        );
+        assert_eq!(
+            serde_json::to_value(&tool).unwrap(),
+            json!({
+                "type": "function",
+                "function": {
+                    "name": "get_weather",
+                    "description": "Current weather for a city",
+                    "parameters": {"type": "object", "properties": {"city": {"type": "string"}}}
+                }
+            })
+        );
+
+        let choices = [
+            ToolChoice::Auto,
+            ToolChoice::None,
+            ToolChoice::Required,
+            ToolChoice::Function("get_weather".to_string()),
+        ];
+        assert_eq!(
+            serde_json::to_value(choices).unwrap(),
+            json!([
+                "auto",
+                "none",
+                "required",
+                {"type": "function", "function": {"name": "get_weather"}}
+            ])
+        );
+    }
+
+    #[test]
+    fn test_tool_call_arguments() {
+        let call: ToolCall = serde_json::from_value(json!({
+            "id": "call_1",
+            "type": "function",
+            "function": {"name": "get_weather", "arguments": "{\"city\": \"Paris\"}"}
+        }))
+        .unwrap();
+        assert_eq!(call.call_type, "function");
+        let arguments: serde_json::Value = call.arguments().unwrap();
+        assert_eq!(arguments, json!({"city": "Paris"}));
+
+        let invalid = ToolCall {
+            function: FunctionCall {
+                name: "get_weather".to_string(),
+                arguments: "{\"city\": ".to_string(),
+            },
+            ..call
+        };
+        assert!(matches!(
+            invalid.arguments::<serde_json::Value>(),
+            Err(DSRSError::ParseError { raw, .. }) if raw == "{\"city\": "
+        ));
+    }
+}
@@ -0,0 +1,257 @@
+//! A JSONL log of every chat request the client sends, for inspecting or
+//! replaying prompts later; see
+//! [`LLMClientBuilder::trace_file`](crate::client::LLMClientBuilder::trace_file).
+//!
+//! Each line is one [`TraceRecord`]. Writes are buffered and flushed when
+//! the client is dropped, and failing to write never fails the request:
+//! the error is logged as a warning instead.
+
+use crate::client::{ChatRequest, CompletionParams, Message, Reply, Usage};
+use crate::errors::{DSRSError, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// One chat request and its outcome.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TraceRecord {
+    /// When the request finished, in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    pub model: String,
+    pub messages: Vec<Message>,
+    /// The generation settings sent, including how many choices were asked
+    /// for.
+    #[serde(default)]
+    pub params: CompletionParams,
+    /// The text of each choice, in order; empty if the request failed.
+    #[serde(default)]
+    pub responses: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    /// Time taken, including retries.
+    pub latency_ms: u64,
+    /// Why the request failed, with the API key redacted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The replies to a chat request, or the error it failed with.
+type Outcome = Result<Vec<Reply>>;
+
+impl TraceRecord {
+    /// Records `request` and its `result`, passing error messages through
+    /// `redact`.
+    pub(crate) fn new(
+        request: &ChatRequest,
+        result: &Outcome,
+        latency_ms: u64,
+        redact: impl Fn(&str) -> String,
+    ) -> Self {
+        let (responses, usage, error) = match result {
+            Ok(replies) => (
+                replies
+                    .iter()
+                    .map(|reply| reply.message.text().unwrap_or_default())
+                    .collect(),
+                replies.first().and_then(|reply| reply.usage),
+                None,
+            ),
+            Err(err) => (Vec::new(), None, Some(redact(&err.to_string()))),
+        };
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| {
+                u64::try_from(since.as_millis()).unwrap_or(u64::MAX)
+            });
+        Self {
+            timestamp_ms,
+            model: request.model.clone(),
+            messages: request.messages.clone(),
+            params: CompletionParams {
+                max_tokens: request.max_tokens,
+                temperature: request.temperature,
+                top_p: request.top_p,
+                frequency_penalty: request.frequency_penalty,
+                presence_penalty: request.presence_penalty,
+                stop: request.stop.clone(),
+                n: request.n,
+                timeout: None,
+            },
+            responses,
+            usage,
+            latency_ms,
+            error,
+        }
+    }
+}
+
+/// Reads every record in the trace file at `path`, oldest first. Lines
+/// that don't parse, such as one cut short by a crash, are skipped.
+pub fn read_trace(path: &Path) -> Result<Vec<TraceRecord>> {
+    let text = std::fs::read_to_string(path).map_err(|err| {
+        DSRSError::ConfigError(format!("Failed to read {}: {err}", path.display()))
+    })?;
+    Ok(text
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+/// Appends [`TraceRecord`]s to a file.
+#[derive(Debug)]
+pub(crate) struct TraceWriter {
+    path: PathBuf,
+    out: Mutex<BufWriter<File>>,
+}
+
+impl TraceWriter {
+    /// Opens `path` for appending, creating it if needed.
+    pub(crate) fn open(path: PathBuf) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|err| {
+                DSRSError::ConfigError(format!(
+                    "Failed to open trace file {}: {err}",
+                    path.display()
+                ))
+            })?;
+        Ok(Self {
+            path,
+            out: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    /// Appends `record` as one line and flushes it, so the file can be
+    /// followed while requests run and a crash loses no finished records.
+    pub(crate) fn write(&self, record: &TraceRecord) {
+        let result = serde_json::to_string(record)
+            .map_err(std::io::Error::other)
+            .and_then(|line| {
+                let mut out = self.out.lock().unwrap_or_else(PoisonError::into_inner);
+                writeln!(out, "{line}")?;
+                out.flush()
+            });
+        if let Err(err) = result {
+            tracing::warn!(path = %self.path.display(), error = %err, "failed to write trace");
+        }
+    }
+}
+
+impl Drop for TraceWriter {
+    fn drop(&mut self) {
+        let out = self.out.get_mut().unwrap_or_else(PoisonError::into_inner);
+        if let Err(err) = out.flush() {
+            tracing::warn!(path = %self.path.display(), error = %err, "failed to flush trace");
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::client::LLMClient;
+    use crate::test_util::{MockResponse, MockServer};
+
+    fn trace_path(test: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("dsrs-trace-{test}-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn test_every_request_is_appended_as_a_record() {
+        let path = trace_path("records");
+        let reply = serde_json::json!({
+            "choices": [{"message": {"role": "assistant", "content": "Paris"}}],
+            "usage": {"prompt_tokens": 9, "completion_tokens": 1, "total_tokens": 10}
+        });
+        let server = MockServer::start(vec![
+            MockResponse::new(200, reply.to_string()),
+            MockResponse::new(400, r#"{"error": {"message": "bad request"}}"#),
+        ])
+        .await;
+        let client = LLMClient::builder()
+            .api_key("test-key")
+            .endpoint(server.url())
+            .max_retries(0)
+            .trace_file(&path)
+            .build()
+            .unwrap();
+        let params = CompletionParams::new().temperature(0.5).stop(["\n"]);
+        client
+            .complete_with_params("Capital of France?", "gpt-4o", &params)
+            .await
+            .unwrap();
+        // Each record is on disk as soon as its request is done
+        assert_eq!(read_trace(&path).unwrap().len(), 1);
+        assert!(
+            client
+                .complete("again", "gpt-4o", None, None)
+                .await
+                .is_err()
+        );
+        drop(client);
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<TraceRecord> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].model, "gpt-4o");
+        assert_eq!(
+            records[0].messages,
+            vec![Message::user("Capital of France?")]
+        );
+        assert_eq!(records[0].params.temperature, Some(0.5));
+        assert_eq!(records[0].params.stop, Some(vec!["\n".to_string()]));
+        assert_eq!(records[0].responses, vec!["Paris".to_string()]);
+        assert_eq!(records[0].usage.map(|usage| usage.total_tokens), Some(10));
+        assert_eq!(records[0].error, None);
+        assert!(records[1].responses.is_empty());
+        assert_eq!(
+            records[1].error.as_deref(),
+            Some("API error: HTTP 400 Bad Request: bad request")
+        );
+        assert_eq!(read_trace(&path).unwrap(), records);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_read_trace_skips_unparseable_lines() {
+        let path = trace_path("partial");
+        let record = TraceRecord {
+            timestamp_ms: 1_700_000_000_000,
+            model: "gpt-4o".to_string(),
+            messages: vec![Message::user("hi")],
+            params: CompletionParams::default(),
+            responses: vec!["hello".to_string()],
+            usage: None,
+            latency_ms: 12,
+            error: None,
+        };
+        let line = serde_json::to_string(&record).unwrap();
+        std::fs::write(&path, format!("{line}\n{{\"timestamp_ms\": 1, \"mod")).unwrap();
+        assert_eq!(read_trace(&path).unwrap(), vec![record]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_unopenable_trace_file_fails_the_build() {
+        let path = std::env::temp_dir()
+            .join("dsrs-no-such-dir")
+            .join("trace.jsonl");
+        let result = LLMClient::builder()
+            .api_key("test-key")
+            .trace_file(path)
+            .build();
+        assert!(matches!(result, Err(DSRSError::ConfigError(_))));
+    }
+}
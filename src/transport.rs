@@ -0,0 +1,166 @@
+//! The HTTP layer under [`LLMClient`](crate::LLMClient).
+//!
+//! Completion and embedding requests are sent through an [`HttpTransport`],
+//! which [`LLMClientBuilder::transport`](crate::client::LLMClientBuilder::transport)
+//! can replace, e.g. with a fake that returns canned responses in tests.
+//! Streams need a live connection and always use `reqwest`.
+
+use crate::errors::{DSRSError, Result};
+use futures_util::future::BoxFuture;
+use reqwest::Client;
+use std::fmt;
+use std::time::Duration;
+
+/// A header's name and value.
+pub type Header = (String, String);
+
+/// What [`HttpTransport::post_json`] returns.
+pub type PostFuture<'a> = BoxFuture<'a, Result<HttpResponse>>;
+
+/// Sends JSON POST requests.
+pub trait HttpTransport: Send + Sync {
+    /// POSTs `body` to `url` with `headers` and reads the whole response.
+    /// Non-success statuses are returned as responses, not errors; only
+    /// failures to get a response at all (connecting, timing out) are
+    /// errors.
+    fn post_json<'a>(
+        &'a self,
+        url: &'a str,
+        headers: &'a [Header],
+        body: &'a serde_json::Value,
+    ) -> PostFuture<'a>;
+}
+
+impl fmt::Debug for dyn HttpTransport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("HttpTransport")
+    }
+}
+
+/// A complete HTTP response.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<Header>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: body.into(),
+        }
+    }
+
+    /// A response with `body` serialized as JSON.
+    pub fn json(status: u16, body: &serde_json::Value) -> Self {
+        Self::new(status, body.to_string()).with_header("Content-Type", "application/json")
+    }
+
+    /// Adds a header.
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// The first value of header `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether the status is 2xx.
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// Reads a `reqwest` response to the end.
+    pub(crate) async fn read(response: reqwest::Response) -> Result<Self> {
+        let status = response.status().as_u16();
+        let headers = response
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| {
+                let value = value.to_str().ok()?;
+                Some((name.as_str().to_string(), value.to_string()))
+            })
+            .collect();
+        let body = response.bytes().await?.to_vec();
+        Ok(Self {
+            status,
+            headers,
+            body,
+        })
+    }
+}
+
+/// The default transport: a `reqwest` client with a total timeout per
+/// request.
+#[derive(Clone, Debug)]
+pub struct ReqwestTransport {
+    client: Client,
+    timeout: Duration,
+}
+
+impl ReqwestTransport {
+    pub fn new(client: Client, timeout: Duration) -> Self {
+        Self { client, timeout }
+    }
+}
+
+impl HttpTransport for ReqwestTransport {
+    fn post_json<'a>(
+        &'a self,
+        url: &'a str,
+        headers: &'a [Header],
+        body: &'a serde_json::Value,
+    ) -> PostFuture<'a> {
+        Box::pin(async move {
+            let mut request = self.client.post(url).timeout(self.timeout).json(body);
+            for (name, value) in headers {
+                request = request.header(name, value);
+            }
+            let response = async { HttpResponse::read(request.send().await?).await };
+            // Report the limit that was hit
+            response.await.map_err(|err| match err {
+                DSRSError::Timeout(None) => DSRSError::Timeout(Some(self.timeout)),
+                err => err,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::test_util::{MockResponse, MockServer};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_reqwest_transport_posts_json() {
+        let server = MockServer::start(vec![
+            MockResponse::new(503, "busy").header("Retry-After", "2"),
+        ])
+        .await;
+        let transport = ReqwestTransport::new(Client::new(), Duration::from_secs(5));
+        let headers = [("X-Test".to_string(), "yes".to_string())];
+        let response = transport
+            .post_json(&server.url(), &headers, &json!({"model": "m"}))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status, 503);
+        assert!(!response.is_success());
+        assert_eq!(response.header("retry-after"), Some("2"));
+        assert_eq!(response.body, b"busy");
+        let request = &server.requests()[0];
+        assert_eq!(request.header("X-Test"), Some("yes"));
+        assert_eq!(request.header("Content-Type"), Some("application/json"));
+        assert_eq!(request.json(), json!({"model": "m"}));
+    }
+}
@@ -0,0 +1,364 @@
+//! End-to-end checks of how the `dsrs` binary assembles its prompt and
+//! prints results. Each run is rejected by the context window check or the
+//! budget, or answered from the cache before any request is sent, so no
+//! server is needed.
+
+// Tests panic to report failures
+#![allow(clippy::unwrap_used)]
+
+use dsrs::cache::{Cache, DiskCache};
+use dsrs::client::{ChatRequest, Completion, CompletionParams, Message, Usage};
+use dsrs::trace::TraceRecord;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+/// A cache directory of this test run, named after `test`.
+fn cache_dir(test: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("dsrs-cli-{test}-{}", std::process::id()))
+}
+
+/// Runs `dsrs` on a 16k-token model with `args`, feeding `stdin`.
+fn dsrs(args: &[&str], stdin: &str) -> Output {
+    dsrs_with_cache(&cache_dir("cache"), args, stdin)
+}
+
+/// Like [`dsrs`], caching responses in `cache`.
+fn dsrs_with_cache(cache: &Path, args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_dsrs"))
+        .args(args)
+        // After `args`, since subcommands only take global flags after them
+        .args(["--model", "gpt-3.5-turbo", "--max-tokens", "100"])
+        // The CLI only caches at temperature 0
+        .args(["--temperature", "0"])
+        .env("LLM_API_KEY", "test-key")
+        .env("DSRS_CACHE_DIR", cache)
+        .env("LLM_ENDPOINT", "http://127.0.0.1:1/v1/chat/completions")
+        .env_remove("LLM_PROVIDER")
+        // No pipe without input, so the CLI doesn't warn that it went unread
+        .stdin(if stdin.is_empty() {
+            Stdio::null()
+        } else {
+            Stdio::piped()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    if let Some(mut pipe) = child.stdin.take() {
+        pipe.write_all(stdin.as_bytes()).unwrap();
+    }
+    child.wait_with_output().unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn test_prompt_from_stdin() {
+    // Each "a " is one token, and the trailing space one more
+    let output = dsrs(&[], &"a ".repeat(17_000));
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("PromptTooLong(17101, 16385)"));
+}
+
+#[test]
+fn test_prompt_dash_reads_stdin() {
+    let output = dsrs(&["--prompt", "-"], &"a ".repeat(17_000));
+    assert!(stderr(&output).contains("PromptTooLong(17101, 16385)"));
+}
+
+#[test]
+fn test_prompt_flag_ignores_stdin() {
+    // Only the flag is counted; piped stdin isn't appended to it
+    let flag = "a ".repeat(17_000);
+    let output = dsrs(&["--prompt", flag.trim_end()], &"a ".repeat(9_000));
+    let message = stderr(&output);
+    assert!(message.contains("PromptTooLong(17100, 16385)"), "{message}");
+    assert!(
+        message
+            .starts_with("Warning: ignoring stdin; pass --prompt - to read the prompt from it\n"),
+        "{message}"
+    );
+}
+
+#[test]
+fn test_prompt_from_file() {
+    let path = std::env::temp_dir().join(format!("dsrs-cli-{}.txt", std::process::id()));
+    std::fs::write(&path, "a ".repeat(17_000)).unwrap();
+    // Piped stdin is ignored when a file is given
+    let output = dsrs(&["--prompt-file", path.to_str().unwrap()], "ignored");
+    std::fs::remove_file(&path).unwrap();
+    assert!(stderr(&output).contains("PromptTooLong(17101, 16385)"));
+}
+
+#[test]
+fn test_batch_prints_one_line_per_prompt() {
+    let path = std::env::temp_dir().join(format!("dsrs-cli-batch-{}.txt", std::process::id()));
+    let lines = ["a ".repeat(17_000), String::new(), "a ".repeat(18_000)];
+    std::fs::write(&path, lines.join("\n")).unwrap();
+    let output = dsrs(&["--prompt-file", path.to_str().unwrap(), "--batch"], "");
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "Error: Prompt too long: 17101 tokens (max: 16385)\n\
+         Error: Prompt too long: 18101 tokens (max: 16385)\n"
+    );
+    assert!(stderr(&output).contains("2 of 2 prompts failed"));
+}
+
+/// The cache key of `dsrs --prompt <prompt>` with the flags [`dsrs`] passes.
+fn cache_key(prompt: &str) -> String {
+    let request = ChatRequest {
+        model: "gpt-3.5-turbo".to_string(),
+        messages: vec![Message::user(prompt)],
+        max_tokens: Some(100),
+        temperature: Some(0.0),
+        stream: None,
+        modalities: None,
+        audio: None,
+        response_format: None,
+        n: None,
+        tools: None,
+        tool_choice: None,
+        top_p: None,
+        frequency_penalty: None,
+        presence_penalty: None,
+        stop: None,
+    };
+    request.stable_hash_hex()
+}
+
+#[test]
+fn test_chat_keeps_the_conversation_until_reset() {
+    let dir = cache_dir("chat");
+    let cache = DiskCache::new(&dir);
+    let completion = Completion {
+        text: "Paris".to_string(),
+        model: None,
+        usage: None,
+        finish_reason: Some("stop".to_string()),
+    };
+    cache.put(&cache_key("Capital of France?"), &completion);
+
+    // Only the first turn of a conversation is cached; the second one
+    // includes the first, so it goes to the unreachable endpoint and fails
+    // without ending the session
+    let output = dsrs_with_cache(
+        &dir,
+        &["chat"],
+        "Capital of France?\nAnd of Spain?\n/reset\nCapital of France?\n/quit\nignored\n",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Paris\nParis\n");
+    assert!(stderr(&output).contains("Error: "));
+    assert!(stderr(&output).contains("Conversation cleared"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_config_show_merges_files_environment_and_flags() {
+    let dir = cache_dir("config");
+    std::fs::create_dir_all(dir.join("dsrs")).unwrap();
+    let file = dir.join("dsrs").join("config.toml");
+    let run = |config: &str| {
+        std::fs::write(&file, config).unwrap();
+        Command::new(env!("CARGO_BIN_EXE_dsrs"))
+            .args(["config", "show", "--timeout", "20"])
+            .env("XDG_CONFIG_HOME", &dir)
+            .env("LLM_TEMPERATURE", "0.7")
+            .env_remove("LLM_MODEL")
+            .env_remove("LLM_ENDPOINT")
+            .env_remove("LLM_MAX_TOKENS")
+            .env_remove("LLM_TIMEOUT")
+            .output()
+            .unwrap()
+    };
+
+    let output = run("model = \"llama3\"\ntemperature = 0.5\ntimeout = 10\n");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!(
+            "# from {}\nmodel = \"llama3\"\nmax_tokens = 1000\ntemperature = 0.7\ntimeout = 20\n",
+            file.display()
+        )
+    );
+
+    let output = run("temperature = warm\n");
+    assert!(!output.status.success());
+    let message = stderr(&output);
+    assert!(message.contains(&file.display().to_string()), "{message}");
+    assert!(message.contains("`temperature`"), "{message}");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_cached_response_skips_the_api() {
+    let dir = cache_dir("hit");
+    let cache = DiskCache::new(&dir);
+    let completion = Completion {
+        text: "Paris".to_string(),
+        model: None,
+        usage: None,
+        finish_reason: Some("stop".to_string()),
+    };
+    cache.put(&cache_key("Capital of France?"), &completion);
+
+    // The endpoint is unreachable, so only the cache can answer. Piped
+    // output has no "Response:" prefix
+    let output = dsrs_with_cache(&dir, &["--prompt", "Capital of France?"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Paris\n");
+
+    let output = dsrs_with_cache(&dir, &["cache", "clear"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(cache.get(&cache_key("Capital of France?")), None);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_json_output_schema() {
+    let dir = cache_dir("json");
+    let completion = Completion {
+        text: "Paris".to_string(),
+        model: Some("gpt-3.5-turbo-0125".to_string()),
+        usage: Some(Usage {
+            prompt_tokens: 12,
+            completion_tokens: 1,
+            total_tokens: 13,
+        }),
+        finish_reason: Some("stop".to_string()),
+    };
+    DiskCache::new(&dir).put(&cache_key("Capital of France?"), &completion);
+
+    let output = dsrs_with_cache(
+        &dir,
+        &["--prompt", "Capital of France?", "--output", "json"],
+        "",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let keys: Vec<&str> = json
+        .as_object()
+        .unwrap()
+        .keys()
+        .map(String::as_str)
+        .collect();
+    assert_eq!(
+        keys,
+        ["elapsed_ms", "finish_reason", "model", "text", "usage"]
+    );
+    assert_eq!(json["text"], "Paris");
+    assert_eq!(json["model"], "gpt-3.5-turbo-0125");
+    assert_eq!(json["finish_reason"], "stop");
+    assert_eq!(
+        json["usage"],
+        serde_json::json!({"prompt_tokens": 12, "completion_tokens": 1, "total_tokens": 13})
+    );
+    assert!(json["elapsed_ms"].is_u64());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_truncated_response_warns() {
+    let dir = cache_dir("truncated");
+    let completion = Completion {
+        text: "Once upon a".to_string(),
+        model: None,
+        usage: None,
+        finish_reason: Some("length".to_string()),
+    };
+    DiskCache::new(&dir).put(&cache_key("Tell me a story"), &completion);
+
+    let output = dsrs_with_cache(&dir, &["--prompt", "Tell me a story"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Once upon a\n");
+    assert_eq!(
+        stderr(&output),
+        "Warning: response was cut off by --max-tokens\n"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_json_output_reports_errors_on_stderr() {
+    let output = dsrs(&["--output", "json"], &"a ".repeat(17_000));
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let json: serde_json::Value = serde_json::from_str(stderr(&output).trim()).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "error": {
+                "kind": "prompt_too_long",
+                "message": "Prompt too long: 17101 tokens (max: 16385)"
+            }
+        })
+    );
+}
+
+#[test]
+fn test_max_cost_refuses_requests_over_budget() {
+    // 100 completion tokens of gpt-3.5-turbo alone cost $0.00015
+    let output = dsrs(
+        &["--prompt", "hi", "--max-cost", "0.0001", "--output", "json"],
+        "",
+    );
+    assert_eq!(output.status.code(), Some(1));
+    let json: serde_json::Value = serde_json::from_str(stderr(&output).trim()).unwrap();
+    assert_eq!(json["error"]["kind"], "budget_exceeded");
+}
+
+#[test]
+fn test_missing_prompt_is_config_error() {
+    let output = dsrs(&[], "");
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("No prompt given"));
+}
+
+#[test]
+fn test_history_show_prints_the_last_records() {
+    let dir = cache_dir("history");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("trace.jsonl");
+    let record = |question: &str, answer: &str| TraceRecord {
+        timestamp_ms: 1_700_000_000_000,
+        model: "gpt-4o".to_string(),
+        messages: vec![Message::user(question)],
+        params: CompletionParams::default(),
+        responses: vec![answer.to_string()],
+        usage: Some(Usage {
+            prompt_tokens: 9,
+            completion_tokens: 1,
+            total_tokens: 10,
+        }),
+        latency_ms: 420,
+        error: None,
+    };
+    let lines: Vec<String> = [
+        record("First?", "One"),
+        record("Capital of France?", "Paris"),
+    ]
+    .iter()
+    .map(|record| serde_json::to_string(record).unwrap())
+    .collect();
+    std::fs::write(&path, lines.join("\n") + "\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_dsrs"))
+        .args(["history", "show", "--last", "1"])
+        .env("DSRS_TRACE", &path)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "== 2023-11-14 22:13:20 UTC gpt-4o (420 ms, 9 prompt + 1 completion tokens)\n\
+         user: Capital of France?\n\
+         assistant: Paris\n"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}